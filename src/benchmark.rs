@@ -6,7 +6,8 @@ mod tests {
     use std::{
         ops::Div,
         path::PathBuf,
-        time::{Instant},
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
     };
 
     fn rand_string(length: usize) -> String {
@@ -19,7 +20,7 @@ mod tests {
 
     #[test]
     fn benchmark_tiny_objects() {
-        let dir_path = PathBuf::from("testdata");
+        let dir_path = PathBuf::from("testdata/benchmark");
         let _ = std::fs::remove_dir_all(&dir_path);
         std::fs::create_dir_all(&dir_path).unwrap();
         const SIZE: usize = 100000;
@@ -27,11 +28,11 @@ mod tests {
         const VALUE_LEN: usize = 100;
         let mut cases: Vec<(String, String)> = Vec::new();
         for i in 0..SIZE {
-            cases.push((format!("{:016}", i), rand_string(VALUE_LEN)));
+            cases.push((format!("{:0width$}", i, width = KEY_LEN), rand_string(VALUE_LEN)));
         }
         {
             let start_time = Instant::now();
-            let mut database = Database::open("testdata", Options::default()).unwrap();
+            let mut database = Database::open("testdata/benchmark", Options::default()).unwrap();
             for (key, value) in cases.iter() {
                 database.write(key.as_bytes(), value.as_bytes()).unwrap();
             }
@@ -43,11 +44,27 @@ mod tests {
                 1.0 / avg_elapsed.as_secs_f64()
             );
         }
+        {
+            let _ = std::fs::remove_dir_all("testdata/benchmark-batch");
+            let start_time = Instant::now();
+            let mut database = Database::open("testdata/benchmark-batch", Options::default()).unwrap();
+            for chunk in cases.chunks(1000) {
+                let pairs: Vec<(&[u8], &[u8])> = chunk.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())).collect();
+                database.write_many(&pairs).unwrap();
+            }
+            let elapsed = Instant::elapsed(&start_time);
+            let avg_elapsed = elapsed.div(SIZE as u32);
+            println!(
+                "batch write {:?} ns/ops {:.3} ops/s",
+                avg_elapsed.as_nanos(),
+                1.0 / avg_elapsed.as_secs_f64()
+            );
+        }
         {
             use rand::seq::SliceRandom;
             cases.shuffle(&mut rand::thread_rng());
             let mut start_time = Instant::now();
-            let database = Database::open("testdata", Options::default()).unwrap();
+            let database = Database::open("testdata/benchmark", Options::default()).unwrap();
             let elapsed = Instant::elapsed(&start_time);
             let avg_elapsed = elapsed.div(SIZE as u32);
             println!("load  {:.3} records/s", 1.0 / avg_elapsed.as_secs_f64());
@@ -70,4 +87,105 @@ mod tests {
             );
         }
     }
+
+    struct MixedWorkload {
+        threads: usize,
+        ops_per_thread: usize,
+        read_percent: u32,
+        value_len: usize,
+        mmap: bool,
+    }
+
+    // percentile of sorted latencies, p in [0, 100]
+    fn percentile(sorted: &[Duration], p: f64) -> Duration {
+        let rank = ((sorted.len() - 1) as f64 * p / 100.0).round() as usize;
+        sorted[rank]
+    }
+
+    // run_mixed shares one database between threads, writers take the write lock so contention shows up in latencies
+    fn run_mixed(name: &str, workload: &MixedWorkload) {
+        const KEY_SPACE: usize = 10000;
+        let dir_path = format!("testdata/benchmark-{}", name);
+        let _ = std::fs::remove_dir_all(&dir_path);
+        let mut database = Database::open(&dir_path, Options::default().mmap(workload.mmap)).unwrap();
+        let value = rand_string(workload.value_len);
+        for i in 0..KEY_SPACE {
+            database.write(format!("{:016}", i).as_bytes(), value.as_bytes()).unwrap();
+        }
+        drop(database);
+        let database = Arc::new(RwLock::new(
+            Database::open(&dir_path, Options::default().mmap(workload.mmap)).unwrap(),
+        ));
+
+        let start_time = Instant::now();
+        let mut latencies: Vec<Duration> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..workload.threads)
+                .map(|_| {
+                    let database = database.clone();
+                    let value = value.as_bytes();
+                    s.spawn(move || {
+                        use rand::Rng;
+                        let mut rng = rand::thread_rng();
+                        let mut latencies: Vec<Duration> = Vec::with_capacity(workload.ops_per_thread);
+                        for _ in 0..workload.ops_per_thread {
+                            let key = format!("{:016}", rng.gen_range(0..KEY_SPACE));
+                            let op_start = Instant::now();
+                            if rng.gen_range(0..100) < workload.read_percent {
+                                database.read().unwrap().read(key.as_bytes()).unwrap();
+                            } else {
+                                database.write().unwrap().write(key.as_bytes(), value).unwrap();
+                            }
+                            latencies.push(op_start.elapsed());
+                        }
+                        latencies
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        let elapsed = start_time.elapsed();
+        latencies.sort();
+        println!(
+            "{} threads={} read={}% value={}B mmap={}: {:.3} ops/s p50 {:?} p99 {:?} p999 {:?} max {:?}",
+            name,
+            workload.threads,
+            workload.read_percent,
+            workload.value_len,
+            workload.mmap,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(&latencies, 50.0),
+            percentile(&latencies, 99.0),
+            percentile(&latencies, 99.9),
+            latencies.last().unwrap(),
+        );
+    }
+
+    // runs with the default SyncPolicy::Never, every write is pushed to the operating system without fsync.
+    // It takes long, run it with cargo test --release benchmark_concurrent_mixed -- --ignored
+    #[test]
+    #[ignore]
+    fn benchmark_concurrent_mixed() {
+        let mut cases: Vec<(String, MixedWorkload)> = Vec::new();
+        for threads in [1, 4] {
+            for value_len in [100, 4096] {
+                for mmap in [false, true] {
+                    for read_percent in [50, 95] {
+                        cases.push((
+                            format!("mixed-t{}-v{}-m{}-r{}", threads, value_len, mmap, read_percent),
+                            MixedWorkload {
+                                threads,
+                                ops_per_thread: 20000 / threads,
+                                read_percent,
+                                value_len,
+                                mmap,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        for (name, workload) in cases.iter() {
+            run_mixed(name, workload);
+        }
+    }
 }
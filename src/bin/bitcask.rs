@@ -0,0 +1,94 @@
+// bitcask runs maintenance operations on a database directory.
+// The database must not be opened by another process meanwhile.
+//
+// usage: bitcask <command> <dir> [--redact]
+//   dump           print live records in key order, --redact prints value lengths instead of values
+//   rebuild-hints  rewrite hint files of all sealed segments
+//   segments       print files of segments with their size, garbage and hint
+//   stats          print memory estimate of the index and garbage of segments
+use std::process::ExitCode;
+
+use bitcask_core::{Database, Options};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(command), Some(dir)) = (args.get(1), args.get(2)) else {
+        eprintln!("usage: bitcask <command> <dir> [--redact]");
+        return ExitCode::FAILURE;
+    };
+    let redact = args.get(3).is_some_and(|flag| flag == "--redact");
+    let result = match command.as_str() {
+        "dump" => dump(dir, redact),
+        "rebuild-hints" => rebuild_hints(dir),
+        "segments" => segments(dir),
+        "stats" => stats(dir),
+        _ => {
+            eprintln!("unknown command {}", command);
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}: {:#}", command, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn dump(dir: &str, redact: bool) -> anyhow::Result<()> {
+    let mut database = Database::open(dir, Options::default())?;
+    if redact {
+        database.set_redactor(|_, value| Some(format!("<{} bytes>", value.len()).into_bytes()));
+    }
+    database.dump(|key, value| {
+        println!("{:?} {:?}", key, value);
+        Ok(())
+    })
+}
+
+fn rebuild_hints(dir: &str) -> anyhow::Result<()> {
+    let database = Database::open(dir, Options::default())?;
+    database.rebuild_hints()?;
+    println!("rebuilt hint files in {}", dir);
+    Ok(())
+}
+
+fn stats(dir: &str) -> anyhow::Result<()> {
+    let database = Database::open(dir, Options::default())?;
+    let stats = database.index_stats();
+    println!("entries: {}", stats.entries);
+    println!("key bytes: {}", stats.key_bytes);
+    println!("segment name bytes: {}", stats.segment_name_bytes);
+    println!("overhead bytes: {}", stats.overhead_bytes);
+    println!("prefix saved bytes: {}", stats.prefix_saved_bytes);
+    println!("estimated index bytes: {}", stats.estimated_bytes());
+    println!("bytes per entry: {}", stats.bytes_per_entry());
+    let stats = database.stats()?;
+    match stats.last_merge_ms {
+        Some(ms) => println!("last merge: {} ms since epoch", ms),
+        None => println!("last merge: never"),
+    }
+    for segment in stats.segments.iter() {
+        println!("segment {}: {} records, {} garbage", segment.segment, segment.records, segment.garbage());
+    }
+    Ok(())
+}
+
+fn segments(dir: &str) -> anyhow::Result<()> {
+    let database = Database::open(dir, Options::default())?;
+    for segment in database.segments()? {
+        println!(
+            "{}: {} bytes, {} records, {} live, ~{} garbage bytes, created {}{}{}",
+            segment.path.display(),
+            segment.bytes,
+            segment.records,
+            segment.live,
+            segment.garbage_bytes,
+            segment.created_secs,
+            if segment.has_hint { ", hint" } else { "" },
+            if segment.active { ", active" } else { "" },
+        );
+    }
+    Ok(())
+}
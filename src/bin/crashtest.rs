@@ -0,0 +1,142 @@
+// crashtest runs random workloads in child processes, kills them at random points,
+// then reopens the directory and validates that acknowledged writes survived and
+// deleted keys did not resurrect.
+//
+// usage: crashtest [rounds] [dir]
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use bitcask_core::{Database, Options};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const KEY_SPACE: u64 = 500;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some("child") {
+        child(&args[2], args[3].parse().unwrap());
+        return;
+    }
+    let rounds: u64 = args.get(1).map(|s| s.parse().unwrap()).unwrap_or(20);
+    let dir = args.get(2).cloned().unwrap_or_else(|| "testdata/crashtest".to_string());
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut rng = rand::thread_rng();
+    // model of acknowledged state, None means deleted
+    let mut model: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for round in 0..rounds {
+        let seed: u64 = rng.gen();
+        let in_flight = run_child(&dir, seed, rng.gen_range(10..300), &mut model);
+        validate(&dir, &model, in_flight.as_ref(), round);
+        // the in-flight operation may or may not have landed, take what is on disk
+        if let Some((key, _)) = in_flight {
+            let database = Database::open(&dir, Options::default()).unwrap();
+            let value = database.read(key.as_bytes()).unwrap().map(|v| v.to_string());
+            model.insert(key, value);
+        }
+        println!("round {} ok, seed {}, {} keys tracked", round, seed, model.len());
+    }
+}
+
+// run_child kills child after run_ms and applies acknowledged operations to model,
+// returns the operation which was started but not acknowledged
+fn run_child(
+    dir: &str,
+    seed: u64,
+    run_ms: u64,
+    model: &mut BTreeMap<String, Option<String>>,
+) -> Option<(String, Option<String>)> {
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["child", dir, &seed.to_string()])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel::<String>();
+    let reader = thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => tx.send(line).unwrap(),
+                Err(_) => break,
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(run_ms));
+    let _ = child.kill();
+    let _ = child.wait();
+    reader.join().unwrap();
+
+    let mut in_flight: Option<(String, Option<String>)> = None;
+    for line in rx.try_iter() {
+        let parts: Vec<&str> = line.split(' ').collect();
+        match parts.as_slice() {
+            ["B", "W", key, value] => in_flight = Some((key.to_string(), Some(value.to_string()))),
+            ["B", "D", key] => in_flight = Some((key.to_string(), None)),
+            ["B", "M"] => in_flight = None,
+            ["A"] => {
+                if let Some((key, value)) = in_flight.take() {
+                    model.insert(key, value);
+                }
+            }
+            _ => {} // torn line of killed child
+        }
+    }
+    in_flight
+}
+
+fn validate(
+    dir: &str,
+    model: &BTreeMap<String, Option<String>>,
+    in_flight: Option<&(String, Option<String>)>,
+    round: u64,
+) {
+    let database = Database::open(dir, Options::default()).unwrap();
+    for (key, expected) in model.iter() {
+        let actual = database.read(key.as_bytes()).unwrap().map(|v| v.to_string());
+        if actual == *expected {
+            continue;
+        }
+        if let Some((k, v)) = in_flight {
+            if k == key && actual == *v {
+                continue;
+            }
+        }
+        panic!(
+            "round {}: key {} expected {:?}, found {:?}",
+            round, key, expected, actual
+        );
+    }
+}
+
+// child prints "B <op>" before and "A" after every operation, so parent knows what is acknowledged
+fn child(dir: &str, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut database = Database::open(dir, Options::default()).unwrap();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for seq in 0u64.. {
+        let key = format!("k{}", rng.gen_range(0..KEY_SPACE));
+        let op: u32 = rng.gen_range(0..1000);
+        if op < 2 {
+            writeln!(out, "B M").unwrap();
+            out.flush().unwrap();
+            database.merge().unwrap();
+        } else if op < 200 {
+            writeln!(out, "B D {}", key).unwrap();
+            out.flush().unwrap();
+            database.delete(key.as_bytes()).unwrap();
+        } else {
+            let value = format!("{}-{}-{}", seed, seq, "x".repeat(rng.gen_range(0..200)));
+            writeln!(out, "B W {} {}", key, value).unwrap();
+            out.flush().unwrap();
+            database.write(key.as_bytes(), value.as_bytes()).unwrap();
+        }
+        writeln!(out, "A").unwrap();
+        out.flush().unwrap();
+    }
+}
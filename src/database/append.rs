@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::database::Database;
+use crate::{
+    error::invalid_input,
+    raw::RawRecord,
+    storage::{
+        segment::{parse_raw_record, raw_key},
+        Bytes, RecordIndex,
+    },
+};
+
+/*
+ * Raw records are appended to the log like writes but are not indexed, applications keep their own
+ * index of locations, e.g. time series buckets. They are durable as writes are under the sync policy.
+ * Merge retains only records of the keydir, so a location is valid until its segment is merged away.
+ */
+
+// Location of a raw record, it is stable as long as its segment exists
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub segment: String,
+    pub offset: u64,
+}
+
+impl Database {
+    // append_raw appends a record the keydir, secondary indexes and key filter do not see, metadata is
+    // application defined. Reads and writes of key are not affected.
+    pub fn append_raw(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, metadata: u8) -> Result<Location> {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        self.throttle((key.len() + value.len()) as u64)?;
+        let idx = self.storage.write_control(&raw_key(key), value, metadata)?;
+        Ok(Location {
+            segment: idx.segment.to_string(),
+            offset: idx.offset,
+        })
+    }
+
+    // read_location reads the raw record at location and verifies its checksum, a location of anything
+    // but a raw record is invalid
+    pub fn read_location(&self, location: &Location) -> Result<RawRecord> {
+        let idx = RecordIndex {
+            key: Bytes::new(),
+            segment: Arc::from(location.segment.as_str()),
+            flag: 0,
+            offset: location.offset,
+            value_size: 0,
+            value: None,
+            version: 0,
+        };
+        let record = self.storage.read_at(&idx)?;
+        let key = parse_raw_record(&record).ok_or_else(|| invalid_input("no raw record at location"))?;
+        Ok(RawRecord {
+            offset: location.offset,
+            key: Bytes::from(key.to_vec()),
+            value: record.value,
+            deleted: false,
+            metadata: record.metadata,
+        })
+    }
+}
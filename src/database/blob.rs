@@ -0,0 +1,70 @@
+use std::{collections::BTreeMap, fs, sync::Arc};
+
+use anyhow::Result;
+
+use super::{database::Database, stats::record_bytes};
+use crate::{error::invalid_input, storage::RecordIndex};
+
+/*
+ * Values of at least Options::blob_threshold bytes live in blob segments <n>.blob, the log holds a pointer
+ * to each of them, see storage/directory.rs. Merge copies pointers only, so a large value is not rewritten
+ * by every merge and does not fill up the active segment. The garbage of a blob segment is its bytes
+ * which no index entry points to, compact_blobs rewrites the live blobs of segments mostly garbage.
+ *
+ * Blob segments are the only files removed while a database is open. compact_blobs takes the database
+ * mutably, so no scan, snapshot or checkpoint of this process runs meanwhile, and records already read
+ * stay readable. Another process, e.g. a Follower, may find a blob segment gone and must read the
+ * log on to the pointer to the moved blob.
+ */
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlobCompactionStats {
+    pub segments_compacted: u64, // removed blob segments
+    pub blobs_moved: u64,
+    pub bytes_reclaimed: u64, // garbage of removed blob segments
+}
+
+impl Database {
+    // compact_blobs moves live blobs of blob segments whose garbage is above Options::blob_garbage_ratio
+    // of their size to the active blob segment and removes those segments. Active blob segment is sealed
+    // first, so every blob segment is a candidate. Moved keys keep their versions.
+    pub fn compact_blobs(&mut self) -> Result<BlobCompactionStats> {
+        if self.index.filter.is_some() {
+            return Err(invalid_input("blob compaction of a key filtered database would drop blobs of other keys"));
+        }
+        // blobs only known to segments are not indexed yet, compaction would drop them
+        self.wait_hydrated()?;
+        let blobs = self.storage.freeze_blobs()?;
+        let mut live: BTreeMap<Arc<str>, Vec<RecordIndex>> =
+            blobs.iter().map(|(name, _)| (Arc::from(name.as_str()), Vec::new())).collect();
+        self.index.map.read().unwrap().for_each(|record_index| {
+            if let Some(records) = live.get_mut(&record_index.segment) {
+                records.push(record_index);
+            }
+            Ok(())
+        })?;
+        let mut stats = BlobCompactionStats::default();
+        for (name, path) in blobs {
+            let mut records = live.remove(name.as_str()).unwrap_or_default();
+            let size = fs::metadata(&path)?.len();
+            let garbage = size.saturating_sub(records.iter().map(record_bytes).sum());
+            if garbage as f64 <= size as f64 * self.blob_garbage_ratio {
+                continue;
+            }
+            // blobs are read front to back
+            records.sort_unstable_by_key(|record_index| record_index.offset);
+            for record_index in records.iter() {
+                let record = self.storage.read_at(record_index)?;
+                let moved = self.storage.write_blob(&record_index.key, &record.value, record.metadata)?;
+                self.index.relocate(moved)?;
+                stats.blobs_moved += 1;
+            }
+            // moved blobs and their pointers must be durable before the segment is gone
+            self.storage.sync()?;
+            self.storage.remove_blob(&name)?;
+            stats.segments_compacted += 1;
+            stats.bytes_reclaimed += garbage;
+        }
+        Ok(stats)
+    }
+}
@@ -1,118 +1,879 @@
-use std::path::{PathBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::{Ok, Result};
 
 use crate::{
-    storage::{directory::Directory, segment::Segment, Bytes, HINT_EXT_NAME},
-    utils::utils::file_exists,
+    error::{corruption, invalid_input, is_corruption, is_segment_not_found, locate, StoreError},
+    storage::{
+        checksum::ChecksumAlgorithm,
+        directory::Directory,
+        io_stats::IoStats,
+        segment::{BatchRecord, Segment},
+        Bytes, Record, RecordIndex, FLAG_DELETED,
+    },
+    utils::{
+        clock::{Clock, SystemClock},
+        throttle::RateLimiter,
+        utils::file_exists,
+    },
+};
+
+use super::{
+    hint::{hint_path, HintAudit, HintAuditReport, HintWriter},
+    hydration::{hydrate, replay_segment},
+    index::Index,
+    invalidate::{Invalidator, WriteOp},
+    redact::Redactor,
+    secondary::SecondaryIndex,
+    stats::StatsCache,
+    tail::TailSignal,
 };
 
-use super::{index::Index, merge::MERGE_FINISH_FILENAME};
+// SyncPolicy decides when written records are fsynced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    #[default]
+    Never, // records are pushed to the operating system, sealed segments are synced on rotation
+    Always, // every write call returns after it is durable with one fsync, a batch shares it, so does an AsyncWriter batch
+}
+
+// Backpressure decides what a write does when it is throttled or the write queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    #[default]
+    Block,
+    Error, // fail with WriteThrottled, the caller may retry later
+}
 
 #[derive(Debug, Clone)]
 pub struct Options {
     mmap: bool,
+    mmap_max_segment_bytes: Option<u64>,
+    clock: Arc<dyn Clock>,
+    sync: SyncPolicy,
+    write_rate_limit: Option<u64>,
+    backpressure: Backpressure,
+    open_progress: Option<OpenProgressCallback>,
+    lazy_index: bool,
+    prefix_compressed_index: bool,
+    checksum: ChecksumAlgorithm,
+    read_repair: bool,
+    paranoid_checks: bool,
+    inline_values: Option<u64>,
+    key_filter: Option<KeyFilter>,
+    hint_audit: HintAudit,
+    persistent_index: bool,
+    checkpoint_every: usize,
+    blob_threshold: Option<u64>,
+    blob_garbage_ratio: f64,
 }
 
-impl Options {
-    pub fn default() -> Self {
+// OpenProgress is reported while Database::open loads index, once before the first segment
+// and once after each segment. A segment with hint file counts the bytes of its hint file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpenProgress {
+    pub files_scanned: usize,
+    pub files_total: usize,
+    pub bytes_processed: u64,
+    pub bytes_total: u64,
+}
+
+// OpenTimings tells where Database::open spent its time, in milliseconds of the clock of options.
+// Segments a lazy open leaves to the background are not in it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OpenTimings {
+    pub merge_adoption: Duration,
+    pub hint_audit: Duration,
+    pub index_load: Duration, // hint loads and segment scans
+    pub segments: Vec<SegmentLoadTiming>, // in load order
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentLoadTiming {
+    pub segment: String,
+    pub from_hint: bool, // false if the segment was scanned
+    pub duration: Duration,
+}
+
+#[derive(Clone)]
+struct OpenProgressCallback(Arc<dyn Fn(OpenProgress) + Send + Sync>);
+
+impl std::fmt::Debug for OpenProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenProgressCallback")
+    }
+}
+
+type KeyPredicate = dyn Fn(&[u8]) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub(super) struct KeyFilter(Arc<KeyPredicate>);
+
+impl KeyFilter {
+    pub(super) fn matches(&self, key: &[u8]) -> bool {
+        (self.0)(key)
+    }
+}
+
+impl std::fmt::Debug for KeyFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KeyFilter")
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
         Options {
             mmap: true,
+            mmap_max_segment_bytes: None,
+            clock: Arc::new(SystemClock),
+            sync: SyncPolicy::Never,
+            write_rate_limit: None,
+            backpressure: Backpressure::Block,
+            open_progress: None,
+            lazy_index: false,
+            prefix_compressed_index: false,
+            checksum: ChecksumAlgorithm::default(),
+            read_repair: false,
+            paranoid_checks: false,
+            inline_values: None,
+            key_filter: None,
+            hint_audit: HintAudit::Off,
+            persistent_index: false,
+            checkpoint_every: 100_000,
+            blob_threshold: None,
+            blob_garbage_ratio: 0.5,
         }
     }
+}
 
+impl Options {
     pub fn mmap(mut self, enable: bool) -> Self {
         self.mmap = enable;
         self
     }
+
+    // mmap_max_segment_bytes reads segments larger than max_bytes by pread though mmap is enabled,
+    // so many large segments do not exhaust address space. The active segment is mapped in steps of 4MB
+    // while it is not larger, its latest records are read by pread.
+    pub fn mmap_max_segment_bytes(mut self, max_bytes: u64) -> Self {
+        self.mmap_max_segment_bytes = Some(max_bytes);
+        self
+    }
+
+    // clock replaces system time, e.g. by SimClock in simulation
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    // write_rate_limit caps bytes of keys and values written per second, tombstones count by key
+    pub fn write_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.write_rate_limit = Some(bytes_per_sec);
+        self
+    }
+
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    // lazy_index makes open return once merged data is indexed, later segments are indexed in background.
+    // Until then reads of keys written after the last merge may miss or return stale values.
+    pub fn lazy_index(mut self, enable: bool) -> Self {
+        self.lazy_index = enable;
+        self
+    }
+
+    // checksum is the algorithm of records written from now on, records of any algorithm can be read
+    pub fn checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = algorithm;
+        self
+    }
+
+    // prefix_compressed_index front codes keys of in-memory index, it saves memory for keys with
+    // long shared prefixes at the cost of slower lookups and writes
+    pub fn prefix_compressed_index(mut self, enable: bool) -> Self {
+        self.prefix_compressed_index = enable;
+        self
+    }
+
+    // read_repair answers a read of a damaged record with the latest older copy of its key, which may be
+    // stale. Such reads are counted by Database::repaired_reads.
+    pub fn read_repair(mut self, enable: bool) -> Self {
+        self.read_repair = enable;
+        self
+    }
+
+    // paranoid_checks verifies on every read that the record is the one index entry describes, so a
+    // broken index is reported as corruption rather than answered with the value of another key
+    pub fn paranoid_checks(mut self, enable: bool) -> Self {
+        self.paranoid_checks = enable;
+        self
+    }
+
+    // inline_values keeps values of at most max_bytes in index entries, so reads of them do not touch disk.
+    // Values are inlined on write or on first read, records with metadata are not inlined.
+    // The prefix compressed index only keeps locations.
+    pub fn inline_values(mut self, max_bytes: u64) -> Self {
+        self.inline_values = Some(max_bytes);
+        self
+    }
+
+    // key_filter makes open index only keys it accepts, e.g. for tools which need one namespace of a large
+    // store. Reads and writes of other keys fail with NotIndexed, scans skip them and merge is refused,
+    // as it would drop their records.
+    pub fn key_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.key_filter = Some(KeyFilter(Arc::new(filter)));
+        self
+    }
+
+    // key_prefix is key_filter of keys starting with prefix
+    pub fn key_prefix(self, prefix: impl Into<Vec<u8>>) -> Self {
+        let prefix = prefix.into();
+        self.key_filter(move |key| key.starts_with(&prefix))
+    }
+
+    // hint_audit makes open check hint files against their segments, a bad hint would index wrong locations.
+    // The result is given by Database::hint_audit.
+    pub fn hint_audit(mut self, audit: HintAudit) -> Self {
+        self.hint_audit = audit;
+        self
+    }
+
+    // persistent_index keeps the index in a checkpoint file which open maps, so open replays only records
+    // written after the last checkpoint, see Database::checkpoint_index. Lookups decode a block of the file
+    // unless the key changed since. It can not be combined with lazy_index, prefix_compressed_index or
+    // key_filter.
+    pub fn persistent_index(mut self, enable: bool) -> Self {
+        self.persistent_index = enable;
+        self
+    }
+
+    // checkpoint_every makes a database with persistent_index write a checkpoint before a write once that many
+    // changes since the last one are kept in memory, 100000 by default. Dropping the database writes one too.
+    pub fn checkpoint_every(mut self, changes: usize) -> Self {
+        self.checkpoint_every = changes.max(1);
+        self
+    }
+
+    // blob_threshold writes values of at least min_bytes to blob segments, which merge does not copy.
+    // It applies to write_many and AsyncWriter too, pointers to blobs of a batch commit with the batch.
+    pub fn blob_threshold(mut self, min_bytes: u64) -> Self {
+        self.blob_threshold = Some(min_bytes);
+        self
+    }
+
+    // blob_garbage_ratio is the share of overwritten and deleted bytes above which compact_blobs rewrites
+    // a blob segment, 0.5 by default
+    pub fn blob_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.blob_garbage_ratio = ratio;
+        self
+    }
+
+    // on_open_progress is called on the opening thread, or on the background thread for lazy_index.
+    // It should return quickly.
+    pub fn on_open_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(OpenProgress) + Send + Sync + 'static,
+    {
+        self.open_progress = Some(OpenProgressCallback(Arc::new(callback)));
+        self
+    }
+}
+
+// WriteOptions holds per-record settings of Database::write_with_options
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    metadata: u8,
+}
+
+impl WriteOptions {
+    // metadata is an application defined byte stored in record header, e.g. content type or schema version
+    pub fn metadata(mut self, metadata: u8) -> Self {
+        self.metadata = metadata;
+        self
+    }
+}
+
+// result of Database::get, an explicitly stored empty value is Found with empty bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetResult {
+    Found(Bytes),
+    NotFound, // never written or deleted
+}
+
+impl GetResult {
+    pub fn is_found(&self) -> bool {
+        matches!(self, GetResult::Found(_))
+    }
+
+    pub fn into_option(self) -> Option<Bytes> {
+        match self {
+            GetResult::Found(value) => Some(value),
+            GetResult::NotFound => None,
+        }
+    }
+}
+
+// error of write_if_version, actual is 0 if key does not exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "version conflict: expected {}, actual {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+// error of a write rejected by rate limit or full write queue with Backpressure::Error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteThrottled;
+
+impl std::fmt::Display for WriteThrottled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "write throttled")
+    }
+}
+
+impl std::error::Error for WriteThrottled {}
+
+// IndexStats estimates memory taken by the in-memory index, which must fit in RAM.
+// key_bytes and segment_name_bytes are exact, overhead_bytes is an estimate of index structures.
+// key_bytes is the length of keys before prefix compression, prefix_saved_bytes are not stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IndexStats {
+    pub entries: u64,
+    pub key_bytes: u64,
+    pub segment_name_bytes: u64,
+    pub overhead_bytes: u64,
+    pub prefix_saved_bytes: u64,
+    pub inline_value_bytes: u64,
+}
+
+impl IndexStats {
+    pub fn estimated_bytes(&self) -> u64 {
+        self.key_bytes - self.prefix_saved_bytes + self.segment_name_bytes + self.overhead_bytes + self.inline_value_bytes
+    }
+
+    // average bytes per entry, multiply by expected key count to predict index size
+    pub fn bytes_per_entry(&self) -> u64 {
+        self.estimated_bytes().checked_div(self.entries).unwrap_or(0)
+    }
 }
 
 pub struct Database {
     pub(super) root_dir: PathBuf,
     pub(super) index: Index,
     pub(super) storage: Directory,
+    pub(super) secondary: BTreeMap<String, SecondaryIndex>,
+    pub(super) redactor: Option<Redactor>, // applied to values of exports and dumps
+    pub(super) invalidator: Option<Invalidator>,
+    pub(super) tail_signal: Arc<TailSignal>,
+    pub(super) clock: Arc<dyn Clock>,
+    pub(super) throttle: Option<RateLimiter>,
+    pub(super) backpressure: Backpressure,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_repair: bool,
+    pub(super) repaired_reads: AtomicU64,
+    pub(super) paranoid_checks: bool,
+    pub(super) inline_values: Option<u64>,
+    pub(super) blob_threshold: Option<u64>,
+    pub(super) blob_garbage_ratio: f64,
+    pub(super) checkpoint_every: usize,
+    pub(super) stats_cache: Arc<Mutex<StatsCache>>, // shared with hint_writer
+    // held from planning a merge to recording it, a second merge would rewrite merge dir under the first
+    pub(super) merge_lock: Arc<Mutex<()>>,
+    pub(super) hint_audit: HintAuditReport,
+    pub(super) open_timings: OpenTimings,
+    // declared after storage, which holds its sender, so its thread ends before it is joined
+    pub(super) hint_writer: HintWriter,
+}
+
+// a persistent index is checkpointed on drop, so the next open does not replay changes kept in memory
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.close_index();
+        self.tail_signal.close();
+    }
 }
 
 impl Database {
-    pub(super) fn get_merge_dir(root_dir: &PathBuf) -> PathBuf {
+    pub(super) fn get_merge_dir(root_dir: &Path) -> PathBuf {
         root_dir.join(PathBuf::from("merged"))
     }
 
-    pub(super) fn get_data_dir(root_dir: &PathBuf) -> PathBuf {
+    pub(super) fn get_data_dir(root_dir: &Path) -> PathBuf {
         root_dir.join(PathBuf::from("data"))
     }
 
     pub fn open(dir: &str, options: Options) -> Result<Self> {
+        if options.persistent_index && (options.lazy_index || options.prefix_compressed_index || options.key_filter.is_some()) {
+            return Err(invalid_input("persistent_index can not be combined with lazy_index, prefix_compressed_index or key_filter"));
+        }
         let root_dir = PathBuf::from(dir);
         let data_dir = Self::get_data_dir(&root_dir);
-        let mut index = Index::new();
+        let mut index = Index::new(options.prefix_compressed_index);
+        index.filter = options.key_filter;
+        let clock = options.clock;
+        let since = |start_ms: u64| Duration::from_millis(clock.now_millis().saturating_sub(start_ms));
+        let open_ms = clock.now_millis();
+        let mut timings = OpenTimings::default();
         Self::try_load_merged(&root_dir)?;
+        timings.merge_adoption = since(open_ms);
         std::fs::create_dir_all(&data_dir)?;
-        let storage = Directory::open(data_dir.to_str().unwrap(), options.mmap)?;
+        let storage = Directory::open(
+            data_dir.to_str().unwrap(),
+            options.mmap.then(|| options.mmap_max_segment_bytes.unwrap_or(u64::MAX)),
+            options.sync == SyncPolicy::Always,
+            options.checksum,
+        )?;
+        let audit_ms = clock.now_millis();
+        let hint_audit = Self::audit_hints(&storage, options.hint_audit)?;
+        timings.hint_audit = since(audit_ms);
         // bug fix: hint file exists but merged dir not exists
-        Self::load_index(&mut index, &data_dir, &storage)?;
+        let report = options.open_progress.map(|callback| callback.0);
+        let load_ms = clock.now_millis();
+        index.start_epoch(storage.active_segment_id());
+        timings.segments = if options.persistent_index {
+            Self::load_persistent_index(&mut index, &storage, &root_dir, report, clock.as_ref())?
+        } else {
+            Self::load_index(&mut index, &storage, report, options.lazy_index, clock.as_ref())?
+        };
+        timings.index_load = since(load_ms);
+        timings.total = since(open_ms);
+        let stats_cache = StatsCache::load(&data_dir);
+        let hint_writer = HintWriter::start(&storage, stats_cache.clone());
         Ok(Self {
             root_dir,
             index,
             storage,
+            secondary: BTreeMap::new(),
+            redactor: None,
+            invalidator: None,
+            tail_signal: Arc::default(),
+            clock,
+            throttle: options.write_rate_limit.map(RateLimiter::new),
+            backpressure: options.backpressure,
+            sync_policy: options.sync,
+            read_repair: options.read_repair,
+            repaired_reads: AtomicU64::new(0),
+            paranoid_checks: options.paranoid_checks,
+            inline_values: options.inline_values,
+            blob_threshold: options.blob_threshold,
+            blob_garbage_ratio: options.blob_garbage_ratio,
+            checkpoint_every: options.checkpoint_every,
+            stats_cache,
+            merge_lock: Arc::new(Mutex::new(())),
+            hint_audit,
+            open_timings: timings,
+            hint_writer,
         })
     }
 
-    pub fn write(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        let idx = self.storage.write(key, value, 0)?;
-        self.index.set(idx)
+    pub fn write(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        self.write_with_options(key, value, &WriteOptions::default())
+    }
+
+    pub fn write_with_options(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        self.check_indexed(key)?;
+        self.checkpoint_if_due()?;
+        self.throttle((key.len() + value.len()) as u64)?;
+        // old value is needed to unlink stale secondary index keys
+        let old_value = if self.secondary.is_empty() {
+            None
+        } else {
+            self.read(key)?
+        };
+        let mut idx = match self.blob_threshold {
+            Some(threshold) if value.len() as u64 >= threshold => self.storage.write_blob(key, value, options.metadata)?,
+            _ => self.storage.write(key, value, 0, options.metadata)?,
+        };
+        if options.metadata == 0 {
+            idx.value = self.inline(value);
+        }
+        self.index.set(idx)?;
+        self.update_secondary(key, old_value, Some(value));
+        self.invalidate(key, WriteOp::Write);
+        Ok(())
+    }
+
+    // write_many appends all pairs with one write and returns the new version of each pair,
+    // a later pair of the same key overwrites the former one. After a crash all pairs or none are loaded.
+    pub fn write_many(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<Vec<u64>> {
+        for (key, _) in pairs {
+            self.check_indexed(key)?;
+        }
+        self.checkpoint_if_due()?;
+        self.throttle(pairs.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum())?;
+        // old value is needed to unlink stale secondary index keys
+        let mut old_values: Vec<Option<Bytes>> = Vec::new();
+        if !self.secondary.is_empty() {
+            let mut pending: BTreeMap<&[u8], &[u8]> = BTreeMap::new();
+            for (key, value) in pairs {
+                let old_value = match pending.get(key) {
+                    Some(v) => Some(Bytes::from(v.to_vec())),
+                    None => self.read(key)?,
+                };
+                old_values.push(old_value);
+                pending.insert(key, value);
+            }
+        }
+        let records: Vec<BatchRecord> = pairs
+            .iter()
+            .map(|(key, value)| BatchRecord {
+                key,
+                value,
+                flag: 0,
+                metadata: 0,
+            })
+            .collect();
+        let mut indexes = self.storage.write_batch_with_blobs(&records, self.blob_threshold, true)?;
+        for (idx, (_, value)) in indexes.iter_mut().zip(pairs) {
+            idx.value = self.inline(value);
+        }
+        let versions = self.index.set_many(indexes)?;
+        for ((key, value), old_value) in pairs.iter().zip(old_values) {
+            self.update_secondary(key, old_value, Some(value));
+        }
+        for (key, _) in pairs {
+            self.invalidate(key, WriteOp::Write);
+        }
+        Ok(versions)
+    }
+
+    // sync returns once every write before it is durable, records are not buffered in user space so
+    // fsync of active segment is enough. With SyncPolicy::Always every write is durable already.
+    pub fn sync(&self) -> Result<()> {
+        self.storage.sync()
     }
 
-    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.storage.write(key, &[], crate::storage::FLAG_DELETED)?;
+    // returns whether the key existed, no tombstone is written for a missing key.
+    // While a lazy index is hydrating a missing key is deleted anyway and counts as existing.
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = key.as_ref();
+        self.check_indexed(key)?;
+        // a key missing from an index which is still hydrating may exist, its tombstone is written anyway
+        if self.index.get(key)?.is_none() && self.index.is_hydrated() {
+            return Ok(false);
+        }
+        self.checkpoint_if_due()?;
+        self.throttle(key.len() as u64)?;
+        let old_value = if self.secondary.is_empty() {
+            None
+        } else {
+            self.read(key)?
+        };
+        self.storage.write(key, &[], FLAG_DELETED, 0)?;
         self.index.delete(&Bytes::from(key.to_vec()))?;
+        self.update_secondary(key, old_value, None);
+        self.invalidate(key, WriteOp::Delete);
+        Ok(true)
+    }
+
+    // delete_many writes tombstones of all existing keys with one write and returns the number of deleted keys
+    pub fn delete_many(&mut self, keys: &[&[u8]]) -> Result<u64> {
+        for key in keys {
+            self.check_indexed(key)?;
+        }
+        let hydrated = self.index.is_hydrated();
+        let mut existing: BTreeSet<&[u8]> = BTreeSet::new();
+        for key in keys {
+            if !hydrated || self.index.get(key)?.is_some() {
+                existing.insert(key);
+            }
+        }
+        let existing: Vec<&[u8]> = existing.into_iter().collect();
+        if existing.is_empty() {
+            return Ok(0);
+        }
+        self.checkpoint_if_due()?;
+        self.throttle(existing.iter().map(|k| k.len() as u64).sum())?;
+        let old_values: Vec<Option<Bytes>> = if self.secondary.is_empty() {
+            Vec::new()
+        } else {
+            existing.iter().map(|key| self.read(key)).collect::<Result<_>>()?
+        };
+        let tombstones: Vec<BatchRecord> = existing
+            .iter()
+            .map(|key| BatchRecord {
+                key,
+                value: &[],
+                flag: FLAG_DELETED,
+                metadata: 0,
+            })
+            .collect();
+        self.storage.write_batch(&tombstones, true)?;
+        self.index.delete_many(&existing)?;
+        for (key, old_value) in existing.iter().zip(old_values) {
+            self.update_secondary(key, old_value, None);
+        }
+        for key in existing.iter() {
+            self.invalidate(key, WriteOp::Delete);
+        }
+        Ok(existing.len() as u64)
+    }
+
+    pub(super) fn throttle(&mut self, bytes: u64) -> Result<()> {
+        let backpressure = self.backpressure;
+        if let Some(limiter) = self.throttle.as_mut() {
+            match backpressure {
+                Backpressure::Block => limiter.acquire(bytes),
+                Backpressure::Error if !limiter.try_acquire(bytes) => return Err(WriteThrottled.into()),
+                Backpressure::Error => {}
+            }
+        }
         Ok(())
     }
 
-    pub fn read(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        if let Some(idx) = self.index.get(key) {
-            let record = self.storage.read_at(&idx)?;
-            return Ok(Some(record.value));
+    pub fn read(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        let key = key.as_ref();
+        Ok(self.get(key)?.into_option())
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<GetResult> {
+        let key = key.as_ref();
+        self.check_indexed(key)?;
+        if let Some(idx) = self.index.get(key)? {
+            let record = self.read_record(&idx)?;
+            return Ok(GetResult::Found(record.value));
+        }
+        Ok(GetResult::NotFound)
+    }
+
+    // write_if_version writes only if the current version of key equals expected_version and returns the new version,
+    // expected_version 0 means key must not exist. A mismatch fails with VersionConflict.
+    pub fn write_if_version(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        expected_version: u64,
+    ) -> Result<u64> {
+        let key = key.as_ref();
+        let value = value.as_ref();
+        self.check_indexed(key)?;
+        let actual = self.index.get(key)?.map(|idx| idx.version).unwrap_or(0);
+        if actual != expected_version {
+            return Err(VersionConflict {
+                expected: expected_version,
+                actual,
+            }
+            .into());
+        }
+        self.write(key, value)?;
+        Ok(self.index.get(key)?.map(|idx| idx.version).unwrap_or(0))
+    }
+
+    // returns value with its current version. Versions are kept in memory only, records loaded on open get new
+    // ones above those of earlier opens, unless a persistent index keeps them. A version is never given twice,
+    // so one read before reopen matches the same record or none.
+    pub fn read_with_version(&self, key: impl AsRef<[u8]>) -> Result<Option<(Bytes, u64)>> {
+        let key = key.as_ref();
+        self.check_indexed(key)?;
+        if let Some(idx) = self.index.get(key)? {
+            let record = self.read_record(&idx)?;
+            return Ok(Some((record.value, idx.version)));
         }
         Ok(None)
     }
 
-    pub(super) fn load_index(
-        index: &mut Index,
-        data_dir: &PathBuf,
-        directory: &Directory,
-    ) -> Result<()> {
-        let map = &mut *(index.map.write().unwrap());
-        let hint_file_path = data_dir.join(format!("{}.{}", 1, HINT_EXT_NAME));
-        let merge_finish_path = data_dir.join(MERGE_FINISH_FILENAME);
-        let max_merged_segment: u64;
-        if file_exists(&hint_file_path) {
-            let merge_finish_file = std::fs::read_to_string(&merge_finish_path)?;
-            max_merged_segment = merge_finish_file.trim().parse::<u64>()?;
-            let hint_file = Segment::open_read_only(hint_file_path);
-                for hint_index in hint_file.iter_with_value() {
-                    let record_index =
-                        Self::decode_record_index(hint_index.key.clone(), hint_index.value.unwrap())?;
-                    map.insert(record_index.key.clone(), record_index);
+    pub fn index_stats(&self) -> IndexStats {
+        self.index.stats()
+    }
+
+    // open_timings returns how long open took by phase and by segment
+    pub fn open_timings(&self) -> &OpenTimings {
+        &self.open_timings
+    }
+
+    // io_stats counts IO of segments since open, see IoStats
+    pub fn io_stats(&self) -> IoStats {
+        self.storage.io_stats()
+    }
+
+    // repaired_reads counts reads answered by an older copy since open, see Options::read_repair
+    pub fn repaired_reads(&self) -> u64 {
+        self.repaired_reads.load(Ordering::Relaxed)
+    }
+
+    // read_record reads the record index points to, a damaged one is repaired if read_repair is enabled
+    pub(super) fn read_record(&self, idx: &RecordIndex) -> Result<Record> {
+        if let Some(value) = idx.value.as_ref() {
+            return Ok(Record {
+                key: idx.key.clone(),
+                value: value.clone(),
+                flag: idx.flag,
+                metadata: 0,
+            });
+        }
+        let record = match self.storage.read_at(idx) {
+            Err(e) if is_segment_not_found(&e) => match self.moved(idx)? {
+                Some(moved) => return self.read_record(&moved),
+                None => return Err(e),
+            },
+            Err(e) if self.read_repair && is_corruption(&e) => match self.storage.read_previous(idx)? {
+                Some(record) => {
+                    self.repaired_reads.fetch_add(1, Ordering::Relaxed);
+                    record
                 }
+                None => return Err(e),
+            },
+            result => result?,
+        };
+        if self.paranoid_checks {
+            Self::check_record(idx, &record)?;
+        }
+        if record.metadata == 0 {
+            if let Some(value) = self.inline(&record.value) {
+                self.index.inline_value(idx, value);
+            }
+        }
+        Ok(record)
+    }
+
+    // moved returns the entry of the key of idx if a merge adopted since idx was looked up put its record elsewhere
+    pub(super) fn moved(&self, idx: &RecordIndex) -> Result<Option<RecordIndex>> {
+        Ok(self.index.get(idx.key.as_slice())?.filter(|current| current.segment != idx.segment))
+    }
+
+    // check_indexed fails with NotIndexed if the key filter leaves key out
+    pub(super) fn check_indexed(&self, key: &[u8]) -> Result<()> {
+        if self.index.filter.as_ref().is_some_and(|filter| !filter.matches(key)) {
+            return Err(StoreError::NotIndexed(key.to_vec()).into());
+        }
+        Ok(())
+    }
+
+    // inline returns value to keep in index entry, none if it is too large or inlining is disabled
+    fn inline(&self, value: &[u8]) -> Option<Bytes> {
+        self.inline_values
+            .filter(|max_bytes| value.len() as u64 <= *max_bytes)
+            .map(|_| Bytes::from(value))
+    }
+
+    // check_record fails if record is not of the key of index entry or their deleted flags differ
+    fn check_record(idx: &RecordIndex, record: &Record) -> Result<()> {
+        let detail = if record.key != idx.key {
+            "index entry points to a record of another key"
+        } else if (record.flag ^ idx.flag) & FLAG_DELETED > 0 {
+            "record flag is inconsistent with index entry"
         } else {
-            max_merged_segment = 0;
+            return Ok(());
+        };
+        Err(locate(corruption(detail), Some(&idx.segment), Some(idx.offset), Some(idx.key.as_slice())))
+    }
+
+    // returns value with the metadata byte it was written with, metadata is 0 if not set
+    pub fn read_with_metadata(&self, key: impl AsRef<[u8]>) -> Result<Option<(Bytes, u8)>> {
+        let key = key.as_ref();
+        self.check_indexed(key)?;
+        if let Some(idx) = self.index.get(key)? {
+            let record = self.read_record(&idx)?;
+            return Ok(Some((record.value, record.metadata)));
         }
-        
-        
+        Ok(None)
+    }
+
+    pub(super) fn load_index(
+        index: &mut Index,
+        directory: &Directory,
+        report: Option<Arc<dyn Fn(OpenProgress) + Send + Sync>>,
+        lazy: bool,
+        clock: &dyn Clock,
+    ) -> Result<Vec<SegmentLoadTiming>> {
         let internal = directory.internal.read().unwrap();
-        for (_, segment) in internal.old_segments.iter() {
-            if segment.index() <= max_merged_segment {
-                continue;
-            }
-            for record_index in segment.iter() {
-                if record_index.is_deleted() {
-                    map.remove(&record_index.key);
+        let mut segments: Vec<&Segment> = internal.old_segments.values().collect();
+        segments.sort_by_key(|s| s.index());
+
+        // hinted segments are read from their hint files
+        let file_len = |path: &Path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let lens: Vec<u64> = segments
+            .iter()
+            .map(|s| {
+                let hint = hint_path(&s.path());
+                if file_exists(&hint) {
+                    file_len(&hint)
                 } else {
-                    map.insert(record_index.key.clone(), record_index);
+                    file_len(&s.path())
                 }
+            })
+            .collect();
+        let mut progress = OpenProgress {
+            files_scanned: 0,
+            files_total: segments.len(),
+            bytes_processed: 0,
+            bytes_total: lens.iter().sum(),
+        };
+        let report_progress = |progress: OpenProgress| {
+            if let Some(report) = report.as_ref() {
+                report(progress);
             }
+        };
+        report_progress(progress);
+
+        // a lazy open indexes leading hinted segments only, replay must keep segment order
+        let eager = if lazy {
+            segments
+                .iter()
+                .take_while(|s| file_exists(hint_path(&s.path())))
+                .count()
+        } else {
+            segments.len()
+        };
+        let mut timings: Vec<SegmentLoadTiming> = Vec::with_capacity(eager);
+        for (segment, len) in segments.iter().zip(lens.iter()).take(eager) {
+            let start_ms = clock.now_millis();
+            let from_hint =
+                replay_segment(&index.map, &index.sequence, &index.timestamps, None, index.filter.as_ref(), segment)?;
+            timings.push(SegmentLoadTiming {
+                segment: segment.name(),
+                from_hint,
+                duration: Duration::from_millis(clock.now_millis().saturating_sub(start_ms)),
+            });
+            progress.files_scanned += 1;
+            progress.bytes_processed += len;
+            report_progress(progress);
         }
-        Ok(())
+        if eager < segments.len() {
+            let pending = segments
+                .iter()
+                .map(|s| s.path())
+                .zip(lens)
+                .skip(eager)
+                .collect();
+            hydrate(index, pending, progress, report);
+        }
+        Ok(timings)
     }
 }
@@ -0,0 +1,238 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use super::{database::Database, hint::read_hint, keydir::KeyDir};
+use crate::{
+    error::corruption,
+    storage::{
+        segment::{parse_segment_stem, Segment},
+        Bytes, RecordIndex, BLOB_EXT_NAME, SEG_EXT_NAME,
+    },
+    utils::utils::file_exists,
+};
+
+/*
+ * A follower reads the data directory of a database another process has open. It never writes, not
+ * even the MANIFEST, and its keydir trails the writer by one refresh.
+ *
+ * Refresh lists segment files. The writer only appends to its active segment, which is the segment of
+ * the highest index, so the segment that was the highest one is read on from where the last refresh
+ * stopped and segments of higher index are read from the start. A torn record or a batch without its
+ * commit yet ends reading, the next refresh reads it again. Anything else, a removed segment or a new
+ * one below the highest, e.g. adoption of merge output, makes refresh reload the keydir. A refresh
+ * while the writer adopts merge output may see both merged and replaced segments, the next one which
+ * sees replaced segments removed reloads.
+ * Blob segments are not listed, pointers in segments are indexed and a read of a blob opens its segment.
+ * The writer removes blob segments while open, see Database::compact_blobs, and segments replaced by
+ * merge output, so a read which finds its segment gone refreshes once to index the moved record.
+ */
+
+pub struct Follower {
+    state: Arc<FollowerState>,
+    // dropping the sender stops the refresh thread
+    stop: Option<Sender<()>>,
+}
+
+struct FollowerState {
+    data_dir: PathBuf,
+    inner: RwLock<FollowerInner>,
+}
+
+struct FollowerInner {
+    segments: BTreeMap<(u64, u64), FollowedSegment>, // by index and generation
+    keydir: KeyDir,
+}
+
+struct FollowedSegment {
+    segment: Segment,
+    len: u64,           // file length when it was last read
+    resume_offset: u64, // where reading goes on if the segment grows
+}
+
+impl Follower {
+    // open indexes the data directory of a database, e.g. one another process writes to. With
+    // refresh_interval a thread refreshes the follower until it is dropped, refresh errors are retried.
+    pub fn open(dir: &str, refresh_interval: Option<Duration>) -> Result<Follower> {
+        let data_dir = Database::get_data_dir(Path::new(dir));
+        let state = Arc::new(FollowerState {
+            inner: RwLock::new(FollowerInner::load(&data_dir)?),
+            data_dir,
+        });
+        let stop = refresh_interval.map(|interval| {
+            let (sender, receiver) = mpsc::channel::<()>();
+            let worker_state = state.clone();
+            thread::spawn(move || {
+                while receiver.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                    let _ = worker_state.refresh();
+                }
+            });
+            sender
+        });
+        Ok(Follower { state, stop })
+    }
+
+    pub fn read(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        let key = key.as_ref();
+        if let Some(value) = self.read_indexed(key)? {
+            return Ok(value);
+        }
+        // the writer compacted the blob segment away or merged the segment, a refresh indexes the moved record
+        self.state.refresh()?;
+        self.read_indexed(key)?.ok_or_else(|| corruption("segment of keydir entry not found"))
+    }
+
+    // read_indexed reads the value the keydir points to, none if its segment is gone
+    fn read_indexed(&self, key: &[u8]) -> Result<Option<Option<Bytes>>> {
+        let inner = self.state.inner.read().unwrap();
+        let Some(idx) = inner.keydir.get(key)? else {
+            return Ok(Some(None));
+        };
+        let id = parse_segment_stem(&idx.segment).ok_or_else(|| corruption("invalid segment name in keydir"))?;
+        if let Some(followed) = inner.segments.get(&(id.1, id.0)) {
+            return match followed.segment.read_at(idx.offset) {
+                Ok(record) => Ok(Some(Some(record.value))),
+                Err(_) if !file_exists(followed.segment.path()) => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+        // blobs are written before their pointers, so the blob segment of an indexed pointer has the blob
+        let blob_path = self.state.data_dir.join(format!("{}.{}", idx.segment, BLOB_EXT_NAME));
+        match Segment::open_read_only(blob_path.clone()).read_at(idx.offset) {
+            Ok(record) => Ok(Some(Some(record.value))),
+            Err(_) if !file_exists(&blob_path) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.inner.read().unwrap().keydir.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // refresh indexes records written since the last refresh
+    pub fn refresh(&self) -> Result<()> {
+        self.state.refresh()
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stop.take();
+    }
+}
+
+impl FollowerState {
+    fn refresh(&self) -> Result<()> {
+        let files = list_segments(&self.data_dir)?;
+        let mut inner = self.inner.write().unwrap();
+        if !inner.refresh(&files) {
+            *inner = FollowerInner::load(&self.data_dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl FollowerInner {
+    fn load(data_dir: &Path) -> Result<FollowerInner> {
+        let mut inner = FollowerInner {
+            segments: BTreeMap::new(),
+            keydir: KeyDir::new(false),
+        };
+        for (id, (path, len)) in list_segments(data_dir)? {
+            let segment = Segment::open_read_only(path);
+            segment.format_version()?;
+            let resume_offset = match read_hint(&segment.path()) {
+                Ok(Some(records)) => {
+                    inner.index(records.into_iter());
+                    len
+                }
+                // a damaged hint file is ignored, the segment is scanned instead
+                _ => inner.index_from(&segment, 0),
+            };
+            inner.segments.insert(id, FollowedSegment { segment, len, resume_offset });
+        }
+        Ok(inner)
+    }
+
+    // refresh reads on grown and new segments, it returns false if the keydir must be reloaded instead
+    fn refresh(&mut self, files: &BTreeMap<(u64, u64), (PathBuf, u64)>) -> bool {
+        let last = self.segments.keys().next_back().copied();
+        if self.segments.keys().any(|id| !files.contains_key(id)) {
+            return false;
+        }
+        for (id, (path, len)) in files {
+            match self.segments.get(id) {
+                Some(followed) if followed.len == *len => {}
+                Some(_) if Some(*id) != last => return false,
+                Some(_) => {
+                    let followed = self.segments.remove(id).unwrap();
+                    let resume_offset = self.index_from(&followed.segment, followed.resume_offset);
+                    self.segments.insert(*id, FollowedSegment { len: *len, resume_offset, ..followed });
+                }
+                None if last.is_some_and(|last| *id < last) => return false,
+                None => {
+                    let segment = Segment::open_read_only(path.clone());
+                    // the header of a just created segment may be incomplete, segments behind it wait too
+                    if segment.format_version().is_err() {
+                        break;
+                    }
+                    let resume_offset = self.index_from(&segment, 0);
+                    self.segments.insert(*id, FollowedSegment { segment, len: *len, resume_offset });
+                }
+            }
+        }
+        true
+    }
+
+    // index_from indexes records of segment from offset on and returns where to resume
+    fn index_from(&mut self, segment: &Segment, offset: u64) -> u64 {
+        let mut iter = segment.iter_from(offset);
+        self.index(iter.by_ref());
+        iter.resume_offset()
+    }
+
+    // the keydir of a follower is plain, it does not fail
+    fn index<I: Iterator<Item = RecordIndex>>(&mut self, records: I) {
+        for record_index in records {
+            let _ = if record_index.is_deleted() {
+                self.keydir.remove(record_index.key.as_slice())
+            } else {
+                self.keydir.insert(record_index)
+            };
+        }
+    }
+}
+
+// list_segments returns segment files by index and generation with their lengths
+fn list_segments(data_dir: &Path) -> Result<BTreeMap<(u64, u64), (PathBuf, u64)>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(data_dir)?.flatten() {
+        let path = entry.path();
+        if path.extension() != Some(OsStr::new(SEG_EXT_NAME)) {
+            continue;
+        }
+        let Some((generation, index)) = path.file_stem().and_then(OsStr::to_str).and_then(parse_segment_stem) else {
+            continue;
+        };
+        // a segment removed since read_dir is seen by the next refresh
+        if let Ok(metadata) = entry.metadata() {
+            files.insert((index, generation), (path, metadata.len()));
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+
+use super::{database::Database, stats::StatsCache};
+use crate::{
+    error::locate,
+    storage::{directory::Directory, segment::Segment, RecordIndex, FLAG_DELETED, HINT_EXT_NAME},
+    utils::utils::{file_exists, os_str_to_string, rename_durable},
+};
+
+/*
+ * Every sealed segment <n>.seg may have a hint file <n>.hint holding the location, value size and
+ * timestamp of each of its records, loader reads it instead of scanning the segment. A hint file is written to a temporary
+ * file and renamed, so an existing hint file is always complete.
+ *
+ * Hint file of former versions is one 1.hint for all merged segments, its entries carry their
+ * segment, so it is loaded as hint of segment 1 and the other merged segments are scanned.
+ */
+
+const HINT_TMP_EXT_NAME: &str = "hint.tmp";
+
+// HintAudit is how open checks hint files against their segments before loading them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HintAudit {
+    #[default]
+    Off,
+    Sample(usize), // every n-th entry is read from the segment it points to
+    Full,          // entries are compared with a scan of the segment, hints of former versions fail it
+}
+
+// HintAuditReport tells what the audit of open found. Failed hint files are removed, so their segments
+// are scanned and their hints written again.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HintAuditReport {
+    pub hints_checked: usize,
+    pub entries_checked: u64,
+    pub failed: Vec<String>, // segments of removed hint files
+}
+
+pub(super) fn hint_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension(HINT_EXT_NAME)
+}
+
+// records carry no write time, the modification time of their segment in seconds since epoch
+// is the timestamp of hint records, it is no earlier than any write to the segment
+pub(super) fn segment_timestamp(segment_path: &Path) -> u64 {
+    fs::metadata(segment_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+// is_same_kind compares flags of a record and its hint by deletion only. Checksum bits tell how each
+// file was written, a hint file has its own, and a scanned blob pointer has none.
+fn is_same_kind(a: u8, b: u8) -> bool {
+    a & FLAG_DELETED == b & FLAG_DELETED
+}
+
+// write_hint writes the hint file of a sealed segment, it replaces the former one. Returns the number of records.
+// A segment which can not be read to its end is not hinted, it is scanned on open instead.
+pub(super) fn write_hint(segment_path: &Path) -> Result<u64> {
+    let segment = Segment::open_read_only(segment_path.to_owned());
+    let dir = segment_path.parent().unwrap_or(Path::new("."));
+    let tmp_path = dir.join(format!("{}.{}", segment.name(), HINT_TMP_EXT_NAME));
+    let _ = fs::remove_file(&tmp_path);
+    let hint_file = Segment::create(dir, segment.generation(), segment.index(), HINT_TMP_EXT_NAME)?;
+    let timestamp = segment_timestamp(segment_path);
+    let mut buf: Vec<u8> = Vec::new();
+    let mut records: u64 = 0;
+    let mut iter = segment.iter();
+    for record_index in iter.by_ref() {
+        Database::encode_record_index(&mut buf, &record_index, timestamp);
+        hint_file.write(record_index.key.as_slice(), buf.as_slice(), record_index.flag)?;
+        records += 1;
+    }
+    if let Err(e) = iter.finish() {
+        drop(hint_file);
+        let _ = fs::remove_file(&tmp_path);
+        return Err(locate(e, Some(&segment.name()), Some(iter.resume_offset()), None));
+    }
+    hint_file.sync()?;
+    drop(hint_file);
+    rename_durable(&tmp_path, &hint_path(segment_path))?;
+    Ok(records)
+}
+
+// read_hint returns records of the hint file of segment in write order, none if there is no hint file
+pub(super) fn read_hint(segment_path: &Path) -> Result<Option<Vec<RecordIndex>>> {
+    Ok(read_hint_with_timestamps(segment_path)?.map(|(records, _)| records))
+}
+
+// read_hint_with_timestamps is read_hint which also returns the timestamps of hint records
+pub(super) fn read_hint_with_timestamps(segment_path: &Path) -> Result<Option<(Vec<RecordIndex>, HintTimestamps)>> {
+    let path = hint_path(segment_path);
+    if !file_exists(&path) {
+        return Ok(None);
+    }
+    let hint_name = os_str_to_string(path.file_name());
+    let hint_file = Segment::open_read_only(path);
+    let mut records: Vec<RecordIndex> = Vec::new();
+    let timestamps = HintTimestamps::default();
+    let mut iter = hint_file.iter_with_value();
+    for hint_index in iter.by_ref() {
+        let (mut record_index, timestamp) =
+            Database::decode_hint(hint_index.key.clone(), hint_index.flag, hint_index.value.unwrap())
+                .map_err(|e| locate(e, Some(&hint_name), Some(hint_index.offset), None))?;
+        // records of a segment are mostly in a row, so they share the name of the former one
+        if let Some(last) = records.last().filter(|last| last.segment == record_index.segment) {
+            record_index.segment = last.segment.clone();
+        }
+        if let Some(timestamp) = timestamp {
+            timestamps.add(&record_index, timestamp);
+        }
+        records.push(record_index);
+    }
+    // a truncated or damaged hint file would leave records behind the damage unindexed
+    iter.finish().map_err(|e| locate(e, Some(&hint_name), Some(iter.resume_offset()), None))?;
+    Ok(Some((records, timestamps)))
+}
+
+// HintTimestamps keeps the timestamps of hint records by the segment of their record, as runs of records
+// of one timestamp from the offset of the first one. A segment has one run, but merged output, whose records
+// keep the timestamps of the segments they were written to, has one per merged segment.
+#[derive(Default)]
+pub(super) struct HintTimestamps {
+    runs: RwLock<BTreeMap<Arc<str>, TimestampRuns>>,
+}
+
+type TimestampRuns = Vec<(u64, u64)>; // offset of the first record of a run and the timestamp of the run
+
+impl HintTimestamps {
+    // records of a segment must be added in write order
+    fn add(&self, record_index: &RecordIndex, timestamp: u64) {
+        let mut runs = self.runs.write().unwrap();
+        let runs = runs.entry(record_index.segment.clone()).or_default();
+        if runs.last().is_none_or(|(_, last)| *last != timestamp) {
+            runs.push((record_index.offset, timestamp));
+        }
+    }
+
+    // merge takes the runs of other, they are of segments replayed from another hint file
+    pub(super) fn merge(&self, other: HintTimestamps) {
+        self.runs.write().unwrap().append(&mut other.runs.into_inner().unwrap());
+    }
+
+    // get returns the timestamp of the record at offset of segment, none if it was not read from a hint file
+    pub(super) fn get(&self, segment: &str, offset: u64) -> Option<u64> {
+        let runs = self.runs.read().unwrap();
+        let runs = runs.get(segment)?;
+        let after = runs.partition_point(|(first_offset, _)| *first_offset <= offset);
+        runs.get(after.checked_sub(1)?).map(|(_, timestamp)| *timestamp)
+    }
+}
+
+// HintWriter writes hint files of sealed segments one at a time on its own thread, so writers
+// never wait for it. It starts with sealed segments which have no hint file yet, and then takes
+// segments sealed by rotation. A failed hint write leaves the segment to be scanned on open, it is
+// counted, see Database::hint_failures. On drop the thread stops before its next segment, segments left
+// are hinted after the next open. The record count of every hinted segment is put in the STATS file,
+// see StatsCache.
+pub(super) struct HintWriter {
+    worker: Option<JoinHandle<()>>,
+    // serializes hint writes of the thread and rebuild_hints, they share temporary files
+    pub(super) lock: Arc<Mutex<()>>,
+    stop: Arc<AtomicBool>,
+    failures: Arc<AtomicU64>,
+}
+
+impl HintWriter {
+    pub(super) fn start(storage: &Directory, stats: Arc<Mutex<StatsCache>>) -> Self {
+        let (sender, receiver) = mpsc::channel::<PathBuf>();
+        let (sealed, _, _) = storage.checkpoint_files();
+        for path in sealed.into_iter().filter(|path| !file_exists(hint_path(path))) {
+            let _ = sender.send(path);
+        }
+        let lock = Arc::new(Mutex::new(()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let failures = Arc::new(AtomicU64::new(0));
+        let (worker_lock, worker_stop, worker_failures) = (lock.clone(), stop.clone(), failures.clone());
+        let worker = thread::spawn(move || {
+            while let Ok(path) = receiver.recv() {
+                if worker_stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let _guard = worker_lock.lock().unwrap();
+                if !file_exists(&path) {
+                    continue;
+                }
+                match write_hint(&path) {
+                    Ok(records) => {
+                        let _ = stats.lock().unwrap().record_sealed(&path, records);
+                    }
+                    Err(_) => {
+                        worker_failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        storage.set_on_seal(Box::new(move |path: &Path| {
+            let _ = sender.send(path.to_owned());
+        }));
+        Self {
+            worker: Some(worker),
+            lock,
+            stop,
+            failures,
+        }
+    }
+}
+
+impl Drop for HintWriter {
+    // the sender is dropped with storage, so storage must be dropped first
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Database {
+    // audit_hints runs before the index is loaded, a hint which fails is removed and the loader scans its segment
+    pub(super) fn audit_hints(storage: &Directory, audit: HintAudit) -> Result<HintAuditReport> {
+        let mut report = HintAuditReport::default();
+        if audit == HintAudit::Off {
+            return Ok(report);
+        }
+        let (sealed, _, _) = storage.checkpoint_files();
+        for path in sealed.iter() {
+            let sound = match read_hint(path) {
+                Ok(None) => continue,
+                Ok(Some(records)) => {
+                    report.entries_checked += records.len() as u64;
+                    match audit {
+                        HintAudit::Full => Self::matches_segment(path, &records),
+                        _ => Self::sample_hint(storage, &records, audit),
+                    }
+                }
+                Err(_) => false,
+            };
+            report.hints_checked += 1;
+            if !sound {
+                fs::remove_file(hint_path(path))?;
+                report.failed.push(os_str_to_string(path.file_stem()));
+            }
+        }
+        Ok(report)
+    }
+
+    // matches_segment tells whether hint records are exactly the records of segment
+    fn matches_segment(segment_path: &Path, records: &[RecordIndex]) -> bool {
+        let segment = Segment::open_read_only(segment_path.to_owned());
+        let same = |hinted: &RecordIndex, scanned: &RecordIndex| {
+            hinted.segment == scanned.segment
+                && hinted.key == scanned.key
+                && hinted.offset == scanned.offset
+                && is_same_kind(hinted.flag, scanned.flag)
+                && hinted.value_size == scanned.value_size
+        };
+        let mut scanned = segment.iter();
+        records.iter().all(|hinted| scanned.next().is_some_and(|s| same(hinted, &s))) && scanned.next().is_none()
+    }
+
+    // sample_hint reads sampled hint records, a dangling one or one of another record fails
+    fn sample_hint(storage: &Directory, records: &[RecordIndex], audit: HintAudit) -> bool {
+        let HintAudit::Sample(every) = audit else {
+            return true;
+        };
+        records.iter().step_by(every.max(1)).all(|hinted| {
+            storage.read_at(hinted).is_ok_and(|record| {
+                record.key == hinted.key
+                    && is_same_kind(record.flag, hinted.flag)
+                    && record.value.len() as u64 == hinted.value_size
+            })
+        })
+    }
+
+    // hint_failures returns how many hint files the background thread failed to write since open. Their segments
+    // are scanned on open until rebuild_hints writes them.
+    pub fn hint_failures(&self) -> u64 {
+        self.hint_writer.failures.load(Ordering::Relaxed)
+    }
+
+    // hint_audit returns the report of the hint audit of open, it is empty unless Options::hint_audit is set
+    pub fn hint_audit(&self) -> &HintAuditReport {
+        &self.hint_audit
+    }
+
+    // rebuild_hints rewrites hint files of all sealed segments from their records,
+    // e.g. after hint files were deleted or damaged
+    pub fn rebuild_hints(&self) -> Result<()> {
+        let (sealed, _, _) = self.storage.checkpoint_files();
+        let _guard = self.hint_writer.lock.lock().unwrap();
+        for path in sealed.iter() {
+            write_hint(path)?;
+        }
+        Ok(())
+    }
+
+    // write_sealed_hint writes the hint file of a sealed segment now rather than leaving it to the next open
+    pub(super) fn write_sealed_hint(&self, segment_path: &Path) -> Result<()> {
+        let _guard = self.hint_writer.lock.lock().unwrap();
+        write_hint(segment_path).map(|_| ())
+    }
+}
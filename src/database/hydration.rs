@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+
+use super::{
+    database::{Database, KeyFilter, OpenProgress},
+    hint::{read_hint_with_timestamps, HintTimestamps},
+    index::Index,
+    keydir::KeyDir,
+};
+use crate::storage::{segment::Segment, Bytes, RecordIndex};
+
+/*
+ * Lazy open: the oldest segments which all have hint files are indexed before open returns, the
+ * segments from the first one without hint file on are replayed by a background thread. Until it
+ * is done, a key whose latest record is in such a segment may be missing or show an older value.
+ *
+ * Keys written after open are touched, replay skips them since their records are newer than
+ * any record in the replayed segments.
+ */
+
+const REPLAY_BATCH: usize = 1024;
+
+pub(super) struct Hydration {
+    state: Mutex<HydrationState>,
+    finished: Condvar,
+}
+
+struct HydrationState {
+    touched: BTreeSet<Bytes>,
+    done: bool,
+    failed: bool, // the background thread panicked, index misses records of segments
+}
+
+impl Hydration {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(HydrationState {
+                touched: BTreeSet::new(),
+                done: false,
+                failed: false,
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    // is_done is false after a failed hydration, the index stays incomplete
+    pub(super) fn is_done(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.done && !state.failed
+    }
+
+    pub(super) fn touch(&self, key: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if !state.done {
+            state.touched.insert(Bytes::from(key));
+        }
+    }
+
+    pub(super) fn wait(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while !state.done {
+            state = self.finished.wait(state).unwrap();
+        }
+        if state.failed {
+            return Err(anyhow!("background indexing panicked, index is incomplete"));
+        }
+        Ok(())
+    }
+
+    fn finish(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        state.failed = failed;
+        state.touched.clear();
+        self.finished.notify_all();
+    }
+}
+
+impl Database {
+    // is_hydrated tells whether index covers all segments, it is always true unless opened with lazy_index
+    pub fn is_hydrated(&self) -> bool {
+        self.index.is_hydrated()
+    }
+
+    // wait_hydrated blocks until background indexing of a lazy open is done, it fails if indexing panicked
+    pub fn wait_hydrated(&self) -> Result<()> {
+        match self.index.hydration.as_ref() {
+            Some(hydration) => hydration.wait(),
+            None => Ok(()),
+        }
+    }
+}
+
+// replay_segment applies records of segment to index in order, from its hint file if there is one.
+// The index lock is taken per batch. Returns whether the hint file was read, it fails like KeyDir::insert.
+pub(super) fn replay_segment(
+    map: &RwLock<KeyDir>,
+    sequence: &AtomicU64,
+    timestamps: &HintTimestamps,
+    hydration: Option<&Hydration>,
+    filter: Option<&KeyFilter>,
+    segment: &Segment,
+) -> Result<bool> {
+    let indexed = |ri: &RecordIndex| filter.is_none_or(|filter| filter.matches(&ri.key));
+    match read_hint_with_timestamps(&segment.path()) {
+        Ok(Some((records, hinted))) => {
+            timestamps.merge(hinted);
+            replay(map, sequence, hydration, records.into_iter().filter(indexed))?;
+            Ok(true)
+        }
+        // a damaged hint file is ignored, the segment is scanned instead
+        _ => {
+            replay(map, sequence, hydration, segment.iter().filter(indexed))?;
+            Ok(false)
+        }
+    }
+}
+
+pub(super) fn replay<I: Iterator<Item = RecordIndex>>(
+    map: &RwLock<KeyDir>,
+    sequence: &AtomicU64,
+    hydration: Option<&Hydration>,
+    mut records: I,
+) -> Result<()> {
+    loop {
+        let batch: Vec<RecordIndex> = records.by_ref().take(REPLAY_BATCH).collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut map = map.write().unwrap();
+        let state = hydration.map(|h| h.state.lock().unwrap());
+        for mut record_index in batch {
+            if state.as_ref().is_some_and(|s| s.touched.contains(&record_index.key)) {
+                continue;
+            }
+            if record_index.is_deleted() {
+                map.remove(record_index.key.as_slice())?;
+            } else {
+                record_index.version = sequence.fetch_add(1, Ordering::Relaxed) + 1;
+                map.insert(record_index)?;
+            }
+        }
+    }
+}
+
+// HydrationGuard finishes hydration when the background thread ends, also by a panic, so waiters wake
+struct HydrationGuard(Arc<Hydration>);
+
+impl Drop for HydrationGuard {
+    fn drop(&mut self) {
+        self.0.finish(thread::panicking());
+    }
+}
+
+// hydrate starts the background replay of segments, report is called after each of them
+pub(super) fn hydrate(
+    index: &mut Index,
+    segments: Vec<(PathBuf, u64)>,
+    mut progress: OpenProgress,
+    report: Option<Arc<dyn Fn(OpenProgress) + Send + Sync>>,
+) {
+    let hydration = Arc::new(Hydration::new());
+    index.hydration = Some(hydration.clone());
+    let map = index.map.clone();
+    let sequence = index.sequence.clone();
+    let timestamps = index.timestamps.clone();
+    let filter = index.filter.clone();
+    thread::spawn(move || {
+        let hydration = HydrationGuard(hydration);
+        for (path, len) in segments {
+            // segments are sealed, reading them through a separate handle is safe
+            let segment = Segment::open_read_only(path);
+            // only a mapped keydir fails, lazy_index does not map one
+            let _ = replay_segment(&map, &sequence, &timestamps, Some(&hydration.0), filter.as_ref(), &segment);
+            progress.files_scanned += 1;
+            progress.bytes_processed += len;
+            if let Some(report) = report.as_ref() {
+                report(progress);
+            }
+        }
+    });
+}
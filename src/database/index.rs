@@ -1,35 +1,130 @@
 use anyhow::{Ok, Result};
 use std::{
-    collections::BTreeMap, sync::RwLock,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
 };
 
+use super::{database::{IndexStats, KeyFilter}, hint::HintTimestamps, hydration::Hydration, keydir::KeyDir};
 use crate::storage::{Bytes, RecordIndex};
 
+// Versions are <epoch><counter of EPOCH_SHIFT bits>. The epoch of an open is the id of the segment it creates,
+// ids grow with every open, so versions of an open are above those of all earlier opens.
+pub(super) const EPOCH_SHIFT: u32 = 40;
+
 pub(super) struct Index {
-    pub(super) map: RwLock<BTreeMap<Bytes, RecordIndex>>,
+    // shared with the hydration thread of a lazily opened database
+    pub(super) map: Arc<RwLock<KeyDir>>,
+    // last assigned version, versions increase with every indexed write of any key, see EPOCH_SHIFT
+    pub(super) sequence: Arc<AtomicU64>,
+    // timestamps of records indexed from hint files, see Database::metadata
+    pub(super) timestamps: Arc<HintTimestamps>,
+    pub(super) hydration: Option<Arc<Hydration>>,
+    // keys left out on load, none indexes all keys
+    pub(super) filter: Option<KeyFilter>,
 }
 
 impl Index {
-    pub(super) fn new() -> Self {
+    pub(super) fn new(prefix_compressed: bool) -> Self {
         Self {
-            map: RwLock::new(BTreeMap::new()),
+            map: Arc::new(RwLock::new(KeyDir::new(prefix_compressed))),
+            sequence: Arc::new(AtomicU64::new(0)),
+            timestamps: Arc::default(),
+            hydration: None,
+            filter: None,
         }
     }
 
-    pub(super) fn get(&self, key: &[u8]) -> Option<RecordIndex> {
+    // start_epoch makes versions assigned from now on those of epoch
+    pub(super) fn start_epoch(&self, epoch: u64) {
+        self.sequence.fetch_max(epoch << EPOCH_SHIFT, Ordering::Relaxed);
+    }
+
+    // get fails with corruption if the entry is in a damaged block of a persistent index
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<RecordIndex>> {
         let map = self.map.read().unwrap();
-        map.get(key).cloned()
+        map.get(key)
+    }
+
+    // is_hydrated tells whether index covers every segment, a missing key may be not loaded yet otherwise
+    pub(super) fn is_hydrated(&self) -> bool {
+        self.hydration.as_ref().is_none_or(|h| h.is_done())
+    }
+
+    // keys written after open must not be overwritten by older records which are hydrated later,
+    // it is called with map locked so hydration sees the write and the key together
+    fn touch(&self, key: &[u8]) {
+        if let Some(hydration) = self.hydration.as_ref() {
+            hydration.touch(key);
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // set stamps record with a new version and returns it
+    pub(super) fn set(&mut self, mut record: RecordIndex) -> Result<u64> {
+        let mut map = self.map.write().unwrap();
+        record.version = self.next_version();
+        let version = record.version;
+        self.touch(record.key.as_slice());
+        map.insert(record)?;
+        Ok(version)
+    }
+
+    // set_many stamps and inserts records under one lock, returns versions in the order of records
+    pub(super) fn set_many(&mut self, records: Vec<RecordIndex>) -> Result<Vec<u64>> {
+        let mut map = self.map.write().unwrap();
+        let mut versions: Vec<u64> = Vec::with_capacity(records.len());
+        for mut record in records {
+            record.version = self.next_version();
+            versions.push(record.version);
+            self.touch(record.key.as_slice());
+            map.insert(record)?;
+        }
+        Ok(versions)
     }
 
-    pub(super) fn set(&mut self, record: RecordIndex) -> Result<()> {
+    // relocate points the entry of the key of record to it and keeps its version, e.g. for a moved record
+    pub(super) fn relocate(&mut self, mut record: RecordIndex) -> Result<()> {
         let mut map = self.map.write().unwrap();
-        map.insert(record.key.clone(), record);
+        if let Some(current) = map.get(record.key.as_slice())? {
+            record.version = current.version;
+            map.insert(record)?;
+        }
         Ok(())
     }
 
     pub(super) fn delete(&mut self, key: &Bytes) -> Result<()> {
         let mut map = self.map.write().unwrap();
-        map.remove(key);
+        self.touch(key.as_slice());
+        map.remove(key)
+    }
+
+    pub(super) fn delete_many(&mut self, keys: &[&[u8]]) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for key in keys {
+            self.touch(key);
+            map.remove(key)?;
+        }
         Ok(())
     }
+
+    // inline_value keeps value in the entry of idx, unless the key was written again meanwhile
+    pub(super) fn inline_value(&self, idx: &RecordIndex, value: Bytes) {
+        self.map.write().unwrap().inline_value(idx, value);
+    }
+
+    // stats walks the index under read lock, it costs about as much as a scan of all keys
+    pub(super) fn stats(&self) -> IndexStats {
+        self.map.read().unwrap().stats()
+    }
+
+    // range returns at most limit entries from lower bound whose keys start with prefix
+    pub(super) fn range(&self, lower: Bound<&[u8]>, prefix: &[u8], limit: usize) -> Result<Vec<RecordIndex>> {
+        self.map.read().unwrap().range(lower, prefix, limit)
+    }
 }
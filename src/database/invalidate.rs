@@ -0,0 +1,30 @@
+use super::database::Database;
+
+// WriteOp tells an invalidator what happened to a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOp {
+    Write,
+    Delete,
+}
+
+// Invalidator is called with every written or deleted key, e.g. to evict it from a cache in front of the store
+pub type Invalidator = Box<dyn Fn(&[u8], WriteOp) + Send + Sync>;
+
+impl Database {
+    // set_invalidator makes every write, delete and ingest call invalidator on the writing thread once the
+    // index is updated, so a read after the call sees the new state. Merge does not change values, it is silent.
+    pub fn set_invalidator<F>(&mut self, invalidator: F)
+    where
+        F: Fn(&[u8], WriteOp) + Send + Sync + 'static,
+    {
+        self.invalidator = Some(Box::new(invalidator));
+    }
+
+    // invalidate is the post-commit point of writes, it wakes tails too
+    pub(super) fn invalidate(&self, key: &[u8], op: WriteOp) {
+        if let Some(invalidator) = self.invalidator.as_ref() {
+            invalidator(key, op);
+        }
+        self.notify_tails();
+    }
+}
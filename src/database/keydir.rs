@@ -0,0 +1,408 @@
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    sync::Arc,
+};
+
+use super::{database::IndexStats, mapped_keydir::MappedKeyDir};
+use crate::{
+    error::corruption,
+    storage::{
+        segment::{parse_segment_stem, segment_stem},
+        Bytes, RecordIndex,
+    },
+    utils::varint::decode_varint_from_slice,
+};
+
+/*
+ * KeyDir maps keys to record locations. The plain form is a BTreeMap of RecordIndex, the prefix
+ * compressed form keeps sorted keys in blocks of at most MAX_BLOCK_ENTRIES front coded entries:
+ * | shared prefix len | suffix len | suffix | flag | generation | segment | offset | value size | version |
+ * integers are varints and the first entry of a block shares nothing. A block is found by its
+ * first key, so a lookup decodes one block. The mapped form is a checkpoint file of such blocks with
+ * changes since in memory, see mapped_keydir.rs.
+ */
+
+pub(super) const MAX_BLOCK_ENTRIES: usize = 64;
+
+// an index key is an Arc<Vec<u8>> allocation, B-tree nodes are about two thirds full
+// so inline entries take half their size again
+pub(super) const KEY_ALLOCATION_OVERHEAD: u64 = 2 * 8 + std::mem::size_of::<Vec<u8>>() as u64;
+
+pub(super) enum KeyDir {
+    Plain(BTreeMap<Bytes, RecordIndex>),
+    Compressed(CompressedKeyDir),
+    Mapped(MappedKeyDir),
+}
+
+pub(super) struct CompressedKeyDir {
+    blocks: BTreeMap<Bytes, Vec<u8>>, // keyed by first key of block
+    len: usize,
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct Location {
+    flag: u8,
+    generation: u64,
+    segment: u64,
+    offset: u64,
+    value_size: u64,
+    version: u64,
+}
+
+impl KeyDir {
+    pub(super) fn new(compressed: bool) -> Self {
+        if compressed {
+            KeyDir::Compressed(CompressedKeyDir {
+                blocks: BTreeMap::new(),
+                len: 0,
+            })
+        } else {
+            KeyDir::Plain(BTreeMap::new())
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self {
+            KeyDir::Plain(map) => map.len(),
+            KeyDir::Compressed(dir) => dir.len,
+            KeyDir::Mapped(dir) => dir.len(),
+        }
+    }
+
+    // get, insert, remove and range fail for the mapped form with corruption of a damaged block, insert also for a
+    // compressed one with a record of a malformed segment name, see Location::of
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<RecordIndex>> {
+        match self {
+            KeyDir::Plain(map) => Ok(map.get(key).cloned()),
+            KeyDir::Compressed(dir) => Ok(dir.get(key).map(|location| location.to_record(key))),
+            KeyDir::Mapped(dir) => dir.get(key),
+        }
+    }
+
+    pub(super) fn insert(&mut self, record: RecordIndex) -> Result<()> {
+        match self {
+            KeyDir::Plain(map) => {
+                map.insert(record.key.clone(), record);
+            }
+            KeyDir::Compressed(dir) => dir.insert(record.key.as_slice(), Location::of(&record)?),
+            KeyDir::Mapped(dir) => dir.insert(record)?,
+        }
+        Ok(())
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self {
+            KeyDir::Plain(map) => {
+                map.remove(key);
+            }
+            KeyDir::Compressed(dir) => dir.remove(key),
+            KeyDir::Mapped(dir) => dir.remove(key)?,
+        }
+        Ok(())
+    }
+
+    // inline_value keeps value in the plain entry of idx if it still points to the same record
+    pub(super) fn inline_value(&mut self, idx: &RecordIndex, value: Bytes) {
+        let record = match self {
+            KeyDir::Plain(map) => map.get_mut(idx.key.as_slice()),
+            KeyDir::Mapped(dir) => dir.changed_mut(idx.key.as_slice()),
+            KeyDir::Compressed(_) => None,
+        };
+        if let Some(record) = record {
+            if record.segment == idx.segment && record.offset == idx.offset {
+                record.value = Some(value);
+            }
+        }
+    }
+
+    pub(super) fn is_mapped(&self) -> bool {
+        matches!(self, KeyDir::Mapped(_))
+    }
+
+    // range returns at most limit entries from lower bound whose keys start with prefix
+    pub(super) fn range(&self, lower: Bound<&[u8]>, prefix: &[u8], limit: usize) -> Result<Vec<RecordIndex>> {
+        match self {
+            KeyDir::Plain(map) => Ok(map
+                .range::<[u8], _>((lower, Bound::Unbounded))
+                .take_while(|(key, _)| key.as_slice().starts_with(prefix))
+                .take(limit)
+                .map(|(_, idx)| idx.clone())
+                .collect()),
+            KeyDir::Compressed(dir) => Ok(dir.range(lower, prefix, limit)),
+            KeyDir::Mapped(dir) => dir.range(lower, prefix, limit),
+        }
+    }
+
+    pub(super) fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats {
+            entries: self.len() as u64,
+            ..Default::default()
+        };
+        match self {
+            KeyDir::Plain(map) => {
+                const ENTRY_INLINE_BYTES: u64 =
+                    (std::mem::size_of::<Bytes>() + std::mem::size_of::<RecordIndex>()) as u64;
+                // segment names are shared, each one is counted once with its reference counts
+                let mut names: BTreeSet<*const u8> = BTreeSet::new();
+                for (key, record) in map.iter() {
+                    stats.key_bytes += key.len() as u64;
+                    stats.inline_value_bytes += record.value.as_ref().map_or(0, |value| value.len() as u64);
+                    if names.insert(Arc::as_ptr(&record.segment) as *const u8) {
+                        stats.segment_name_bytes += 2 * 8 + record.segment.len() as u64;
+                    }
+                }
+                stats.overhead_bytes = stats.entries * (KEY_ALLOCATION_OVERHEAD + ENTRY_INLINE_BYTES * 3 / 2);
+            }
+            KeyDir::Compressed(dir) => {
+                const BLOCK_INLINE_BYTES: u64 =
+                    (std::mem::size_of::<Bytes>() + std::mem::size_of::<Vec<u8>>()) as u64;
+                let mut stored_suffix_bytes: u64 = 0;
+                let mut block_bytes: u64 = 0;
+                for (first_key, data) in dir.blocks.iter() {
+                    decode_block(data, |key, shared, _| {
+                        stats.key_bytes += key.len() as u64;
+                        stats.prefix_saved_bytes += shared as u64;
+                        stored_suffix_bytes += (key.len() - shared) as u64;
+                        true
+                    });
+                    block_bytes += data.capacity() as u64
+                        + first_key.len() as u64
+                        + KEY_ALLOCATION_OVERHEAD
+                        + BLOCK_INLINE_BYTES * 3 / 2;
+                }
+                // segments are varints inside entries, counted by overhead
+                stats.overhead_bytes = block_bytes - stored_suffix_bytes;
+            }
+            KeyDir::Mapped(dir) => dir.add_stats(&mut stats),
+        }
+        stats
+    }
+
+    // for_each passes entries to f in key order, it stops at the first error
+    pub(super) fn for_each<F: FnMut(RecordIndex) -> Result<()>>(&self, mut f: F) -> Result<()> {
+        match self {
+            KeyDir::Plain(map) => {
+                for record in map.values() {
+                    f(record.clone())?;
+                }
+            }
+            KeyDir::Compressed(dir) => {
+                for data in dir.blocks.values() {
+                    let mut result = Ok(());
+                    decode_block(data, |key, _, location| {
+                        result = f(location.to_record(key));
+                        result.is_ok()
+                    });
+                    result?;
+                }
+            }
+            KeyDir::Mapped(dir) => dir.for_each(f)?,
+        }
+        Ok(())
+    }
+}
+
+impl Location {
+    // of fails for a segment name which is no segment stem, a location can only hold generation and index
+    pub(super) fn of(record: &RecordIndex) -> Result<Self> {
+        let (generation, segment) = parse_segment_stem(&record.segment)
+            .ok_or_else(|| corruption(format!("index entry of malformed segment name {:?}", record.segment)))?;
+        Ok(Location {
+            flag: record.flag,
+            generation,
+            segment,
+            offset: record.offset,
+            value_size: record.value_size,
+            version: record.version,
+        })
+    }
+
+    pub(super) fn to_record(self, key: &[u8]) -> RecordIndex {
+        RecordIndex {
+            key: Bytes::from(key),
+            segment: Arc::from(segment_stem(self.generation, self.segment)),
+            flag: self.flag,
+            offset: self.offset,
+            value_size: self.value_size,
+            value: None,
+            version: self.version,
+        }
+    }
+}
+
+impl CompressedKeyDir {
+    // first key of the block which may hold key, it is the first block if key is before all blocks
+    fn block_of(&self, key: &[u8]) -> Option<Bytes> {
+        self.blocks
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .or_else(|| self.blocks.iter().next())
+            .map(|(first_key, _)| first_key.clone())
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Location> {
+        let (_, data) = self
+            .blocks
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()?;
+        let mut found = None;
+        decode_block(data, |k, _, location| {
+            if k == key {
+                found = Some(*location);
+            }
+            found.is_none() && k < key
+        });
+        found
+    }
+
+    fn insert(&mut self, key: &[u8], location: Location) {
+        let Some(first_key) = self.block_of(key) else {
+            self.blocks.insert(Bytes::from(key), encode_block(&[(key.to_vec(), location)]));
+            self.len += 1;
+            return;
+        };
+        let mut entries = decode_entries(&self.blocks[&first_key]);
+        match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+            Ok(i) => entries[i].1 = location,
+            Err(i) => {
+                entries.insert(i, (key.to_vec(), location));
+                self.len += 1;
+            }
+        }
+        self.replace_block(first_key, entries);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(first_key) = self.block_of(key) else {
+            return;
+        };
+        let mut entries = decode_entries(&self.blocks[&first_key]);
+        let Ok(i) = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) else {
+            return;
+        };
+        entries.remove(i);
+        self.len -= 1;
+        self.replace_block(first_key, entries);
+    }
+
+    // replace_block stores entries of the block at first_key, splitting a full block in halves
+    fn replace_block(&mut self, first_key: Bytes, entries: Vec<(Vec<u8>, Location)>) {
+        if entries.first().is_some_and(|(k, _)| k.as_slice() == first_key.as_slice())
+            && entries.len() <= MAX_BLOCK_ENTRIES
+        {
+            *self.blocks.get_mut(&first_key).unwrap() = encode_block(&entries);
+            return;
+        }
+        self.blocks.remove(&first_key);
+        let chunk = if entries.len() > MAX_BLOCK_ENTRIES {
+            entries.len().div_ceil(2)
+        } else {
+            MAX_BLOCK_ENTRIES
+        };
+        for part in entries.chunks(chunk) {
+            self.blocks.insert(Bytes::from(part[0].0.as_slice()), encode_block(part));
+        }
+    }
+
+    fn range(&self, lower: Bound<&[u8]>, prefix: &[u8], limit: usize) -> Vec<RecordIndex> {
+        let mut result: Vec<RecordIndex> = Vec::new();
+        let start = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key),
+            Bound::Unbounded => self.blocks.keys().next().cloned(),
+        };
+        let Some(start) = start else {
+            return result;
+        };
+        let after_lower = |key: &[u8]| match lower {
+            Bound::Included(l) => key >= l,
+            Bound::Excluded(l) => key > l,
+            Bound::Unbounded => true,
+        };
+        let mut done = false;
+        let blocks = self.blocks.range::<[u8], _>((Bound::Included(start.as_slice()), Bound::Unbounded));
+        for (_, data) in blocks {
+            decode_block(data, |key, _, location| {
+                if !after_lower(key) {
+                    return true;
+                }
+                if !key.starts_with(prefix) || result.len() >= limit {
+                    done = true;
+                    return false;
+                }
+                result.push(location.to_record(key));
+                true
+            });
+            if done {
+                break;
+            }
+        }
+        result
+    }
+}
+
+pub(super) fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+pub(super) fn encode_block(entries: &[(Vec<u8>, Location)]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut previous: &[u8] = &[];
+    for (key, location) in entries {
+        let shared = previous.iter().zip(key.iter()).take_while(|(a, b)| a == b).count();
+        put_varint(&mut buf, shared as u64);
+        put_varint(&mut buf, (key.len() - shared) as u64);
+        buf.extend_from_slice(&key[shared..]);
+        buf.push(location.flag);
+        put_varint(&mut buf, location.generation);
+        put_varint(&mut buf, location.segment);
+        put_varint(&mut buf, location.offset);
+        put_varint(&mut buf, location.value_size);
+        put_varint(&mut buf, location.version);
+        previous = key;
+    }
+    buf.shrink_to_fit();
+    buf
+}
+
+// decode_block passes every entry with its shared prefix length to f in order until f returns false
+pub(super) fn decode_block<F: FnMut(&[u8], usize, &Location) -> bool>(data: &[u8], mut f: F) {
+    // blocks are only written by encode_block, they can not be malformed
+    let varint = |i: &mut usize| decode_varint_from_slice(data, i).unwrap() as usize;
+    let mut key: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let shared = varint(&mut i);
+        let suffix_len = varint(&mut i);
+        key.truncate(shared);
+        key.extend_from_slice(&data[i..i + suffix_len]);
+        i += suffix_len;
+        let flag = data[i];
+        i += 1;
+        let location = Location {
+            flag,
+            generation: varint(&mut i) as u64,
+            segment: varint(&mut i) as u64,
+            offset: varint(&mut i) as u64,
+            value_size: varint(&mut i) as u64,
+            version: varint(&mut i) as u64,
+        };
+        if !f(&key, shared, &location) {
+            return;
+        }
+    }
+}
+
+pub(super) fn decode_entries(data: &[u8]) -> Vec<(Vec<u8>, Location)> {
+    let mut entries = Vec::new();
+    decode_block(data, |key, _, location| {
+        entries.push((key.to_vec(), *location));
+        true
+    });
+    entries
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use crc::{Crc, CRC_32_ISCSI};
+use memmap::Mmap;
+
+use super::{
+    database::{Database, IndexStats, OpenProgress, SegmentLoadTiming},
+    hydration::replay,
+    index::Index,
+    keydir::{
+        decode_block, decode_entries, encode_block, put_varint, KeyDir, Location, KEY_ALLOCATION_OVERHEAD,
+        MAX_BLOCK_ENTRIES,
+    },
+};
+use crate::{
+    error::{corruption, invalid_input, is_corruption},
+    storage::{directory::Directory, segment::Segment, Bytes, RecordIndex},
+    utils::{
+        clock::Clock,
+        utils::{file_exists, os_str_to_string, rename_durable},
+        varint::decode_varint_from_slice,
+    },
+};
+
+/*
+ * A persistent index is a checkpoint file of the keydir which open maps rather than replaying segments,
+ * changes since the checkpoint are kept in memory. Checkpoint file:
+ * | block | crc32c (4B) | ... | block | crc32c (4B) | first keys | block table | segments | footer |
+ * Blocks are front coded entries of keydir.rs in key order, each followed by the crc of its data which is
+ * verified whenever the block is decoded. First keys holds the first key of each block with its place,
+ * | key len | key | block offset | block len |, and the block table the u64 offset of each of them, so a
+ * lookup binary searches the table and decodes one block. Segments are those the checkpoint covers with
+ * the length covered, | count | name len | name | length |. Footer:
+ * | first keys offset u64 | table offset u64 | blocks u64 | entries u64 | segments offset u64 | version u64 |
+ * | crc32c (4B) | magic (8B) |
+ * Integers are varints unless sized, sized ones are little endian. The footer crc covers first keys up to
+ * it, open verifies it, so open reads no block.
+ *
+ * Open replays records written to covered segments behind the covered length and segments created after
+ * the checkpoint. It is stale once a covered segment is gone or shorter, e.g. after merge adoption, then open
+ * replays all segments and writes a new one. The file is written to a temporary file and renamed.
+ * A checkpoint is written once Options::checkpoint_every changes are kept in memory and when the database
+ * is dropped. A damaged block fails lookups in it with corruption, the checkpoint is
+ * removed then on drop and the next open rebuilds it.
+ */
+
+const KEYDIR_FILENAME: &str = "KEYDIR";
+const KEYDIR_TMP_FILENAME: &str = "KEYDIR.tmp";
+const KEYDIR_MAGIC: &[u8] = b"bckeydir";
+const FOOTER_BYTES: usize = 6 * 8 + 4 + 8;
+const BLOCK_CRC_BYTES: usize = 4;
+static CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+pub(super) struct MappedKeyDir {
+    base: Option<KeyDirFile>,
+    changes: BTreeMap<Bytes, Option<RecordIndex>>, // none removes the key of base
+    len: usize,
+}
+
+// KeyDirFile is a mapped checkpoint file whose footer checksum was verified, blocks are verified when decoded
+pub(super) struct KeyDirFile {
+    mmap: Mmap,
+    blocks: usize,
+    damaged: AtomicBool, // a block failed its checksum
+    table_offset: usize,
+    entries: usize,
+    segments: BTreeMap<String, u64>,
+    version: u64, // last version assigned when it was written
+}
+
+impl MappedKeyDir {
+    pub(super) fn new(base: Option<KeyDirFile>) -> Self {
+        MappedKeyDir {
+            len: base.as_ref().map_or(0, |base| base.entries),
+            base,
+            changes: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<RecordIndex>> {
+        match (self.changes.get(key), self.base.as_ref()) {
+            (Some(change), _) => Ok(change.clone()),
+            (None, Some(base)) => Ok(base.get(key)?.map(|location| location.to_record(key))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn in_base(&self, key: &[u8]) -> Result<bool> {
+        match self.base.as_ref() {
+            Some(base) => Ok(base.get(key)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    pub(super) fn insert(&mut self, record: RecordIndex) -> Result<()> {
+        if self.get(record.key.as_slice())?.is_none() {
+            self.len += 1;
+        }
+        self.changes.insert(record.key.clone(), Some(record));
+        Ok(())
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) -> Result<()> {
+        if self.get(key)?.is_none() {
+            return Ok(());
+        }
+        self.len -= 1;
+        if self.in_base(key)? {
+            self.changes.insert(Bytes::from(key), None);
+        } else {
+            self.changes.remove(key);
+        }
+        Ok(())
+    }
+
+    // is_damaged tells whether a block of the checkpoint failed its checksum
+    fn is_damaged(&self) -> bool {
+        self.base.as_ref().is_some_and(|base| base.damaged.load(Ordering::Relaxed))
+    }
+
+    // changed_mut is the entry of key written since the checkpoint
+    pub(super) fn changed_mut(&mut self, key: &[u8]) -> Option<&mut RecordIndex> {
+        self.changes.get_mut(key)?.as_mut()
+    }
+
+    // visit passes entries from lower bound on to f in key order until f returns false, it fails at a damaged block
+    fn visit<F: FnMut(RecordIndex) -> bool>(&self, lower: Bound<&[u8]>, mut f: F) -> Result<()> {
+        let mut base = self.base.iter().flat_map(|base| base.entries_from(lower)).peekable();
+        let mut changes = self.changes.range::<[u8], _>((lower, Bound::Unbounded)).peekable();
+        loop {
+            let order = match (base.peek(), changes.peek()) {
+                (Some(Err(_)), _) => return base.next().unwrap().map(|_| ()),
+                (None, None) => return Ok(()),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(Ok((key, _))), Some((changed, _))) => key.as_slice().cmp(changed.as_slice()),
+            };
+            let entry = match order {
+                std::cmp::Ordering::Less => base.next().and_then(Result::ok).map(|(key, location)| location.to_record(&key)),
+                std::cmp::Ordering::Equal => {
+                    base.next();
+                    changes.next().and_then(|(_, change)| change.clone())
+                }
+                std::cmp::Ordering::Greater => changes.next().and_then(|(_, change)| change.clone()),
+            };
+            if let Some(entry) = entry {
+                if !f(entry) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    pub(super) fn range(&self, lower: Bound<&[u8]>, prefix: &[u8], limit: usize) -> Result<Vec<RecordIndex>> {
+        let mut result: Vec<RecordIndex> = Vec::new();
+        self.visit(lower, |entry| {
+            if !entry.key.as_slice().starts_with(prefix) || result.len() >= limit {
+                return false;
+            }
+            result.push(entry);
+            true
+        })?;
+        Ok(result)
+    }
+
+    pub(super) fn for_each<F: FnMut(RecordIndex) -> Result<()>>(&self, mut f: F) -> Result<()> {
+        let mut result = Ok(());
+        self.visit(Bound::Unbounded, |entry| {
+            result = f(entry);
+            result.is_ok()
+        })?;
+        result
+    }
+
+    // add_stats counts keys of all entries, the memory of changes only. The mapped file is paged by the OS.
+    // Keys behind a damaged block are not counted, lookups of them report it.
+    pub(super) fn add_stats(&self, stats: &mut IndexStats) {
+        const CHANGE_INLINE_BYTES: u64 = (std::mem::size_of::<Bytes>() + std::mem::size_of::<Option<RecordIndex>>()) as u64;
+        let _ = self.visit(Bound::Unbounded, |entry| {
+            stats.key_bytes += entry.key.len() as u64;
+            true
+        });
+        for change in self.changes.values().flatten() {
+            stats.inline_value_bytes += change.value.as_ref().map_or(0, |value| value.len() as u64);
+            stats.segment_name_bytes += change.segment.len() as u64;
+        }
+        stats.overhead_bytes = self.changes.len() as u64 * (KEY_ALLOCATION_OVERHEAD + CHANGE_INLINE_BYTES * 3 / 2);
+    }
+}
+
+impl KeyDirFile {
+    // open maps and verifies the checkpoint file, none if there is none
+    fn open(path: &Path) -> Result<Option<KeyDirFile>> {
+        if !file_exists(path) {
+            return Ok(None);
+        }
+        let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+        let invalid = || corruption("invalid index checkpoint");
+        let footer_start = mmap.len().checked_sub(FOOTER_BYTES).ok_or_else(invalid)?;
+        let footer = &mmap[footer_start..];
+        if &footer[FOOTER_BYTES - KEYDIR_MAGIC.len()..] != KEYDIR_MAGIC {
+            return Err(invalid());
+        }
+        let sized = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        let crc = u32::from_le_bytes(footer[48..52].try_into().unwrap());
+        let first_keys_offset = sized(0) as usize;
+        if first_keys_offset > footer_start || CRC32C.checksum(&mmap[first_keys_offset..footer_start + 48]) != crc {
+            return Err(invalid());
+        }
+        let (table_offset, blocks, entries, segments_offset, version) =
+            (sized(1) as usize, sized(2) as usize, sized(3) as usize, sized(4) as usize, sized(5));
+        let mut i = segments_offset;
+        let mut segments = BTreeMap::new();
+        for _ in 0..decode_varint_from_slice(&mmap, &mut i)? {
+            let len = decode_varint_from_slice(&mmap, &mut i)? as usize;
+            let name = mmap.get(i..i + len).ok_or_else(invalid)?;
+            let name = String::from_utf8(name.to_vec()).map_err(|_| invalid())?;
+            i += len;
+            segments.insert(name, decode_varint_from_slice(&mmap, &mut i)?);
+        }
+        Ok(Some(KeyDirFile {
+            mmap,
+            blocks,
+            damaged: AtomicBool::new(false),
+            table_offset,
+            entries,
+            segments,
+            version,
+        }))
+    }
+
+    // block returns the first key, offset and length of the i-th block
+    fn block(&self, i: usize) -> (&[u8], usize, usize) {
+        // the footer was verified, offsets are the ones written
+        let at = self.table_offset + i * 8;
+        let mut i = u64::from_le_bytes(self.mmap[at..at + 8].try_into().unwrap()) as usize;
+        let varint = |i: &mut usize| decode_varint_from_slice(&self.mmap, i).unwrap() as usize;
+        let key_len = varint(&mut i);
+        let key_start = i;
+        i += key_len;
+        let (offset, len) = (varint(&mut i), varint(&mut i));
+        (&self.mmap[key_start..key_start + key_len], offset, len)
+    }
+
+    // block_data returns the data of the i-th block once it matches its checksum
+    fn block_data(&self, i: usize) -> Result<&[u8]> {
+        let (_, offset, len) = self.block(i);
+        let data = &self.mmap[offset..offset + len];
+        let crc = u32::from_le_bytes(self.mmap[offset + len..offset + len + BLOCK_CRC_BYTES].try_into().unwrap());
+        if CRC32C.checksum(data) != crc {
+            self.damaged.store(true, Ordering::Relaxed);
+            return Err(corruption("damaged index checkpoint block"));
+        }
+        Ok(data)
+    }
+
+    // block_of is the last block whose first key is not after key
+    fn block_of(&self, key: &[u8]) -> Option<usize> {
+        let (mut low, mut high) = (0, self.blocks);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.block(mid).0 <= key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.checked_sub(1)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Location>> {
+        let Some(block) = self.block_of(key) else {
+            return Ok(None);
+        };
+        let mut found = None;
+        decode_block(self.block_data(block)?, |k, _, location| {
+            if k == key {
+                found = Some(*location);
+            }
+            found.is_none() && k < key
+        });
+        Ok(found)
+    }
+
+    fn entries_from(&self, lower: Bound<&[u8]>) -> FileEntries<'_> {
+        let next_block = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_of(key).unwrap_or(0),
+            Bound::Unbounded => 0,
+        };
+        FileEntries {
+            file: self,
+            next_block,
+            entries: Vec::new().into_iter(),
+            lower: lower.map(<[u8]>::to_vec),
+        }
+    }
+
+    // replay_from tells where replay of each segment starts, none if the checkpoint is stale. Segments
+    // are sorted by index and those created after the checkpoint are all newer than covered ones.
+    fn replay_from<'a>(&self, segments: &[&'a Segment]) -> Option<Vec<(&'a Segment, u64)>> {
+        let mut found = 0;
+        let mut uncovered = false;
+        let mut plan = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let len = fs::metadata(segment.path()).ok()?.len();
+            match self.segments.get(&segment.name()) {
+                Some(covered) if *covered > len || uncovered => return None,
+                Some(covered) => {
+                    found += 1;
+                    plan.push((*segment, *covered));
+                }
+                None => {
+                    uncovered = true;
+                    plan.push((*segment, 0));
+                }
+            }
+        }
+        (found == self.segments.len()).then_some(plan)
+    }
+}
+
+struct FileEntries<'a> {
+    file: &'a KeyDirFile,
+    next_block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, Location)>,
+    lower: Bound<Vec<u8>>,
+}
+
+// a damaged block ends FileEntries with its error
+impl Iterator for FileEntries<'_> {
+    type Item = Result<(Vec<u8>, Location)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for entry in self.entries.by_ref() {
+                let after_lower = match &self.lower {
+                    Bound::Included(l) => entry.0 >= *l,
+                    Bound::Excluded(l) => entry.0 > *l,
+                    Bound::Unbounded => true,
+                };
+                if after_lower {
+                    return Some(Ok(entry));
+                }
+            }
+            if self.next_block >= self.file.blocks {
+                return None;
+            }
+            let data = self.file.block_data(self.next_block);
+            self.next_block += 1;
+            match data {
+                Ok(data) => self.entries = decode_entries(data).into_iter(),
+                Err(e) => {
+                    self.next_block = self.file.blocks;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// CheckpointWriter writes the checkpoint file, digest covers what is written from first keys on
+struct CheckpointWriter {
+    out: BufWriter<File>,
+    digest: crc::Digest<'static, u32>,
+    written: u64,
+}
+
+impl CheckpointWriter {
+    fn put(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        self.digest.update(data);
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn put_varint(&mut self, v: u64) -> Result<()> {
+        let mut buf = Vec::with_capacity(10);
+        put_varint(&mut buf, v);
+        self.put(&buf)
+    }
+
+    // put_block writes entries as one block with its checksum and returns its first key, offset and length
+    fn put_block(&mut self, entries: &[(Vec<u8>, Location)]) -> Result<(Vec<u8>, u64, u64)> {
+        let data = encode_block(entries);
+        let offset = self.written;
+        self.put(&data)?;
+        self.put(&CRC32C.checksum(&data).to_le_bytes())?;
+        Ok((entries[0].0.clone(), offset, data.len() as u64))
+    }
+}
+
+impl Database {
+    pub(super) fn checkpoint_path(root_dir: &Path) -> PathBuf {
+        root_dir.join(KEYDIR_FILENAME)
+    }
+
+    // checkpoint_index writes the index of a database opened with persistent_index, so the next open
+    // replays only what is written after it. Changes kept in memory since the last checkpoint are released.
+    pub fn checkpoint_index(&mut self) -> Result<()> {
+        if !self.index.map.read().unwrap().is_mapped() {
+            return Err(invalid_input("index is not persistent"));
+        }
+        Self::write_checkpoint(&self.root_dir, &self.index, &self.storage)
+    }
+
+    // checkpoint_if_due checkpoints a persistent index once checkpoint_every changes are kept in memory,
+    // writes call it before they change the index
+    pub(super) fn checkpoint_if_due(&mut self) -> Result<()> {
+        let due = match &*self.index.map.read().unwrap() {
+            KeyDir::Mapped(dir) => dir.changes.len() >= self.checkpoint_every,
+            _ => false,
+        };
+        if due {
+            Self::write_checkpoint(&self.root_dir, &self.index, &self.storage)?;
+        }
+        Ok(())
+    }
+
+    // close_index checkpoints changes of a persistent index kept in memory, it is called on drop.
+    // A checkpoint with a damaged block is removed by the attempt, see write_checkpoint.
+    pub(super) fn close_index(&self) {
+        let due = match &*self.index.map.read().unwrap() {
+            KeyDir::Mapped(dir) => !dir.changes.is_empty() || dir.is_damaged(),
+            _ => false,
+        };
+        if due {
+            let _ = Self::write_checkpoint(&self.root_dir, &self.index, &self.storage);
+        }
+    }
+
+    // write_checkpoint replaces the checkpoint with one of index. If a block of the mapped one is damaged
+    // it fails with corruption and removes the checkpoint, so the next open rebuilds it.
+    fn write_checkpoint(root_dir: &Path, index: &Index, storage: &Directory) -> Result<()> {
+        let result = Self::write_checkpoint_file(root_dir, index, storage);
+        if result.as_ref().is_err_and(is_corruption) {
+            let _ = fs::remove_file(Self::checkpoint_path(root_dir));
+        }
+        result
+    }
+
+    fn write_checkpoint_file(root_dir: &Path, index: &Index, storage: &Directory) -> Result<()> {
+        let (sealed, active, active_len) = storage.checkpoint_files();
+        let mut segments: Vec<(String, u64)> = Vec::with_capacity(sealed.len() + 1);
+        for path in sealed {
+            // sealed segments do not change
+            let len = fs::metadata(&path)?.len();
+            segments.push((os_str_to_string(path.file_stem()), len));
+        }
+        segments.push((os_str_to_string(active.file_stem()), active_len));
+        let tmp_path = root_dir.join(KEYDIR_TMP_FILENAME);
+        let mut writer = CheckpointWriter {
+            out: BufWriter::new(File::create(&tmp_path)?),
+            digest: CRC32C.digest(),
+            written: 0,
+        };
+        let mut first_keys: Vec<(Vec<u8>, u64, u64)> = Vec::new();
+        let (entries, version) = {
+            let map = index.map.read().unwrap();
+            let mut block: Vec<(Vec<u8>, Location)> = Vec::with_capacity(MAX_BLOCK_ENTRIES);
+            map.for_each(|entry| {
+                block.push((entry.key.to_vec(), Location::of(&entry)?));
+                if block.len() == MAX_BLOCK_ENTRIES {
+                    first_keys.push(writer.put_block(&block)?);
+                    block.clear();
+                }
+                Ok(())
+            })?;
+            if !block.is_empty() {
+                first_keys.push(writer.put_block(&block)?);
+            }
+            (map.len() as u64, index.sequence.load(Ordering::Relaxed))
+        };
+        // blocks carry their own checksums, the footer one covers what follows them
+        let first_keys_offset = writer.written;
+        writer.digest = CRC32C.digest();
+        let mut table: Vec<u64> = Vec::with_capacity(first_keys.len());
+        for (key, offset, len) in first_keys.iter() {
+            table.push(writer.written);
+            writer.put_varint(key.len() as u64)?;
+            writer.put(key)?;
+            writer.put_varint(*offset)?;
+            writer.put_varint(*len)?;
+        }
+        let table_offset = writer.written;
+        for offset in table {
+            writer.put(&offset.to_le_bytes())?;
+        }
+        let segments_offset = writer.written;
+        writer.put_varint(segments.len() as u64)?;
+        for (name, len) in segments.iter() {
+            writer.put_varint(name.len() as u64)?;
+            writer.put(name.as_bytes())?;
+            writer.put_varint(*len)?;
+        }
+        for v in [first_keys_offset, table_offset, first_keys.len() as u64, entries, segments_offset, version] {
+            writer.put(&v.to_le_bytes())?;
+        }
+        let CheckpointWriter { mut out, digest, .. } = writer;
+        out.write_all(&digest.finalize().to_le_bytes())?;
+        out.write_all(KEYDIR_MAGIC)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        let path = Self::checkpoint_path(root_dir);
+        rename_durable(&tmp_path, &path)?;
+        let file = KeyDirFile::open(&path)?.ok_or_else(|| corruption("index checkpoint not found"))?;
+        *index.map.write().unwrap() = KeyDir::Mapped(MappedKeyDir::new(Some(file)));
+        Ok(())
+    }
+
+    // load_persistent_index maps the checkpoint and replays what was written after it. Without a usable
+    // checkpoint all segments are replayed and one is written, a damaged one is ignored like a hint file.
+    // So is one with a damaged block which replay looks up.
+    pub(super) fn load_persistent_index(
+        index: &mut Index,
+        storage: &Directory,
+        root_dir: &Path,
+        report: Option<Arc<dyn Fn(OpenProgress) + Send + Sync>>,
+        clock: &dyn Clock,
+    ) -> Result<Vec<SegmentLoadTiming>> {
+        let file = KeyDirFile::open(&Self::checkpoint_path(root_dir)).unwrap_or(None);
+        let mut timings: Vec<SegmentLoadTiming> = Vec::new();
+        let mut replayed = false;
+        {
+            let internal = storage.internal.read().unwrap();
+            let mut segments: Vec<&Segment> = internal.old_segments.values().collect();
+            segments.sort_by_key(|s| s.index());
+            let plan = file.as_ref().and_then(|file| file.replay_from(&segments));
+            if let (Some(file), Some(plan)) = (file, plan) {
+                index.sequence.fetch_max(file.version, Ordering::Relaxed);
+                *index.map.write().unwrap() = KeyDir::Mapped(MappedKeyDir::new(Some(file)));
+                replayed = true;
+                for (segment, offset) in plan {
+                    let start_ms = clock.now_millis();
+                    if replay(&index.map, &index.sequence, None, segment.iter_from(offset)).is_err() {
+                        replayed = false;
+                        break;
+                    }
+                    timings.push(SegmentLoadTiming {
+                        segment: segment.name(),
+                        from_hint: false,
+                        duration: Duration::from_millis(clock.now_millis().saturating_sub(start_ms)),
+                    });
+                }
+            }
+        }
+        if !replayed {
+            *index.map.write().unwrap() = KeyDir::Mapped(MappedKeyDir::new(None));
+            timings = Self::load_index(index, storage, report, false, clock)?;
+            Self::write_checkpoint(root_dir, index, storage)?;
+        }
+        Ok(timings)
+    }
+}
@@ -2,89 +2,415 @@ use std::{
     collections::{BTreeMap},
     ffi::OsStr,
     fs,
-    path::{PathBuf},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use super::database::Database;
+use super::{
+    database::Database,
+    hint::{read_hint, read_hint_with_timestamps, segment_timestamp, HintTimestamps},
+};
 use crate::{
-    storage::{segment::Segment, Bytes, RecordIndex, HINT_EXT_NAME, SEG_EXT_NAME},
-    utils::utils::{dir_exists, file_exists},
+    error::{corruption, invalid_input, locate},
+    storage::{
+        checksum::ChecksumAlgorithm,
+        directory::{MergePreparation, MergeRange},
+        fault,
+        segment::{parse_segment_stem, segment_stem, Segment},
+        Bytes, RecordIndex, BLOB_EXT_NAME, HINT_EXT_NAME, SEG_EXT_NAME,
+    },
+    utils::{
+        clock::Clock,
+        utils::{dir_exists, file_exists, rename_durable, sync_dir},
+    },
 };
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use std::io::prelude::*;
 
 pub(crate) static MERGE_FINISH_FILENAME: &str = "merge-finish";
 
-pub(super) struct LoadMerged {
-    pub(super) max_merged_segment: u64,
-    pub(super) hint_file: Option<PathBuf>,
+/*
+ * Merge finish file lists what adoption does, line by line:
+ * merge-finish v2
+ * generation <generation of output>
+ * source <segment>           for every merged segment
+ * output <file name> <size>  for every segment and hint file in merge dir
+ * crc32c <hex>               of the lines before it
+ * Merge finish files of former versions are "<max merged index> [<generation>]", or
+ * "<min merged index>-<max merged index> [<generation>]" after a partial merge, adoption removes older
+ * segments in that index range and copies every file of merge dir.
+ */
+const MERGE_FINISH_HEADER: &str = "merge-finish v2";
+
+struct MergeManifest {
+    generation: u64,
+    sources: Vec<String>,
+    outputs: Vec<(String, u64)>,
+}
+
+impl MergeManifest {
+    fn encode(&self) -> String {
+        let mut body = format!("{}\ngeneration {}\n", MERGE_FINISH_HEADER, self.generation);
+        for source in self.sources.iter() {
+            body.push_str(&format!("source {}\n", source));
+        }
+        for (name, size) in self.outputs.iter() {
+            body.push_str(&format!("output {} {}\n", name, size));
+        }
+        let sum = Self::checksum(&body);
+        body + &format!("crc32c {:08x}\n", sum)
+    }
+
+    // decode returns none for merge finish files of former versions
+    fn decode(content: &str) -> Result<Option<MergeManifest>> {
+        if !content.starts_with(MERGE_FINISH_HEADER) {
+            return Ok(None);
+        }
+        let invalid = || corruption("invalid merge finish file");
+        let body_len = content.trim_end().rfind('\n').ok_or_else(invalid)? + 1;
+        let (body, sum_line) = content.split_at(body_len);
+        let sum = sum_line.trim().strip_prefix("crc32c ").ok_or_else(invalid)?;
+        if u32::from_str_radix(sum, 16).ok() != Some(Self::checksum(body)) {
+            return Err(corruption("checksum mismatch of merge finish file"));
+        }
+        let mut manifest = MergeManifest {
+            generation: 0,
+            sources: Vec::new(),
+            outputs: Vec::new(),
+        };
+        for line in body.lines().skip(1) {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["generation", generation] => manifest.generation = generation.parse().map_err(|_| invalid())?,
+                ["source", segment] => manifest.sources.push(segment.to_string()),
+                ["output", name, size] => manifest.outputs.push((name.to_string(), size.parse().map_err(|_| invalid())?)),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    fn checksum(body: &str) -> u32 {
+        let digest = ChecksumAlgorithm::Crc32c.digest(&[], body.as_bytes(), &[]);
+        u32::from_le_bytes(digest[..4].try_into().unwrap())
+    }
+}
+
+// MergeStats tells how effective a merge was. Merged segments replace the old ones when merge returns,
+// or on next open if a reader of segments held them meanwhile. bytes_reclaimed is freed then.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergeStats {
+    pub segments_merged: u64,
+    pub records_retained: u64,
+    pub records_dropped: u64, // overwritten or deleted records, and tombstones no longer needed
+    pub bytes_reclaimed: u64,  // bytes of merged segments minus bytes of their replacement
+    pub duration: Duration,
+}
+
+// MergeJob is a merge planned by the database. It only reads sealed segments and writes to merge dir,
+// so it runs without borrowing the database and writes may go on meanwhile.
+pub(super) struct MergeJob {
+    preparation: MergePreparation,
+    merge_dir: PathBuf,
+    checksum: ChecksumAlgorithm,
+    clock: Arc<dyn Clock>,
+    start_ms: u64,
+    timestamps: Arc<HintTimestamps>, // of records indexed from hint files, e.g. of former merged output
+}
+
+// MergeOutput is a finished merge which is not adopted yet
+pub(super) struct MergeOutput {
+    stats: MergeStats,
+    sources: Vec<String>,
+    outputs: Vec<PathBuf>, // merged segments in data dir once adopted
+    moved: Vec<(RecordIndex, RecordIndex)>, // live records of sources and their copies
 }
 
 impl Database {
-    pub fn merge(&self) -> Result<()> {
+    pub fn merge(&self) -> Result<MergeStats> {
+        let _merging = self.merge_lock.lock().unwrap();
+        self.merge_locked(MergeRange::All)
+    }
+
+    // merge_newest merges the newest sealed segments only, at most segments of them. Older segments stay
+    // un-merged, so tombstones of merged segments are kept until a later merge takes those segments too.
+    pub fn merge_newest(&self, segments: usize) -> Result<MergeStats> {
+        let _merging = self.merge_lock.lock().unwrap();
+        self.merge_locked(MergeRange::Newest(segments))
+    }
+
+    // compact_range reclaims space of keys in range, e.g. after they were deleted. Space is reclaimed by
+    // segment, so it merges the oldest segment which holds a record of a key in range and all newer ones,
+    // records of other keys in them are rewritten too. Segments are searched in their hint files, a segment
+    // without one is scanned. It merges nothing if no segment holds a key in range.
+    pub fn compact_range<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<MergeStats> {
+        let (start, end) = (range.start.as_ref(), range.end.as_ref());
+        let _merging = self.merge_lock.lock().unwrap();
+        // the active segment is sealed by the merge, so it is searched as well
+        let (mut segments, active, _) = self.storage.checkpoint_files();
+        segments.push(active);
+        for path in segments.iter() {
+            if Self::holds_key_in(path, start, end)? {
+                let from = path.file_stem().and_then(OsStr::to_str).and_then(parse_segment_stem);
+                let (_, from) = from.ok_or_else(|| corruption(format!("malformed segment name {:?}", path)))?;
+                return self.merge_locked(MergeRange::From(from));
+            }
+        }
+        Ok(MergeStats::default())
+    }
+
+    // holds_key_in tells whether segment holds a record of a key from start to end, end excluded
+    fn holds_key_in(segment_path: &Path, start: &[u8], end: &[u8]) -> Result<bool> {
+        let in_range = |key: &Bytes| start <= key.as_slice() && key.as_slice() < end;
+        if let Ok(Some(records)) = read_hint(segment_path) {
+            return Ok(records.iter().any(|record_index| in_range(&record_index.key)));
+        }
+        let segment = Segment::open_read_only(segment_path.to_owned());
+        let mut iter = segment.iter();
+        if iter.by_ref().any(|record_index| in_range(&record_index.key)) {
+            return Ok(true);
+        }
+        iter.finish().map_err(|e| locate(e, Some(&segment.name()), Some(iter.resume_offset()), None))?;
+        Ok(false)
+    }
+
+    // callers hold merge_lock
+    fn merge_locked(&self, range: MergeRange) -> Result<MergeStats> {
+        let job = match self.plan_merge(range)? {
+            Some(job) => job,
+            None => return Ok(MergeStats::default()),
+        };
+        let output = job.run()?;
+        self.finish_merge(output)
+    }
+
+    // finish_merge adopts merged output while the database is open. Under the index lock, entries which still
+    // point at a record of a source point at its copy then, keys written since merge began keep theirs.
+    // Sources are removed after. Callers hold merge_lock.
+    pub(super) fn finish_merge(&self, output: MergeOutput) -> Result<MergeStats> {
+        // the hint thread must not write hints of removed sources
+        let _hints = self.hint_writer.lock.lock().unwrap();
+        self.storage.replace_segments(&output.sources, &output.outputs, || {
+            Self::try_load_merged(&self.root_dir)?;
+            let mut map = self.index.map.write().unwrap();
+            for (source, mut copy) in output.moved {
+                let Some(current) = map.get(source.key.as_slice())? else {
+                    continue;
+                };
+                if current.segment != source.segment || current.offset != source.offset {
+                    continue;
+                }
+                copy.version = current.version;
+                copy.value = current.value;
+                map.insert(copy)?;
+            }
+            drop(map);
+            // merged records keep their timestamps, as they do when output is adopted on open
+            for path in output.outputs.iter() {
+                if let Ok(Some((_, timestamps))) = read_hint_with_timestamps(path) {
+                    self.index.timestamps.merge(timestamps);
+                }
+            }
+            Ok(())
+        })?;
+        self.record_merge()?;
+        Ok(output.stats)
+    }
+
+    // plan_merge seals the active segment and returns the merge of the sealed segments of range. It returns none
+    // if there are none. Callers hold merge_lock until the merge is recorded.
+    pub(super) fn plan_merge(&self, range: MergeRange) -> Result<Option<MergeJob>> {
+        let start_ms = self.clock.now_millis();
+        if self.index.filter.is_some() {
+            return Err(invalid_input("merge of a key filtered database would drop records of other keys"));
+        }
+        // records only known to segments are not indexed yet, merge would drop them
+        self.wait_hydrated()?;
         // load record index
-        let preparation = self.storage.prepare_merge()?;
+        let preparation = self.storage.prepare_merge(range)?;
         if preparation.to_merge.is_empty() {
-            return Ok(());
+            return Ok(None);
         }
+        Ok(Some(MergeJob {
+            preparation,
+            merge_dir: Self::get_merge_dir(&self.root_dir),
+            checksum: self.storage.checksum(),
+            clock: self.clock.clone(),
+            start_ms,
+            timestamps: self.index.timestamps.clone(),
+        }))
+    }
+}
+
+impl MergeJob {
+    // run rewrites live records of planned segments, Database::finish_merge puts merged output in their place
+    pub(super) fn run(self) -> Result<MergeOutput> {
+        let preparation = &self.preparation;
+        let mut stats = MergeStats::default();
+        // replay segments from oldest to newest, tombstones must shadow former records
         let mut records: BTreeMap<Bytes, RecordIndex> = BTreeMap::new();
-        let mut segments: BTreeMap<String, Segment> = BTreeMap::new();
-        for path in preparation.to_merge.iter() {
+        let mut segments: BTreeMap<String, (usize, Segment)> = BTreeMap::new(); // with position in to_merge
+        let mut min_merged_segment: u64 = u64::MAX;
+        let mut max_merged_segment: u64 = 0;
+        let mut max_merged_generation: u64 = 0;
+        let mut records_scanned: u64 = 0;
+        let mut bytes_merged: u64 = 0;
+        for (position, path) in preparation.to_merge.iter().enumerate() {
             let seg = Segment::open_read_only(path.to_owned());
             for ri in seg.iter() {
-                if !ri.is_deleted() {
-                    records.insert(ri.key.clone(), ri);
-                }
+                records_scanned += 1;
+                records.insert(ri.key.clone(), ri);
             }
-            segments.insert(seg.name(), seg);
+            min_merged_segment = min_merged_segment.min(seg.index());
+            max_merged_segment = max_merged_segment.max(seg.index());
+            bytes_merged += fs::metadata(path).map_or(0, |m| m.len());
+            max_merged_generation = max_merged_generation.max(seg.generation());
+            segments.insert(seg.name(), (position, seg));
         }
-        let merge_dir = Self::get_merge_dir(&self.root_dir);
+        // A tombstone can be dropped only if no un-merged segment older than it exists,
+        // otherwise the deleted key would resurrect from that segment on next load.
+        records.retain(|_, ri| {
+            !ri.is_deleted()
+                || preparation
+                    .min_unmerged_segment
+                    .is_some_and(|min| parse_segment_stem(&ri.segment).is_some_and(|(_, index)| min < index))
+        });
+        let merge_dir = &self.merge_dir;
+        let data_dir = preparation.to_merge.first().and_then(|path| path.parent()).unwrap_or(Path::new("."));
         // remove former merged data
-        let _ = std::fs::remove_dir_all(&merge_dir);
-        std::fs::create_dir_all(&merge_dir)?;
+        let _ = std::fs::remove_dir_all(merge_dir);
+        std::fs::create_dir_all(merge_dir)?;
 
-        // write to new segments
-        let mut index: u64 = 1;
-        let mut active_segment = Segment::create(&merge_dir, index, SEG_EXT_NAME)?;
-        let hint_file = Segment::create(&merge_dir, 1, HINT_EXT_NAME)?;
+        // write to new segments of the next generation. Output of a partial merge takes indexes within those
+        // of merged segments, so it is replayed after older un-merged segments and before newer ones.
+        let generation = max_merged_generation + 1;
+        let first_index = if preparation.min_unmerged_segment.is_some() { min_merged_segment } else { 1 };
+        let mut index = first_index;
+        let checksum = self.checksum;
+        let mut active_segment = Segment::create(merge_dir, generation, index, SEG_EXT_NAME)?.with_checksum(checksum);
+        // every merged segment has its own hint file
+        let mut hint_file = Segment::create(merge_dir, generation, index, HINT_EXT_NAME)?;
         let mut buf: Vec<u8> = Vec::new();
-        for (_, record_index) in records.iter() {
-            if let Some(seg) = segments.get(record_index.segment.as_str()) {
+        let mut moved: Vec<(RecordIndex, RecordIndex)> = Vec::new();
+        let mut source_timestamps: BTreeMap<Arc<str>, u64> = BTreeMap::new();
+        // live records are copied in the order of their sources, so every source is read front to back once
+        let mut retained: Vec<(usize, &RecordIndex)> = records
+            .values()
+            .map(|ri| (segments.get(&*ri.segment).map_or(usize::MAX, |(position, _)| *position), ri))
+            .collect();
+        retained.sort_unstable_by_key(|(position, ri)| (*position, ri.offset));
+        for (_, record_index) in retained {
+            // records keep their own timestamp if it is known, the one of the segment they were written to otherwise
+            let timestamp = match self.timestamps.get(&record_index.segment, record_index.offset) {
+                Some(timestamp) => timestamp,
+                None => *source_timestamps.entry(record_index.segment.clone()).or_insert_with(|| match segments.get(&*record_index.segment) {
+                    Some((_, seg)) => segment_timestamp(&seg.path()),
+                    None => segment_timestamp(&data_dir.join(format!("{}.{}", record_index.segment, BLOB_EXT_NAME))),
+                }),
+            };
+            let (write_result, hint_record) = if let Some((_, seg)) = segments.get(&*record_index.segment) {
                 let record = seg.read_at(record_index.offset)?;
-                let write_result = active_segment.write(
+                let write_result = active_segment.write_with_metadata(
                     record.key.as_slice(),
                     record.value.as_slice(),
                     record.flag,
+                    record.metadata,
                 )?;
                 let hint_record = RecordIndex {
                     key: record_index.key.clone(),
-                    segment: active_segment.name(),
-                    flag: 0,
+                    segment: active_segment.shared_name(),
+                    flag: record.flag,
                     offset: write_result.begin_offset,
+                    value_size: record.value.len() as u64,
                     value: None,
+                    version: 0,
                 };
-                Self::encode_record_index(&mut buf, &hint_record);
-                // use only one hint file, ignore is_segment_full
-                hint_file.write(record.key.as_slice(), buf.as_slice(), 0)?;
-                if write_result.is_segment_full {
-                    index += 1;
-                    active_segment = Segment::create(&merge_dir, index, SEG_EXT_NAME)?
+                if !hint_record.is_deleted() {
+                    moved.push((record_index.clone(), hint_record.clone()));
                 }
+                (write_result, hint_record)
             } else {
-                // unreachable
-                return Err(anyhow!("segment not found"));
+                // the record is in a blob segment, only the pointer to it is copied
+                (active_segment.write_blob_pointer(record_index)?, record_index.clone())
+            };
+            Database::encode_record_index(&mut buf, &hint_record, timestamp);
+            // retained tombstones keep their flag in hint file
+            hint_file.write(hint_record.key.as_slice(), buf.as_slice(), hint_record.flag)?;
+            if write_result.is_segment_full {
+                if index >= max_merged_segment {
+                    // no free index left between un-merged segments, the output would be replayed out of order
+                    let _ = std::fs::remove_dir_all(merge_dir);
+                    return Err(invalid_input("merge output outgrows the segment indexes of its sources"));
+                }
+                active_segment.sync()?;
+                hint_file.sync()?;
+                index += 1;
+                active_segment = Segment::create(merge_dir, generation, index, SEG_EXT_NAME)?.with_checksum(checksum);
+                hint_file = Segment::create(merge_dir, generation, index, HINT_EXT_NAME)?;
             }
         }
 
-        // write merge finish file into
-        let merge_finish_path = Self::get_merge_dir(&self.root_dir).join(MERGE_FINISH_FILENAME);
-        let mut merge_finish_file = std::fs::File::create(&merge_finish_path)?;
-        let max_merged_segment_name = segments.last_key_value().unwrap().1.name();
-        merge_finish_file.write(max_merged_segment_name.as_bytes())?;
-        Ok(())
+        active_segment.sync()?;
+        hint_file.sync()?;
+
+        // write merge finish file into, merged data must be durable before it
+        fault::check("merge.finish")?;
+        let merge_finish_path = merge_dir.join(MERGE_FINISH_FILENAME);
+        let merge_finish_tmp_path = merge_dir.join(format!("{}.tmp", MERGE_FINISH_FILENAME));
+        let mut outputs: Vec<(String, u64)> = Vec::new();
+        for ext in [SEG_EXT_NAME, HINT_EXT_NAME] {
+            for i in first_index..=index {
+                let name = format!("{}.{}", segment_stem(generation, i), ext);
+                outputs.push((name.clone(), fs::metadata(merge_dir.join(&name))?.len()));
+            }
+        }
+        let manifest = MergeManifest {
+            generation,
+            sources: segments.keys().cloned().collect(),
+            outputs,
+        };
+        let mut merge_finish_file = fs::File::create(&merge_finish_tmp_path)?;
+        merge_finish_file.write_all(manifest.encode().as_bytes())?;
+        merge_finish_file.sync_all()?;
+        rename_durable(&merge_finish_tmp_path, &merge_finish_path)?;
+
+        let seg_suffix = format!(".{}", SEG_EXT_NAME);
+        let bytes_written: u64 = manifest
+            .outputs
+            .iter()
+            .filter(|(name, _)| name.ends_with(&seg_suffix))
+            .map(|(_, size)| size)
+            .sum();
+        stats.segments_merged = preparation.to_merge.len() as u64;
+        stats.records_retained = records.len() as u64;
+        stats.records_dropped = records_scanned - stats.records_retained;
+        stats.bytes_reclaimed = bytes_merged.saturating_sub(bytes_written);
+        stats.duration = Duration::from_millis(self.clock.now_millis().saturating_sub(self.start_ms));
+        let outputs = (first_index..=index)
+            .map(|i| data_dir.join(format!("{}.{}", segment_stem(generation, i), SEG_EXT_NAME)))
+            .collect();
+        Ok(MergeOutput {
+            stats,
+            sources: manifest.sources,
+            outputs,
+            moved,
+        })
+    }
+}
+
+impl Database {
+    // migrate_format rewrites segments of former formats in the current one. It is a merge of all
+    // sealed segments and takes effect like one, nothing is done if they are all of the current format.
+    pub fn migrate_format(&self) -> Result<MergeStats> {
+        if self.storage.old_format_segments()? == 0 {
+            return Ok(MergeStats::default());
+        }
+        self.merge()
     }
 
-    pub(super) fn try_load_merged(root_path: &PathBuf) -> Result<()> {
+    pub(super) fn try_load_merged(root_path: &Path) -> Result<()> {
         let merge_dir = Self::get_merge_dir(root_path);
         let data_dir = Self::get_data_dir(root_path);
         if !dir_exists(merge_dir.as_path()) {
@@ -98,75 +424,151 @@ impl Database {
             return Ok(());
         }
 
-        // remove merged segments
+        // remove merged segments, the sources of the merge finish file. Older merge finish files name the
+        // merged index range, merged segments are of an older generation than merged output with an index
+        // in it. The oldest ones hold no generation, their merged output takes the names of the segments it replaces.
         // If this process is interrupted, it will continue to delete old segments on the next startup because the merged finish file is still exists
         let merge_finish_file = fs::read_to_string(&merge_finish_path)?;
-        let max_merged_segment = merge_finish_file.trim().parse::<u64>()?;
-        for i in 1..(max_merged_segment + 1) {
-            let merged_segment_name = format!("{}.{}", i, SEG_EXT_NAME);
-            let merged_path = data_dir.join(merged_segment_name);
-            fs::remove_file(merged_path)?;
-        }
-
-        // copy merged segments to data dir
-        // The maximum index of merged segments must be less than or equal to deleted segments
-        // If this process is interrupted, it will continue to copy merged segments on the next startup because the merged directory is still complete
-        for e in fs::read_dir(merge_dir.as_path())? {
-            if let Ok(entry) = e {
+        let manifest = MergeManifest::decode(&merge_finish_file)?;
+        if let Some(manifest) = manifest.as_ref() {
+            // outputs are checked before any source is removed, an interrupted adoption moved some of them already
+            let size_of = |path: PathBuf| fs::metadata(path).map(|m| m.len()).ok();
+            for (name, size) in manifest.outputs.iter() {
+                if size_of(merge_dir.join(name)) != Some(*size) && size_of(data_dir.join(name)) != Some(*size) {
+                    return Err(corruption(format!("merge output {} is missing or of another size", name)));
+                }
+            }
+        }
+        let (merged_range, merged_generation) = match manifest.as_ref() {
+            Some(manifest) => ((1, 0), Some(manifest.generation)),
+            None => {
+                let mut fields = merge_finish_file.split_whitespace();
+                let merged_range = Self::parse_merge_finish(fields.next().unwrap_or_default())?;
+                (merged_range, fields.next().map(|g| g.parse::<u64>()).transpose()?)
+            }
+        };
+        let is_merged = |path: &Path| {
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            if let Some(manifest) = manifest.as_ref() {
+                return stem.is_some_and(|stem| manifest.sources.iter().any(|source| source == stem));
+            }
+            stem.and_then(parse_segment_stem).is_some_and(|(generation, index)| {
+                (merged_range.0..=merged_range.1).contains(&index)
+                    && merged_generation.is_none_or(|merged| generation < merged)
+            })
+        };
+        let is_output = |path: &Path| {
+            let name = path.file_name().and_then(OsStr::to_str);
+            manifest.as_ref().is_none_or(|manifest| {
+                name.is_some_and(|name| manifest.outputs.iter().any(|(output, _)| output == name))
+            })
+        };
+        // hint of a removed segment must not be taken for the hint of a later segment with its id
+        for ext in [HINT_EXT_NAME, SEG_EXT_NAME] {
+            for entry in fs::read_dir(data_dir.as_path())?.flatten() {
                 let p = entry.path();
-                if p.is_file() && p.extension() == Some(OsStr::new(SEG_EXT_NAME)) {
-                    let target_path = data_dir.join(p.file_name().unwrap());
-                    fs::copy(p.as_path(), target_path.as_path())?;
+                if p.is_file() && p.extension() == Some(OsStr::new(ext)) && is_merged(&p) {
+                    fs::remove_file(p)?;
+                    fault::check("merge.adopt.remove")?;
                 }
             }
         }
 
-        // copy hint file
-        let hint_filename = format!("{}.{}", 1, HINT_EXT_NAME);
-        let src_hint_file = merge_dir.join(hint_filename.as_str());
-        let mut hint_file: Option<PathBuf> = None;
-        if file_exists(&src_hint_file) {
-            let target_path = data_dir.join(hint_filename);
-            fs::copy(src_hint_file.as_path(), target_path.as_path())?;
-            hint_file = Some(target_path);
+        // move merged segments to data dir, then their hint files
+        // The maximum index of merged segments must be less than or equal to deleted segments, so they are replayed before unmerged ones
+        // If this process is interrupted, it will continue to move merged segments on the next startup because
+        // the merge finish file is still in merge dir. Output of the oldest merge finish files takes the names of
+        // merged segments, the removal above would take moved ones for merged segments, so it is copied.
+        // Moved files were synced by merge, copies are synced here.
+        let mut copied: Vec<PathBuf> = Vec::new();
+        for ext in [SEG_EXT_NAME, HINT_EXT_NAME] {
+            for entry in fs::read_dir(merge_dir.as_path())?.flatten() {
+                let p = entry.path();
+                if p.is_file() && p.extension() == Some(OsStr::new(ext)) && is_output(&p) {
+                    let target_path = data_dir.join(p.file_name().unwrap());
+                    // rename fails across file systems
+                    if merged_generation.is_none() || fs::rename(&p, &target_path).is_err() {
+                        fs::copy(p.as_path(), target_path.as_path())?;
+                        copied.push(target_path);
+                    }
+                    fault::check("merge.adopt.copy")?;
+                }
+            }
         }
 
         // copy merge finish file
         let target_merge_finish_path = data_dir.join(MERGE_FINISH_FILENAME);
-        fs::copy(merge_finish_path, target_merge_finish_path)?;
-        
+        fs::copy(merge_finish_path, &target_merge_finish_path)?;
+        copied.push(target_merge_finish_path);
+
+        // copies, moves and removals must be durable before merged data is removed
+        for path in copied.iter() {
+            fs::File::open(path)?.sync_all()?;
+        }
+        sync_dir(&data_dir)?;
+
         // The data dir is complete now, it is safe to remove merge dir
         fs::remove_dir_all(merge_dir.as_path())?;
         Ok(())
     }
 
-    // encode segment name and offset to bytes for hint file
-    pub(super) fn encode_record_index(buf: &mut Vec<u8>, index: &RecordIndex) {
+    // parse_merge_finish returns the lowest and highest index of merged segments from the range field of a former
+    // merge finish file. A full merge wrote the highest index only, a partial merge "<lowest>-<highest>".
+    pub(super) fn parse_merge_finish(merge_finish: &str) -> Result<(u64, u64)> {
+        let parse = |x: &str| x.parse::<u64>().map_err(|_| corruption("invalid merge finish file"));
+        match merge_finish.trim().split_once('-') {
+            Some((min, max)) => Ok((parse(min)?, parse(max)?)),
+            None => Ok((1, parse(merge_finish.trim())?)),
+        }
+    }
+
+    // encode record location to bytes for hint file:
+    // | segment name | \0 | offset (8B) | value size (8B) | timestamp (8B) |, integers are little endian.
+    // Hint records of former versions end after offset.
+    pub(super) fn encode_record_index(buf: &mut Vec<u8>, index: &RecordIndex, timestamp: u64) {
         buf.clear();
         buf.extend_from_slice(index.segment.as_bytes());
         buf.push(b'\0'); // separator
         buf.extend_from_slice(index.offset.to_le_bytes().as_slice());
+        buf.extend_from_slice(index.value_size.to_le_bytes().as_slice());
+        buf.extend_from_slice(timestamp.to_le_bytes().as_slice());
     }
 
-    pub(super) fn decode_record_index(key: Bytes, hint_value: Bytes) -> Result<RecordIndex> {
-        let segment: String;
-        let offset: u64;
-        match hint_value.as_slice().iter().position(|&x| x == 0) {
-            Some(pivot) => {
-                let seg_bytes = hint_value.as_slice()[..pivot].to_vec();
-                segment = String::from_utf8(seg_bytes)?;
-                offset = u64::from_le_bytes(hint_value.as_slice()[pivot + 1..].try_into().unwrap());
-            }
-            None => {
-                return Err(anyhow!("pivot not found in hint record"));
-            }
-        };
-        Ok(RecordIndex {
-            key: key,
-            segment: segment,
-            flag: 0,
-            offset: offset,
-            value: None,
-        })
+    // decode_hint returns the index entry of a hint record and its timestamp, none for hints of former versions
+    pub(crate) fn decode_hint(key: Bytes, hint_flag: u8, hint_value: Bytes) -> Result<(RecordIndex, Option<u64>)> {
+        Self::decode_hint_value(hint_value.as_slice())
+            .map(|(segment, offset, value_size, timestamp)| {
+                let record_index = RecordIndex {
+                    key: key.clone(),
+                    segment: Arc::from(segment),
+                    flag: hint_flag,
+                    offset,
+                    value_size,
+                    value: None,
+                    version: 0,
+                };
+                (record_index, timestamp)
+            })
+            .map_err(|e| locate(e, None, None, Some(key.as_slice())))
+    }
+
+    // returns segment, offset, value size and timestamp
+    fn decode_hint_value(hint_value: &[u8]) -> Result<(String, u64, u64, Option<u64>)> {
+        let pivot = hint_value
+            .iter()
+            .position(|&x| x == 0)
+            .ok_or_else(|| corruption("pivot not found in hint record"))?;
+        let segment = String::from_utf8(hint_value[..pivot].to_vec())
+            .map_err(|_| corruption("invalid segment name in hint record"))?;
+        let fields: Vec<u64> = hint_value[pivot + 1..]
+            .chunks(8)
+            .map(|chunk| chunk.try_into().map(u64::from_le_bytes))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| corruption("invalid hint record length"))?;
+        match fields.as_slice() {
+            [offset] => Ok((segment, *offset, 0, None)),
+            [offset, value_size, timestamp] => Ok((segment, *offset, *value_size, Some(*timestamp))),
+            _ => Err(corruption("invalid hint record length")),
+        }
     }
 }
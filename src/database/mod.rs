@@ -1,3 +1,24 @@
+pub(crate) mod append;
+pub(crate) mod blob;
+pub(crate) mod hint;
+mod hydration;
 mod index;
+pub(crate) mod invalidate;
+mod keydir;
+mod mapped_keydir;
+#[allow(clippy::module_inception)]
 pub mod database;
-mod merge;
\ No newline at end of file
+pub(crate) mod follower;
+pub(crate) mod merge;
+pub(crate) mod pipeline;
+pub(crate) mod redact;
+pub(crate) mod redis;
+pub(crate) mod scan;
+pub(crate) mod secondary;
+pub(crate) mod sharded;
+pub(crate) mod stats;
+mod snapshot;
+mod sstable;
+pub(crate) mod tail;
+mod transfer;
+pub(crate) mod typed;
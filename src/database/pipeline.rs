@@ -0,0 +1,324 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Result};
+
+use super::{
+    database::{Backpressure, Database, SyncPolicy, WriteThrottled},
+    merge::MergeStats,
+};
+use crate::{
+    error::StoreError,
+    storage::{directory::MergeRange, Bytes},
+};
+
+/*
+ * Async write pipeline, every mutation goes through one writer thread:
+ * callers -> bounded channel -> writer thread: batch (write_many, delete_many) -> fsync -> complete handles
+ * A batch is applied in the order of its mutations, consecutive ones of the same kind with one write.
+ * Mutations of concurrent callers share the fsync of their batch, which is the group commit of
+ * SyncPolicy::Always for callers of the pipeline.
+ *
+ * Callers only pay for enqueueing, a full channel blocks write() or fails it with WriteThrottled
+ * according to Backpressure of the database, which bounds queued memory.
+ *
+ * Queued mutations are buffered in user space until their batch is applied, read() serves a key from
+ * the latest queued mutation of it, see PendingKeys.
+ */
+
+const MAX_BATCH: usize = 1024;
+
+struct PendingWrite {
+    key: Vec<u8>,
+    seq: u64, // of the mutation in PendingKeys
+    mutation: Mutation,
+}
+
+// PendingKeys maps every key with queued mutations to the sequence and value of the latest one, none for a deletion.
+// A key is removed once its latest mutation is applied, or failed, so reads fall back to the database.
+#[derive(Default)]
+struct PendingKeys {
+    next_seq: u64,
+    keys: BTreeMap<Vec<u8>, (u64, Option<Vec<u8>>)>,
+}
+
+impl PendingKeys {
+    // push returns the sequence of the mutation
+    fn push(&mut self, key: &[u8], value: Option<Vec<u8>>) -> u64 {
+        self.next_seq += 1;
+        self.keys.insert(key.to_vec(), (self.next_seq, value));
+        self.next_seq
+    }
+
+    // pop removes key unless a later mutation of it is queued
+    fn pop(&mut self, key: &[u8], seq: u64) {
+        if self.keys.get(key).is_some_and(|(latest, _)| *latest == seq) {
+            self.keys.remove(key);
+        }
+    }
+}
+
+impl PendingWrite {
+    fn is_write(&self) -> bool {
+        matches!(self.mutation, Mutation::Write { .. })
+    }
+
+    // complete passes the result of apply to the handle
+    fn complete(&self, result: Result<u64>) {
+        match &self.mutation {
+            Mutation::Write { done, .. } => {
+                let _ = done.send(result);
+            }
+            Mutation::Delete { done } => {
+                let _ = done.send(result.map(|existed| existed > 0));
+            }
+        }
+    }
+}
+
+enum Mutation {
+    Write {
+        value: Vec<u8>,
+        done: mpsc::Sender<Result<u64>>,
+    },
+    Delete {
+        done: mpsc::Sender<Result<bool>>,
+    },
+}
+
+// WriteHandle completes when its record is written and synced
+pub struct WriteHandle {
+    done: Receiver<Result<u64>>,
+}
+
+impl WriteHandle {
+    // wait blocks until the record is durable and returns its version
+    pub fn wait(self) -> Result<u64> {
+        self.done.recv().map_err(|_| anyhow::Error::from(StoreError::Closed))?
+    }
+}
+
+// DeleteHandle completes when its tombstone is written and synced
+pub struct DeleteHandle {
+    done: Receiver<Result<bool>>,
+}
+
+impl DeleteHandle {
+    // wait blocks until the deletion is durable and returns whether the key existed, see Database::delete
+    pub fn wait(self) -> Result<bool> {
+        self.done.recv().map_err(|_| anyhow::Error::from(StoreError::Closed))?
+    }
+}
+
+pub struct AsyncWriter {
+    database: Arc<RwLock<Database>>,
+    pending: Arc<Mutex<PendingKeys>>,
+    // serializes enqueueing, so mutations are sent in the order of their sequences. It is not pending,
+    // a send blocked by a full channel must not keep the writer thread from popping applied keys.
+    order: Mutex<()>,
+    sender: Option<SyncSender<PendingWrite>>,
+    worker: Option<JoinHandle<()>>,
+    backpressure: Backpressure,
+}
+
+impl Database {
+    // into_async moves database into a write pipeline, capacity bounds queued writes
+    pub fn into_async(self, capacity: usize) -> AsyncWriter {
+        let backpressure = self.backpressure;
+        let database = Arc::new(RwLock::new(self));
+        let pending = Arc::new(Mutex::new(PendingKeys::default()));
+        let (sender, receiver) = mpsc::sync_channel::<PendingWrite>(capacity);
+        let worker_database = database.clone();
+        let worker_pending = pending.clone();
+        let worker = thread::spawn(move || run_writer(worker_database, worker_pending, receiver));
+        AsyncWriter {
+            database,
+            pending,
+            order: Mutex::new(()),
+            sender: Some(sender),
+            worker: Some(worker),
+            backpressure,
+        }
+    }
+}
+
+impl AsyncWriter {
+    pub fn write(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<WriteHandle> {
+        let (done, receiver) = mpsc::channel();
+        let value = value.as_ref();
+        let mutation = Mutation::Write {
+            value: value.to_vec(),
+            done,
+        };
+        self.enqueue(key.as_ref(), Some(value.to_vec()), mutation)?;
+        Ok(WriteHandle { done: receiver })
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<DeleteHandle> {
+        let (done, receiver) = mpsc::channel();
+        self.enqueue(key.as_ref(), None, Mutation::Delete { done })?;
+        Ok(DeleteHandle { done: receiver })
+    }
+
+    // the mutation is in PendingKeys before it is sent, so the writer thread never applies one missing there.
+    // Its sequence is taken and it is sent under the order lock, so a later mutation of a key is applied later.
+    fn enqueue(&self, key: &[u8], value: Option<Vec<u8>>, mutation: Mutation) -> Result<()> {
+        let _order = self.order.lock().unwrap();
+        let seq = self.pending.lock().unwrap().push(key, value);
+        let pending = PendingWrite {
+            key: key.to_vec(),
+            seq,
+            mutation,
+        };
+        let sender = self.sender.as_ref().unwrap();
+        let sent = match self.backpressure {
+            Backpressure::Block => sender.send(pending).map_err(|_| anyhow::Error::from(StoreError::Closed)),
+            Backpressure::Error => sender.try_send(pending).map_err(|e| match e {
+                TrySendError::Full(_) => anyhow::Error::from(WriteThrottled),
+                TrySendError::Disconnected(_) => anyhow::Error::from(StoreError::Closed),
+            }),
+        };
+        if sent.is_err() {
+            self.pending.lock().unwrap().pop(key, seq);
+        }
+        sent
+    }
+
+    // reads see a mutation once it is queued. A queued one is served from PendingKeys, an applied one
+    // from the database, which may be before its handle completes.
+    pub fn read(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        let key = key.as_ref();
+        if let Some((_, value)) = self.pending.lock().unwrap().keys.get(key) {
+            return Ok(value.as_deref().map(Bytes::from));
+        }
+        self.database.read().unwrap().read(key)
+    }
+
+    // merge holds the database only to plan and to finish, queued writes go on while segments are
+    // rewritten. Keys written since planning keep their entries when merged output is adopted.
+    // Merges are serialized by merge_lock of the database, which is not held with the database.
+    pub fn merge(&self) -> Result<MergeStats> {
+        let merge_lock = self.database.read().unwrap().merge_lock.clone();
+        let _merging = merge_lock.lock().unwrap();
+        let job = self.database.read().unwrap().plan_merge(MergeRange::All)?;
+        let job = match job {
+            Some(job) => job,
+            None => return Ok(MergeStats::default()),
+        };
+        let output = job.run()?;
+        self.database.read().unwrap().finish_merge(output)
+    }
+
+    // close waits for queued writes and returns the database
+    pub fn close(mut self) -> Result<Database> {
+        self.stop();
+        let database = self.database.clone();
+        drop(self);
+        let lock = Arc::try_unwrap(database).map_err(|_| anyhow!("database is still shared"))?;
+        lock.into_inner().map_err(|_| anyhow!("database lock is poisoned"))
+    }
+
+    fn stop(&mut self) {
+        // writer thread exits after draining the channel once all senders are dropped
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_writer(database: Arc<RwLock<Database>>, pending: Arc<Mutex<PendingKeys>>, receiver: Receiver<PendingWrite>) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match receiver.try_recv() {
+                Ok(pending) => batch.push(pending),
+                Err(_) => break,
+            }
+        }
+        let (results, failure, synced_by_write) = {
+            let mut database = database.write().unwrap();
+            let (results, failure) = apply(&mut database, &batch);
+            // keys are popped before readers get the database, a failed mutation is not seen any more
+            let mut pending = pending.lock().unwrap();
+            for p in batch.iter() {
+                pending.pop(&p.key, p.seq);
+            }
+            (results, failure, database.sync_policy == SyncPolicy::Always)
+        };
+        // readers are not blocked during fsync, applied mutations complete once durable.
+        // With SyncPolicy::Always every run was synced by its write already.
+        let synced = if results.is_empty() || synced_by_write { Ok(()) } else { database.read().unwrap().sync() };
+        for (i, pending) in batch.iter().enumerate() {
+            let result = match (results.get(i), &synced, failure.as_ref()) {
+                (Some(result), Ok(()), _) => Ok(*result),
+                (Some(_), Err(e), _) => Err(clone_error(e)),
+                (None, _, Some(e)) => Err(clone_error(e)),
+                (None, _, None) => unreachable!(),
+            };
+            pending.complete(result);
+        }
+    }
+}
+
+// apply writes runs of consecutive writes or deletes of batch in order. It returns the version of every
+// write and 1 for every deletion of an existing key, 0 otherwise, of the runs applied, and the error of
+// the run which failed. Runs after a failed one are not applied.
+fn apply(database: &mut Database, batch: &[PendingWrite]) -> (Vec<u64>, Option<anyhow::Error>) {
+    let mut results: Vec<u64> = Vec::with_capacity(batch.len());
+    for run in batch.chunk_by(|a, b| a.is_write() == b.is_write()) {
+        if let Err(e) = apply_run(database, run, &mut results) {
+            return (results, Some(e));
+        }
+    }
+    (results, None)
+}
+
+// apply_run appends results of run only if it was applied
+fn apply_run(database: &mut Database, run: &[PendingWrite], results: &mut Vec<u64>) -> Result<()> {
+    if run[0].is_write() {
+        let pairs: Vec<(&[u8], &[u8])> = run
+            .iter()
+            .map(|p| match &p.mutation {
+                Mutation::Write { value, .. } => (p.key.as_slice(), value.as_slice()),
+                Mutation::Delete { .. } => unreachable!(),
+            })
+            .collect();
+        results.extend(database.write_many(&pairs)?);
+        return Ok(());
+    }
+    // a key deleted twice in a run existed only for the first deletion
+    let hydrated = database.index.is_hydrated();
+    let mut deleted: BTreeSet<&[u8]> = BTreeSet::new();
+    let mut existed: Vec<u64> = Vec::with_capacity(run.len());
+    for p in run {
+        existed.push(((!hydrated || database.index.get(&p.key)?.is_some()) && deleted.insert(&p.key)) as u64);
+    }
+    let keys: Vec<&[u8]> = run.iter().map(|p| p.key.as_slice()).collect();
+    database.delete_many(&keys)?;
+    results.extend(existed);
+    Ok(())
+}
+
+// every handle of a failed run and of the runs after it gets the error, typed errors keep their type
+fn clone_error(e: &anyhow::Error) -> anyhow::Error {
+    if let Some(store_error) = e.downcast_ref::<StoreError>() {
+        return store_error.clone().into();
+    }
+    if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+        return std::io::Error::new(io_error.kind(), io_error.to_string()).into();
+    }
+    anyhow!("{}", e)
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+
+use super::database::Database;
+use crate::storage::Bytes;
+
+// returns the value shown in place of a value in exports and dumps, none keeps the value.
+// It is given the key too, so sensitive records can be told by key.
+pub type Redactor = Box<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+impl Database {
+    // set_redactor makes export_sstable, export_resp and dump pass values through redactor, reads are
+    // not affected. Snapshots and checkpoints copy segment files, they are not redacted.
+    pub fn set_redactor<F>(&mut self, redactor: F)
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.redactor = Some(Box::new(redactor));
+    }
+
+    // dump visits live records in key order with redacted values, it is meant for diagnostics
+    pub fn dump<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(&Bytes, Bytes) -> Result<()>,
+    {
+        self.walk_redacted(f)
+    }
+
+    // walk_redacted is walk for paths which let values leave the process
+    pub(super) fn walk_redacted<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Bytes, Bytes) -> Result<()>,
+    {
+        self.walk(|key, value| match self.redactor.as_ref().and_then(|r| r(key.as_slice(), value.as_slice())) {
+            Some(redacted) => f(key, Bytes::from(redacted)),
+            None => f(key, value),
+        })
+    }
+}
@@ -0,0 +1,341 @@
+use std::io::{BufWriter, Read, Write};
+
+use anyhow::Result;
+
+use crate::error::invalid_input;
+
+use super::database::Database;
+
+/*
+ * Redis RDB file:
+ * | "REDIS" | Version(4B) | Aux/Select DB/Resize DB/... | [Expire] Value Type | Key | Value | ... | EOF(0xFF) | Checksum(8B) |
+ *
+ * Only string values are imported, values of other types are skipped. Keys of all dbs would share one
+ * keyspace, so a dump of more than one db is refused.
+ *
+ * Export writes a "SET key value" RESP command per record, it is also a valid AOF file:
+ * *3\r\n$3\r\nSET\r\n$<key len>\r\n<key>\r\n$<value len>\r\n<value>\r\n
+ */
+
+const RDB_OPCODE_FUNCTION: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_QUICKLIST: u8 = 14;
+const RDB_TYPE_QUICKLIST_2: u8 = 18;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+// lzf expands input 88 times at most, a back reference of 3 bytes to 264 bytes. A longer length is corrupted.
+const LZF_MAX_EXPANSION: usize = 256;
+// imported strings are written with one write_many per batch
+const IMPORT_BATCH: usize = 1024;
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RdbImportStats {
+    pub imported: u64,
+    pub expired: u64,          // already expired when imported, skipped
+    pub ttl_dropped: u64,      // imported without their ttl, the store has no expiration
+    pub skipped_non_string: u64,
+}
+
+impl Database {
+    // import_rdb loads string keys of the redis db in dump into this database, a dump selecting a second db
+    // fails with invalid input
+    pub fn import_rdb<R: Read>(&mut self, reader: R) -> Result<RdbImportStats> {
+        let mut parser = RdbParser { r: reader };
+        let mut magic = [0u8; 9];
+        parser.r.read_exact(&mut magic)?;
+        if &magic[..5] != b"REDIS" {
+            return Err(invalid_input("not a redis rdb file"));
+        }
+        let now_ms = self.clock.now_millis();
+        let mut stats = RdbImportStats::default();
+        let mut expire_at_ms: Option<u64> = None;
+        let mut db: Option<u64> = None;
+        let mut batch: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut batch_bytes: usize = 0;
+        loop {
+            let opcode = parser.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => {
+                    self.import_batch(&mut batch)?;
+                    return Ok(stats);
+                }
+                RDB_OPCODE_SELECTDB => {
+                    let selected = parser.read_length()?;
+                    if let Some(db) = db.filter(|db| *db != selected) {
+                        return Err(invalid_input(format!("rdb has keys of db {} and db {}", db, selected)));
+                    }
+                    db = Some(selected);
+                }
+                RDB_OPCODE_RESIZEDB => {
+                    parser.read_length()?;
+                    parser.read_length()?;
+                }
+                RDB_OPCODE_AUX => {
+                    parser.read_string()?;
+                    parser.read_string()?;
+                }
+                RDB_OPCODE_EXPIRETIME => {
+                    expire_at_ms = Some(parser.read_le_u32()? as u64 * 1000);
+                }
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    expire_at_ms = Some(parser.read_le_u64()?);
+                }
+                RDB_OPCODE_IDLE => {
+                    parser.read_length()?;
+                }
+                RDB_OPCODE_FREQ => {
+                    parser.read_u8()?;
+                }
+                RDB_OPCODE_FUNCTION | RDB_OPCODE_MODULE_AUX => {
+                    return Err(invalid_input(format!("unsupported rdb opcode {:#x}", opcode)));
+                }
+                value_type => {
+                    let key = parser.read_string()?;
+                    if value_type != RDB_TYPE_STRING {
+                        parser.skip_value(value_type)?;
+                        stats.skipped_non_string += 1;
+                    } else {
+                        let value = parser.read_string()?;
+                        match expire_at_ms {
+                            Some(at) if at <= now_ms => stats.expired += 1,
+                            _ => {
+                                if expire_at_ms.is_some() {
+                                    stats.ttl_dropped += 1;
+                                }
+                                batch_bytes += key.len() + value.len();
+                                batch.push((key, value));
+                                stats.imported += 1;
+                                if batch.len() >= IMPORT_BATCH || batch_bytes >= IMPORT_BATCH_BYTES {
+                                    self.import_batch(&mut batch)?;
+                                    batch_bytes = 0;
+                                }
+                            }
+                        }
+                    }
+                    expire_at_ms = None;
+                }
+            }
+        }
+    }
+
+    // import_batch writes pairs of batch with one write_many and empties it
+    fn import_batch(&mut self, batch: &mut Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let pairs: Vec<(&[u8], &[u8])> = batch.iter().map(|(k, v)| (k.as_slice(), v.as_slice())).collect();
+        self.write_many(&pairs)?;
+        batch.clear();
+        Ok(())
+    }
+
+    // export_resp writes all records in key order as redis SET commands and returns the number of records,
+    // values are redacted
+    pub fn export_resp<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut w = BufWriter::new(writer);
+        let mut exported: u64 = 0;
+        self.walk_redacted(|key, value| {
+            w.write_all(b"*3\r\n$3\r\nSET\r\n")?;
+            write_bulk_string(&mut w, key.as_slice())?;
+            write_bulk_string(&mut w, value.as_slice())?;
+            exported += 1;
+            Ok(())
+        })?;
+        w.flush()?;
+        Ok(exported)
+    }
+}
+
+fn write_bulk_string<W: Write>(w: &mut W, s: &[u8]) -> Result<()> {
+    write!(w, "${}\r\n", s.len())?;
+    w.write_all(s)?;
+    w.write_all(b"\r\n")?;
+    Ok(())
+}
+
+struct RdbParser<R: Read> {
+    r: R,
+}
+
+enum RdbLength {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<R: Read> RdbParser<R> {
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.r.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_le_u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.r.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_le_u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.r.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::new();
+        let n = (&mut self.r).take(len).read_to_end(&mut buf)?;
+        if n as u64 != len {
+            return Err(invalid_input("unexpected end of rdb file"));
+        }
+        Ok(buf)
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<RdbLength> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(RdbLength::Len((first & 0x3f) as u64)),
+            1 => Ok(RdbLength::Len((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64)),
+            2 => match first {
+                0x80 => {
+                    let mut buf = [0u8; 4];
+                    self.r.read_exact(&mut buf)?;
+                    Ok(RdbLength::Len(u32::from_be_bytes(buf) as u64))
+                }
+                0x81 => {
+                    let mut buf = [0u8; 8];
+                    self.r.read_exact(&mut buf)?;
+                    Ok(RdbLength::Len(u64::from_be_bytes(buf)))
+                }
+                _ => Err(invalid_input(format!("invalid rdb length {:#x}", first))),
+            },
+            _ => Ok(RdbLength::Encoded(first & 0x3f)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<u64> {
+        match self.read_length_or_encoding()? {
+            RdbLength::Len(len) => Ok(len),
+            RdbLength::Encoded(_) => Err(invalid_input("unexpected encoded rdb length")),
+        }
+    }
+
+    // read_string decodes raw, integer and lzf compressed strings
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        match self.read_length_or_encoding()? {
+            RdbLength::Len(len) => self.read_bytes(len),
+            RdbLength::Encoded(RDB_ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            RdbLength::Encoded(RDB_ENC_INT16) => {
+                let mut buf = [0u8; 2];
+                self.r.read_exact(&mut buf)?;
+                Ok(i16::from_le_bytes(buf).to_string().into_bytes())
+            }
+            RdbLength::Encoded(RDB_ENC_INT32) => Ok((self.read_le_u32()? as i32).to_string().into_bytes()),
+            RdbLength::Encoded(RDB_ENC_LZF) => {
+                let compressed_len = self.read_length()?;
+                let len = self.read_length()?;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(&compressed, len as usize)
+            }
+            RdbLength::Encoded(enc) => Err(invalid_input(format!("unknown rdb string encoding {}", enc))),
+        }
+    }
+
+    fn skip_value(&mut self, value_type: u8) -> Result<()> {
+        match value_type {
+            RDB_TYPE_LIST | RDB_TYPE_SET | RDB_TYPE_QUICKLIST => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                }
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_HASH => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    self.read_string()?;
+                }
+            }
+            RDB_TYPE_ZSET_2 => {
+                for _ in 0..self.read_length()? {
+                    self.read_string()?;
+                    self.read_bytes(8)?; // binary double score
+                }
+            }
+            RDB_TYPE_QUICKLIST_2 => {
+                for _ in 0..self.read_length()? {
+                    self.read_length()?; // container type
+                    self.read_string()?;
+                }
+            }
+            // zipmap, ziplist, intset and listpack encodings are stored as a single string
+            9..=13 | 16 | 17 | 20 => {
+                self.read_string()?;
+            }
+            _ => return Err(invalid_input(format!("unsupported rdb value type {}", value_type))),
+        }
+        Ok(())
+    }
+}
+
+// lzf_decompress fails if len is beyond what input may expand to, output never grows beyond len
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    if len > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return Err(invalid_input("corrupted lzf string"));
+    }
+    let mut output: Vec<u8> = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run
+            let run = ctrl + 1;
+            let literal = input.get(i..i + run).ok_or_else(|| invalid_input("corrupted lzf string"))?;
+            if output.len() + run > len {
+                return Err(invalid_input("corrupted lzf string"));
+            }
+            output.extend_from_slice(literal);
+            i += run;
+        } else {
+            // back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(|| invalid_input("corrupted lzf string"))? as usize;
+                i += 1;
+            }
+            run += 2;
+            let low = *input.get(i).ok_or_else(|| invalid_input("corrupted lzf string"))? as usize;
+            i += 1;
+            let distance = ((ctrl & 0x1f) << 8) + low + 1;
+            if distance > output.len() || output.len() + run > len {
+                return Err(invalid_input("corrupted lzf string"));
+            }
+            let start = output.len() - distance;
+            for k in 0..run {
+                output.push(output[start + k]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(invalid_input("corrupted lzf string"));
+    }
+    Ok(output)
+}
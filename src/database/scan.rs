@@ -0,0 +1,334 @@
+use std::{
+    ops::Bound,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::error::invalid_input;
+
+use super::database::Database;
+use crate::storage::{segment::Segment, Bytes, RecordIndex};
+
+// Cursor marks the last key returned by a scan, scanning resumes right after it.
+// It is based on key rather than position, so it stays valid across writes and merges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    after: Vec<u8>,
+}
+
+impl Cursor {
+    pub(super) fn after(key: &[u8]) -> Cursor {
+        Cursor { after: key.to_vec() }
+    }
+
+    // encode cursor as hex string which is safe to put in urls
+    pub fn encode(&self) -> String {
+        self.after.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn decode(s: &str) -> Result<Cursor> {
+        if !s.len().is_multiple_of(2) {
+            return Err(invalid_input("invalid cursor"));
+        }
+        let after = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(s.get(i..i + 2).unwrap_or_default(), 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid_input("invalid cursor"))?;
+        Ok(Cursor { after })
+    }
+}
+
+const WALK_BATCH: usize = 1024;
+
+// records of one page and the cursor of next page
+pub type ScanPage = (Vec<(Bytes, Bytes)>, Option<Cursor>);
+
+impl Database {
+    // scan returns at most limit records with the prefix in key order, and a cursor if more records may follow.
+    // A limit of 0 is invalid, a page of it could not tell whether records follow.
+    pub fn scan(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let prefix = prefix.as_ref();
+        if limit == 0 {
+            return Err(invalid_input("scan limit must not be 0"));
+        }
+        let lower = match cursor {
+            Some(c) if c.after.as_slice() >= prefix => Bound::Excluded(c.after.as_slice()),
+            _ => Bound::Included(prefix),
+        };
+        // take one more record to find out whether there is a next page
+        let mut indexes = self.index.range(lower, prefix, limit.saturating_add(1))?;
+        let has_more = indexes.len() > limit;
+        indexes.truncate(limit);
+        let mut items: Vec<(Bytes, Bytes)> = Vec::with_capacity(indexes.len());
+        for idx in indexes.iter() {
+            let record = self.read_record(idx)?;
+            items.push((idx.key.clone(), record.value));
+        }
+        let next_cursor = if has_more {
+            items.last().map(|(key, _)| Cursor::after(key.as_slice()))
+        } else {
+            None
+        };
+        Ok((items, next_cursor))
+    }
+
+    // walk visits all live records in key order, index is walked in batches,
+    // so the index lock is not held while reading values
+    pub(super) fn walk<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Bytes, Bytes) -> Result<()>,
+    {
+        let mut last_key: Option<Bytes> = None;
+        loop {
+            let lower = match last_key.as_ref() {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let batch = self.index.range(lower, &[], WALK_BATCH)?;
+            for idx in batch.iter() {
+                let record = self.read_record(idx)?;
+                f(&idx.key, record.value)?;
+            }
+            if batch.len() < WALK_BATCH {
+                return Ok(());
+            }
+            last_key = batch.last().map(|idx| idx.key.clone());
+        }
+    }
+
+    // for_each visits all live records in segment order, which reads disk sequentially
+    // rather than randomly as key order does. Records are not visited in key order.
+    // No lock of the directory is held while f runs, but segments are pinned, so a merge meanwhile
+    // is adopted on next open.
+    pub fn for_each<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Bytes, &Bytes) -> Result<()>,
+    {
+        let _pinned = self.storage.pin();
+        for segment in self.storage.segment_readers() {
+            self.for_each_in_segment(&segment, &mut f)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_in_segment<F>(&self, segment: &Segment, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&Bytes, &Bytes) -> Result<()>,
+    {
+        for record in segment.iter_with_value() {
+            // only the record which index points to is live
+            let live = self
+                .index
+                .get(record.key.as_slice())?
+                .is_some_and(|idx| idx.segment == record.segment && idx.offset == record.offset);
+            match record.value.as_ref() {
+                Some(value) if live => f(&record.key, value)?,
+                // a blob pointer carries no value, the blob is read by its pointer
+                None if live => f(&record.key, &self.storage.read_blob(&record)?.value)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // par_for_each is for_each on several threads, each thread takes whole segments.
+    // The first error stops all threads and is returned.
+    pub fn par_for_each<F>(&self, threads: usize, f: F) -> Result<()>
+    where
+        F: Fn(&Bytes, &Bytes) -> Result<()> + Sync,
+    {
+        let _pinned = self.storage.pin();
+        let segments = self.storage.segment_readers();
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let workers: Vec<_> = (0..threads.max(1))
+                .map(|_| {
+                    s.spawn(|| -> Result<()> {
+                        let mut visit = |key: &Bytes, value: &Bytes| {
+                            if failed.load(Ordering::Relaxed) {
+                                return Err(Stopped.into());
+                            }
+                            f(key, value)
+                        };
+                        while let Some(segment) = segments.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            if let Err(e) = self.for_each_in_segment(segment, &mut visit) {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            let mut result: Result<()> = Ok(());
+            for worker in workers {
+                let r = worker.join().map_err(|_| anyhow!("worker thread panicked")).and_then(|r| r);
+                match r {
+                    // keep the error which stopped others
+                    Err(e) if result.is_ok() && e.downcast_ref::<Stopped>().is_none() => result = Err(e),
+                    _ => {}
+                }
+            }
+            result
+        })
+    }
+
+    // delete_where deletes keys matching predicate. Index is walked in key order without reading values,
+    // matches of each batch are deleted with one write. Returns the number of deleted keys.
+    pub fn delete_where<F>(&mut self, mut predicate: F) -> Result<u64>
+    where
+        F: FnMut(&Bytes) -> bool,
+    {
+        let mut deleted = 0;
+        let mut last_key: Option<Bytes> = None;
+        loop {
+            let lower = match last_key.as_ref() {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let batch = self.index.range(lower, &[], WALK_BATCH)?;
+            let matched: Vec<&[u8]> = batch.iter().map(|idx| &idx.key).filter(|key| predicate(key)).map(Bytes::as_slice).collect();
+            deleted += self.delete_many(&matched)?;
+            if batch.len() < WALK_BATCH {
+                return Ok(deleted);
+            }
+            last_key = batch.last().map(|idx| idx.key.clone());
+        }
+    }
+
+    // delete_where_value is delete_where which also tells the value. Records are read in segment order like
+    // for_each, matched keys are kept until the scan is done and then deleted in batches.
+    pub fn delete_where_value<F>(&mut self, mut predicate: F) -> Result<u64>
+    where
+        F: FnMut(&Bytes, &Bytes) -> bool,
+    {
+        let mut matched: Vec<Bytes> = Vec::new();
+        self.for_each(|key, value| {
+            if predicate(key, value) {
+                matched.push(key.clone());
+            }
+            Ok(())
+        })?;
+        let mut deleted = 0;
+        for chunk in matched.chunks(WALK_BATCH) {
+            let keys: Vec<&[u8]> = chunk.iter().map(Bytes::as_slice).collect();
+            deleted += self.delete_many(&keys)?;
+        }
+        Ok(deleted)
+    }
+
+    pub fn fold<T, F>(&self, init: T, mut f: F) -> Result<T>
+    where
+        F: FnMut(T, &Bytes, &Bytes) -> Result<T>,
+    {
+        let mut acc = Some(init);
+        self.for_each(|key, value| {
+            acc = Some(f(acc.take().unwrap(), key, value)?);
+            Ok(())
+        })?;
+        Ok(acc.unwrap())
+    }
+
+    // iter yields entries in key order, value of an entry is read only when asked for.
+    // A merge while iterating neither skips nor repeats entries. A damaged block of a persistent index
+    // ends iteration, see Iter::finish.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            database: self,
+            batch: Vec::new().into_iter(),
+            last_key: None,
+            done: false,
+            error: None,
+        }
+    }
+}
+
+// error of a par_for_each worker which was stopped by another worker's error
+#[derive(Debug)]
+struct Stopped;
+
+impl std::fmt::Display for Stopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stopped by error of another thread")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+// Entry is a key with the location of its value at the time it was iterated
+pub struct Entry<'a> {
+    database: &'a Database,
+    index: RecordIndex,
+}
+
+impl<'a> Entry<'a> {
+    pub fn key(&self) -> &Bytes {
+        &self.index.key
+    }
+
+    // value reads the value from disk, it is the value when the entry was iterated even if overwritten later,
+    // unless a merge removed the segment of the entry before it was read
+    pub fn value(&self) -> Result<Bytes> {
+        Ok(self.database.read_record(&self.index)?.value)
+    }
+}
+
+pub struct Iter<'a> {
+    database: &'a Database,
+    batch: std::vec::IntoIter<RecordIndex>,
+    last_key: Option<Bytes>,
+    done: bool,
+    error: Option<anyhow::Error>,
+}
+
+impl Iter<'_> {
+    // finish returns the error which ended iteration early, if any
+    pub fn finish(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(index) = self.batch.next() {
+                self.last_key = Some(index.key.clone());
+                return Some(Entry {
+                    database: self.database,
+                    index,
+                });
+            }
+            if self.done {
+                return None;
+            }
+            // index is read in batches, so the index lock is not held between calls
+            let lower = match self.last_key.as_ref() {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let batch = match self.database.index.range(lower, &[], WALK_BATCH) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    self.error = Some(e);
+                    self.done = true;
+                    return None;
+                }
+            };
+            self.done = batch.len() < WALK_BATCH;
+            self.batch = batch.into_iter();
+        }
+    }
+}
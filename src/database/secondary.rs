@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+
+use crate::error::invalid_input;
+
+use super::database::Database;
+use crate::storage::Bytes;
+
+/*
+ * Secondary indexes live in memory only, nothing of them is written to segments. They are gone when the
+ * database is closed, create_index must be called again after every open and rebuilds the index from all
+ * live records, which reads every value once.
+ *
+ * Index entries are not written in the batch of their record, there is no bucket to write them to. They
+ * are updated with the primary index under the same &mut borrow once the record is written, so readers
+ * never see them apart, and a crash loses nothing since they are rebuilt from the records.
+ */
+
+// extracts secondary index keys from a value, a value may have zero or many index keys
+pub type IndexExtractor = Box<dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+// SecondaryIndex maps index key to primary keys, it is not persisted
+pub(super) struct SecondaryIndex {
+    extractor: IndexExtractor,
+    entries: BTreeMap<Bytes, BTreeSet<Bytes>>,
+}
+
+impl SecondaryIndex {
+    fn add(&mut self, key: &Bytes, value: &[u8]) {
+        for index_key in (self.extractor)(value) {
+            self.entries
+                .entry(Bytes::from(index_key))
+                .or_default()
+                .insert(key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Bytes, value: &[u8]) {
+        for index_key in (self.extractor)(value) {
+            if let Some(keys) = self.entries.get_mut(index_key.as_slice()) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(index_key.as_slice());
+                }
+            }
+        }
+    }
+}
+
+impl Database {
+    // create_index registers a secondary index and builds it from existing records. The index is in memory
+    // only, it is registered and built again after every open.
+    pub fn create_index<F>(&mut self, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        if self.secondary.contains_key(name) {
+            return Err(invalid_input(format!("index {} already exists", name)));
+        }
+        let mut index = SecondaryIndex {
+            extractor: Box::new(extractor),
+            entries: BTreeMap::new(),
+        };
+        self.wait_hydrated()?;
+        let map = self.index.map.read().unwrap();
+        map.for_each(|record_index| {
+            let record = self.storage.read_at(&record_index)?;
+            index.add(&record_index.key, record.value.as_slice());
+            Ok(())
+        })?;
+        drop(map);
+        self.secondary.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        self.secondary.remove(name).is_some()
+    }
+
+    // find_by_index returns primary keys whose value has the given index key, in key order
+    pub fn find_by_index(&self, name: &str, index_key: impl AsRef<[u8]>) -> Result<Vec<Bytes>> {
+        let index_key = index_key.as_ref();
+        let index = self
+            .secondary
+            .get(name)
+            .ok_or_else(|| invalid_input(format!("index {} not found", name)))?;
+        Ok(index
+            .entries
+            .get(index_key)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    // update_secondary must be called after the primary index is updated, new_value is None for delete
+    pub(super) fn update_secondary(&mut self, key: &[u8], old_value: Option<Bytes>, new_value: Option<&[u8]>) {
+        let key = Bytes::from(key.to_vec());
+        for index in self.secondary.values_mut() {
+            if let Some(old) = old_value.as_ref() {
+                index.remove(&key, old.as_slice());
+            }
+            if let Some(new) = new_value {
+                index.add(&key, new);
+            }
+        }
+    }
+}
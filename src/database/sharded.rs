@@ -0,0 +1,157 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::RwLock,
+    thread,
+};
+
+use anyhow::Result;
+
+use super::{
+    database::{Database, Options},
+    merge::MergeStats,
+    scan::{Cursor, ScanPage},
+};
+use crate::{
+    error::{corruption, invalid_input},
+    storage::{directory::MergeRange, Bytes},
+    utils::{utils::rename_durable, xxhash::Xxh64},
+};
+
+/*
+ * ShardedDatabase partitions keys by XXH64 of the key across shard-<i> directories, each one a database.
+ * SHARDS file of the root directory holds the shard count, a store can not be reopened with another one.
+ * Shards are locked separately, so writes to different shards and merges of all shards run in parallel.
+ * A merge holds its shard only to plan and to finish, writes to the shard go on while segments are rewritten.
+ */
+
+const SHARDS_FILENAME: &str = "SHARDS";
+const SHARDS_TMP_FILENAME: &str = "SHARDS.tmp";
+
+pub struct ShardedDatabase {
+    shards: Vec<RwLock<Database>>,
+}
+
+impl ShardedDatabase {
+    // open opens every shard with options, shard count is fixed on creation
+    pub fn open(dir: &str, shard_count: usize, options: Options) -> Result<ShardedDatabase> {
+        if shard_count == 0 {
+            return Err(invalid_input("shard count must be positive"));
+        }
+        let root_dir = Path::new(dir);
+        fs::create_dir_all(root_dir)?;
+        let shards_path = root_dir.join(SHARDS_FILENAME);
+        match fs::read_to_string(&shards_path) {
+            Ok(content) => {
+                let existing: usize = content
+                    .trim()
+                    .parse()
+                    .map_err(|_| corruption(format!("malformed {} file: {:?}", SHARDS_FILENAME, content.trim())))?;
+                if existing != shard_count {
+                    return Err(invalid_input(format!("{} has {} shards, not {}", dir, existing, shard_count)));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let tmp_path = root_dir.join(SHARDS_TMP_FILENAME);
+                let mut file = File::create(&tmp_path)?;
+                file.write_all(format!("{}\n", shard_count).as_bytes())?;
+                file.sync_all()?;
+                rename_durable(&tmp_path, &shards_path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let shards = (0..shard_count)
+            .map(|i| {
+                let shard_dir = root_dir.join(format!("shard-{}", i));
+                Ok(RwLock::new(Database::open(shard_dir.to_str().unwrap(), options.clone())?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedDatabase { shards })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // shard_of returns the index of the shard which holds key
+    pub fn shard_of(&self, key: impl AsRef<[u8]>) -> usize {
+        let mut hasher = Xxh64::new(0);
+        hasher.update(key.as_ref());
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    pub fn read(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>> {
+        let key = key.as_ref();
+        self.shards[self.shard_of(key)].read().unwrap().read(key)
+    }
+
+    pub fn write(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.shards[self.shard_of(key)].write().unwrap().write(key, value)
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        let key = key.as_ref();
+        self.shards[self.shard_of(key)].write().unwrap().delete(key)
+    }
+
+    // merge merges all shards in parallel and returns their stats by shard. Like AsyncWriter::merge, a shard is
+    // locked to plan and to finish only, merges of a shard are serialized by its merge_lock.
+    pub fn merge(&self) -> Result<Vec<MergeStats>> {
+        thread::scope(|s| {
+            let workers: Vec<_> = self.shards.iter().map(|shard| s.spawn(move || Self::merge_shard(shard))).collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        })
+    }
+
+    fn merge_shard(shard: &RwLock<Database>) -> Result<MergeStats> {
+        let merge_lock = shard.read().unwrap().merge_lock.clone();
+        let _merging = merge_lock.lock().unwrap();
+        let job = shard.read().unwrap().plan_merge(MergeRange::All)?;
+        let job = match job {
+            Some(job) => job,
+            None => return Ok(MergeStats::default()),
+        };
+        let output = job.run()?;
+        shard.read().unwrap().finish_merge(output)
+    }
+
+    // scan is Database::scan across shards, records of all shards come in key order. Every shard is scanned
+    // for a page of limit records, the first limit records of their union are the page.
+    pub fn scan(&self, prefix: impl AsRef<[u8]>, cursor: Option<&Cursor>, limit: usize) -> Result<ScanPage> {
+        let prefix = prefix.as_ref();
+        let mut items: Vec<(Bytes, Bytes)> = Vec::new();
+        let mut has_more = false;
+        for shard in self.shards.iter() {
+            let (page, next_cursor) = shard.read().unwrap().scan(prefix, cursor, limit)?;
+            has_more |= next_cursor.is_some();
+            items.extend(page);
+        }
+        items.sort_by(|(a, _), (b, _)| a.as_slice().cmp(b.as_slice()));
+        has_more |= items.len() > limit;
+        items.truncate(limit);
+        let next_cursor = if has_more {
+            items.last().map(|(key, _)| Cursor::after(key.as_slice()))
+        } else {
+            None
+        };
+        Ok((items, next_cursor))
+    }
+
+    // with_shard passes the database of a shard under its read lock, e.g. to scan it
+    pub fn with_shard<R, F>(&self, shard: usize, f: F) -> R
+    where
+        F: FnOnce(&Database) -> R,
+    {
+        f(&self.shards[shard].read().unwrap())
+    }
+
+    // with_shard_mut passes the database of a shard under its write lock, writes to the shard wait meanwhile
+    pub fn with_shard_mut<R, F>(&self, shard: usize, f: F) -> R
+    where
+        F: FnOnce(&mut Database) -> R,
+    {
+        f(&mut self.shards[shard].write().unwrap())
+    }
+}
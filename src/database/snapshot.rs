@@ -0,0 +1,149 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::error::invalid_input;
+
+use super::{
+    database::{Database, Options},
+    hint::hint_path,
+    merge::MERGE_FINISH_FILENAME,
+};
+use crate::{
+    utils::{
+        tar::{read_entries, TarWriter},
+        utils::{dir_exists, file_exists},
+    },
+};
+
+const MANIFEST_FILENAME: &str = "MANIFEST";
+const SNAPSHOT_VERSION: &str = "bitcask-snapshot 1";
+
+/*
+ * Snapshot is a ustar archive:
+ * MANIFEST: version line followed by "<name> <size>" line for every data file
+ * data/<segment>.seg, data/<segment>.hint, data/<segment>.blob, data/merge-finish, segment is [<generation>-]<index>
+ */
+impl Database {
+    // export_snapshot streams sealed segments, their hint files, blob segments and merge finish file as an
+    // archive. Active segments are sealed first, so the snapshot contains every write before the call.
+    pub fn export_snapshot<W: Write>(&self, writer: W) -> Result<W> {
+        let data_dir = Self::get_data_dir(&self.root_dir);
+        // merged output replaces no segment while they are copied
+        let _pinned = self.storage.pin();
+        let mut files = self.storage.freeze()?;
+        let hints: Vec<PathBuf> = files.iter().map(|path| hint_path(path)).filter(|path| file_exists(path)).collect();
+        files.extend(hints);
+        // blob segments are sealed after segments, so they hold every blob a sealed pointer points to.
+        // Only compact_blobs removes them while open, it can not run during export as it borrows mutably.
+        files.extend(self.storage.freeze_blobs()?.into_iter().map(|(_, path)| path));
+        let merge_finish_path = data_dir.join(MERGE_FINISH_FILENAME);
+        if file_exists(&merge_finish_path) {
+            files.push(merge_finish_path);
+        }
+
+        let mut entries: Vec<(String, u64)> = Vec::new();
+        for path in files.iter() {
+            let name = format!("data/{}", path.file_name().unwrap().to_str().unwrap());
+            entries.push((name, fs::metadata(path)?.len()));
+        }
+        let mut manifest = format!("{}\n", SNAPSHOT_VERSION);
+        for (name, size) in entries.iter() {
+            manifest.push_str(&format!("{} {}\n", name, size));
+        }
+
+        let mut tar = TarWriter::new(writer);
+        tar.append(MANIFEST_FILENAME, manifest.len() as u64, &mut manifest.as_bytes())?;
+        for (path, (name, size)) in files.iter().zip(entries.iter()) {
+            tar.append(name, *size, &mut File::open(path)?)?;
+        }
+        tar.finish()
+    }
+
+    // import_snapshot unpacks a snapshot into an empty directory and opens it
+    pub fn import_snapshot<R: Read>(reader: R, dir: &str, options: Options) -> Result<Database> {
+        let root_dir = Path::new(dir);
+        let data_dir = Self::get_data_dir(root_dir);
+        if dir_exists(&data_dir) && fs::read_dir(&data_dir)?.next().is_some() {
+            return Err(invalid_input(format!("{} is not empty", data_dir.display())));
+        }
+        fs::create_dir_all(&data_dir)?;
+
+        let mut manifest: Option<String> = None;
+        let mut unpacked: Vec<(String, u64)> = Vec::new();
+        read_entries(reader, |name, content| {
+            if name == MANIFEST_FILENAME {
+                let mut s = String::new();
+                content.read_to_string(&mut s)?;
+                manifest = Some(s);
+                return Ok(());
+            }
+            let filename = name
+                .strip_prefix("data/")
+                .filter(|f| {
+                    let mut components = Path::new(f).components();
+                    matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+                })
+                .ok_or_else(|| invalid_input(format!("unexpected file in snapshot: {}", name)))?;
+            let mut file = File::create(data_dir.join(filename))?;
+            let size = std::io::copy(content, &mut file)?;
+            file.sync_all()?;
+            unpacked.push((name.to_string(), size));
+            Ok(())
+        })?;
+
+        // every file listed in manifest must be unpacked completely
+        let manifest = manifest.ok_or_else(|| invalid_input("manifest not found in snapshot"))?;
+        let mut lines = manifest.lines();
+        if lines.next() != Some(SNAPSHOT_VERSION) {
+            return Err(invalid_input("unsupported snapshot version"));
+        }
+        for line in lines {
+            let (name, size) = line
+                .rsplit_once(' ')
+                .ok_or_else(|| invalid_input(format!("invalid manifest line: {}", line)))?;
+            let size = size
+                .parse::<u64>()
+                .map_err(|_| invalid_input(format!("invalid manifest line: {}", line)))?;
+            if !unpacked.iter().any(|(n, s)| n == name && *s == size) {
+                return Err(invalid_input(format!("{} is missing or truncated in snapshot", name)));
+            }
+        }
+        Database::open(dir, options)
+    }
+
+    // checkpoint creates a consistent copy in dir: sealed segments and blob segments are hard linked (copied
+    // if linking fails), the written part of active segment, hint files and merge finish file are copied.
+    // hint and merge finish files are rewritten in place, so they must not be linked.
+    pub fn checkpoint(&self, dir: &str) -> Result<()> {
+        let src_data_dir = Self::get_data_dir(&self.root_dir);
+        let data_dir = Self::get_data_dir(Path::new(dir));
+        if dir_exists(&data_dir) {
+            return Err(invalid_input(format!("{} already exists", data_dir.display())));
+        }
+        fs::create_dir_all(&data_dir)?;
+        let (sealed, active, active_len) = self.storage.checkpoint_files();
+        // active blob segment is sealed after, so blobs of all pointers copied are in sealed blob segments.
+        // They are not removed meanwhile, compact_blobs borrows the database mutably.
+        let blobs = self.storage.freeze_blobs()?;
+        for path in sealed.iter().chain(blobs.iter().map(|(_, path)| path)) {
+            let target = data_dir.join(path.file_name().unwrap());
+            if fs::hard_link(path, &target).is_err() {
+                fs::copy(path, &target)?;
+            }
+        }
+        let mut file = File::create(data_dir.join(active.file_name().unwrap()))?;
+        std::io::copy(&mut File::open(&active)?.take(active_len), &mut file)?;
+        file.sync_all()?;
+        let mut copied: Vec<PathBuf> = sealed.iter().map(|path| hint_path(path)).collect();
+        copied.push(src_data_dir.join(MERGE_FINISH_FILENAME));
+        for path in copied.iter().filter(|path| file_exists(path)) {
+            fs::copy(path, data_dir.join(path.file_name().unwrap()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+
+use super::{database::Database, invalidate::WriteOp};
+use crate::{
+    error::invalid_input,
+    storage::{
+        sstable::{SSTableReader, SSTableWriter},
+        Bytes, RecordIndex, SEG_EXT_NAME,
+    },
+};
+
+impl Database {
+    // export_sstable writes all live records as a sorted sstable file and returns the number of records,
+    // values are redacted
+    pub fn export_sstable<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut sst = SSTableWriter::new(writer)?;
+        self.walk_redacted(|key, value| sst.append(key.as_slice(), value.as_slice()))?;
+        sst.finish()
+    }
+
+    // ingest_sstable adds records of a sorted sstable file as new segments, they override existing records.
+    // The input is validated while written, a broken file is rejected before the index is touched.
+    pub fn ingest_sstable<R: Read>(&mut self, reader: R) -> Result<u64> {
+        let sst = SSTableReader::new(reader)?;
+        self.checkpoint_if_due()?;
+        let indexes = self.storage.ingest(sst)?;
+        self.index_ingested(indexes)
+    }
+
+    // bulk_load adds pairs sorted by key as new sealed segments with their hint files, so they are indexed
+    // from the hint on open. Keys must be strictly ascending, the input is rejected at the first one which
+    // is not and nothing is kept. Without secondary indexes or key filter all pairs are indexed under one lock.
+    pub fn bulk_load<I, K, V>(&mut self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.checkpoint_if_due()?;
+        let mut last: Option<Vec<u8>> = None;
+        let records = pairs.into_iter().map(|(key, value)| {
+            let key = key.as_ref().to_vec();
+            if last.as_ref().is_some_and(|last| *last >= key) {
+                return Err(invalid_input("keys of bulk load are not strictly ascending"));
+            }
+            last = Some(key.clone());
+            Ok((key, value.as_ref().to_vec()))
+        });
+        let indexes = self.storage.ingest(records)?;
+        // records come by segment, a failed hint write leaves its segment to be scanned on open
+        let data_dir = Self::get_data_dir(&self.root_dir);
+        for records in indexes.chunk_by(|a, b| a.segment == b.segment) {
+            let _ = self.write_sealed_hint(&data_dir.join(format!("{}.{}", records[0].segment, SEG_EXT_NAME)));
+        }
+        if !self.secondary.is_empty() || self.index.filter.is_some() {
+            return self.index_ingested(indexes);
+        }
+        let keys: Vec<Bytes> = match self.invalidator {
+            Some(_) => indexes.iter().map(|idx| idx.key.clone()).collect(),
+            None => Vec::new(),
+        };
+        let count = indexes.len() as u64;
+        self.index.set_many(indexes)?;
+        for key in keys {
+            self.invalidate(key.as_slice(), WriteOp::Write);
+        }
+        Ok(count)
+    }
+
+    // index_ingested indexes records of an ingested segment and returns their number
+    pub(super) fn index_ingested(&mut self, indexes: Vec<RecordIndex>) -> Result<u64> {
+        let count = indexes.len() as u64;
+        for idx in indexes {
+            let key = idx.key.clone();
+            // records of keys left out by the key filter are stored, though not indexed
+            if self.check_indexed(key.as_slice()).is_err() {
+                continue;
+            }
+            let old_value = if self.secondary.is_empty() {
+                None
+            } else {
+                self.read(key.as_slice())?
+            };
+            self.index.set(idx)?;
+            if !self.secondary.is_empty() {
+                let new_value = self.read(key.as_slice())?;
+                self.update_secondary(key.as_slice(), old_value, new_value.as_ref().map(|v| v.as_slice()));
+            }
+            self.invalidate(key.as_slice(), WriteOp::Write);
+        }
+        Ok(count)
+    }
+}
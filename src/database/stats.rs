@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+
+use super::{
+    database::Database,
+    hint::{hint_path, read_hint, segment_timestamp},
+};
+use crate::{
+    error::is_segment_not_found,
+    storage::{checksum::ChecksumAlgorithm, segment::Segment, RecordIndex, BLOB_EXT_NAME, SEG_EXT_NAME},
+    utils::utils::{file_exists, os_str_to_string, rename_durable},
+};
+
+/*
+ * STATS file of data dir caches numbers which are costly to learn after open:
+ * "last-merge <ms>" and "segment <name> <records>" of every sealed segment.
+ * Sealed segments are immutable, so their record counts stay right as long as they exist. Live
+ * records are counted from index, garbage of a segment is its records minus its live records.
+ * The file is only a cache, a missing or malformed one is rebuilt from hint files and segments.
+ * It is written whenever the hint of a sealed segment is written, by merge, and by stats if it counted a segment.
+ */
+
+const STATS_FILENAME: &str = "STATS";
+const STATS_TMP_FILENAME: &str = "STATS.tmp";
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SegmentStats {
+    pub segment: String,
+    pub records: u64, // tombstones and overwritten records included
+    pub live: u64,    // records index entries point to
+}
+
+impl SegmentStats {
+    // records merge would drop
+    pub fn garbage(&self) -> u64 {
+        self.records.saturating_sub(self.live)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StoreStats {
+    pub keys: u64,
+    pub value_sizes: ValueSizeHistogram, // of live values
+    pub segments: Vec<SegmentStats>, // ordered by index, the active segment is the last
+    pub last_merge_ms: Option<u64>,  // by clock of options, none if never merged
+}
+
+// ValueSizeHistogram counts values by size in powers of two, bucket 0 holds empty values and bucket i
+// values of 2^(i-1) to 2^i - 1 bytes. Values indexed from hint files of former versions count as empty.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ValueSizeHistogram {
+    pub buckets: Vec<u64>,
+}
+
+impl ValueSizeHistogram {
+    fn add(&mut self, size: u64) {
+        let bucket = (u64::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    // bucket_max is the largest size counted by bucket
+    pub fn bucket_max(bucket: usize) -> u64 {
+        match bucket {
+            0 => 0,
+            64.. => u64::MAX,
+            _ => (1u64 << bucket) - 1,
+        }
+    }
+
+    // quantile is an upper bound of the size below which fraction q of values are, e.g. q = 0.99
+    pub fn quantile(&self, q: f64) -> u64 {
+        let target = (self.count() as f64 * q.clamp(0.0, 1.0)).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return Self::bucket_max(bucket);
+            }
+        }
+        0
+    }
+}
+
+// SegmentInfo describes the file of a segment, see Database::segments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub segment: String, // [<generation>-]<index>
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub records: u64,
+    pub live: u64,
+    pub garbage_bytes: u64, // estimate, bytes minus the size of live records
+    pub created_secs: u64,  // since epoch, modification time if the file system lacks creation time
+    pub has_hint: bool,
+    pub active: bool,
+}
+
+// KeyMetadata describes the index entry of a key, see Database::metadata. Records carry no write time and no
+// TTL, keys never expire, so timestamp_secs is a file time: the modification time of the segment the record was
+// written to, as of when its hint was written, or of its segment now if it was not indexed from a hint file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    pub segment: String,
+    pub offset: u64,
+    pub value_size: u64, // 0 if indexed from a hint file of former versions
+    pub version: u64,
+    pub inlined: bool, // value is kept in the index entry, see Options::inline_values
+    pub flag: u8,      // of the record as written, a key has metadata only while its deleted bit is clear
+    pub checksum: ChecksumAlgorithm, // of the record
+    pub timestamp_secs: u64, // since epoch, segment file modification time, no write time
+}
+
+struct CollectedSegment {
+    stats: SegmentStats,
+    path: PathBuf,
+    live_bytes: u64,
+}
+
+#[derive(Default)]
+pub(super) struct StatsCache {
+    records: BTreeMap<String, u64>, // of sealed segments
+    last_merge_ms: Option<u64>,
+}
+
+impl StatsCache {
+    pub(super) fn load(data_dir: &Path) -> Arc<Mutex<Self>> {
+        let mut cache = Self::default();
+        let content = std::fs::read_to_string(data_dir.join(STATS_FILENAME)).unwrap_or_default();
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["last-merge", ms] => cache.last_merge_ms = ms.parse().ok(),
+                ["segment", name, records] => {
+                    if let Ok(records) = records.parse() {
+                        cache.records.insert(name.to_string(), records);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Arc::new(Mutex::new(cache))
+    }
+
+    // record_sealed puts the record count of a sealed segment in the file, its hint was just written
+    pub(super) fn record_sealed(&mut self, segment_path: &Path, records: u64) -> Result<()> {
+        let name = os_str_to_string(segment_path.file_stem());
+        if self.records.get(&name) == Some(&records) {
+            return Ok(());
+        }
+        self.records.insert(name, records);
+        self.persist(segment_path.parent().unwrap_or(Path::new(".")))
+    }
+
+    fn persist(&self, data_dir: &Path) -> Result<()> {
+        let mut content = String::new();
+        if let Some(ms) = self.last_merge_ms {
+            content.push_str(&format!("last-merge {}\n", ms));
+        }
+        for (name, records) in self.records.iter() {
+            content.push_str(&format!("segment {} {}\n", name, records));
+        }
+        let tmp_path = data_dir.join(STATS_TMP_FILENAME);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        rename_durable(&tmp_path, &data_dir.join(STATS_FILENAME))?;
+        Ok(())
+    }
+}
+
+impl Database {
+    // stats counts records of every segment and their live part. Counts of sealed segments, which are
+    // missing from STATS file, are taken from hint files or segments and persisted. While a lazy index
+    // is hydrating, live records are undercounted.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let (value_sizes, segments) = self.collect_segments()?;
+        Ok(StoreStats {
+            keys: value_sizes.count(),
+            value_sizes,
+            segments: segments.into_iter().map(|collected| collected.stats).collect(),
+            last_merge_ms: self.stats_cache.lock().unwrap().last_merge_ms,
+        })
+    }
+
+    // segments describes every segment ordered by index, the active segment is the last. Live bytes are
+    // estimated from key and value sizes of index entries, values indexed from hint files of former
+    // versions count as empty, so their garbage is overestimated.
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let (_, segments) = self.collect_segments()?;
+        let last = segments.len() - 1;
+        segments
+            .into_iter()
+            .enumerate()
+            .map(|(i, CollectedSegment { stats, path, live_bytes })| {
+                let metadata = fs::metadata(&path)?;
+                let created = metadata.created().or_else(|_| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+                Ok(SegmentInfo {
+                    bytes: metadata.len(),
+                    garbage_bytes: metadata.len().saturating_sub(live_bytes),
+                    created_secs: created.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+                    has_hint: file_exists(hint_path(&path)),
+                    active: i == last,
+                    segment: stats.segment,
+                    records: stats.records,
+                    live: stats.live,
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    // metadata returns the index entry of key and the flag of its record, none if key does not exist.
+    // Only the flag byte of the record is read.
+    pub fn metadata(&self, key: impl AsRef<[u8]>) -> Result<Option<KeyMetadata>> {
+        let key = key.as_ref();
+        self.check_indexed(key)?;
+        let Some(mut idx) = self.index.get(key)? else {
+            return Ok(None);
+        };
+        let flag = loop {
+            match self.storage.read_flag(&idx) {
+                Err(e) if is_segment_not_found(&e) => idx = self.moved(&idx)?.ok_or(e)?,
+                result => break result?,
+            }
+        };
+        let mut path = Self::get_data_dir(&self.root_dir).join(format!("{}.{}", idx.segment, SEG_EXT_NAME));
+        if !file_exists(&path) {
+            path.set_extension(BLOB_EXT_NAME);
+        }
+        Ok(Some(KeyMetadata {
+            segment: idx.segment.to_string(),
+            offset: idx.offset,
+            value_size: idx.value_size,
+            version: idx.version,
+            inlined: idx.value.is_some(),
+            flag,
+            checksum: ChecksumAlgorithm::of_flag(flag)?,
+            timestamp_secs: self.index.timestamps.get(&idx.segment, idx.offset).unwrap_or_else(|| segment_timestamp(&path)),
+        }))
+    }
+
+    // collect_segments returns the sizes of live values, one per key, and stats, path and live bytes of
+    // every segment
+    fn collect_segments(&self) -> Result<(ValueSizeHistogram, Vec<CollectedSegment>)> {
+        let mut live: BTreeMap<Arc<str>, (u64, u64)> = BTreeMap::new();
+        let mut value_sizes = ValueSizeHistogram::default();
+        {
+            let map = self.index.map.read().unwrap();
+            map.for_each(|record_index| {
+                value_sizes.add(record_index.value_size);
+                let entry = live.entry(record_index.segment.clone()).or_default();
+                entry.0 += 1;
+                entry.1 += record_bytes(&record_index);
+                Ok(())
+            })?;
+        }
+        // paths and cached counts are taken under the locks, segments missing from the cache are counted after
+        let (sealed, active) = {
+            let cache = self.stats_cache.lock().unwrap();
+            self.storage.with_segments(|segments| {
+                let (active, sealed) = segments.split_last().unwrap();
+                let sealed: Vec<(String, PathBuf, Option<u64>)> = sealed
+                    .iter()
+                    .map(|segment| {
+                        let name = segment.name();
+                        let records = cache.records.get(&name).copied();
+                        (name, segment.path(), records)
+                    })
+                    .collect();
+                let active = (active.name(), active.shared_name(), active.records_written(), active.path());
+                (sealed, active)
+            })
+        };
+        let mut counted: Vec<(String, u64)> = Vec::new();
+        let mut segments: Vec<CollectedSegment> = Vec::with_capacity(sealed.len() + 1);
+        for (name, path, records) in sealed {
+            let records = match records {
+                Some(records) => records,
+                None => {
+                    let (records, complete) = match read_hint(&path).ok().flatten() {
+                        Some(hint) => (hint.len() as u64, true),
+                        None => {
+                            let segment = Segment::open_read_only(path.clone());
+                            let mut iter = segment.iter();
+                            let records = iter.by_ref().count() as u64;
+                            (records, iter.finish().is_ok())
+                        }
+                    };
+                    // the count of a segment which does not read to its end is not kept
+                    if complete {
+                        counted.push((name.clone(), records));
+                    }
+                    records
+                }
+            };
+            let (live_records, live_bytes) = live.get(name.as_str()).copied().unwrap_or_default();
+            segments.push(CollectedSegment {
+                stats: SegmentStats {
+                    live: live_records,
+                    segment: name,
+                    records,
+                },
+                path,
+                live_bytes,
+            });
+        }
+        let (active_name, active_shared_name, active_records, active_path) = active;
+        let (live_records, live_bytes) = live.get(&*active_shared_name).copied().unwrap_or_default();
+        segments.push(CollectedSegment {
+            stats: SegmentStats {
+                segment: active_name,
+                records: active_records,
+                live: live_records,
+            },
+            path: active_path,
+            live_bytes,
+        });
+        if !counted.is_empty() {
+            let mut cache = self.stats_cache.lock().unwrap();
+            cache.records.extend(counted);
+            let names: Vec<&str> = segments.iter().map(|collected| collected.stats.segment.as_str()).collect();
+            self.persist_stats_locked(&mut cache, &names)?;
+        }
+        Ok((value_sizes, segments))
+    }
+
+    // record_merge remembers when the last merge finished, its output is counted on the next stats
+    pub(super) fn record_merge(&self) -> Result<()> {
+        let mut cache = self.stats_cache.lock().unwrap();
+        cache.last_merge_ms = Some(self.clock.now_millis());
+        cache.persist(&Self::get_data_dir(&self.root_dir))
+    }
+
+    // counts of segments which are gone are dropped from the file
+    fn persist_stats_locked(&self, cache: &mut StatsCache, segments: &[&str]) -> Result<()> {
+        cache.records.retain(|name, _| segments.contains(&name.as_str()));
+        cache.persist(&Self::get_data_dir(&self.root_dir))
+    }
+}
+
+// record_bytes estimates the size of the record of an index entry, a metadata byte is not counted
+pub(super) fn record_bytes(record_index: &RecordIndex) -> u64 {
+    let varint_len = |v: u64| (64 - v.max(1).leading_zeros() as u64).div_ceil(7);
+    let checksum = ChecksumAlgorithm::of_flag(record_index.flag).map_or(4, |c| c.size()) as u64;
+    let (key_len, value_len) = (record_index.key.len() as u64, record_index.value_size);
+    1 + varint_len(key_len) + varint_len(value_len) + key_len + value_len + checksum
+}
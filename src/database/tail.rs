@@ -0,0 +1,183 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use anyhow::Result;
+
+use super::database::Database;
+use crate::{
+    error::corruption,
+    storage::{
+        segment::{parse_segment_stem, segment_stem, Segment},
+        Bytes, BLOB_EXT_NAME, SEG_EXT_NAME,
+    },
+    utils::utils::file_exists,
+};
+
+/*
+ * A tail yields records appended since it was made, like tail -f of the log. It reads segments on from an
+ * offset: the active segment, and the segment of the next index once the one it reads is sealed. The
+ * post-commit point of writes, where the invalidator is called, signals tails, so a tail waiting for records
+ * wakes once they are indexed.
+ * Merge removes sealed segments, a tail which did not read one to its end before that ends with an error.
+ */
+
+// TailSignal counts commits of the database, tails wait for it to change
+#[derive(Default)]
+pub(super) struct TailSignal {
+    state: Mutex<(u64, bool)>, // commits and whether the database is closed
+    changed: Condvar,
+}
+
+impl TailSignal {
+    pub(super) fn notify(&self) {
+        self.state.lock().unwrap().0 += 1;
+        self.changed.notify_all();
+    }
+
+    pub(super) fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+
+    fn commits(&self) -> (u64, bool) {
+        *self.state.lock().unwrap()
+    }
+
+    // wait returns once commits differ from seen or the database is closed
+    fn wait(&self, seen: u64) {
+        let state = self.state.lock().unwrap();
+        let _state = self.changed.wait_while(state, |(commits, closed)| *commits == seen && !*closed).unwrap();
+    }
+}
+
+// Tail iterates records appended since Database::tail, the value of a deletion is none. Iteration blocks until
+// records follow, it ends once the database is dropped and records written before are yielded.
+pub struct Tail {
+    data_dir: PathBuf,
+    segment: (u64, u64), // index and generation of the segment read
+    offset: u64,         // where reading the segment goes on
+    signal: Arc<TailSignal>,
+    records: VecDeque<(Bytes, Option<Bytes>)>,
+    done: bool,
+}
+
+impl Database {
+    // tail returns an iterator of records written from now on, by this database or an AsyncWriter of it.
+    // Records of a bulk load or an ingested sstable follow once they are in the segment read.
+    pub fn tail(&self) -> Result<Tail> {
+        let (_, active, written) = self.storage.checkpoint_files();
+        let segment = segment_id(&active).ok_or_else(|| corruption(format!("malformed segment name {:?}", active)))?;
+        Ok(Tail {
+            data_dir: Self::get_data_dir(&self.root_dir),
+            segment,
+            offset: written,
+            signal: self.tail_signal.clone(),
+            records: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    // notify_tails wakes tails after records are indexed, it costs nothing without tails
+    pub(super) fn notify_tails(&self) {
+        if Arc::strong_count(&self.tail_signal) > 1 {
+            self.tail_signal.notify();
+        }
+    }
+}
+
+impl Tail {
+    // read_segment reads records of the segment from offset on, it returns the newer segment if one exists
+    fn read_segment(&mut self) -> Result<Option<(u64, u64)>> {
+        let (index, generation) = self.segment;
+        let path = self.data_dir.join(format!("{}.{}", segment_stem(generation, index), SEG_EXT_NAME));
+        // the newer segment is listed first, once it exists the segment read is sealed
+        let next = self.next_segment()?;
+        if !file_exists(&path) {
+            return Err(corruption(format!("segment {:?} was removed before tail read it", path)));
+        }
+        let segment = Segment::open_read_only(path);
+        let mut iter = segment.iter_from(self.offset);
+        for record_index in iter.by_ref() {
+            let value = if record_index.is_deleted() {
+                None
+            } else if *record_index.segment != *segment.name() {
+                let blob_path = self.data_dir.join(format!("{}.{}", record_index.segment, BLOB_EXT_NAME));
+                Some(Segment::open_read_only(blob_path).read_at(record_index.offset)?.value)
+            } else {
+                Some(segment.read_at(record_index.offset)?.value)
+            };
+            self.records.push_back((record_index.key, value));
+        }
+        self.offset = iter.resume_offset();
+        // a record being written ends reading of the active segment, the next read takes it again. A sealed
+        // segment is complete, so it must read to its end.
+        if next.is_some() {
+            iter.finish()?;
+        }
+        Ok(next)
+    }
+
+    // next_segment returns the segment of the lowest index above the one read, segments are created in order
+    fn next_segment(&self) -> Result<Option<(u64, u64)>> {
+        let mut next: Option<(u64, u64)> = None;
+        for entry in fs::read_dir(&self.data_dir)?.flatten() {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new(SEG_EXT_NAME)) {
+                continue;
+            }
+            if let Some(id) = segment_id(&path).filter(|id| id.0 > self.segment.0) {
+                next = Some(next.map_or(id, |next| next.min(id)));
+            }
+        }
+        Ok(next)
+    }
+}
+
+impl Iterator for Tail {
+    type Item = Result<(Bytes, Option<Bytes>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.pop_front() {
+                return Some(Ok(record));
+            }
+            if self.done {
+                return None;
+            }
+            let (seen, closed) = self.signal.commits();
+            match self.read_segment() {
+                Ok(Some(next)) => {
+                    if self.records.is_empty() {
+                        self.segment = next;
+                        self.offset = 0;
+                    }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+            if !self.records.is_empty() {
+                continue;
+            }
+            if closed {
+                self.done = true;
+                continue;
+            }
+            self.signal.wait(seen);
+        }
+    }
+}
+
+// segment_id returns index and generation of a segment path, so ids sort by index
+fn segment_id(path: &Path) -> Option<(u64, u64)> {
+    let (generation, index) = path.file_stem().and_then(OsStr::to_str).and_then(parse_segment_stem)?;
+    Some((index, generation))
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+
+use super::{database::Database, hint::hint_path};
+use crate::{
+    error::{corruption, invalid_input},
+    storage::{segment::Segment, Bytes, RecordIndex, HINT_EXT_NAME, SEG_EXT_NAME},
+    utils::{
+        tar::{read_entries, TarWriter},
+        utils::file_exists,
+    },
+};
+
+/*
+ * A segment unit is a ustar archive of one sealed segment: <segment>.seg and <segment>.hint if it exists.
+ * Import re-ingests live records of the segment as a new segment of the target, so ids and offsets of
+ * the source do not matter and the hint is not used.
+ */
+
+const IMPORT_FILENAME: &str = "segment.import"; // ignored by loader
+
+impl Database {
+    // export_segment writes sealed segment as a segment unit. Values are not redacted, so it refuses
+    // to export while a redactor is set. Segments pointing to blobs are refused too.
+    pub fn export_segment<W: Write>(&self, segment: &str, writer: W) -> Result<W> {
+        if self.redactor.is_some() {
+            return Err(invalid_input("segments can not be exported redacted"));
+        }
+        let _pinned = self.storage.pin();
+        let (sealed, _, _) = self.storage.checkpoint_files();
+        let path = sealed
+            .into_iter()
+            .find(|path| path.file_stem() == Some(OsStr::new(segment)))
+            .ok_or_else(|| invalid_input(format!("sealed segment {} not found", segment)))?;
+        // a segment unit holds no blob segments, pointers in it would dangle
+        let source = Segment::open_read_only(path.clone());
+        if source.iter().any(|ri| ri.segment != source.shared_name()) {
+            return Err(invalid_input(format!("segment {} points to blobs", segment)));
+        }
+        let mut tar = TarWriter::new(writer);
+        let name = format!("{}.{}", segment, SEG_EXT_NAME);
+        tar.append(&name, fs::metadata(&path)?.len(), &mut File::open(&path)?)?;
+        // hint files are replaced by rename, the one read is complete
+        let hint_path = hint_path(&path);
+        if file_exists(&hint_path) {
+            let hint = fs::read(&hint_path)?;
+            let name = format!("{}.{}", segment, HINT_EXT_NAME);
+            tar.append(&name, hint.len() as u64, &mut hint.as_slice())?;
+        }
+        tar.finish()
+    }
+
+    // import_segment adds the live records of a segment unit as a new segment, they override existing
+    // records like ingest_sstable. Metadata bytes of records are not kept. Returns the number of records.
+    pub fn import_segment<R: Read>(&mut self, reader: R) -> Result<u64> {
+        let tmp_path = Self::get_data_dir(&self.root_dir).join(IMPORT_FILENAME);
+        let mut found = false;
+        let unpacked = read_entries(reader, |name, content| {
+            match Path::new(name).extension().and_then(OsStr::to_str) {
+                Some(SEG_EXT_NAME) if !found => {
+                    std::io::copy(content, &mut File::create(&tmp_path)?)?;
+                    found = true;
+                }
+                Some(HINT_EXT_NAME) => {}
+                _ => return Err(invalid_input(format!("unexpected file in segment unit: {}", name))),
+            }
+            Ok(())
+        });
+        let result = unpacked.and_then(|_| {
+            if !found {
+                return Err(invalid_input("segment not found in segment unit"));
+            }
+            self.import_segment_file(&tmp_path)
+        });
+        let _ = fs::remove_file(&tmp_path);
+        result
+    }
+
+    fn import_segment_file(&mut self, path: &Path) -> Result<u64> {
+        let segment = Segment::open_read_only(path.to_owned());
+        segment.format_version()?;
+        // the last record of a key in the segment wins, deleted keys are not imported
+        let mut latest: BTreeMap<Bytes, RecordIndex> = BTreeMap::new();
+        let mut iter = segment.iter();
+        for record_index in iter.by_ref() {
+            if record_index.segment != segment.shared_name() {
+                return Err(invalid_input("segment unit points to blobs"));
+            }
+            latest.insert(record_index.key.clone(), record_index);
+        }
+        iter.finish()?;
+        // a tail like padding without record ends iteration quietly, the unit must be read to its end
+        if iter.resume_offset() != fs::metadata(path)?.len() {
+            return Err(corruption("segment unit ends before end of segment"));
+        }
+        let records = latest.into_values().filter(|ri| !ri.is_deleted()).map(|ri| {
+            let record = segment.read_at(ri.offset)?;
+            Ok((record.key.to_vec(), record.value.to_vec()))
+        });
+        let indexes = self.storage.ingest(records)?;
+        self.index_ingested(indexes)
+    }
+}
@@ -0,0 +1,62 @@
+use std::marker::PhantomData;
+
+use anyhow::Result;
+
+use super::database::Database;
+
+// Codec converts typed keys or values to bytes stored in database.
+// Note: no serde, bincode or msgpack codec is built in, those crates are not dependencies of this crate and
+// could not be fetched when the typed layer was added. A caller which uses serde implements Codec with it.
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+// Utf8Codec stores strings as raw utf-8 bytes
+pub struct Utf8Codec;
+
+impl Codec<String> for Utf8Codec {
+    fn encode(value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+// TypedDatabase encodes keys with KC and values with VC on every access
+pub struct TypedDatabase<'a, K, V, KC, VC> {
+    database: &'a mut Database,
+    _marker: PhantomData<(K, V, KC, VC)>,
+}
+
+impl<'a, K, V, KC: Codec<K>, VC: Codec<V>> TypedDatabase<'a, K, V, KC, VC> {
+    pub fn write(&mut self, key: &K, value: &V) -> Result<()> {
+        let key = KC::encode(key)?;
+        let value = VC::encode(value)?;
+        self.database.write(&key, &value)
+    }
+
+    pub fn read(&self, key: &K) -> Result<Option<V>> {
+        let key = KC::encode(key)?;
+        match self.database.read(&key)? {
+            Some(value) => Ok(Some(VC::decode(value.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<bool> {
+        let key = KC::encode(key)?;
+        self.database.delete(&key)
+    }
+}
+
+impl Database {
+    pub fn typed<K, V, KC: Codec<K>, VC: Codec<V>>(&mut self) -> TypedDatabase<'_, K, V, KC, VC> {
+        TypedDatabase {
+            database: self,
+            _marker: PhantomData,
+        }
+    }
+}
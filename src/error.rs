@@ -0,0 +1,92 @@
+use crate::storage::Bytes;
+
+// StoreError is the typed failure of a store operation, it is carried in anyhow::Error
+// and can be told apart by downcast_ref::<StoreError>(). IO failures are std::io::Error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreError {
+    // on-disk data is malformed, location is filled as far as it is known
+    Corruption {
+        detail: String,
+        segment: Option<String>,
+        offset: Option<u64>,
+        key: Option<Vec<u8>>,
+    },
+    SegmentNotFound(String),   // index points to a segment which does not exist
+    InvalidInput(String),      // malformed import file, cursor or argument
+    UnsupportedFormat(String), // data written by a newer version
+    NotIndexed(Vec<u8>),       // key is left out by the key filter of open
+    Closed,                    // background writer is gone
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Corruption {
+                detail,
+                segment,
+                offset,
+                key,
+            } => {
+                write!(f, "corruption: {}", detail)?;
+                if let Some(segment) = segment {
+                    write!(f, ", segment {}", segment)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, ", offset {}", offset)?;
+                }
+                if let Some(key) = key {
+                    write!(f, ", key {:?}", Bytes::from(key.as_slice()))?;
+                }
+                Ok(())
+            }
+            StoreError::SegmentNotFound(segment) => write!(f, "segment not found: {}", segment),
+            StoreError::InvalidInput(detail) => write!(f, "invalid input: {}", detail),
+            StoreError::UnsupportedFormat(detail) => write!(f, "unsupported format: {}", detail),
+            StoreError::NotIndexed(key) => write!(f, "key {:?} is not indexed by key filter", Bytes::from(key.as_slice())),
+            StoreError::Closed => write!(f, "writer is closed"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub(crate) fn corruption(detail: impl Into<String>) -> anyhow::Error {
+    StoreError::Corruption {
+        detail: detail.into(),
+        segment: None,
+        offset: None,
+        key: None,
+    }
+    .into()
+}
+
+// locate fills the unknown parts of a corruption location, other errors pass through
+pub(crate) fn locate(mut e: anyhow::Error, at_segment: Option<&str>, at_offset: Option<u64>, at_key: Option<&[u8]>) -> anyhow::Error {
+    if let Some(StoreError::Corruption {
+        segment, offset, key, ..
+    }) = e.downcast_mut::<StoreError>()
+    {
+        if segment.is_none() {
+            *segment = at_segment.map(|s| s.to_string());
+        }
+        if offset.is_none() {
+            *offset = at_offset;
+        }
+        if key.is_none() {
+            *key = at_key.map(|k| k.to_vec());
+        }
+    }
+    e
+}
+
+pub(crate) fn is_corruption(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. }))
+}
+
+pub(crate) fn is_segment_not_found(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<StoreError>(), Some(StoreError::SegmentNotFound(_)))
+}
+
+pub(crate) fn invalid_input(detail: impl Into<String>) -> anyhow::Error {
+    StoreError::InvalidInput(detail.into()).into()
+}
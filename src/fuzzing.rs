@@ -0,0 +1,35 @@
+// entry points for fuzz targets in fuzz/, only built with --cfg fuzzing which is set by cargo-fuzz
+use crate::{
+    database::database::Database,
+    storage::{segment::Segment, Bytes},
+    utils::varint::{decode_varint, decode_varint_from_slice},
+};
+
+pub fn varint(data: &[u8]) {
+    let _ = decode_varint(&mut &data[..]);
+    let mut i = 0;
+    while i < data.len() {
+        if decode_varint_from_slice(data, &mut i).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn hint_record(data: &[u8]) {
+    let _ = Database::decode_hint(Bytes::new(), 0, Bytes::from(data.to_vec()));
+}
+
+// segment parses data as segment file by iteration and by random reads, with and without mmap
+pub fn segment(data: &[u8]) {
+    let path = std::env::temp_dir().join(format!("bitcask-fuzz-{}.seg", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let segment = Segment::open_read_only(path.clone());
+    let offsets: Vec<u64> = segment.iter_with_value().map(|ri| ri.offset).collect();
+    for offset in offsets.iter().chain([0, data.len() as u64 / 2].iter()) {
+        let _ = segment.read_at(*offset);
+        if let Ok(s) = Segment::open_mmap(path.clone()) {
+            let _ = s.read_at(*offset);
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,189 @@
+use anyhow::Result;
+
+use crate::database::typed::Codec;
+use crate::error::invalid_input;
+
+/*
+ * Order-preserving key encodings:
+ * Index is ordered by raw bytes, encoded keys compare in the same order as the original values.
+ *
+ * u64: big endian
+ * i64: big endian with sign bit flipped
+ * f64: big endian, flip sign bit of positives and all bits of negatives
+ * str/bytes in composite key: 0x00 escaped as 0x00 0xFF, terminated by 0x00 0x01
+ */
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+pub fn encode_u64(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+pub fn decode_u64(bytes: &[u8]) -> Result<u64> {
+    let arr: [u8; 8] = bytes.try_into().map_err(|_| invalid_input("u64 key must be 8 bytes"))?;
+    Ok(u64::from_be_bytes(arr))
+}
+
+pub fn encode_i64(v: i64) -> [u8; 8] {
+    ((v as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_i64(bytes: &[u8]) -> Result<i64> {
+    Ok((decode_u64(bytes)? ^ (1 << 63)) as i64)
+}
+
+pub fn encode_f64(v: f64) -> [u8; 8] {
+    let bits = v.to_bits();
+    let ordered = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+    ordered.to_be_bytes()
+}
+
+pub fn decode_f64(bytes: &[u8]) -> Result<f64> {
+    let ordered = decode_u64(bytes)?;
+    let bits = if ordered >> 63 == 1 { ordered ^ (1 << 63) } else { !ordered };
+    Ok(f64::from_bits(bits))
+}
+
+// CompositeKey concatenates several encoded components, keys compare component by component
+#[derive(Debug, Clone, Default)]
+pub struct CompositeKey {
+    buf: Vec<u8>,
+}
+
+impl CompositeKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_u64(mut self, v: u64) -> Self {
+        self.buf.extend_from_slice(&encode_u64(v));
+        self
+    }
+
+    pub fn push_i64(mut self, v: i64) -> Self {
+        self.buf.extend_from_slice(&encode_i64(v));
+        self
+    }
+
+    pub fn push_f64(mut self, v: f64) -> Self {
+        self.buf.extend_from_slice(&encode_f64(v));
+        self
+    }
+
+    pub fn push_str(self, v: &str) -> Self {
+        self.push_bytes(v.as_bytes())
+    }
+
+    pub fn push_bytes(mut self, v: &[u8]) -> Self {
+        for &b in v {
+            self.buf.push(b);
+            if b == ESCAPE {
+                self.buf.push(ESCAPED_ZERO);
+            }
+        }
+        self.buf.push(ESCAPE);
+        self.buf.push(TERMINATOR);
+        self
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.buf.as_slice()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// CompositeKeyReader decodes components in the order they were pushed
+pub struct CompositeKeyReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CompositeKeyReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take_fixed(&mut self) -> Result<&'a [u8]> {
+        let end = self.pos + 8;
+        let slice = self.buf.get(self.pos..end).ok_or_else(|| invalid_input("unexpected end of key"))?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        decode_u64(self.take_fixed()?)
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        decode_i64(self.take_fixed()?)
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64> {
+        decode_f64(self.take_fixed()?)
+    }
+
+    pub fn read_str(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.read_bytes()?)?)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut result: Vec<u8> = Vec::new();
+        loop {
+            let b = *self.buf.get(self.pos).ok_or_else(|| invalid_input("unexpected end of key"))?;
+            self.pos += 1;
+            if b != ESCAPE {
+                result.push(b);
+                continue;
+            }
+            let next = *self.buf.get(self.pos).ok_or_else(|| invalid_input("unexpected end of key"))?;
+            self.pos += 1;
+            match next {
+                ESCAPED_ZERO => result.push(ESCAPE),
+                TERMINATOR => return Ok(result),
+                _ => return Err(invalid_input("invalid escape in key")),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+// OrderedCodec plugs the order-preserving encodings into TypedDatabase
+pub struct OrderedCodec;
+
+impl Codec<u64> for OrderedCodec {
+    fn encode(value: &u64) -> Result<Vec<u8>> {
+        Ok(encode_u64(*value).to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<u64> {
+        decode_u64(bytes)
+    }
+}
+
+impl Codec<i64> for OrderedCodec {
+    fn encode(value: &i64) -> Result<Vec<u8>> {
+        Ok(encode_i64(*value).to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<i64> {
+        decode_i64(bytes)
+    }
+}
+
+impl Codec<f64> for OrderedCodec {
+    fn encode(value: &f64) -> Result<Vec<u8>> {
+        Ok(encode_f64(*value).to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<f64> {
+        decode_f64(bytes)
+    }
+}
@@ -1,7 +1,37 @@
-#![feature(file_create_new)]
-
 mod database;
+mod error;
 mod storage;
 mod utils;
 mod benchmark;
 mod test;
+pub mod keys;
+pub mod raw;
+#[cfg(fuzzing)]
+pub mod fuzzing;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+
+pub use database::database::{
+    Backpressure, Database, GetResult, IndexStats, OpenProgress, OpenTimings, Options, SegmentLoadTiming, SyncPolicy,
+    VersionConflict, WriteOptions, WriteThrottled,
+};
+pub use database::append::Location;
+pub use database::blob::BlobCompactionStats;
+pub use database::follower::Follower;
+pub use database::hint::{HintAudit, HintAuditReport};
+pub use database::invalidate::{Invalidator, WriteOp};
+pub use database::merge::MergeStats;
+pub use database::pipeline::{AsyncWriter, DeleteHandle, WriteHandle};
+pub use database::redact::Redactor;
+pub use database::redis::RdbImportStats;
+pub use database::scan::{Cursor, Entry, Iter, ScanPage};
+pub use database::secondary::IndexExtractor;
+pub use database::sharded::ShardedDatabase;
+pub use database::tail::Tail;
+pub use database::stats::{KeyMetadata, SegmentInfo, SegmentStats, StoreStats, ValueSizeHistogram};
+pub use error::StoreError;
+pub use database::typed::{Codec, TypedDatabase, Utf8Codec};
+pub use storage::checksum::ChecksumAlgorithm;
+pub use storage::io_stats::IoStats;
+pub use storage::Bytes;
+pub use utils::clock::{Clock, SimClock, SystemClock};
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::storage::{
+    segment::{Segment, SegmentIter},
+    Bytes, FLAG_DELETED,
+};
+
+/*
+ * Read-only access to segment files for tools and forensics, independent of a database.
+ * A segment is a log of records, see storage/segment.rs for its layout. Control records, the segment
+ * header and batch markers, are not returned, nor are records of a batch without its commit.
+ * Pointers to blobs are control records too, a blob segment <n>.blob is a segment of its own.
+ */
+
+// RawRecord is a record of a segment, offset is where its header starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawRecord {
+    pub offset: u64,
+    pub key: Bytes,
+    pub value: Bytes, // empty for a deletion
+    pub deleted: bool,
+    pub metadata: u8, // application defined, 0 if not set
+}
+
+pub struct SegmentReader {
+    segment: Segment,
+}
+
+impl SegmentReader {
+    // open fails for segments of a newer format than this version reads
+    pub fn open(path: impl AsRef<Path>) -> Result<SegmentReader> {
+        let segment = Segment::open_read_only(path.as_ref().to_owned());
+        segment.format_version()?;
+        Ok(SegmentReader { segment })
+    }
+
+    // name is [<generation>-]<index>
+    pub fn name(&self) -> String {
+        self.segment.name()
+    }
+
+    pub fn format_version(&self) -> Result<u8> {
+        self.segment.format_version()
+    }
+
+    // read_at reads the record at offset and verifies its checksum
+    pub fn read_at(&self, offset: u64) -> Result<RawRecord> {
+        let record = self.segment.read_at(offset)?;
+        Ok(RawRecord {
+            offset,
+            deleted: record.flag & FLAG_DELETED != 0,
+            key: record.key,
+            value: record.value,
+            metadata: record.metadata,
+        })
+    }
+
+    // records iterates records in write order, each one is read and verified by read_at
+    pub fn records(&self) -> RawRecords<'_> {
+        RawRecords {
+            reader: self,
+            iter: self.segment.iter(),
+        }
+    }
+}
+
+// RawRecords ends at the end of segment or at the first malformed or torn record header
+pub struct RawRecords<'a> {
+    reader: &'a SegmentReader,
+    iter: SegmentIter<'a>,
+}
+
+impl RawRecords<'_> {
+    // end_offset is where iteration stopped once it returned none, it is less than the file length
+    // if a malformed or torn record follows
+    pub fn end_offset(&self) -> u64 {
+        self.iter.resume_offset()
+    }
+}
+
+impl Iterator for RawRecords<'_> {
+    type Item = Result<RawRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record_index = self.iter.next()?;
+            // iteration yields the blob record of a pointer
+            if record_index.segment == self.reader.segment.shared_name() {
+                return Some(self.reader.read_at(record_index.offset));
+            }
+        }
+    }
+}
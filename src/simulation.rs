@@ -0,0 +1,30 @@
+// deterministic simulation support, enabled by the simulation feature.
+// Combine seeded faults with SimClock passed by Options::clock to replay a run exactly.
+// Fail points cover what changes files: segment creation, writes, syncs and truncation through the segment
+// backend, renames which put files in place, rotation and merge steps. Reads and directory listings are not
+// injected, a run is deterministic as long as the files it reads are.
+use anyhow::Result;
+
+use crate::{error::invalid_input, storage::fault};
+
+pub use crate::utils::clock::SimClock;
+
+// seed_faults makes every fail point of current thread fail with probability, driven by seed.
+// Probability must be from 0 to 1.
+pub fn seed_faults(seed: u64, probability: f64) -> Result<()> {
+    if !(0.0..=1.0).contains(&probability) {
+        return Err(invalid_input(format!("fault probability {} is not from 0 to 1", probability)));
+    }
+    fault::simulate(seed, probability);
+    Ok(())
+}
+
+// inject_crash makes fail point named point fail once, after skip hits
+pub fn inject_crash(point: &'static str, skip: u64) {
+    fault::inject(point, skip, fault::Fault::Crash);
+}
+
+pub fn clear_faults() {
+    fault::stop_simulation();
+    fault::clear();
+}
@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+use super::fault::{self, Fault};
+
+// Backend is what segments change their files through: appends, syncs and truncation. Files are the backend
+// of segments, Faulty puts the fail points of fault in front of one for crash consistency tests.
+pub(crate) trait Backend {
+    fn append(&mut self, buf: &[u8]) -> std::io::Result<()>;
+    fn sync(&mut self) -> std::io::Result<()>;
+    // truncate cuts the file to len bytes, appends go on from there
+    fn truncate(&mut self, len: u64) -> std::io::Result<()>;
+}
+
+impl Backend for File {
+    fn append(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.set_len(len)?;
+        self.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+}
+
+// Faulty triggers the fail points segment.write, segment.sync and segment.truncate, they are no-ops
+// without cfg(test) or the simulation feature
+pub(crate) struct Faulty<'a, B: Backend>(pub(crate) &'a mut B);
+
+impl<B: Backend> Backend for Faulty<'_, B> {
+    fn append(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match fault::hit("segment.write") {
+            Some(Fault::ShortWrite(n)) => {
+                self.0.append(&buf[..n.min(buf.len())])?;
+                Err(fault::injected_error("segment.write"))
+            }
+            Some(Fault::Crash) => Err(fault::injected_error("segment.write")),
+            None => self.0.append(buf),
+        }
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        fault::check("segment.sync")?;
+        self.0.sync()
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        fault::check("segment.truncate")?;
+        self.0.truncate(len)
+    }
+}
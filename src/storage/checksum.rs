@@ -0,0 +1,112 @@
+use anyhow::Result;
+use crc::{Algorithm, Crc, Table, CRC_32_ISCSI};
+
+use crate::{
+    error::{corruption, locate},
+    utils::xxhash::Xxh64,
+};
+
+/*
+ * A record ends with its checksum. Bits 3 and 4 of its flag tell the algorithm, so records
+ * written with different algorithms can be in one segment:
+ * 0: CRC-16 stored in 4 bytes, record format v1 which leaves 2 bytes unused
+ * 1: CRC-32C in 4 bytes, record format v2 and the default
+ * 2: XXH64 with seed 0 in 8 bytes
+ * Bit 5 tells the checksum covers the header, i.e. flag, metadata and lengths, besides key and value.
+ * Records written by former versions lack it, so a damaged header of theirs may go unnoticed.
+ * Files of v1 stay readable, their records are rewritten in v2 by merge.
+ */
+
+pub(crate) const FLAG_CHECKSUM_MASK: u8 = 0b11 << 3;
+const FLAG_CRC32C: u8 = 1 << 3;
+const FLAG_XXH64: u8 = 2 << 3;
+pub(crate) const FLAG_HEADER_CHECKSUM: u8 = 1 << 5;
+
+const CRC16: Crc<u32> = Crc::<u32>::new(&Algorithm {
+    width: 16,
+    poly: 0x8005,
+    init: 0xffff,
+    refin: false,
+    refout: false,
+    xorout: 0x0000,
+    check: 0xaee7,
+    residue: 0x0000,
+});
+// slicing by 16 tables, the crc32c and crc32fast crates with SSE4.2/ARM instructions are unavailable
+const CRC32C: Crc<u32, Table<16>> = Crc::<u32, Table<16>>::new(&CRC_32_ISCSI);
+
+// ChecksumAlgorithm is the checksum of records written from now on, existing records keep theirs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    Crc16, // v1, detects fewer errors
+    #[default]
+    Crc32c,
+    Xxh64,  // fastest on very large values, not a CRC
+}
+
+impl ChecksumAlgorithm {
+    pub(crate) fn of_flag(flag: u8) -> Result<Self> {
+        match flag & FLAG_CHECKSUM_MASK {
+            0 => Ok(ChecksumAlgorithm::Crc16),
+            FLAG_CRC32C => Ok(ChecksumAlgorithm::Crc32c),
+            FLAG_XXH64 => Ok(ChecksumAlgorithm::Xxh64),
+            _ => Err(corruption("unknown checksum algorithm")),
+        }
+    }
+
+    pub(crate) fn flag(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc16 => 0,
+            ChecksumAlgorithm::Crc32c => FLAG_CRC32C,
+            ChecksumAlgorithm::Xxh64 => FLAG_XXH64,
+        }
+    }
+
+    // bytes of checksum at the end of record
+    pub(crate) fn size(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc16 | ChecksumAlgorithm::Crc32c => 4,
+            ChecksumAlgorithm::Xxh64 => 8,
+        }
+    }
+
+    // digest returns the checksum of header, key and value in its first size() bytes,
+    // header is empty for records whose checksum does not cover it
+    pub(crate) fn digest(self, header: &[u8], key: &[u8], value: &[u8]) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        match self {
+            ChecksumAlgorithm::Crc16 => {
+                let mut digest = CRC16.digest();
+                [header, key, value].iter().for_each(|part| digest.update(part));
+                out[..4].copy_from_slice(&digest.finalize().to_le_bytes());
+            }
+            ChecksumAlgorithm::Crc32c => {
+                let mut digest = CRC32C.digest();
+                [header, key, value].iter().for_each(|part| digest.update(part));
+                out[..4].copy_from_slice(&digest.finalize().to_le_bytes());
+            }
+            ChecksumAlgorithm::Xxh64 => {
+                let mut hasher = Xxh64::new(0);
+                [header, key, value].iter().for_each(|part| hasher.update(part));
+                out.copy_from_slice(&hasher.finish().to_le_bytes());
+            }
+        }
+        out
+    }
+
+    pub(crate) fn verify(self, header: &[u8], key: &[u8], value: &[u8], stored: &[u8]) -> Result<()> {
+        if self.digest(header, key, value)[..self.size()] != *stored {
+            return Err(locate(corruption("checksum mismatch"), None, None, Some(key)));
+        }
+        Ok(())
+    }
+}
+
+// covered_header returns the part of header bytes which checksum of record covers
+pub(crate) fn covered_header(flag: u8, header: &[u8]) -> &[u8] {
+    if flag & FLAG_HEADER_CHECKSUM > 0 {
+        header
+    } else {
+        &[]
+    }
+}
@@ -1,58 +1,136 @@
 use std::{
     collections::{BTreeMap},
     ffi::OsStr,
-    path::PathBuf,
-    sync::RwLock,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard},
 };
 
-use crate::utils::utils::os_str_to_string;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+
+use crate::error::{corruption, is_corruption, is_segment_not_found, locate, StoreError};
+use crate::utils::utils::rename_durable;
 
 use super::{
-    segment::{Segment, WriteResult},
-    Bytes, Record, RecordIndex, SEG_EXT_NAME,
+    checksum::ChecksumAlgorithm,
+    fault,
+    io_stats::{IoCounters, IoStats},
+    segment::{
+        blob_pointer, parse_segment_stem, segment_stem, BatchRecord, BatchWriteResult, Segment, MAX_SEGMENT_BYTES,
+        SEGMENT_FORMAT_VERSION,
+    },
+    Bytes, Record, RecordIndex, BLOB_EXT_NAME, FLAG_CONTROL, INGEST_EXT_NAME, SEG_EXT_NAME,
 };
 
 pub(crate) struct Directory {
     pub(crate) internal: RwLock<DirectoryInternal>,
+    // every write call is synced once before it returns, a batch of it shares the fsync
+    sync_always: bool,
+    pub(crate) blobs: RwLock<BlobSegments>,
+    // held for reading by readers of sealed segments by path, segments are replaced only if none holds it
+    pinned: RwLock<()>,
+}
+
+// Values of at least Options::blob_threshold bytes are written to blob segments <n>.blob and a pointer
+// to each of them to the log, so the log keeps the order of writes and index entries point to blobs.
+// Merge copies pointers but not blobs, see Database::compact_blobs. Blob segments are locked apart
+// from internal, a thread holding both took internal first.
+pub(crate) struct BlobSegments {
+    active: Option<Segment>, // created by the first blob write after open or seal
+    sealed: BTreeMap<String, Segment>,
+    dir_path: PathBuf,
+    mmap_limit: Option<u64>,
+    checksum: ChecksumAlgorithm,
+    io: Arc<IoCounters>,
+}
+
+impl BlobSegments {
+    fn has(&self, name: &str) -> bool {
+        self.active.as_ref().is_some_and(|blob| &*blob.shared_name() == name) || self.sealed.contains_key(name)
+    }
 }
 
 pub(crate) struct DirectoryInternal {
     pub(crate) dir_path: PathBuf,
     pub(crate) active_segment: Segment,
     pub(crate) old_segments: BTreeMap<String, Segment>,
-    pub(crate) use_mmap: bool,
+    pub(crate) mmap_limit: Option<u64>, // segments up to this size are mapped, none maps none
+    pub(crate) checksum: ChecksumAlgorithm, // of records written to new segments
+    // called with the path of every segment sealed by rotation, it must not block
+    pub(crate) on_seal: Option<SealHook>,
+    // id of the next created segment, it is persisted before the segment is created
+    pub(crate) next_segment_id: u64,
+    pub(crate) io: Arc<IoCounters>, // shared by all segments
+}
+
+pub(crate) type SealHook = Box<dyn Fn(&Path) + Send + Sync>;
+
+// MANIFEST of data dir holds "next-segment-id <n>", so ids of removed segments are never given
+// to new segments. Without it the next id follows the highest existing segment.
+pub(crate) const MANIFEST_FILENAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILENAME: &str = "MANIFEST.tmp";
+const INGEST_BATCH_BYTES: usize = 1024 * 1024; // ingested records are written in batches of about this size
+
+// MergeRange selects the sealed segments of a merge. It always ends at the newest one, merged output takes
+// the place of its inputs by index and must not be older than an un-merged segment.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum MergeRange {
+    All,
+    Newest(usize), // the newest n sealed segments
+    From(u64),     // sealed segments of this index on
 }
 
 pub(crate) struct MergePreparation {
-    pub(crate) to_merge: Vec<PathBuf>,
+    pub(crate) to_merge: Vec<PathBuf>, // sorted by segment index
+    // oldest sealed segment which is not an input of merge, tombstones newer than it must be retained
+    pub(crate) min_unmerged_segment: Option<u64>,
 }
 
 impl Directory {
-    pub(crate) fn open(dir: &str, use_mmap: bool) -> Result<Self> {
+    pub(crate) fn open(dir: &str, mmap_limit: Option<u64>, sync_always: bool, checksum: ChecksumAlgorithm) -> Result<Self> {
         let dir_path = PathBuf::from(dir);
         let read_dir = std::fs::read_dir(&dir_path)?;
+        let io: Arc<IoCounters> = Arc::default();
         let mut old_segment_vec: Vec<Segment> = Vec::new();
-        for e in read_dir {
-            if let Ok(entry) = e {
-                let p = entry.path();
-                if p.is_file() && p.extension() == Some(OsStr::new(SEG_EXT_NAME)) {
-                    let segment = if use_mmap {
-                        Segment::open_mmap(p)?
-                    } else {
-                        Segment::open_read_only(p)
-                    };
+        let mut blobs = BlobSegments {
+            active: None,
+            sealed: BTreeMap::new(),
+            dir_path: dir_path.clone(),
+            mmap_limit,
+            checksum,
+            io: io.clone(),
+        };
+        for entry in read_dir.flatten() {
+            let p = entry.path();
+            let is_blob = p.extension() == Some(OsStr::new(BLOB_EXT_NAME));
+            if p.is_file() && (is_blob || p.extension() == Some(OsStr::new(SEG_EXT_NAME))) {
+                if p.file_stem().and_then(|x| x.to_str()).and_then(parse_segment_stem).is_none() {
+                    return Err(corruption(format!("invalid segment file name: {}", p.display())));
+                }
+                let segment = Self::open_segment(p, mmap_limit, &io)?;
+                // refuse segments of a newer format before anything is written
+                segment.format_version()?;
+                if is_blob {
+                    blobs.sealed.insert(segment.name(), segment);
+                } else {
                     old_segment_vec.push(segment);
                 }
             }
         }
         if old_segment_vec.is_empty() {
-            return Self::new_directory(dir, use_mmap);
+            return Self::new_directory(dir, mmap_limit, sync_always, checksum, io, blobs);
         }
-        let last_file_stem = os_str_to_string(old_segment_vec.last().unwrap().path().file_stem());
-        let last_file_index: usize = last_file_stem.parse()?;
-        let active_segment_index = last_file_index as u64 + 1;
-        let active_segment = Segment::create(&dir_path, active_segment_index, SEG_EXT_NAME)?;
+        // read_dir yields entries in no particular order
+        let last_file_index = old_segment_vec.iter().map(|s| s.index()).max().unwrap();
+        let active_segment_index = Self::read_next_segment_id(&dir_path)?.max(last_file_index + 1);
+        // new segments are written in the latest generation, which is the one of the last merge
+        let generation = old_segment_vec.iter().map(|s| s.generation()).max().unwrap();
+        Self::write_next_segment_id(&dir_path, active_segment_index + 1)?;
+        let active_segment =
+            Self::with_remap(Segment::create(&dir_path, generation, active_segment_index, SEG_EXT_NAME)?, mmap_limit)
+                .with_checksum(checksum)
+                .with_io(io.clone());
 
         let old_segments: BTreeMap<String, Segment> =
             old_segment_vec.into_iter().map(|s| (s.name(), s)).collect();
@@ -61,86 +139,689 @@ impl Directory {
                 dir_path,
                 active_segment,
                 old_segments,
-                use_mmap,
+                mmap_limit,
+                on_seal: None,
+                checksum,
+                next_segment_id: active_segment_index + 1,
+                io,
             }),
+            sync_always,
+            blobs: RwLock::new(blobs),
+            pinned: RwLock::new(()),
         })
     }
 
-    fn new_directory(dir: &str, use_mmap: bool) -> Result<Self> {
+    fn new_directory(
+        dir: &str,
+        mmap_limit: Option<u64>,
+        sync_always: bool,
+        checksum: ChecksumAlgorithm,
+        io: Arc<IoCounters>,
+        blobs: BlobSegments,
+    ) -> Result<Self> {
         let dir_path = PathBuf::from(dir);
         std::fs::create_dir_all(&dir_path)?;
-        let active_segment_index: u64 = 1;
-        let active_segment = Segment::create(&dir_path, active_segment_index, SEG_EXT_NAME)?;
+        // segments may all be gone while manifest remembers their ids
+        let active_segment_index: u64 = Self::read_next_segment_id(&dir_path)?.max(1);
+        Self::write_next_segment_id(&dir_path, active_segment_index + 1)?;
+        let active_segment = Self::with_remap(Segment::create(&dir_path, 0, active_segment_index, SEG_EXT_NAME)?, mmap_limit)
+            .with_checksum(checksum)
+            .with_io(io.clone());
         Ok(Directory {
             internal: RwLock::new(DirectoryInternal {
                 dir_path,
                 active_segment,
                 old_segments: BTreeMap::new(),
-                use_mmap
+                mmap_limit,
+                on_seal: None,
+                checksum,
+                next_segment_id: active_segment_index + 1,
+                io,
             }),
+            sync_always,
+            blobs: RwLock::new(blobs),
+            pinned: RwLock::new(()),
         })
     }
 
-    pub(crate) fn prepare_merge(&self) -> Result<MergePreparation> {
+    // active_segment_id returns the id of the active segment, ids are never given twice
+    pub(crate) fn active_segment_id(&self) -> u64 {
+        self.internal.read().unwrap().active_segment.index()
+    }
+
+    pub(crate) fn checksum(&self) -> ChecksumAlgorithm {
+        self.internal.read().unwrap().checksum
+    }
+
+    pub(crate) fn io_stats(&self) -> IoStats {
+        self.internal.read().unwrap().io.snapshot()
+    }
+
+    // old_format_segments counts sealed segments of a format older than the one written now
+    pub(crate) fn old_format_segments(&self) -> Result<usize> {
+        let internal = self.internal.read().unwrap();
+        let mut count = 0;
+        for segment in internal.old_segments.values() {
+            if segment.format_version()? < SEGMENT_FORMAT_VERSION {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn set_on_seal(&self, hook: SealHook) {
+        self.internal.write().unwrap().on_seal = Some(hook);
+    }
+
+    // freeze seals the active segment and returns paths of all sealed segments sorted by index,
+    // sealed segments are immutable so they can be read without holding any lock
+    pub(crate) fn freeze(&self) -> Result<Vec<PathBuf>> {
         let internal = &mut *(self.internal.write().unwrap());
         Self::rotate_active_segment(internal)?;
-        let to_merge = internal
-            .old_segments
-            .iter()
-            .map(|(_, x)| x.path())
-            .collect::<Vec<PathBuf>>();
-        Ok(MergePreparation { to_merge })
+        let mut sealed: Vec<&Segment> = internal.old_segments.values().collect();
+        sealed.sort_by_key(|x| x.index());
+        Ok(sealed.iter().map(|x| x.path()).collect())
     }
 
-    pub(crate) fn read_at(&self, index: &RecordIndex) -> Result<Record> {
+    // checkpoint_files returns sealed segments sorted by index, and the active segment with its written length
+    pub(crate) fn checkpoint_files(&self) -> (Vec<PathBuf>, PathBuf, u64) {
+        let internal = self.internal.read().unwrap();
+        let mut sealed: Vec<&Segment> = internal.old_segments.values().collect();
+        sealed.sort_by_key(|x| x.index());
+        let active = &internal.active_segment;
+        (
+            sealed.iter().map(|x| x.path()).collect(),
+            active.path(),
+            active.written(),
+        )
+    }
+
+    // prepare_merge seals the active segment and takes the sealed segments of range
+    pub(crate) fn prepare_merge(&self, range: MergeRange) -> Result<MergePreparation> {
+        let mut to_merge = self.freeze()?;
+        let unmerged = match range {
+            MergeRange::All => 0,
+            MergeRange::Newest(n) => to_merge.len().saturating_sub(n),
+            MergeRange::From(from) => to_merge
+                .iter()
+                .position(|path| {
+                    let index = path.file_stem().and_then(OsStr::to_str).and_then(parse_segment_stem);
+                    index.is_some_and(|(_, index)| index >= from)
+                })
+                .unwrap_or(to_merge.len()),
+        };
+        let min_unmerged_segment = to_merge[..unmerged]
+            .first()
+            .and_then(|path| path.file_stem().and_then(OsStr::to_str).and_then(parse_segment_stem))
+            .map(|(_, index)| index);
+        to_merge.drain(..unmerged);
+        Ok(MergePreparation {
+            to_merge,
+            min_unmerged_segment,
+        })
+    }
+
+    // segment_readers returns read-only handles of sealed segments by index and then of the active segment.
+    // Paths are taken under the lock and opened after it is released, so reading them blocks no rotation.
+    pub(crate) fn segment_readers(&self) -> Vec<Segment> {
+        let (paths, io) = {
+            let internal = self.internal.read().unwrap();
+            let mut segments: Vec<&Segment> = internal.old_segments.values().collect();
+            segments.sort_by_key(|s| s.index());
+            segments.push(&internal.active_segment);
+            let paths: Vec<PathBuf> = segments.iter().map(|s| s.path()).collect();
+            (paths, internal.io.clone())
+        };
+        paths
+            .into_iter()
+            .map(|path| Segment::open_read_only(path).with_io(io.clone()))
+            .collect()
+    }
+
+    // pin keeps sealed segments in place until the guard is dropped, see replace_segments
+    pub(crate) fn pin(&self) -> RwLockReadGuard<'_, ()> {
+        self.pinned.read().unwrap()
+    }
+
+    // replace_segments puts the sealed segments at outputs in place of sources, adopt moves their files
+    // meanwhile. Readers of a replaced segment find it gone and must look up the record again. Returns false
+    // and does nothing if segments are pinned.
+    pub(crate) fn replace_segments<F>(&self, sources: &[String], outputs: &[PathBuf], adopt: F) -> Result<bool>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let Ok(_unpinned) = self.pinned.try_write() else {
+            return Ok(false);
+        };
+        let internal = &mut *self.internal.write().unwrap();
+        adopt()?;
+        for name in sources {
+            internal.old_segments.remove(name);
+        }
+        for path in outputs {
+            let segment = Self::open_sealed(internal, path.clone())?;
+            internal.old_segments.insert(segment.name(), segment);
+        }
+        Ok(true)
+    }
+
+    // with_segments passes all segments ordered by index, the active one is the last
+    pub(crate) fn with_segments<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(Vec<&Segment>) -> R,
+    {
         let internal = self.internal.read().unwrap();
-        if index.segment == internal.active_segment.name() {
-            return internal.active_segment.read_at(index.offset);
+        let mut segments: Vec<&Segment> = internal.old_segments.values().collect();
+        segments.sort_by_key(|s| s.index());
+        segments.push(&internal.active_segment);
+        f(segments)
+    }
+
+    // sealed segments are synced on rotation, only active segments may hold records not on disk.
+    // Active blob segment is synced first, so no pointer is durable before its blob.
+    pub(crate) fn sync(&self) -> Result<()> {
+        let internal = self.internal.read().unwrap();
+        if let Some(blob) = self.blobs.read().unwrap().active.as_ref() {
+            blob.sync()?;
+        }
+        internal.active_segment.sync()
+    }
+
+    pub(crate) fn read_at(&self, index: &RecordIndex) -> Result<Record> {
+        {
+            let internal = self.internal.read().unwrap();
+            let segment = if index.segment == internal.active_segment.shared_name() {
+                Some(&internal.active_segment)
+            } else {
+                internal.old_segments.get(&*index.segment)
+            };
+            if let Some(segment) = segment {
+                // the key in index is the one which was asked for, even if the record is too damaged to tell
+                return segment
+                    .read_at(index.offset)
+                    .map_err(|e| locate(e, None, None, Some(index.key.as_slice())));
+            }
+        }
+        self.read_blob(index)
+    }
+
+    // read_flag returns the flag of the record index points to as it was written, with its checksum bits
+    pub(crate) fn read_flag(&self, index: &RecordIndex) -> Result<u8> {
+        {
+            let internal = self.internal.read().unwrap();
+            let segment = if index.segment == internal.active_segment.shared_name() {
+                Some(&internal.active_segment)
+            } else {
+                internal.old_segments.get(&*index.segment)
+            };
+            if let Some(segment) = segment {
+                return segment.read_flag_at(index.offset);
+            }
+        }
+        let blobs = self.blobs.read().unwrap();
+        let segment = match blobs.active.as_ref().filter(|blob| blob.shared_name() == index.segment) {
+            Some(blob) => blob,
+            None => blobs
+                .sealed
+                .get(&*index.segment)
+                .ok_or_else(|| StoreError::SegmentNotFound(index.segment.to_string()))?,
+        };
+        segment.read_flag_at(index.offset)
+    }
+
+    // read_blob reads a record of a blob segment, it takes no lock of internal
+    pub(crate) fn read_blob(&self, index: &RecordIndex) -> Result<Record> {
+        let blobs = self.blobs.read().unwrap();
+        let segment = match blobs.active.as_ref().filter(|blob| blob.shared_name() == index.segment) {
+            Some(blob) => blob,
+            None => blobs
+                .sealed
+                .get(&*index.segment)
+                .ok_or_else(|| StoreError::SegmentNotFound(index.segment.to_string()))?,
+        };
+        segment
+            .read_at(index.offset)
+            .map_err(|e| locate(e, None, None, Some(index.key.as_slice())))
+    }
+
+    // read_previous returns the latest readable record of the key older than the one index points to,
+    // none if there is none or it is a deletion. It scans segments and is meant for the rare damaged record.
+    // A copy in a blob segment removed by compaction is skipped like a damaged one.
+    pub(crate) fn read_previous(&self, index: &RecordIndex) -> Result<Option<Record>> {
+        self.with_segments(|segments| {
+            // the pointer to a damaged blob may be in any segment, it is searched from the newest one and
+            // copies newer than it are passed over. A damaged record of a segment file is passed by offset,
+            // since iteration may stop before a record with a damaged header.
+            let (pos, mut is_passed) = match segments.iter().position(|s| s.shared_name() == index.segment) {
+                Some(pos) => (pos, true),
+                None if self.blobs.read().unwrap().has(&index.segment) => (segments.len() - 1, false),
+                None => return Ok(None),
+            };
+            for segment in segments[..=pos].iter().rev() {
+                let is_failed = segment.shared_name() == index.segment;
+                // records behind the damaged one in its segment are newer
+                let mut iter = segment.iter();
+                let copies: Vec<RecordIndex> = iter
+                    .by_ref()
+                    .filter(|ri| ri.key == index.key && !(is_failed && ri.offset >= index.offset))
+                    .collect();
+                // a copy behind damage of another segment may be newer than the ones found, e.g. a tombstone
+                if let Err(e) = iter.finish() {
+                    if !(is_failed && is_passed && iter.resume_offset() >= index.offset) {
+                        return Err(e);
+                    }
+                }
+                for copy in copies.iter().rev() {
+                    if !is_passed {
+                        is_passed = copy.segment == index.segment && copy.offset == index.offset;
+                        continue;
+                    }
+                    if copy.is_deleted() {
+                        return Ok(None);
+                    }
+                    let read = if copy.segment == segment.shared_name() {
+                        segment.read_at(copy.offset)
+                    } else {
+                        self.read_blob(copy)
+                    };
+                    match read {
+                        Err(e) if is_corruption(&e) => continue,
+                        Err(e) if is_segment_not_found(&e) => continue,
+                        result => return result.map(Some),
+                    }
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    pub(crate) fn write(&self, key: &[u8], value: &[u8], flag: u8, metadata: u8) -> Result<RecordIndex> {
+        let mut indexes = self.write_batch(
+            &[BatchRecord {
+                key,
+                value,
+                flag,
+                metadata,
+            }],
+            false,
+        )?;
+        Ok(indexes.remove(0))
+    }
+
+    // write_control appends a control record, iteration does not yield it
+    pub(crate) fn write_control(&self, key: &[u8], value: &[u8], metadata: u8) -> Result<RecordIndex> {
+        self.write(key, value, FLAG_CONTROL, metadata)
+    }
+
+    // write_blob appends the record to active blob segment and a pointer to it to the log, the returned
+    // index entry points to the blob. With sync_always the blob is durable before its pointer is written.
+    pub(crate) fn write_blob(&self, key: &[u8], value: &[u8], metadata: u8) -> Result<RecordIndex> {
+        let index = self.append_blob(key, value, metadata)?;
+        let (pointer_key, pointer_value) = blob_pointer(&index);
+        self.write_control(&pointer_key, &pointer_value, 0)?;
+        Ok(index)
+    }
+
+    // write_batch_with_blobs is write_batch whose values of at least blob_threshold bytes are appended to
+    // blob segments first, their pointers are records of the batch
+    pub(crate) fn write_batch_with_blobs(
+        &self,
+        records: &[BatchRecord],
+        blob_threshold: Option<u64>,
+        atomic: bool,
+    ) -> Result<Vec<RecordIndex>> {
+        let mut blob_indexes: Vec<Option<RecordIndex>> = Vec::with_capacity(records.len());
+        for record in records {
+            let index = match blob_threshold {
+                Some(threshold) if record.value.len() as u64 >= threshold => {
+                    Some(self.append_blob(record.key, record.value, record.metadata)?)
+                }
+                _ => None,
+            };
+            blob_indexes.push(index);
         }
-        if let Some(segment) = internal.old_segments.get(&index.segment) {
-            return segment.read_at(index.offset);
+        let pointers: Vec<Option<(Vec<u8>, Vec<u8>)>> = blob_indexes.iter().map(|index| index.as_ref().map(blob_pointer)).collect();
+        let batch: Vec<BatchRecord> = records
+            .iter()
+            .zip(pointers.iter())
+            .map(|(record, pointer)| match pointer {
+                Some((key, value)) => BatchRecord {
+                    key,
+                    value,
+                    flag: FLAG_CONTROL,
+                    metadata: 0,
+                },
+                None => BatchRecord {
+                    key: record.key,
+                    value: record.value,
+                    flag: record.flag,
+                    metadata: record.metadata,
+                },
+            })
+            .collect();
+        let indexes = self.write_batch(&batch, atomic)?;
+        Ok(indexes
+            .into_iter()
+            .zip(blob_indexes)
+            .map(|(index, blob_index)| blob_index.unwrap_or(index))
+            .collect())
+    }
+
+    // append_blob appends the record to active blob segment, with sync_always it is durable when it returns
+    fn append_blob(&self, key: &[u8], value: &[u8], metadata: u8) -> Result<RecordIndex> {
+        let index = loop {
+            // id is allocated before blobs are locked, internal must not be locked under blobs
+            let new_id = if self.blobs.read().unwrap().active.is_some() {
+                None
+            } else {
+                let internal = &mut *(self.internal.write().unwrap());
+                Some((Self::allocate_segment_id(internal)?, internal.active_segment.generation()))
+            };
+            let blobs = &mut *(self.blobs.write().unwrap());
+            if blobs.active.is_none() {
+                // sealed by a concurrent write meanwhile
+                let Some((id, generation)) = new_id else { continue };
+                let blob = Segment::create(&blobs.dir_path, generation, id, BLOB_EXT_NAME)?
+                    .with_checksum(blobs.checksum)
+                    .with_io(blobs.io.clone());
+                blobs.active = Some(blob);
+            }
+            let blob = blobs.active.as_ref().unwrap();
+            let write_result = blob.write_with_metadata(key, value, 0, metadata)?;
+            let index = RecordIndex {
+                key: Bytes::from(key),
+                segment: blob.shared_name(),
+                flag: 0,
+                offset: write_result.begin_offset,
+                value_size: value.len() as u64,
+                value: None,
+                version: 0,
+            };
+            if self.sync_always {
+                blob.sync()?;
+            }
+            if write_result.is_segment_full {
+                Self::seal_blob(blobs)?;
+            }
+            break index;
+        };
+        Ok(index)
+    }
+
+    fn seal_blob(blobs: &mut BlobSegments) -> Result<()> {
+        if let Some(blob) = blobs.active.take() {
+            blob.sync()?;
+            let sealed = Self::open_segment(blob.path(), blobs.mmap_limit, &blobs.io)?;
+            blobs.sealed.insert(sealed.name(), sealed);
         }
-        Err(anyhow!("segment not found"))
+        Ok(())
+    }
+
+    // freeze_blobs seals active blob segment and returns names and paths of all blob segments
+    pub(crate) fn freeze_blobs(&self) -> Result<Vec<(String, PathBuf)>> {
+        let blobs = &mut *(self.blobs.write().unwrap());
+        Self::seal_blob(blobs)?;
+        Ok(blobs.sealed.iter().map(|(name, blob)| (name.clone(), blob.path())).collect())
     }
 
-    pub(crate) fn write(&self, key: &[u8], value: &[u8], flag: u8) -> Result<RecordIndex> {
-        let write_result: WriteResult;
-        let current_active_segment: String;
+    // remove_blob removes a sealed blob segment, records read from it stay readable
+    pub(crate) fn remove_blob(&self, name: &str) -> Result<()> {
+        if let Some(blob) = self.blobs.write().unwrap().sealed.remove(name) {
+            std::fs::remove_file(blob.path())?;
+        }
+        Ok(())
+    }
+
+    // write_batch appends all records to active segment with one write, the segment rotates after the batch.
+    // Records of an atomic batch are loaded all or none after a crash.
+    pub(crate) fn write_batch(&self, records: &[BatchRecord], atomic: bool) -> Result<Vec<RecordIndex>> {
+        let write_result: BatchWriteResult;
+        let current_active_segment: Arc<str>;
         {
             // fields of directory will not be changed, read lock is enough
             let internal = self.internal.read().unwrap();
-            write_result = internal.active_segment.write(key, value, flag)?;
-            current_active_segment = internal.active_segment.name();
+            current_active_segment = internal.active_segment.shared_name();
+            write_result = match internal.active_segment.write_batch(records, atomic) {
+                Ok(write_result) => write_result,
+                Err(e) => {
+                    if internal.active_segment.is_failed() {
+                        drop(internal);
+                        // later writes go to a new segment, none may follow the torn write. If rotation fails,
+                        // the next write tries again.
+                        let internal = &mut *(self.internal.write().unwrap());
+                        if internal.active_segment.shared_name() == current_active_segment {
+                            let _ = Self::rotate_active_segment(internal);
+                        }
+                    }
+                    return Err(e);
+                }
+            };
         }
         if write_result.is_segment_full {
             let internal = &mut *(self.internal.write().unwrap());
-            if internal.active_segment.name() == current_active_segment {
+            if internal.active_segment.shared_name() == current_active_segment {
                 // check-lock-check
                 Self::rotate_active_segment(internal)?;
             }
         }
-        Ok(RecordIndex {
-            key: Bytes::from(key.to_vec()),
-            segment: current_active_segment,
-            offset: write_result.begin_offset,
-            flag,
-            value: None,
-        })
+        if self.sync_always {
+            // a record in a sealed segment was synced by rotation, syncing active segment is enough
+            self.sync()?;
+        }
+        Ok(records
+            .iter()
+            .zip(write_result.begin_offsets)
+            .map(|(record, offset)| RecordIndex {
+                key: Bytes::from(record.key.to_vec()),
+                segment: current_active_segment.clone(),
+                offset,
+                value_size: record.value.len() as u64,
+                flag: record.flag,
+                value: None,
+                version: 0,
+            })
+            .collect())
+    }
+
+    // ingest writes records into dedicated segments which are newer than all existing segments, a new one is
+    // begun once one is full, as rotation does. Writers are blocked until ingest finished. Records are written
+    // to temporary files first, so a broken input leaves nothing behind for the loader. Records are returned
+    // in input order, so are their segments.
+    pub(crate) fn ingest<I>(&self, records: I) -> Result<Vec<RecordIndex>>
+    where
+        I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        self.ingest_into(records, MAX_SEGMENT_BYTES)
+    }
+
+    // ingest_into is ingest into segments of about segment_bytes, a segment is full once a batch reached it
+    pub(crate) fn ingest_into<I>(&self, records: I, segment_bytes: u64) -> Result<Vec<RecordIndex>>
+    where
+        I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let internal = &mut *(self.internal.write().unwrap());
+        let mut stems: Vec<String> = Vec::new();
+        let mut indexes: Vec<RecordIndex> = Vec::new();
+        let result = Self::write_ingest_segments(internal, records, segment_bytes, &mut stems, &mut indexes);
+        if let Err(e) = result {
+            for stem in stems.iter() {
+                let _ = std::fs::remove_file(internal.dir_path.join(format!("{}.{}", stem, INGEST_EXT_NAME)));
+            }
+            return Err(e);
+        }
+        // segments are put in place in order, after a crash meanwhile the loader finds the former ones only
+        let mut paths: Vec<PathBuf> = Vec::with_capacity(stems.len());
+        for stem in stems.iter() {
+            let path = internal.dir_path.join(format!("{}.{}", stem, SEG_EXT_NAME));
+            rename_durable(&internal.dir_path.join(format!("{}.{}", stem, INGEST_EXT_NAME)), &path)?;
+            paths.push(path);
+        }
+        // seal active segment, the new active segment gets a higher id than the ingested ones
+        Self::rotate_active_segment(internal)?;
+        for path in paths {
+            let ingested = Self::open_sealed(internal, path)?;
+            internal.old_segments.insert(ingested.name(), ingested);
+        }
+        Ok(indexes)
+    }
+
+    // write_ingest_segments writes records to temporary files of ingested segments, stems gets their stems
+    fn write_ingest_segments<I>(
+        internal: &mut DirectoryInternal,
+        records: I,
+        segment_bytes: u64,
+        stems: &mut Vec<String>,
+        indexes: &mut Vec<RecordIndex>,
+    ) -> Result<()>
+    where
+        I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let mut segment = Self::create_ingest_segment(internal, stems)?;
+        let mut pending: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut pending_bytes = 0;
+        for record in records {
+            let (key, value) = record?;
+            pending_bytes += key.len() + value.len();
+            pending.push((key, value));
+            if pending_bytes >= INGEST_BATCH_BYTES {
+                if segment.written() >= segment_bytes {
+                    segment = Self::create_ingest_segment(internal, stems)?;
+                }
+                Self::write_ingested(&segment, &mut pending, indexes)?;
+                pending_bytes = 0;
+            }
+        }
+        if !pending.is_empty() && segment.written() >= segment_bytes {
+            segment = Self::create_ingest_segment(internal, stems)?;
+        }
+        Self::write_ingested(&segment, &mut pending, indexes)?;
+        drop(segment);
+        for stem in stems.iter() {
+            File::open(internal.dir_path.join(format!("{}.{}", stem, INGEST_EXT_NAME)))?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // create_ingest_segment creates the temporary file of the next ingested segment and adds its stem to stems
+    fn create_ingest_segment(internal: &mut DirectoryInternal, stems: &mut Vec<String>) -> Result<Segment> {
+        let ingest_index = Self::allocate_segment_id(internal)?;
+        let generation = internal.active_segment.generation();
+        let stem = segment_stem(generation, ingest_index);
+        // leftover of former failed ingest
+        let _ = std::fs::remove_file(internal.dir_path.join(format!("{}.{}", stem, INGEST_EXT_NAME)));
+        let segment = Segment::create(&internal.dir_path, generation, ingest_index, INGEST_EXT_NAME)?
+            .with_checksum(internal.checksum)
+            .with_io(internal.io.clone());
+        stems.push(stem);
+        Ok(segment)
+    }
+
+    // write_ingested appends pending records with one write, ingest decides when a segment is full
+    fn write_ingested(segment: &Segment, pending: &mut Vec<(Vec<u8>, Vec<u8>)>, indexes: &mut Vec<RecordIndex>) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<BatchRecord> = pending
+            .iter()
+            .map(|(key, value)| BatchRecord {
+                key,
+                value,
+                flag: 0,
+                metadata: 0,
+            })
+            .collect();
+        let write_result = segment.write_batch(&records, false)?;
+        for ((key, value), offset) in pending.drain(..).zip(write_result.begin_offsets) {
+            indexes.push(RecordIndex {
+                key: Bytes::from(key),
+                segment: segment.shared_name(),
+                offset,
+                value_size: value.len() as u64,
+                flag: 0,
+                value: None,
+                version: 0,
+            });
+        }
+        Ok(())
     }
 
     fn rotate_active_segment(internal: &mut DirectoryInternal) -> Result<()> {
+        let new_index = Self::allocate_segment_id(internal)?;
         let old_segment_path = internal.dir_path.join(format!(
             "{}.{}",
             internal.active_segment.name(),
             SEG_EXT_NAME
         ));
-        let new_index = internal.active_segment.index() + 1;
-        let new_active_segment = Segment::create(&internal.dir_path, new_index, SEG_EXT_NAME)?;
+        internal.active_segment.sync()?;
+        let generation = internal.active_segment.generation();
+        let new_active_segment = Segment::create(&internal.dir_path, generation, new_index, SEG_EXT_NAME)?;
+        let new_active_segment = Self::with_remap(new_active_segment, internal.mmap_limit)
+            .with_checksum(internal.checksum)
+            .with_io(internal.io.clone());
+        fault::check("directory.rotate")?;
         internal.active_segment = new_active_segment; // old segment should be dropped
-        let old_active_segment = Segment::open_read_only(old_segment_path);
+        let old_active_segment = Self::open_sealed(internal, old_segment_path.clone())?;
         internal
             .old_segments
-            .insert(internal.active_segment.name(), old_active_segment);
+            .insert(old_active_segment.name(), old_active_segment);
+        if let Some(on_seal) = internal.on_seal.as_ref() {
+            on_seal(&old_segment_path);
+        }
         Ok(())
     }
+
+    // allocate_segment_id persists the counter before returning an id, so a crash never hands it out twice
+    fn allocate_segment_id(internal: &mut DirectoryInternal) -> Result<u64> {
+        let id = internal.next_segment_id;
+        Self::write_next_segment_id(&internal.dir_path, id + 1)?;
+        internal.next_segment_id = id + 1;
+        Ok(id)
+    }
+
+    fn read_next_segment_id(dir_path: &Path) -> Result<u64> {
+        let content = match std::fs::read_to_string(dir_path.join(MANIFEST_FILENAME)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        content
+            .trim()
+            .strip_prefix("next-segment-id ")
+            .and_then(|id| id.parse::<u64>().ok())
+            .ok_or_else(|| corruption(format!("invalid manifest: {}", content.trim())))
+    }
+
+    // manifest is replaced by rename, so it is either the former or the new one after a crash
+    fn write_next_segment_id(dir_path: &Path, id: u64) -> Result<()> {
+        let tmp_path = dir_path.join(MANIFEST_TMP_FILENAME);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(format!("next-segment-id {}\n", id).as_bytes())?;
+        file.sync_all()?;
+        rename_durable(&tmp_path, &dir_path.join(MANIFEST_FILENAME))?;
+        Ok(())
+    }
+
+    fn open_sealed(internal: &DirectoryInternal, path: PathBuf) -> Result<Segment> {
+        Self::open_segment(path, internal.mmap_limit, &internal.io)
+    }
+
+    // with_remap maps the written part of active segment as it grows if mmap is enabled
+    fn with_remap(segment: Segment, mmap_limit: Option<u64>) -> Segment {
+        match mmap_limit {
+            Some(limit) => segment.with_remap(limit),
+            None => segment,
+        }
+    }
+
+    // open_segment maps a sealed segment unless it is larger than mmap_limit, which keeps few large
+    // segments from taking much address space. Others are read by pread.
+    fn open_segment(path: PathBuf, mmap_limit: Option<u64>, io: &Arc<IoCounters>) -> Result<Segment> {
+        let len = std::fs::metadata(&path)?.len();
+        let segment = if mmap_limit.is_some_and(|limit| len <= limit) {
+            Segment::open_mmap(path)?
+        } else {
+            Segment::open_read_only(path)
+        };
+        Ok(segment.with_io(io.clone()))
+    }
 }
@@ -0,0 +1,103 @@
+// Fault injection for crash consistency tests and simulation.
+// Faults are registered per thread, so tests running in parallel do not affect each other.
+// Without cfg(test) or the simulation feature every fail point is a no-op.
+#[cfg(any(test, feature = "simulation"))]
+use std::cell::RefCell;
+
+#[cfg(feature = "simulation")]
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(any(test, feature = "simulation")), allow(dead_code))]
+pub(crate) enum Fault {
+    Crash,             // operation stops at fail point with an io error, as if the process died there
+    ShortWrite(usize), // only the first n bytes reach the file, then the write fails
+}
+
+#[cfg(any(test, feature = "simulation"))]
+struct FailPoint {
+    name: &'static str,
+    skip: u64, // number of hits to pass before the fault triggers
+    fault: Fault,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+thread_local! {
+    static FAIL_POINTS: RefCell<Vec<FailPoint>> = const { RefCell::new(Vec::new()) };
+}
+
+// seeded random faults, every fail point triggers with the given probability
+#[cfg(feature = "simulation")]
+thread_local! {
+    static SIMULATION: RefCell<Option<(StdRng, f64)>> = const { RefCell::new(None) };
+}
+
+#[cfg(feature = "simulation")]
+pub(crate) fn simulate(seed: u64, probability: f64) {
+    SIMULATION.with(|sim| *sim.borrow_mut() = Some((StdRng::seed_from_u64(seed), probability)));
+}
+
+#[cfg(feature = "simulation")]
+pub(crate) fn stop_simulation() {
+    SIMULATION.with(|sim| *sim.borrow_mut() = None);
+}
+
+#[cfg(feature = "simulation")]
+fn simulated_hit(name: &'static str) -> Option<Fault> {
+    SIMULATION.with(|sim| {
+        let mut sim = sim.borrow_mut();
+        let (rng, probability) = sim.as_mut()?;
+        if !rng.gen_bool(*probability) {
+            return None;
+        }
+        if name == "segment.write" && rng.gen_bool(0.5) {
+            return Some(Fault::ShortWrite(rng.gen_range(0..64)));
+        }
+        Some(Fault::Crash)
+    })
+}
+
+// inject makes the fail point named name trigger fault once after skip hits
+#[cfg(any(test, feature = "simulation"))]
+pub(crate) fn inject(name: &'static str, skip: u64, fault: Fault) {
+    FAIL_POINTS.with(|points| points.borrow_mut().push(FailPoint { name, skip, fault }));
+}
+
+#[cfg(any(test, feature = "simulation"))]
+pub(crate) fn clear() {
+    FAIL_POINTS.with(|points| points.borrow_mut().clear());
+}
+
+#[cfg(any(test, feature = "simulation"))]
+pub(crate) fn hit(name: &'static str) -> Option<Fault> {
+    let injected = FAIL_POINTS.with(|points| {
+        let mut points = points.borrow_mut();
+        let i = points.iter().position(|p| p.name == name)?;
+        if points[i].skip > 0 {
+            points[i].skip -= 1;
+            return None;
+        }
+        Some(points.remove(i).fault)
+    });
+    #[cfg(feature = "simulation")]
+    let injected = injected.or_else(|| simulated_hit(name));
+    injected
+}
+
+#[cfg(not(any(test, feature = "simulation")))]
+#[inline(always)]
+pub(crate) fn hit(_name: &'static str) -> Option<Fault> {
+    None
+}
+
+pub(crate) fn injected_error(name: &str) -> std::io::Error {
+    std::io::Error::other(format!("fault injected at {}", name))
+}
+
+// check returns an error if a crash is injected at fail point
+pub(crate) fn check(name: &'static str) -> std::io::Result<()> {
+    match hit(name) {
+        Some(_) => Err(injected_error(name)),
+        None => Ok(()),
+    }
+}
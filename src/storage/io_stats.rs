@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// IoStats counts IO of segments since open, hint files and merge output are not counted.
+// read_calls are positional reads of files, mmap_reads are records read from mmap without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64, // padding included
+    pub read_calls: u64,
+    pub mmap_reads: u64,
+    pub fsyncs: u64,
+    pub padding_bytes: u64, // written to fill blocks
+}
+
+// IoCounters are shared by segments of a directory
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_calls: AtomicU64,
+    mmap_reads: AtomicU64,
+    fsyncs: AtomicU64,
+    padding_bytes: AtomicU64,
+}
+
+impl IoCounters {
+    pub(crate) fn read_call(&self, bytes: usize) {
+        self.read_calls.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn mmap_read(&self, bytes: usize) {
+        self.mmap_reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write(&self, bytes: usize, padding: u64) {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.padding_bytes.fetch_add(padding, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fsync(&self) {
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> IoStats {
+        IoStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            read_calls: self.read_calls.load(Ordering::Relaxed),
+            mmap_reads: self.mmap_reads.load(Ordering::Relaxed),
+            fsyncs: self.fsyncs.load(Ordering::Relaxed),
+            padding_bytes: self.padding_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,20 +1,34 @@
-use std::{borrow::Borrow, rc::Rc};
+use std::{borrow::Borrow, sync::Arc};
 
+use memmap::Mmap;
+
+pub(crate) mod backend;
+pub(crate) mod checksum;
 pub(crate) mod directory;
+pub(crate) mod fault;
+pub(crate) mod io_stats;
 pub(crate) mod segment;
+pub(crate) mod sstable;
 
 const FLAG_PADDING: u8 = 1;
 pub(crate) const FLAG_DELETED: u8 = 1 << 1;
+const FLAG_METADATA: u8 = 1 << 2; // a metadata byte follows the flag
+const FLAG_CONTROL: u8 = 1 << 6; // record of the log itself, segment header or batch marker, see segment.rs
+const FLAG_RESERVED: u8 = 1 << 7; // set only by record layouts this version does not know
 pub(crate) const SEG_EXT_NAME: &str = "seg";
 pub(crate) const HINT_EXT_NAME: &str = "hint";
+pub(crate) const INGEST_EXT_NAME: &str = "ingest"; // segment being ingested, ignored by loader
+pub(crate) const BLOB_EXT_NAME: &str = "blob"; // segment of large values, see directory.rs
 
 #[derive(Debug, Clone)]
 pub(crate) struct RecordIndex {
     pub(crate) key: Bytes,
-    pub(crate) segment: String,
+    pub(crate) segment: Arc<str>, // interned, entries of one segment share its name
     pub(crate) flag: u8,
     pub(crate) offset: u64,
-    pub(crate) value: Option<Bytes>, // only is some in iter_with_value
+    pub(crate) value_size: u64,      // 0 if loaded from a hint file of former versions
+    pub(crate) value: Option<Bytes>, // only is some in iter_with_value, but for blobs, or if inlined in index
+    pub(crate) version: u64,         // assigned by keydir, 0 until indexed
 }
 
 impl RecordIndex {
@@ -23,37 +37,165 @@ impl RecordIndex {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+// Bytes is a cheaply cloneable immutable byte string, keys and values are returned as it.
+// It is a view of a shared buffer, so key and value of a record or a mmap segment are not copied.
+#[derive(Clone)]
 pub struct Bytes {
-    value: Rc<Vec<u8>>,
+    buf: Buffer,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Clone)]
+enum Buffer {
+    Heap(Arc<Vec<u8>>),
+    Mapped(Arc<Mmap>), // sealed segment, never changes while mapped
+}
+
+impl Default for Bytes {
+    fn default() -> Self {
+        Bytes::from(Vec::new())
+    }
 }
 
 impl Bytes {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // slice of a shared heap buffer, range must be within buf
+    pub(crate) fn from_shared(buf: Arc<Vec<u8>>, start: usize, end: usize) -> Self {
+        debug_assert!(start <= end && end <= buf.len());
+        Bytes {
+            buf: Buffer::Heap(buf),
+            start,
+            end,
+        }
+    }
+
+    // slice of a mmap segment, range must be within mmap
+    pub(crate) fn from_mmap(mmap: Arc<Mmap>, start: usize, end: usize) -> Self {
+        debug_assert!(start <= end && end <= mmap.len());
         Bytes {
-            value: Rc::new(Vec::new()),
+            buf: Buffer::Mapped(mmap),
+            start,
+            end,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.buf {
+            Buffer::Heap(v) => &v[self.start..self.end],
+            Buffer::Mapped(m) => &m[self.start..self.end],
         }
     }
 
-    pub(crate) fn from(v: Vec<u8>) -> Self {
-        Bytes { value: Rc::new(v) }
+    // into_vec avoids copying if this is the only reference to a whole heap buffer
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.buf {
+            Buffer::Heap(v) if self.start == 0 && self.end == v.len() => {
+                Arc::try_unwrap(v).unwrap_or_else(|shared| (*shared).clone())
+            }
+            _ => self.as_slice().to_vec(),
+        }
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Bytes {}
+
+impl PartialOrd for Bytes {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bytes {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_slice().cmp(other.as_slice())
+    }
+}
+
+impl std::hash::Hash for Bytes {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
     }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(v: Vec<u8>) -> Self {
+        let end = v.len();
+        Bytes::from_shared(Arc::new(v), 0, end)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(v: &[u8]) -> Self {
+        Bytes::from(v.to_vec())
+    }
+}
+
+impl From<&str> for Bytes {
+    fn from(v: &str) -> Self {
+        Bytes::from(v.as_bytes().to_vec())
+    }
+}
 
-    pub(crate) fn as_slice(&self) -> &[u8] {
-        (*self.value).as_slice()
+impl From<String> for Bytes {
+    fn from(v: String) -> Self {
+        Bytes::from(v.into_bytes())
     }
 }
 
 impl Borrow<[u8]> for Bytes {
     fn borrow(&self) -> &[u8] {
-        &self.value
+        self.as_slice()
     }
 }
 
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl std::ops::Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+// Display writes valid utf-8 as is and invalid bytes as \xNN
 impl std::fmt::Display for Bytes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let bytes: Vec<u8> = (&*self.value).to_owned();
-        write!(f, "{}", String::from_utf8(bytes).unwrap())
+        for chunk in self.as_slice().utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Debug writes a quoted and escaped string, e.g. "key\n\xff"
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"")?;
+        for chunk in self.as_slice().utf8_chunks() {
+            write!(f, "{}", chunk.valid().escape_debug())?;
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        f.write_str("\"")
     }
 }
 
@@ -62,4 +204,5 @@ pub(crate) struct Record {
     pub(crate) key: Bytes,
     pub(crate) value: Bytes,
     pub(crate) flag: u8,
+    pub(crate) metadata: u8, // application defined, 0 if not set
 }
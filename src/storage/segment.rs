@@ -1,16 +1,23 @@
 use anyhow::{anyhow, Ok, Result};
-use crc::{Algorithm, Crc};
 use memmap::Mmap;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::Write;
 use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
-use std::sync::{Mutex, RwLock};
-
-use crate::utils::utils::{os_str_to_string, is_empty_file};
-use crate::utils::varint::{decode_varint, decode_varint_from_mmap, encode_varint_to_vec};
-
-use super::{Bytes, Record, RecordIndex, FLAG_PADDING};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::error::{corruption, locate, StoreError};
+use crate::utils::utils::{is_empty_file, os_str_to_string, sync_dir};
+use crate::utils::varint::{decode_varint, decode_varint_from_slice, encode_varint_to_vec};
+
+use super::{
+    checksum::{covered_header, ChecksumAlgorithm, FLAG_CHECKSUM_MASK, FLAG_HEADER_CHECKSUM},
+    backend::{Backend, Faulty},
+    fault,
+    io_stats::IoCounters,
+    Bytes, Record, RecordIndex, FLAG_METADATA, FLAG_PADDING, FLAG_RESERVED, FLAG_CONTROL,
+};
 
 /*
  * Segment Strurt:
@@ -20,58 +27,160 @@ use super::{Bytes, Record, RecordIndex, FLAG_PADDING};
  *  <--------block----------->
  *
  * Short Record Format:
- * | Flag(1B) | Key Length(varint) | Value Length(varint) | Key | Value | CRC(4B) |
- *  <-------------------------header---------------------------->
+ * | Flag(1B) | [Metadata(1B)] | Key Length(varint) | Value Length(varint) | Key | Value | CRC(4B) |
+ *  <-------------------------------header------------------------------>
+ * Metadata byte exists only if FLAG_METADATA is set, it is application defined
+ * Flag tells the checksum algorithm of CRC, see checksum.rs
  *
  * Multi Block Record Format:
  * |     Header     |                  Payload                | CRC(4B) | Padding |
  * <-------------block1--------------><----block2----><-----------block3---------->
  *
+ * Control Records:
+ * Records flagged FLAG_CONTROL belong to the log rather than to a key, iteration does not yield them.
+ * A segment of format v2 starts with one as header, its key is "bitcask" and its value the format
+ * version in one byte. Segments of former versions have no header and are v1.
+ * An atomic batch is enclosed in "batch-begin" holding the number of its records and "batch-commit"
+ * holding the offset of its begin, both varints. Iteration yields records of a batch only once its
+ * commit is read, so a batch torn by a crash is not loaded at all.
+ * Format v3 adds raw records, see Database::append_raw. Their key is "raw:" followed by the key given,
+ * the keydir never holds them and iteration skips them. Former versions would stop reading at them.
+ * Format v4 adds blob pointers, see directory.rs. Their key is "blob:" followed by the key written and
+ * their value is | Offset(8B) | Value Size(8B) | Blob Segment Name |, iteration yields the blob record.
+ * Pointers may be records of a batch, e.g. of Database::write_many.
+ * Record layout is told by flag of each record, a reserved bit is set only by layouts to come.
 */
 pub(crate) struct Segment {
     mutable: bool,
     path: PathBuf,
+    name: Arc<str>, // shared by index entries of its records
+    checksum: ChecksumAlgorithm, // of records written through it
     internal: Mutex<SegmentInternal>, // fd will be changed anyway, no need for RwLock
-    mmap: Option<RwLock<Mmap>>,
+    mmap: Option<Arc<Mmap>>, // shared with Bytes read from it
+    io: Arc<IoCounters>,
+    remap_limit: Option<u64>, // the written part of an active segment is mapped again as it grows up to it
+    tail_map: RwLock<Option<Arc<Mmap>>>, // of a prefix of active segment which ends at a record
 }
 
 struct SegmentInternal {
     fd: Option<File>,
     block_written: u64,
     segment_written: u64,
+    records_written: u64, // header and padding are no records
     buffer: Vec<u8>,
+    failed: bool, // a torn write could not be cut off, nothing may be appended behind it
 }
 
 const BLOCK_BYTES: u64 = 32 * 1024; // 32KB
-const MAX_SEGMENT_BYTES: u64 = 1024 * 1024 * 1024; // 1GB, , large record may cause segment exceed limit
-const CRC_CONFIG: Algorithm<u32> = Algorithm {
-    width: 16,
-    poly: 0x8005,
-    init: 0xffff,
-    refin: false,
-    refout: false,
-    xorout: 0x0000,
-    check: 0xaee7,
-    residue: 0x0000,
-};
+const REMAP_BYTES: u64 = 4 * 1024 * 1024; // growth of active segment which maps it again
+const MAX_HEADER_BYTES: usize = 1 + 1 + 10 + 10; // flag, metadata and two u64 varints
+pub(crate) const MAX_SEGMENT_BYTES: u64 = 1024 * 1024 * 1024; // 1GB, , large record may cause segment exceed limit
+const SEGMENT_MAGIC: &[u8] = b"bitcask"; // key of segment header
+const BATCH_BEGIN_KEY: &[u8] = b"batch-begin";
+const BATCH_COMMIT_KEY: &[u8] = b"batch-commit";
+const RAW_KEY_PREFIX: &[u8] = b"raw:";
+const BLOB_KEY_PREFIX: &[u8] = b"blob:";
+pub(crate) const SEGMENT_FORMAT_VERSION: u8 = 4; // written to new segments, older ones are read too
+pub(crate) const SEGMENT_HEADER_BYTES: u64 = 1 + 1 + 1 + 7 + 1 + 4; // offset of the first record
+
+// raw_key is the key of the raw record of key
+pub(crate) fn raw_key(key: &[u8]) -> Vec<u8> {
+    [RAW_KEY_PREFIX, key].concat()
+}
+
+// parse_raw_record returns the key given to append_raw if record is a raw record
+pub(crate) fn parse_raw_record(record: &Record) -> Option<&[u8]> {
+    if record.flag & FLAG_CONTROL == 0 {
+        return None;
+    }
+    record.key.as_slice().strip_prefix(RAW_KEY_PREFIX)
+}
+
+// blob_pointer returns key and value of the pointer record to the blob record of index
+pub(crate) fn blob_pointer(index: &RecordIndex) -> (Vec<u8>, Vec<u8>) {
+    let mut value: Vec<u8> = Vec::with_capacity(16 + index.segment.len());
+    value.extend_from_slice(&index.offset.to_le_bytes());
+    value.extend_from_slice(&index.value_size.to_le_bytes());
+    value.extend_from_slice(index.segment.as_bytes());
+    ([BLOB_KEY_PREFIX, index.key.as_slice()].concat(), value)
+}
+
+// parse_blob_pointer returns the index entry of the blob record a pointer record points to
+fn parse_blob_pointer(record: &Record) -> Option<RecordIndex> {
+    let key = record.key.as_slice().strip_prefix(BLOB_KEY_PREFIX)?;
+    let value = record.value.as_slice();
+    let offset = u64::from_le_bytes(value.get(..8)?.try_into().ok()?);
+    let value_size = u64::from_le_bytes(value.get(8..16)?.try_into().ok()?);
+    let segment = std::str::from_utf8(&value[16..]).ok()?;
+    Some(RecordIndex {
+        key: Bytes::from(key),
+        segment: Arc::from(segment),
+        flag: 0,
+        offset,
+        value_size,
+        value: None,
+        version: 0,
+    })
+}
 
 pub(crate) struct WriteResult {
     pub(crate) is_segment_full: bool,
     pub(crate) begin_offset: u64,
 }
 
+pub(crate) struct BatchRecord<'a> {
+    pub(crate) key: &'a [u8],
+    pub(crate) value: &'a [u8],
+    pub(crate) flag: u8,
+    pub(crate) metadata: u8,
+}
+
+pub(crate) struct BatchWriteResult {
+    pub(crate) is_segment_full: bool,
+    pub(crate) begin_offsets: Vec<u64>, // in the order of records
+}
+
+// segment file stem is "<index>" in generation 0 and "<generation>-<index>" later. A merge writes
+// its segments one generation above the segments it replaces, so both can be in one directory.
+pub(crate) fn segment_stem(generation: u64, index: u64) -> String {
+    if generation == 0 {
+        index.to_string()
+    } else {
+        format!("{}-{}", generation, index)
+    }
+}
+
+// returns generation and index of a segment file stem
+pub(crate) fn parse_segment_stem(stem: &str) -> Option<(u64, u64)> {
+    match stem.split_once('-') {
+        Some((generation, index)) => Some((generation.parse().ok()?, index.parse().ok()?)),
+        None => Some((0, stem.parse().ok()?)),
+    }
+}
+
+fn segment_name(path: &Path) -> Arc<str> {
+    Arc::from(os_str_to_string(path.file_stem()))
+}
+
 impl Segment {
     // create a segment, but do not open fd
     pub(crate) fn open_read_only(path: PathBuf) -> Self {
         Self {
             mutable: false,
+            name: segment_name(&path),
+            checksum: ChecksumAlgorithm::default(),
             path,
             mmap: None,
+            io: Arc::default(),
+            remap_limit: None,
+            tail_map: RwLock::new(None),
             internal: Mutex::new(SegmentInternal {
                 fd: None,
                 block_written: 0,
                 segment_written: 0,
+                records_written: 0,
                 buffer: Vec::new(),
+                failed: false,
             }),
         }
     }
@@ -84,13 +193,20 @@ impl Segment {
         let mmap = unsafe { memmap::MmapOptions::new().map(&fd)? };
         Ok(Self {
             mutable: false,
+            name: segment_name(&path),
+            checksum: ChecksumAlgorithm::default(),
             path,
-            mmap: Some(RwLock::new(mmap)),
+            mmap: Some(Arc::new(mmap)),
+            io: Arc::default(),
+            remap_limit: None,
+            tail_map: RwLock::new(None),
             internal: Mutex::new(SegmentInternal {
                 fd: Some(fd),
                 block_written: 0,
                 segment_written: 0,
+                records_written: 0,
                 buffer: Vec::new(),
+                failed: false,
             }),
         })
     }
@@ -100,125 +216,355 @@ impl Segment {
     }
 
     pub(crate) fn name(&self) -> String {
-        os_str_to_string(self.path.file_stem())
+        self.name.to_string()
+    }
+
+    // shared_name is the name held by index entries, cloning it does not allocate
+    pub(crate) fn shared_name(&self) -> Arc<str> {
+        self.name.clone()
+    }
+
+    // is_failed tells whether a torn write could not be cut off, the segment takes no more writes then
+    pub(crate) fn is_failed(&self) -> bool {
+        self.internal.lock().unwrap().failed
+    }
+
+    // bytes written through this segment, only meaningful for mutable segment
+    pub(crate) fn written(&self) -> u64 {
+        self.internal.lock().unwrap().segment_written
+    }
+
+    // records written through this segment, only meaningful for mutable segment
+    pub(crate) fn records_written(&self) -> u64 {
+        self.internal.lock().unwrap().records_written
+    }
+
+    // sync makes written records durable, segment writes no user space buffer so fsync is enough
+    pub(crate) fn sync(&self) -> Result<()> {
+        let internal = &mut *self.internal.lock().unwrap();
+        if let Some(fd) = internal.fd.as_mut() {
+            Faulty(fd).sync()?;
+            self.io.fsync();
+        }
+        Ok(())
     }
 
     pub(crate) fn index(&self) -> u64 {
-        self.name().parse::<u64>().unwrap()
+        parse_segment_stem(&self.name).unwrap().1
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        parse_segment_stem(&self.name).unwrap().0
     }
 
-    // create is the only way to get a mutable segment
-    pub(crate) fn create(dir: &PathBuf, index: u64, ext: &str) -> Result<Self> {
-        let filename = format!("{}.{}", index, ext);
-        let path = dir.join(filename);
-        let fd: File = File::create_new(&path)?;
+    // create is the only way to get a mutable segment. The file and its entry in dir are synced once
+    // the header is written, a file cut within its header by a crash is taken for an empty one.
+    pub(crate) fn create(dir: &Path, generation: u64, index: u64, ext: &str) -> Result<Self> {
+        let filename = format!("{}.{}", segment_stem(generation, index), ext);
+        let path = dir.join(&filename);
+        fault::check("segment.create")?;
+        let mut fd: File = File::create_new(&path)?;
+        // header is written before rename, no segment of format v2 lacks it
+        let mut buffer = Vec::new();
+        let (mut block_written, mut segment_written) = (0, 0);
+        let header = BatchRecord {
+            key: SEGMENT_MAGIC,
+            value: &[SEGMENT_FORMAT_VERSION],
+            flag: FLAG_CONTROL,
+            metadata: 0,
+        };
+        Self::encode_record(&mut buffer, &mut block_written, &mut segment_written, &header, ChecksumAlgorithm::default())?;
+        debug_assert_eq!(segment_written, SEGMENT_HEADER_BYTES);
+        fd.write_all(&buffer)?;
+        fd.sync_all()?;
+        sync_dir(dir)?;
         Ok(Self {
             mutable: true,
+            name: segment_name(&path),
+            checksum: ChecksumAlgorithm::default(),
             path,
             mmap: None,
+            io: Arc::default(),
+            remap_limit: None,
+            tail_map: RwLock::new(None),
             internal: Mutex::new(SegmentInternal {
                 fd: Some(fd),
-                block_written: 0,
-                segment_written: 0,
+                block_written,
+                segment_written,
+                records_written: 0,
                 buffer: Vec::new(),
+                failed: false,
             }),
         })
     }
 
+    pub(crate) fn with_checksum(mut self, checksum: ChecksumAlgorithm) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    // with_io counts IO of this segment into io, which segments of a directory share
+    pub(crate) fn with_io(mut self, io: Arc<IoCounters>) -> Self {
+        self.io = io;
+        self
+    }
+
+    // with_remap makes reads of an active segment of at most limit bytes use mmap for all but its last
+    // REMAP_BYTES at most
+    pub(crate) fn with_remap(mut self, limit: u64) -> Self {
+        self.remap_limit = Some(limit);
+        self
+    }
+
+    // remap_tail maps the first len bytes, they must end at a record. A failed map leaves reads to fd.
+    fn remap_tail(&self, len: u64) {
+        let mapped = File::open(&self.path).and_then(|fd| unsafe { memmap::MmapOptions::new().len(len as usize).map(&fd) });
+        if let std::result::Result::Ok(mmap) = mapped {
+            *self.tail_map.write().unwrap() = Some(Arc::new(mmap));
+        }
+    }
+
+    fn mapped_tail(&self, offset: u64) -> Option<Arc<Mmap>> {
+        self.tail_map.read().unwrap().as_ref().filter(|mmap| offset < mmap.len() as u64).cloned()
+    }
+
+    // format_version reads the segment header, segments without one are v1.
+    // Segments of a newer format are refused rather than misread.
+    pub(crate) fn format_version(&self) -> Result<u8> {
+        let mut flag = [0u8; 1];
+        let n = match &self.mmap {
+            Some(mmap) => mmap.first().map_or(0, |f| {
+                flag[0] = *f;
+                1
+            }),
+            None => File::open(&self.path)?.read_at(&mut flag, 0)?,
+        };
+        if n == 0 || flag[0] & FLAG_CONTROL == 0 {
+            return Ok(1);
+        }
+        // creation did not finish, there is no record
+        if std::fs::metadata(&self.path)?.len() < SEGMENT_HEADER_BYTES {
+            return Ok(SEGMENT_FORMAT_VERSION);
+        }
+        let header = self.read_at(0)?;
+        let version = match (header.key.as_slice(), header.value.as_slice()) {
+            (SEGMENT_MAGIC, [version]) => *version,
+            _ => return Err(locate(corruption("invalid segment header"), Some(&self.name()), Some(0), None)),
+        };
+        if version > SEGMENT_FORMAT_VERSION {
+            return Err(StoreError::UnsupportedFormat(format!("segment {} is of format v{}", self.name, version)).into());
+        }
+        Ok(version)
+    }
+
     pub(crate) fn write(&self, key: &[u8], value: &[u8], flag: u8) -> Result<WriteResult> {
+        self.write_with_metadata(key, value, flag, 0)
+    }
+
+    // write_blob_pointer appends the pointer record to the blob record of index
+    pub(crate) fn write_blob_pointer(&self, index: &RecordIndex) -> Result<WriteResult> {
+        let (key, value) = blob_pointer(index);
+        self.write(&key, &value, FLAG_CONTROL)
+    }
+
+    // metadata byte is written only if it is not zero, records without metadata keep the original format
+    pub(crate) fn write_with_metadata(&self, key: &[u8], value: &[u8], flag: u8, metadata: u8) -> Result<WriteResult> {
+        let result = self.write_batch(
+            &[BatchRecord {
+                key,
+                value,
+                flag,
+                metadata,
+            }],
+            false,
+        )?;
+        Ok(WriteResult {
+            is_segment_full: result.is_segment_full,
+            begin_offset: result.begin_offsets[0],
+        })
+    }
+
+    // write_batch encodes all records into one buffer and writes it with a single write call.
+    // Records of an atomic batch are enclosed in batch markers, so none of them is loaded if it is torn.
+    pub(crate) fn write_batch(&self, records: &[BatchRecord], atomic: bool) -> Result<BatchWriteResult> {
         if !self.mutable {
             return Err(anyhow!("segment is immutable"));
         }
         let internal = &mut *(self.internal.lock().unwrap());
+        if internal.failed {
+            return Err(anyhow!("segment failed, a torn write could not be cut off"));
+        }
+        let mut buffer = std::mem::take(&mut internal.buffer);
+        buffer.clear();
+        let mut block_written = internal.block_written;
+        let mut segment_written = internal.segment_written;
+        let mut begin_offsets: Vec<u64> = Vec::with_capacity(records.len());
+        let batch_begin = if atomic && records.len() > 1 {
+            let count = encode_varint_to_vec(records.len() as u64)?;
+            let marker = BatchRecord {
+                key: BATCH_BEGIN_KEY,
+                value: &count,
+                flag: FLAG_CONTROL,
+                metadata: 0,
+            };
+            Some(Self::encode_record(&mut buffer, &mut block_written, &mut segment_written, &marker, self.checksum)?)
+        } else {
+            None
+        };
+        let mut padding: u64 = 0;
+        for record in records {
+            let written = segment_written;
+            let begin_offset =
+                Self::encode_record(&mut buffer, &mut block_written, &mut segment_written, record, self.checksum)?;
+            padding += begin_offset - written;
+            begin_offsets.push(begin_offset);
+        }
+        if let Some(batch_begin) = batch_begin {
+            let begin = encode_varint_to_vec(batch_begin)?;
+            let marker = BatchRecord {
+                key: BATCH_COMMIT_KEY,
+                value: &begin,
+                flag: FLAG_CONTROL,
+                metadata: 0,
+            };
+            Self::encode_record(&mut buffer, &mut block_written, &mut segment_written, &marker, self.checksum)?;
+        }
         let fd = internal.fd.as_mut().unwrap();
+        let result = Faulty(&mut *fd).append(&buffer);
+        internal.buffer = buffer;
+        if let Err(e) = result {
+            // a torn write is cut off, records appended later must not follow it
+            internal.failed = Faulty(fd).truncate(internal.segment_written).is_err();
+            return Err(e.into());
+        }
+        self.io.write(internal.buffer.len(), padding);
+        internal.block_written = block_written;
+        internal.segment_written = segment_written;
+        internal.records_written += records.len() as u64;
+        if self.remap_limit.is_some_and(|limit| segment_written <= limit) {
+            let mapped = self.tail_map.read().unwrap().as_ref().map_or(0, |mmap| mmap.len() as u64);
+            if segment_written - mapped >= REMAP_BYTES {
+                self.remap_tail(segment_written);
+            }
+        }
+        Ok(BatchWriteResult {
+            is_segment_full: segment_written >= MAX_SEGMENT_BYTES,
+            begin_offsets,
+        })
+    }
 
+    // encode_record appends padding if necessary and the record to buffer, returns offset of the record
+    fn encode_record(
+        buffer: &mut Vec<u8>,
+        block_written: &mut u64,
+        segment_written: &mut u64,
+        record: &BatchRecord,
+        checksum: ChecksumAlgorithm,
+    ) -> Result<u64> {
+        let (key, value, metadata) = (record.key, record.value, record.metadata);
         // encode key and value length
         let key_len_encoding = encode_varint_to_vec(key.len() as u64)?;
         let value_len_encoding = encode_varint_to_vec(value.len() as u64)?;
-        let header_len = (key_len_encoding.len() + value_len_encoding.len() + 1) as u64;
+        // metadata flag is derived from metadata, flag copied from another record must not carry it alone
+        let flag = if metadata != 0 { record.flag | FLAG_METADATA } else { record.flag & !FLAG_METADATA };
+        // so are checksum flags, records are written with the algorithm of this segment and a covered header
+        let flag = flag & !(FLAG_CHECKSUM_MASK | FLAG_HEADER_CHECKSUM) | checksum.flag() | FLAG_HEADER_CHECKSUM;
+        let metadata_len = if metadata != 0 { 1 } else { 0 };
+        let header_len = (key_len_encoding.len() + value_len_encoding.len() + 1 + metadata_len) as u64;
         // let record_len = (header_len + value.len() as u64 + 4) as u64;
 
         // padding if necessary
-        if header_len + internal.block_written > BLOCK_BYTES {
+        if header_len + *block_written > BLOCK_BYTES {
             // padding the rest of block
-            // internal.block_written may be greater or equal with MAX_BLOCK_BYTES
-            if BLOCK_BYTES - internal.block_written > 0 {
-                let mut padding = vec![0; BLOCK_BYTES as usize - internal.block_written as usize];
-                padding[0] = FLAG_PADDING;
-                fd.write_all(&padding)?;
-                internal.segment_written += padding.len() as u64;
+            // block_written may be greater or equal with MAX_BLOCK_BYTES
+            if BLOCK_BYTES - *block_written > 0 {
+                let padding_len = BLOCK_BYTES as usize - *block_written as usize;
+                let padding_start = buffer.len();
+                buffer.resize(padding_start + padding_len, 0);
+                buffer[padding_start] = FLAG_PADDING;
+                *segment_written += padding_len as u64;
             }
             // new block
-            internal.block_written = 0;
+            *block_written = 0;
         }
 
-        let crc = Crc::<u32>::new(&CRC_CONFIG);
-        let mut digest = crc.digest();
-        digest.update(&key);
-        digest.update(&value);
-        let checksum = digest.finalize().to_le_bytes();
         // write record
-        let begin_offset = internal.segment_written;
-        internal.buffer.clear();
-        internal.buffer.push(flag);
-        internal.buffer.extend(key_len_encoding);
-        internal.buffer.extend(value_len_encoding);
-        internal.buffer.extend(key);
-        internal.buffer.extend(value);
-        internal.buffer.extend(checksum);
-        let written = fd.write(internal.buffer.as_slice())?;
-        internal.block_written += written as u64;
-        internal.block_written %= BLOCK_BYTES;
-        internal.segment_written += written as u64;
-        let is_segment_full = internal.segment_written >= MAX_SEGMENT_BYTES;
-        return Ok(WriteResult {
-            is_segment_full,
-            begin_offset,
-        });
+        let begin_offset = *segment_written;
+        let record_start = buffer.len();
+        buffer.push(flag);
+        if metadata != 0 {
+            buffer.push(metadata);
+        }
+        buffer.extend(key_len_encoding);
+        buffer.extend(value_len_encoding);
+        let sum = checksum.digest(&buffer[record_start..], key, value);
+        buffer.extend(key);
+        buffer.extend(value);
+        buffer.extend_from_slice(&sum[..checksum.size()]);
+        let written = (buffer.len() - record_start) as u64;
+        *block_written += written;
+        *block_written %= BLOCK_BYTES;
+        *segment_written += written;
+        Ok(begin_offset)
     }
 
     pub(crate) fn read_at(&self, offset: u64) -> Result<Record> {
-        if self.mmap.is_some() {
-            self.read_at_mmap(offset)
+        let result = if let Some(mmap) = self.mmap.as_ref() {
+            self.read_at_mmap(mmap, offset)
+        } else if let Some(mmap) = self.mapped_tail(offset) {
+            self.read_at_mmap(&mmap, offset)
         } else {
             self.read_at_fd(offset)
-        }
+        };
+        result.map_err(|e| locate(e, Some(&self.name()), Some(offset), None))
     }
 
-    pub(crate) fn read_at_mmap(&self, offset: u64) -> Result<Record> {
+    fn read_at_mmap(&self, shared: &Arc<Mmap>, offset: u64) -> Result<Record> {
         let mut offset: usize = offset as usize;
-        let mmap = &*(self.mmap.as_ref().unwrap().read().unwrap());
-        let flag = if let Some(f) = mmap.get(offset as usize) {
+        let record_start = offset;
+        let mmap: &[u8] = shared;
+        let flag = if let Some(f) = mmap.get(offset) {
             f.to_owned()
         } else {
-            return Err(anyhow!("reach end of file"));
+            return Err(corruption("reach end of file"));
         };
         offset += 1;
         if flag & FLAG_PADDING > 0 {
             return Ok(Record {
                 key: Bytes::new(),
                 value: Bytes::new(),
-                flag: flag,
+                flag,
+                metadata: 0,
             });
         }
-        let key_len = decode_varint_from_mmap(mmap, &mut offset)? as usize;
-        let value_len = decode_varint_from_mmap(mmap, &mut offset)? as usize;
-        let key: Vec<u8> = if let Some(slice) = mmap.get(offset..offset + key_len) {
-            slice.to_vec()
-        } else {
-            return Err(anyhow!("reach end of file"));
-        };
-        offset += key_len;
-        let value: Vec<u8> = if let Some(slice) = mmap.get(offset..offset + value_len) {
-            slice.to_vec()
-        } else {
-            return Err(anyhow!("reach end of file"));
-        };
-        offset += value_len;
+        let mut metadata: u8 = 0;
+        if flag & FLAG_METADATA > 0 {
+            metadata = *mmap.get(offset).ok_or_else(|| corruption("reach end of file"))?;
+            offset += 1;
+        }
+        let key_len = decode_varint_from_slice(mmap, &mut offset).map_err(|e| corruption(e.to_string()))? as usize;
+        let value_len = decode_varint_from_slice(mmap, &mut offset).map_err(|e| corruption(e.to_string()))? as usize;
+        let key_end = offset.checked_add(key_len).ok_or_else(|| corruption("invalid key length"))?;
+        let value_end = key_end.checked_add(value_len).ok_or_else(|| corruption("invalid value length"))?;
+        check_record_layout(flag)?;
+        let checksum = ChecksumAlgorithm::of_flag(flag)?;
+        let crc_end = value_end.checked_add(checksum.size()).ok_or_else(|| corruption("invalid value length"))?;
+        if crc_end > mmap.len() {
+            return Err(corruption("reach end of file"));
+        }
+        checksum.verify(
+            covered_header(flag, &mmap[record_start..offset]),
+            &mmap[offset..key_end],
+            &mmap[key_end..value_end],
+            &mmap[value_end..crc_end],
+        )?;
+        self.io.mmap_read(crc_end - record_start);
+        // key and value point into mmap, nothing is copied
         Ok(Record {
-            key: Bytes::from(key),
-            value: Bytes::from(value),
+            key: Bytes::from_mmap(shared.clone(), offset, key_end),
+            value: Bytes::from_mmap(shared.clone(), key_end, value_end),
             flag,
+            metadata,
         })
     }
 
@@ -231,46 +577,75 @@ impl Segment {
             internal.fd = Some(fd);
             internal.fd.as_mut().unwrap()
         };
-        let mut flag_buffer = [0u8; 1];
-        let n = fd.read_at(&mut flag_buffer, offset).unwrap();
+        // use positional reads only, fd of active segment is shared with writer and must not be seeked
+        let mut header_buffer = [0u8; MAX_HEADER_BYTES];
+        let n = fd.read_at(&mut header_buffer, offset)?;
+        self.io.read_call(n);
         if n == 0 {
             // reach end of file
-            return Err(anyhow!("reach end of file"));
+            return Err(corruption("reach end of file"));
         }
         // read flag
-        let flag = flag_buffer[0];
+        let flag = header_buffer[0];
         if flag & FLAG_PADDING > 0 {
             // it is a padding, move to next block
             return Ok(Record {
                 key: Bytes::new(),
                 value: Bytes::new(),
-                flag: flag,
+                flag,
+                metadata: 0,
             });
         }
-        // move to startof key_len_encoding
-        fd.seek(SeekFrom::Start(offset + 1))?;
+        let (metadata, metadata_len) = read_metadata(flag, &header_buffer[..n])?;
 
         // read length
-        let (key_len, _) = decode_varint(fd)?;
-        let (value_len, _) = decode_varint(fd)?;
-
-        // read key
-        internal.buffer.resize(key_len as usize, 0);
-        fd.read_exact(&mut internal.buffer).unwrap();
-        let key = internal.buffer.clone();
-
-        // read value
-        internal.buffer.resize(value_len as usize, 0);
-        fd.read_exact(&mut internal.buffer).unwrap();
-        let value = internal.buffer.clone();
+        let mut header: &[u8] = &header_buffer[1 + metadata_len..n];
+        let (key_len, key_len_size) = decode_varint(&mut header).map_err(|e| corruption(e.to_string()))?;
+        let (value_len, value_len_size) = decode_varint(&mut header).map_err(|e| corruption(e.to_string()))?;
+        let data_offset = offset + 1 + metadata_len as u64 + key_len_size + value_len_size;
+        // corrupted lengths must not cause huge allocation
+        let data_len = key_len.checked_add(value_len).ok_or_else(|| corruption("invalid record length"))?;
+        if data_len > BLOCK_BYTES && data_offset.saturating_add(data_len) > fd.metadata()?.len() {
+            return Err(corruption("record exceeds end of segment"));
+        }
 
+        // read key and value with one read into one buffer, they share it
+        check_record_layout(flag)?;
+        let checksum = ChecksumAlgorithm::of_flag(flag)?;
+        let mut data = vec![0u8; data_len as usize + checksum.size()];
+        fd.read_exact_at(&mut data, data_offset).map_err(truncated)?;
+        self.io.read_call(data.len());
+        let (key_len, data_len) = (key_len as usize, data_len as usize);
+        let header = covered_header(flag, &header_buffer[..(data_offset - offset) as usize]);
+        checksum.verify(header, &data[..key_len], &data[key_len..data_len], &data[data_len..])?;
+        let data = Arc::new(data);
         Ok(Record {
-            key: Bytes::from(key),
-            value: Bytes::from(value),
+            key: Bytes::from_shared(data.clone(), 0, key_len),
+            value: Bytes::from_shared(data, key_len, data_len),
             flag,
+            metadata,
         })
     }
 
+    // read_flag_at returns the flag of the record at offset, the record is neither read nor verified
+    pub(crate) fn read_flag_at(&self, offset: u64) -> Result<u8> {
+        if let Some(mmap) = self.mmap.clone().or_else(|| self.mapped_tail(offset)) {
+            return mmap.get(offset as usize).copied().ok_or_else(|| corruption("reach end of file"));
+        }
+        let internal = &mut *(self.internal.lock().unwrap());
+        let fd = if let Some(fd) = internal.fd.as_mut() {
+            fd
+        } else {
+            let fd = File::open(&self.path)?;
+            internal.fd = Some(fd);
+            internal.fd.as_mut().unwrap()
+        };
+        let mut flag = [0u8; 1];
+        fd.read_exact_at(&mut flag, offset).map_err(truncated)?;
+        self.io.read_call(flag.len());
+        Ok(flag[0])
+    }
+
     pub(crate) fn iter(&self) -> SegmentIter<'_> {
         SegmentIter::new(self, false)
     }
@@ -278,17 +653,111 @@ impl Segment {
     pub(crate) fn iter_with_value(&self) -> SegmentIter<'_> {
         SegmentIter::new(self, true)
     }
+
+    // iter_from starts at a record boundary, e.g. resume_offset of a former iteration
+    pub(crate) fn iter_from(&self, offset: u64) -> SegmentIter<'_> {
+        let mut iter = SegmentIter::new(self, false);
+        iter.offset = offset;
+        iter.resume_offset = offset;
+        iter
+    }
 }
 
 pub(crate) struct SegmentIter<'a> {
     segment: &'a Segment,
     offset: u64,
-    buffer: Vec<u8>,
+    file_len: Option<u64>,
     with_value: bool,
+    read_ahead: ReadAhead,
+    committed: VecDeque<RecordIndex>, // records of a committed batch not yielded yet
+    resume_offset: u64, // end of records yielded before the current call, torn batches excluded
+    error: Option<anyhow::Error>, // why iteration ended before the end of segment
+}
+
+const READ_AHEAD_BYTES: usize = 256 * 1024;
+
+// ReadAhead serves sequential small reads from one large read, records larger than it are read directly
+struct ReadAhead {
+    buf: Vec<u8>,
+    offset: u64, // file offset of buf[0]
+    io: Arc<IoCounters>,
+}
+
+impl ReadAhead {
+    fn new(io: Arc<IoCounters>) -> Self {
+        Self {
+            buf: Vec::new(),
+            offset: 0,
+            io,
+        }
+    }
+
+    // read_at behaves like FileExt::read_at, it may return less than out.len() at end of file
+    fn read_at(&mut self, fd: &File, out: &mut [u8], offset: u64) -> Result<usize> {
+        if out.len() > READ_AHEAD_BYTES {
+            let n = fd.read_at(out, offset)?;
+            self.io.read_call(n);
+            return Ok(n);
+        }
+        let end = offset + out.len() as u64;
+        if offset < self.offset || end > self.offset + self.buf.len() as u64 {
+            self.buf.resize(READ_AHEAD_BYTES, 0);
+            let mut n = 0;
+            while n < self.buf.len() {
+                let read = fd.read_at(&mut self.buf[n..], offset + n as u64)?;
+                self.io.read_call(read);
+                if read == 0 {
+                    break;
+                }
+                n += read;
+            }
+            self.buf.truncate(n);
+            self.offset = offset;
+        }
+        let start = (offset - self.offset) as usize;
+        let available = (self.buf.len() - start).min(out.len());
+        out[..available].copy_from_slice(&self.buf[start..start + available]);
+        Ok(available)
+    }
+
+    fn read_exact_at(&mut self, fd: &File, out: &mut [u8], offset: u64) -> Result<()> {
+        if out.len() > READ_AHEAD_BYTES {
+            fd.read_exact_at(out, offset).map_err(truncated)?;
+            self.io.read_call(out.len());
+        } else if self.read_at(fd, out, offset)? < out.len() {
+            return Err(corruption("reach end of file"));
+        }
+        Ok(())
+    }
+}
+
+// a record cut short by end of file is corruption rather than an io failure
+fn truncated(e: std::io::Error) -> anyhow::Error {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        return corruption("reach end of file");
+    }
+    e.into()
+}
+
+// a record of an unknown layout can not even be skipped, its length may be encoded differently
+fn check_record_layout(flag: u8) -> Result<()> {
+    if flag & FLAG_RESERVED > 0 {
+        return Err(StoreError::UnsupportedFormat("record of unknown layout".to_string()).into());
+    }
+    Ok(())
+}
+
+// returns metadata byte and its size in header
+fn read_metadata(flag: u8, header: &[u8]) -> Result<(u8, usize)> {
+    if flag & FLAG_METADATA == 0 {
+        return Ok((0, 0));
+    }
+    let metadata = *header.get(1).ok_or_else(|| corruption("reach end of file"))?;
+    Ok((metadata, 1))
 }
 
 fn next_block_offset(offset: u64) -> u64 {
-    if offset % BLOCK_BYTES == 0 {
+    if offset.is_multiple_of(BLOCK_BYTES) {
         // if offset is start of block, move to next
         offset + BLOCK_BYTES
     } else {
@@ -300,99 +769,173 @@ impl<'a> Iterator for SegmentIter<'a> {
     type Item = RecordIndex;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        // a malformed or torn record ends iteration, records behind it can not be located
+        self.next_record().unwrap_or_else(|e| {
+            self.error = Some(e);
+            None
+        })
+    }
+}
+
+impl<'a> SegmentIter<'a> {
+    // resume_offset is where a later iteration of a growing segment continues once this one returned none
+    pub(crate) fn resume_offset(&self) -> u64 {
+        self.resume_offset
+    }
+
+    fn new(segment: &'a Segment, with_value: bool) -> Self {
+        SegmentIter {
+            segment,
+            offset: 0,
+            file_len: None,
+            with_value,
+            read_ahead: ReadAhead::new(segment.io.clone()),
+            committed: VecDeque::new(),
+            resume_offset: 0,
+            error: None,
+        }
+    }
+
+    // finish returns the error which ended iteration before the end of segment, e.g. a damaged or torn record.
+    // Iteration of a file which must be read whole is only complete if it returns ok.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<RecordIndex>> {
+        loop {
+            if let Some(ri) = self.committed.pop_front() {
+                return Ok(Some(ri));
+            }
+            self.resume_offset = self.offset;
+            let Some(ri) = self.read_next()? else {
+                return Ok(None);
+            };
+            if ri.flag & FLAG_CONTROL == 0 {
+                return Ok(Some(ri));
+            }
+            let control = self.segment.read_at(ri.offset)?;
+            match control.key.as_slice() {
+                SEGMENT_MAGIC if ri.offset == 0 => {}
+                key if key.starts_with(RAW_KEY_PREFIX) => {}
+                key if key.starts_with(BLOB_KEY_PREFIX) => {
+                    return parse_blob_pointer(&control).map(Some).ok_or_else(|| corruption("malformed blob pointer"));
+                }
+                BATCH_BEGIN_KEY => {
+                    let count = decode_varint_from_slice(control.value.as_slice(), &mut 0)
+                        .map_err(|e| corruption(e.to_string()))?;
+                    self.committed = self.read_batch(ri.offset, count)?;
+                }
+                // a commit without begin is as malformed as an unknown control record
+                _ => return Err(corruption("unexpected control record")),
+            }
+        }
+    }
+
+    // read_batch returns records of the batch begun at begin, it fails if its commit is missing
+    fn read_batch(&mut self, begin: u64, count: u64) -> Result<VecDeque<RecordIndex>> {
+        let mut records: VecDeque<RecordIndex> = VecDeque::new();
+        loop {
+            let ri = self.read_next()?.ok_or_else(|| corruption("batch without commit"))?;
+            if ri.flag & FLAG_CONTROL == 0 {
+                records.push_back(ri);
+                continue;
+            }
+            let control = self.segment.read_at(ri.offset)?;
+            // pointers of blobs written by the batch belong to it
+            if let Some(pointer) = parse_blob_pointer(&control) {
+                records.push_back(pointer);
+                continue;
+            }
+            let committed = control.key.as_slice() == BATCH_COMMIT_KEY
+                && decode_varint_from_slice(control.value.as_slice(), &mut 0).ok() == Some(begin)
+                && records.len() as u64 == count;
+            if !committed {
+                return Err(corruption("batch without commit"));
+            }
+            return Ok(records);
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<RecordIndex>> {
         let segment = self.segment;
         let internal = &mut *(segment.internal.lock().unwrap());
         let fd = if let Some(fd) = internal.fd.as_mut() {
             fd
         } else {
-            let fd = File::open(&segment.path).unwrap();
+            let fd = File::open(&segment.path)?;
             internal.fd = Some(fd);
             internal.fd.as_mut().unwrap()
         };
+        let file_len = match self.file_len {
+            Some(len) => len,
+            None => *self.file_len.insert(fd.metadata()?.len()),
+        };
         let mut record_offset = self.offset;
-        let mut flag_buffer = [0u8; 1];
-        let n = fd.read_at(&mut flag_buffer, self.offset).unwrap();
+        let mut header_buffer = [0u8; MAX_HEADER_BYTES];
+        let mut n = self.read_ahead.read_at(fd, &mut header_buffer, record_offset)?;
         if n == 0 {
             // reach end of file
-            return None;
+            return Ok(None);
         }
-        // read flag
-        let mut flag = flag_buffer[0];
-        if flag & FLAG_PADDING > 0 {
+        if header_buffer[0] & FLAG_PADDING > 0 {
             // it is a padding, move to next block
-            self.offset = next_block_offset(self.offset);
-            // now self.offset is at beginning of record, read flag of record
-            record_offset = self.offset;
-            if let Err(e) = fd.seek(SeekFrom::Start(self.offset)) {
-                panic!("seek err: {:?}", e)
-            }
-            let n = fd.read(&mut flag_buffer).unwrap();
+            record_offset = next_block_offset(record_offset);
+            n = self.read_ahead.read_at(fd, &mut header_buffer, record_offset)?;
             if n == 0 {
                 // reach end of file
-                return None;
-            }
-            self.offset += n as u64;
-            // read flag
-            flag = flag_buffer[0];
-        } else {
-            self.offset += 1; // move offset to first byte of key length
-            if let Err(e) = fd.seek(SeekFrom::Start(self.offset)) {
-                panic!("seek err: {:?}", e)
+                return Ok(None);
             }
         }
-
-        // read key len
-        let key_len_result = decode_varint(fd);
-        if key_len_result.is_err() {
-            panic!("{:?}", key_len_result.unwrap_err())
-        }
-        let (key_len, n) = key_len_result.unwrap();
-        self.offset += n;
-
-        // read value len
-        let value_len_result = decode_varint(fd);
-        if value_len_result.is_err() {
-            panic!("{:?}", value_len_result.unwrap_err())
+        // read flag
+        let flag = header_buffer[0];
+        let (_, metadata_len) = read_metadata(flag, &header_buffer[..n])?;
+
+        // read key len and value len
+        let mut header: &[u8] = &header_buffer[1 + metadata_len..n];
+        let (key_len, key_len_size) = decode_varint(&mut header).map_err(|e| corruption(e.to_string()))?;
+        let (value_len, value_len_size) = decode_varint(&mut header).map_err(|e| corruption(e.to_string()))?;
+        let data_offset = record_offset + 1 + metadata_len as u64 + key_len_size + value_len_size;
+        check_record_layout(flag)?;
+        let checksum = ChecksumAlgorithm::of_flag(flag)?;
+        let record_end = data_offset
+            .checked_add(key_len)
+            .and_then(|x| x.checked_add(value_len))
+            .and_then(|x| x.checked_add(checksum.size() as u64))
+            .ok_or_else(|| corruption("invalid record length"))?;
+        if record_end > file_len {
+            return Err(corruption("record exceeds end of segment"));
         }
-        let (value_len, n) = value_len_result.unwrap();
-        self.offset += n;
 
         // read key
-        self.buffer.resize(key_len as usize, 0);
-        fd.read_exact(&mut self.buffer).unwrap();
-        self.offset += key_len;
-        let key = Bytes::from(self.buffer.clone());
+        let mut key = vec![0u8; key_len as usize];
+        self.read_ahead.read_exact_at(fd, &mut key, data_offset)?;
+        let key = Bytes::from(key);
 
         // read value
         let value: Option<Bytes> = if self.with_value {
-            self.buffer.resize(value_len as usize, 0);
-            fd.read_exact(&mut self.buffer).unwrap();
-            self.offset += value_len;
-            Some(Bytes::from(self.buffer.clone()))
+            let mut value = vec![0u8; value_len as usize];
+            self.read_ahead.read_exact_at(fd, &mut value, data_offset + key_len)?;
+            Some(Bytes::from(value))
         } else {
-            self.offset += value_len;
             None
         };
-        // skip crc
-        self.offset += 4;
+        self.offset = record_end;
 
-        Some(RecordIndex {
-            segment: segment.name(),
+        Ok(Some(RecordIndex {
+            segment: segment.shared_name(),
             key,
             offset: record_offset,
+            value_size: value_len,
             flag,
             value,
-        })
-    }
-}
-
-impl<'a> SegmentIter<'a> {
-    fn new(segment: &'a Segment, with_value: bool) -> Self {
-        SegmentIter {
-            segment: segment,
-            offset: 0,
-            buffer: Vec::new(),
-            with_value,
-        }
+            version: 0,
+        }))
     }
 }
@@ -0,0 +1,133 @@
+use anyhow::Result;
+
+use crate::error::invalid_input;
+use std::io::{Read, Write};
+
+use crate::utils::varint::{decode_varint, encode_varint_to_vec};
+
+/*
+ * SSTable File Format:
+ * | Magic(8B) | Entry | Entry | ... | End Tag(0x00) | Count(8B LE) |
+ * Entry: | Tag(0x01) | Key Length(varint) | Value Length(varint) | Key | Value |
+ * Keys are strictly ascending.
+ */
+
+const SST_MAGIC: &[u8; 8] = b"BCSST001";
+const TAG_ENTRY: u8 = 1;
+const TAG_END: u8 = 0;
+
+pub(crate) struct SSTableWriter<W: Write> {
+    w: W,
+    count: u64,
+    last_key: Option<Vec<u8>>,
+}
+
+impl<W: Write> SSTableWriter<W> {
+    pub(crate) fn new(mut w: W) -> Result<Self> {
+        w.write_all(SST_MAGIC)?;
+        Ok(Self {
+            w,
+            count: 0,
+            last_key: None,
+        })
+    }
+
+    pub(crate) fn append(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(invalid_input("sstable keys must be strictly ascending"));
+        }
+        self.w.write_all(&[TAG_ENTRY])?;
+        self.w.write_all(&encode_varint_to_vec(key.len() as u64)?)?;
+        self.w.write_all(&encode_varint_to_vec(value.len() as u64)?)?;
+        self.w.write_all(key)?;
+        self.w.write_all(value)?;
+        self.count += 1;
+        self.last_key = Some(key.to_vec());
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<u64> {
+        self.w.write_all(&[TAG_END])?;
+        self.w.write_all(&self.count.to_le_bytes())?;
+        self.w.flush()?;
+        Ok(self.count)
+    }
+}
+
+// SSTableReader yields entries and validates order and entry count
+pub(crate) struct SSTableReader<R: Read> {
+    r: R,
+    count: u64,
+    last_key: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl<R: Read> SSTableReader<R> {
+    pub(crate) fn new(mut r: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SST_MAGIC {
+            return Err(invalid_input("not a sstable file"));
+        }
+        Ok(Self {
+            r,
+            count: 0,
+            last_key: None,
+            finished: false,
+        })
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut tag = [0u8; 1];
+        self.r.read_exact(&mut tag)?;
+        match tag[0] {
+            TAG_END => {
+                let mut count = [0u8; 8];
+                self.r.read_exact(&mut count)?;
+                if u64::from_le_bytes(count) != self.count {
+                    return Err(invalid_input("sstable entry count mismatch"));
+                }
+                Ok(None)
+            }
+            TAG_ENTRY => {
+                let (key_len, _) = decode_varint(&mut self.r)?;
+                let (value_len, _) = decode_varint(&mut self.r)?;
+                let mut key: Vec<u8> = Vec::new();
+                let mut value: Vec<u8> = Vec::new();
+                (&mut self.r).take(key_len).read_to_end(&mut key)?;
+                (&mut self.r).take(value_len).read_to_end(&mut value)?;
+                if key.len() as u64 != key_len || value.len() as u64 != value_len {
+                    return Err(invalid_input("unexpected end of sstable"));
+                }
+                if self.last_key.as_ref().is_some_and(|last| *last >= key) {
+                    return Err(invalid_input("sstable keys must be strictly ascending"));
+                }
+                self.last_key = Some(key.clone());
+                self.count += 1;
+                Ok(Some((key, value)))
+            }
+            t => Err(invalid_input(format!("invalid sstable tag {}", t))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for SSTableReader<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.read_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
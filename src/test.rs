@@ -1,7 +1,18 @@
 #[cfg(test)]
 mod tests {
-    use crate::database::{
-        database::{Database, Options},
+    use crate::{
+        database::{
+            database::{Backpressure, Database, GetResult, OpenProgress, Options, SyncPolicy, VersionConflict, WriteOptions, WriteThrottled},
+            scan::{Cursor, Entry},
+            typed::{Codec, Utf8Codec},
+        },
+        storage::{
+            fault::{self, Fault},
+            segment::SEGMENT_HEADER_BYTES,
+            Bytes,
+        },
+        raw::SegmentReader,
+        ChecksumAlgorithm, Follower, Location, HintAudit, IoStats, ShardedDatabase, StoreError, WriteOp,
     };
     use std::{
         path::PathBuf,
@@ -9,7 +20,7 @@ mod tests {
 
     #[test]
     fn test_read_write_delete() {
-        let dir_path = PathBuf::from("testdata");
+        let dir_path = PathBuf::from("testdata/read_write_delete");
         let _ = std::fs::remove_dir_all(&dir_path);
         std::fs::create_dir_all(&dir_path).unwrap();
         let mut cases: Vec<(String, String)> = Vec::new();
@@ -17,7 +28,7 @@ mod tests {
             cases.push((format!("{:016}", i), format!("v{:016}", i)));
         }
         {
-            let mut database = Database::open("testdata", Options::default()).unwrap();
+            let mut database = Database::open("testdata/read_write_delete", Options::default()).unwrap();
             for (key, value) in cases.iter() {
                 database.write(key.as_bytes(), value.as_bytes()).unwrap();
             }
@@ -32,7 +43,7 @@ mod tests {
             }
         }
         {
-            let mut database = Database::open("testdata", Options::default()).unwrap();
+            let mut database = Database::open("testdata/read_write_delete", Options::default()).unwrap();
             for (key, value) in cases.iter() {
                 let result = database.read(key.as_bytes()).unwrap();
                 if result.is_none() {
@@ -44,20 +55,25 @@ mod tests {
             }
 
             for (key, _) in cases.iter() {
-                database.delete(key.as_bytes()).unwrap();
+                if !database.delete(key.as_bytes()).unwrap() {
+                    panic!("delete should find the record")
+                }
             }
             for (key, _) in cases.iter() {
+                if database.delete(key.as_bytes()).unwrap() {
+                    panic!("delete should not find the record")
+                }
                 let result = database.read(key.as_bytes()).unwrap();
-                if !result.is_none() {
+                if result.is_some() {
                     panic!("record should be none")
                 }
             }
         }
         {
-            let database = Database::open("testdata", Options::default()).unwrap();
+            let database = Database::open("testdata/read_write_delete", Options::default()).unwrap();
             for (key, _value) in cases.iter() {
                 let result = database.read(key.as_bytes()).unwrap();
-                if !result.is_none() {
+                if result.is_some() {
                     panic!("record should be none")
                 }
             }
@@ -66,7 +82,7 @@ mod tests {
 
     #[test]
     fn test_merge() {
-        let dir_path = PathBuf::from("testdata");
+        let dir_path = PathBuf::from("testdata/merge");
         let _ = std::fs::remove_dir_all(&dir_path);
         std::fs::create_dir_all(&dir_path).unwrap();
         let mut cases: Vec<(String, String)> = Vec::new();
@@ -74,7 +90,7 @@ mod tests {
             cases.push((format!("{:016}", i), format!("v{:016}", i)));
         }
         {
-            let mut database = Database::open("testdata", Options::default()).unwrap();
+            let mut database = Database::open("testdata/merge", Options::default()).unwrap();
             for (key, value) in cases.iter() {
                 database.write(key.as_bytes(), value.as_bytes()).unwrap();
             }
@@ -84,7 +100,7 @@ mod tests {
             database.merge().unwrap();
         }
         {
-            let database = Database::open("testdata", Options::default()).unwrap();
+            let database = Database::open("testdata/merge", Options::default()).unwrap();
             for (key, value) in cases.iter() {
                 let result = database.read(key.as_bytes()).unwrap();
                 if result.is_none() {
@@ -96,4 +112,3011 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_merge_with_deletes() {
+        let dir_path = PathBuf::from("testdata/merge_with_deletes");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut cases: Vec<(String, String)> = Vec::new();
+        for i in 0..1000 {
+            cases.push((format!("{:016}", i), format!("v{:016}", i)));
+        }
+        {
+            let mut database = Database::open("testdata/merge_with_deletes", Options::default()).unwrap();
+            for (key, value) in cases.iter() {
+                database.write(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+        }
+        {
+            // tombstones live in a newer segment than the records they delete
+            let mut database = Database::open("testdata/merge_with_deletes", Options::default()).unwrap();
+            for (key, _) in cases.iter().step_by(2) {
+                database.delete(key.as_bytes()).unwrap();
+            }
+            database.merge().unwrap();
+        }
+        for _ in 0..2 {
+            let database = Database::open("testdata/merge_with_deletes", Options::default()).unwrap();
+            for (i, (key, value)) in cases.iter().enumerate() {
+                let result = database.read(key.as_bytes()).unwrap();
+                if i % 2 == 0 {
+                    if result.is_some() {
+                        panic!("deleted record resurrected")
+                    }
+                } else if result.unwrap().as_slice() != value.as_bytes() {
+                    panic!("read returns wrong result")
+                }
+            }
+            database.merge().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partial_merge_retains_tombstones() {
+        let dir_path = PathBuf::from("testdata/partial_merge_retains_tombstones");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let dir = "testdata/partial_merge_retains_tombstones";
+        let mut cases: Vec<(String, String)> = Vec::new();
+        for i in 0..1000 {
+            cases.push((format!("{:016}", i), format!("v{:016}", i)));
+        }
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for (key, value) in cases.iter() {
+                database.write(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+        }
+        {
+            // the segment of deleted records stays un-merged, so merged tombstones must be kept
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for (key, _) in cases.iter().step_by(2) {
+                database.delete(key.as_bytes()).unwrap();
+            }
+            let stats = database.merge_newest(1).unwrap();
+            assert_eq!(stats.segments_merged, 1);
+            assert_eq!(stats.records_retained, 500);
+        }
+        let check = |database: &Database, rewritten: bool| {
+            for (i, (key, value)) in cases.iter().enumerate() {
+                let result = database.read(key.as_bytes()).unwrap();
+                if i == 0 && rewritten {
+                    assert_eq!(result.unwrap().as_slice(), b"again");
+                } else if i % 2 == 0 {
+                    assert!(result.is_none(), "deleted record resurrected");
+                } else {
+                    assert_eq!(result.unwrap().as_slice(), value.as_bytes());
+                }
+            }
+        };
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            check(&database, false);
+            // records written after a partial merge win over its output
+            database.write(cases[0].0.as_bytes(), b"again").unwrap();
+        }
+        {
+            let database = Database::open(dir, Options::default()).unwrap();
+            check(&database, true);
+            // once all segments are merged tombstones are dropped
+            let stats = database.merge().unwrap();
+            assert_eq!(stats.records_retained, 501);
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        check(&database, true);
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let dir = "testdata/compact_range";
+        let _ = std::fs::remove_dir_all(dir);
+        // a keys are in 1.seg, b keys in 2.seg, their deletes in 3.seg
+        for prefix in ["a", "b"] {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..10 {
+                database.write(format!("{}{}", prefix, i), b"v").unwrap();
+            }
+        }
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..10 {
+            database.delete(format!("b{}", i)).unwrap();
+        }
+        assert_eq!(database.compact_range("x".."y").unwrap().segments_merged, 0);
+        let stats = database.compact_range("b".."c").unwrap();
+        assert_eq!(stats.segments_merged, 2);
+        assert_eq!(stats.records_retained, 10); // tombstones shadow records of b keys in unmerged segments
+        let segments: Vec<String> = database.segments().unwrap().into_iter().map(|info| info.segment).collect();
+        assert_eq!(segments.first().map(String::as_str), Some("1"));
+        drop(database);
+        let database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..10 {
+            assert_eq!(database.read(format!("a{}", i)).unwrap().unwrap().as_slice(), b"v");
+            assert!(database.read(format!("b{}", i)).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_empty_value() {
+        let dir_path = PathBuf::from("testdata/empty_value");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/empty_value", Options::default()).unwrap();
+            database.write(b"empty", []).unwrap();
+            database.write(b"deleted", b"v").unwrap();
+            database.delete(b"deleted").unwrap();
+            assert_eq!(database.get(b"empty").unwrap(), GetResult::Found(Bytes::new()));
+        }
+        for mmap in [true, false] {
+            let database = Database::open("testdata/empty_value", Options::default().mmap(mmap)).unwrap();
+            assert_eq!(database.get(b"empty").unwrap(), GetResult::Found(Bytes::new()));
+            assert_eq!(database.get(b"deleted").unwrap(), GetResult::NotFound);
+            assert_eq!(database.get(b"missing").unwrap(), GetResult::NotFound);
+        }
+    }
+
+    struct U64Codec;
+
+    impl Codec<u64> for U64Codec {
+        fn encode(value: &u64) -> anyhow::Result<Vec<u8>> {
+            Ok(value.to_be_bytes().to_vec())
+        }
+
+        fn decode(bytes: &[u8]) -> anyhow::Result<u64> {
+            Ok(u64::from_be_bytes(bytes.try_into()?))
+        }
+    }
+
+    #[test]
+    fn test_typed() {
+        let dir_path = PathBuf::from("testdata/typed");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/typed", Options::default()).unwrap();
+        let mut typed = database.typed::<String, u64, Utf8Codec, U64Codec>();
+        for i in 0..100u64 {
+            typed.write(&format!("k{}", i), &i).unwrap();
+        }
+        for i in 0..100u64 {
+            assert_eq!(typed.read(&format!("k{}", i)).unwrap(), Some(i));
+        }
+        assert!(typed.delete(&"k0".to_string()).unwrap());
+        assert_eq!(typed.read(&"k0".to_string()).unwrap(), None);
+        assert_eq!(database.read(b"k1").unwrap().unwrap().as_slice(), 1u64.to_be_bytes());
+    }
+
+    #[test]
+    fn test_ordered_keys() {
+        use crate::keys::{encode_f64, encode_i64, CompositeKey, CompositeKeyReader};
+        let ints: Vec<i64> = vec![i64::MIN, -100, -1, 0, 1, 100, i64::MAX];
+        for pair in ints.windows(2) {
+            assert!(encode_i64(pair[0]) < encode_i64(pair[1]));
+        }
+        let floats: Vec<f64> = vec![f64::NEG_INFINITY, -1.5, -0.0, 0.0, 1e-10, 2.5, f64::INFINITY];
+        for pair in floats.windows(2) {
+            assert!(encode_f64(pair[0]) <= encode_f64(pair[1]));
+        }
+        let composites = [
+            CompositeKey::new().push_str("a").push_u64(2),
+            CompositeKey::new().push_str("a").push_u64(10),
+            CompositeKey::new().push_str("a\0").push_u64(1),
+            CompositeKey::new().push_str("ab").push_u64(0),
+        ];
+        for pair in composites.windows(2) {
+            assert!(pair[0].as_slice() < pair[1].as_slice());
+        }
+        let key = CompositeKey::new().push_str("a\0b").push_i64(-7).push_f64(-2.5);
+        let mut reader = CompositeKeyReader::new(key.as_slice());
+        assert_eq!(reader.read_str().unwrap(), "a\0b");
+        assert_eq!(reader.read_i64().unwrap(), -7);
+        assert_eq!(reader.read_f64().unwrap(), -2.5);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_secondary_index() {
+        let dir_path = PathBuf::from("testdata/secondary_index");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/secondary_index", Options::default()).unwrap();
+        // value format: "<city>:<name>"
+        database.write(b"u1", b"paris:alice").unwrap();
+        database.write(b"u2", b"tokyo:bob").unwrap();
+        database
+            .create_index("city", |v| vec![v.split(|&b| b == b':').next().unwrap().to_vec()])
+            .unwrap();
+        database.write(b"u3", b"paris:carol").unwrap();
+        let keys: Vec<String> = database
+            .find_by_index("city", b"paris")
+            .unwrap()
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        assert_eq!(keys, vec!["u1", "u3"]);
+        database.write(b"u1", b"tokyo:alice").unwrap();
+        database.delete(b"u3").unwrap();
+        assert!(database.find_by_index("city", b"paris").unwrap().is_empty());
+        assert_eq!(database.find_by_index("city", b"tokyo").unwrap().len(), 2);
+        assert!(database.find_by_index("missing", b"paris").is_err());
+    }
+
+    #[test]
+    fn test_read_active_then_append() {
+        let dir = "testdata/read_active_then_append";
+        let _ = std::fs::remove_dir_all(dir);
+        // without mmap reads of the active segment share the fd of its writer, they must not move its cursor
+        let mut database = Database::open(dir, Options::default().mmap(false)).unwrap();
+        for i in 0..100 {
+            database.write(format!("k{}", i), format!("v{}", i)).unwrap();
+            assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"v0");
+        }
+        drop(database);
+        let database = Database::open(dir, Options::default().mmap(false)).unwrap();
+        for i in 0..100 {
+            assert_eq!(database.read(format!("k{}", i)).unwrap().unwrap().to_string(), format!("v{}", i));
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir_path = PathBuf::from("testdata/scan");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/scan", Options::default()).unwrap();
+        for i in 0..25 {
+            database.write(format!("a{:03}", i).as_bytes(), b"v").unwrap();
+            database.write(format!("b{:03}", i).as_bytes(), b"v").unwrap();
+        }
+        let mut keys: Vec<String> = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let (items, next) = database.scan(b"a", cursor.as_ref(), 10).unwrap();
+            keys.extend(items.iter().map(|(k, _)| k.to_string()));
+            // writes between pages must not invalidate the cursor
+            database.write(b"a000", b"changed").unwrap();
+            match next {
+                Some(c) => cursor = Some(Cursor::decode(&c.encode()).unwrap()),
+                None => break,
+            }
+        }
+        let expected: Vec<String> = (0..25).map(|i| format!("a{:03}", i)).collect();
+        assert_eq!(keys, expected);
+        assert!(Cursor::decode("zz").is_err());
+        assert!(database.scan(b"a", None, 0).is_err());
+        let (items, next) = database.scan(b"b", None, usize::MAX).unwrap();
+        assert_eq!((items.len(), next), (25, None));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir_path = PathBuf::from("testdata/snapshot");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut cases: Vec<(String, String)> = Vec::new();
+        for i in 0..1000 {
+            cases.push((format!("{:016}", i), format!("v{:016}", i)));
+        }
+        let mut archive: Vec<u8> = Vec::new();
+        {
+            let mut database = Database::open("testdata/snapshot/src", Options::default()).unwrap();
+            for (key, value) in cases.iter() {
+                database.write(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+            database.merge().unwrap();
+        }
+        {
+            let mut database = Database::open("testdata/snapshot/src", Options::default()).unwrap();
+            database.delete(cases[0].0.as_bytes()).unwrap();
+            archive = database.export_snapshot(archive).unwrap();
+        }
+        let database = Database::import_snapshot(archive.as_slice(), "testdata/snapshot/dst", Options::default()).unwrap();
+        assert!(database.read(cases[0].0.as_bytes()).unwrap().is_none());
+        for (key, value) in cases.iter().skip(1) {
+            assert_eq!(database.read(key.as_bytes()).unwrap().unwrap().as_slice(), value.as_bytes());
+        }
+        assert!(Database::import_snapshot(archive.as_slice(), "testdata/snapshot/dst", Options::default()).is_err());
+        archive.truncate(archive.len() / 2);
+        assert!(Database::import_snapshot(archive.as_slice(), "testdata/snapshot/truncated", Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir_path = PathBuf::from("testdata/checkpoint");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/checkpoint/src", Options::default()).unwrap();
+        for i in 0..100 {
+            database.write(format!("k{}", i).as_bytes(), b"v1").unwrap();
+        }
+        drop(database);
+        let mut database = Database::open("testdata/checkpoint/src", Options::default()).unwrap();
+        database.write(b"k0", b"v2").unwrap();
+        database.checkpoint("testdata/checkpoint/dst").unwrap();
+        // writes after checkpoint are not visible in it
+        database.write(b"k1", b"v2").unwrap();
+        assert!(database.checkpoint("testdata/checkpoint/dst").is_err());
+        let copy = Database::open("testdata/checkpoint/dst", Options::default()).unwrap();
+        assert_eq!(copy.read(b"k0").unwrap().unwrap().as_slice(), b"v2");
+        assert_eq!(copy.read(b"k1").unwrap().unwrap().as_slice(), b"v1");
+        assert_eq!(copy.read(b"k99").unwrap().unwrap().as_slice(), b"v1");
+    }
+
+    #[test]
+    fn test_import_rdb() {
+        let dir_path = PathBuf::from("testdata/import_rdb");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut rdb: Vec<u8> = b"REDIS0009".to_vec();
+        rdb.extend([0xFA, 9]);
+        rdb.extend(b"redis-ver");
+        rdb.extend([5]);
+        rdb.extend(b"7.0.0");
+        rdb.extend([0xFE, 0, 0xFB, 4, 1]);
+        // plain string
+        rdb.extend([0, 1, b'a', 5]);
+        rdb.extend(b"hello");
+        // int encoded value
+        rdb.extend([0, 1, b'b', 0xC0, 0x7B]);
+        // lzf compressed "abcabcabc"
+        rdb.extend([0, 1, b'c', 0xC3, 6, 9, 0x02, b'a', b'b', b'c', 0x80, 0x02]);
+        // expired long ago
+        rdb.extend([0xFC]);
+        rdb.extend(1000u64.to_le_bytes());
+        rdb.extend([0, 1, b'd', 1, b'x']);
+        // list is skipped
+        rdb.extend([1, 1, b'e', 2, 1, b'x', 1, b'y']);
+        rdb.extend([0xFF]);
+        rdb.extend([0u8; 8]);
+
+        let mut database = Database::open("testdata/import_rdb", Options::default()).unwrap();
+        let stats = database.import_rdb(rdb.as_slice()).unwrap();
+        assert_eq!(stats.imported, 3);
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.skipped_non_string, 1);
+        assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"hello");
+        assert_eq!(database.read(b"b").unwrap().unwrap().as_slice(), b"123");
+        assert_eq!(database.read(b"c").unwrap().unwrap().as_slice(), b"abcabcabc");
+        assert!(database.read(b"d").unwrap().is_none());
+        assert!(database.read(b"e").unwrap().is_none());
+
+        // keys of two dbs would collide
+        let mut rdb: Vec<u8> = b"REDIS0009".to_vec();
+        rdb.extend([0xFE, 0, 0, 1, b'f', 1, b'x']);
+        rdb.extend([0xFE, 1, 0, 1, b'f', 1, b'y']);
+        rdb.extend([0xFF]);
+        rdb.extend([0u8; 8]);
+        let err = database.import_rdb(rdb.as_slice()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::InvalidInput(_))), "{}", err);
+        assert!(database.read(b"f").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_import_rdb_malformed_lzf() {
+        let dir_path = PathBuf::from("testdata/import_rdb_malformed_lzf");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let rdb_of = |lzf: &[u8]| {
+            let mut rdb: Vec<u8> = b"REDIS0009".to_vec();
+            rdb.extend([0, 1, b'a', 1, b'x']);
+            rdb.extend([0, 1, b'c', 0xC3]);
+            rdb.extend(lzf);
+            rdb.extend([0xFF]);
+            rdb.extend([0u8; 8]);
+            rdb
+        };
+        let mut database = Database::open("testdata/import_rdb_malformed_lzf", Options::default()).unwrap();
+        let cases: [&[u8]; 3] = [
+            // length of 4GB out of 6 bytes is refused before it is allocated
+            &[6, 0x80, 0xFF, 0xFF, 0xFF, 0xFF, 0x02, b'a', b'b', b'c', 0x80, 0x02],
+            // back reference beyond the length
+            &[6, 5, 0x02, b'a', b'b', b'c', 0x80, 0x02],
+            // back reference before the start of output
+            &[6, 9, 0x02, b'a', b'b', b'c', 0x80, 0x09],
+        ];
+        for lzf in cases {
+            let err = database.import_rdb(rdb_of(lzf).as_slice()).unwrap_err();
+            assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::InvalidInput(_))), "{}", err);
+        }
+        assert!(database.read(b"c").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_export_resp() {
+        let dir_path = PathBuf::from("testdata/export_resp");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/export_resp", Options::default()).unwrap();
+        database.write(b"b", b"2").unwrap();
+        database.write(b"a", b"hello").unwrap();
+        database.write(b"c", b"x").unwrap();
+        database.delete(b"c").unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        assert_eq!(database.export_resp(&mut out).unwrap(), 2);
+        assert_eq!(
+            out.as_slice(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nhello\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_sstable() {
+        let dir_path = PathBuf::from("testdata/sstable");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut sst: Vec<u8> = Vec::new();
+        {
+            let mut database = Database::open("testdata/sstable/src", Options::default()).unwrap();
+            for i in (0..100).rev() {
+                database.write(format!("k{:03}", i).as_bytes(), b"sst").unwrap();
+            }
+            assert_eq!(database.export_sstable(&mut sst).unwrap(), 100);
+        }
+        {
+            let mut database = Database::open("testdata/sstable/dst", Options::default()).unwrap();
+            database.write(b"k000", b"old").unwrap();
+            database.write(b"z", b"old").unwrap();
+            assert_eq!(database.ingest_sstable(sst.as_slice()).unwrap(), 100);
+            database.write(b"k001", b"new").unwrap();
+            let mut truncated = sst.clone();
+            truncated.truncate(sst.len() - 3);
+            assert!(database.ingest_sstable(truncated.as_slice()).is_err());
+        }
+        let database = Database::open("testdata/sstable/dst", Options::default()).unwrap();
+        assert_eq!(database.read(b"k000").unwrap().unwrap().as_slice(), b"sst");
+        assert_eq!(database.read(b"k001").unwrap().unwrap().as_slice(), b"new");
+        assert_eq!(database.read(b"k099").unwrap().unwrap().as_slice(), b"sst");
+        assert_eq!(database.read(b"z").unwrap().unwrap().as_slice(), b"old");
+    }
+
+    #[test]
+    fn test_corrupted_segment() {
+        use rand::Rng;
+        let dir_path = PathBuf::from("testdata/corrupted_segment");
+        let mut rng = rand::thread_rng();
+        for round in 0..50 {
+            let _ = std::fs::remove_dir_all(&dir_path);
+            std::fs::create_dir_all(&dir_path).unwrap();
+            {
+                let mut database = Database::open("testdata/corrupted_segment", Options::default()).unwrap();
+                for i in 0..200 {
+                    database.write(format!("k{}", i).as_bytes(), vec![b'v'; i * 10]).unwrap();
+                }
+            }
+            let seg_path = dir_path.join("data").join("1.seg");
+            let mut data = std::fs::read(&seg_path).unwrap();
+            if round % 2 == 0 {
+                data.truncate(rng.gen_range(0..data.len()));
+            }
+            for _ in 0..rng.gen_range(1..20) {
+                let i = rng.gen_range(0..data.len().max(1));
+                if let Some(b) = data.get_mut(i) {
+                    *b = rng.gen();
+                }
+            }
+            std::fs::write(&seg_path, &data).unwrap();
+            // opening and reading must not panic, wrong or failed reads are acceptable here
+            for mmap in [true, false] {
+                if let Ok(database) = Database::open("testdata/corrupted_segment", Options::default().mmap(mmap)) {
+                    for i in 0..200 {
+                        let _ = database.read(format!("k{}", i).as_bytes());
+                    }
+                }
+            }
+        }
+
+        // a crash while a segment was created leaves it cut within its header
+        let _ = std::fs::remove_dir_all(&dir_path);
+        let mut database = Database::open("testdata/corrupted_segment", Options::default()).unwrap();
+        database.write(b"k", b"v").unwrap();
+        drop(database);
+        let header = std::fs::read(dir_path.join("data").join("1.seg")).unwrap();
+        std::fs::write(dir_path.join("data").join("2.seg"), &header[..5]).unwrap();
+        for mmap in [true, false] {
+            let database = Database::open("testdata/corrupted_segment", Options::default().mmap(mmap)).unwrap();
+            assert_eq!(database.read(b"k").unwrap().unwrap().as_slice(), b"v");
+        }
+    }
+
+    fn assert_all_present(dir: &str, cases: &[(String, String)]) {
+        let database = Database::open(dir, Options::default()).unwrap();
+        for (key, value) in cases.iter() {
+            let result = database.read(key.as_bytes()).unwrap();
+            assert_eq!(result.unwrap().as_slice(), value.as_bytes(), "key {}", key);
+        }
+    }
+
+    // wait_until polls cond for up to 5s, background threads like the hint writer are not waited for on drop
+    fn wait_until(cond: impl Fn() -> bool) -> bool {
+        for _ in 0..500 {
+            if cond() {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        cond()
+    }
+
+    // merge_unadopted merges while for_each pins segments, so merged output is left for the next open to adopt
+    fn merge_unadopted(database: &Database) {
+        let mut merged = false;
+        database
+            .for_each(|_, _| {
+                if !merged {
+                    database.merge()?;
+                    merged = true;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert!(merged);
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let dir = "testdata/fault_injection";
+        let mut cases: Vec<(String, String)> = Vec::new();
+        for i in 0..500 {
+            cases.push((format!("{:016}", i), format!("v{:016}", i)));
+        }
+        let prepare = || {
+            let _ = std::fs::remove_dir_all(dir);
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for (key, value) in cases.iter() {
+                database.write(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+            database
+        };
+
+        // torn write: acknowledged writes survive, the torn record is dropped
+        let mut database = prepare();
+        fault::inject("segment.write", 0, Fault::ShortWrite(5));
+        assert!(database.write(b"torn", b"value").is_err());
+        drop(database);
+        assert_all_present(dir, &cases);
+        assert!(Database::open(dir, Options::default()).unwrap().read(b"torn").unwrap().is_none());
+
+        // a torn write is cut off, writes after it are not lost behind it. If it can not be cut off,
+        // they go to a new segment.
+        for cut_fails in [false, true] {
+            let mut database = prepare();
+            fault::inject("segment.write", 0, Fault::ShortWrite(5));
+            if cut_fails {
+                fault::inject("segment.truncate", 0, Fault::Crash);
+            }
+            assert!(database.write(b"torn", b"value").is_err());
+            database.write(b"after", b"value").unwrap();
+            drop(database);
+            assert_all_present(dir, &cases);
+            let database = Database::open(dir, Options::default()).unwrap();
+            assert!(database.read(b"torn").unwrap().is_none());
+            assert_eq!(database.read(b"after").unwrap().unwrap().as_slice(), b"value");
+        }
+
+        // a failed fsync is reported
+        let database = prepare();
+        fault::inject("segment.sync", 0, Fault::Crash);
+        assert!(database.sync().is_err());
+        drop(database);
+        assert_all_present(dir, &cases);
+
+        // crash during rotation of merge
+        let database = prepare();
+        fault::inject("directory.rotate", 0, Fault::Crash);
+        assert!(database.merge().is_err());
+        drop(database);
+        assert_all_present(dir, &cases);
+
+        // crash creating the segment rotation of merge seals the active one into, and putting a file of merge in place
+        for point in ["segment.create", "file.rename"] {
+            let database = prepare();
+            fault::inject(point, 0, Fault::Crash);
+            assert!(database.merge().is_err());
+            fault::clear();
+            drop(database);
+            assert_all_present(dir, &cases);
+        }
+
+        // crash before merge finished
+        let database = prepare();
+        fault::inject("merge.finish", 0, Fault::Crash);
+        assert!(database.merge().is_err());
+        drop(database);
+        assert_all_present(dir, &cases);
+
+        // crash during adoption of merged segments, next open resumes adoption
+        for point in ["merge.adopt.remove", "merge.adopt.copy"] {
+            let database = prepare();
+            merge_unadopted(&database);
+            drop(database);
+            fault::inject(point, 0, Fault::Crash);
+            assert!(Database::open(dir, Options::default()).is_err());
+            fault::clear();
+            assert_all_present(dir, &cases);
+        }
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn test_simulation() {
+        use crate::simulation::{clear_faults, seed_faults};
+        let dir = "testdata/simulation";
+        for probability in [-0.1, 1.5, f64::NAN] {
+            assert!(seed_faults(0, probability).is_err());
+        }
+        for seed in 0..20 {
+            let _ = std::fs::remove_dir_all(dir);
+            let mut acked: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new();
+            seed_faults(seed, 0.01).unwrap();
+            if let Ok(mut database) = Database::open(dir, Options::default()) {
+                for i in 0..300 {
+                    let (key, value) = (format!("k{}", i % 50), format!("v{}", i));
+                    if database.write(key.as_bytes(), value.as_bytes()).is_err() {
+                        break; // crash
+                    }
+                    acked.insert(key, value);
+                    if i % 100 == 99 && database.merge().is_err() {
+                        break;
+                    }
+                }
+            }
+            clear_faults();
+            let database = Database::open(dir, Options::default()).unwrap();
+            for (key, value) in acked.iter() {
+                assert_eq!(database.read(key.as_bytes()).unwrap().unwrap().as_slice(), value.as_bytes(), "seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_metadata() {
+        let dir = "testdata/metadata";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            database.write_with_options(b"json", b"{}", &WriteOptions::default().metadata(7)).unwrap();
+            database.write(b"plain", b"v").unwrap();
+            assert_eq!(database.read_with_metadata(b"json").unwrap().unwrap().1, 7);
+        }
+        for mmap in [false, true] {
+            let database = Database::open(dir, Options::default().mmap(mmap)).unwrap();
+            let (value, metadata) = database.read_with_metadata(b"json").unwrap().unwrap();
+            assert_eq!(value.as_slice(), b"{}");
+            assert_eq!(metadata, 7);
+            assert_eq!(database.read_with_metadata(b"plain").unwrap().unwrap().1, 0);
+            assert!(database.read_with_metadata(b"missing").unwrap().is_none());
+        }
+        {
+            let database = Database::open(dir, Options::default()).unwrap();
+            database.merge().unwrap();
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(database.read_with_metadata(b"json").unwrap().unwrap().1, 7);
+        assert_eq!(database.read(b"plain").unwrap().unwrap().as_slice(), b"v");
+    }
+
+    #[test]
+    fn test_write_if_version() {
+        let dir = "testdata/write_if_version";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        let v1 = database.write_if_version(b"doc", b"a", 0).unwrap();
+        assert!(database.write_if_version(b"doc", b"b", 0).is_err());
+        let v2 = database.write_if_version(b"doc", b"b", v1).unwrap();
+        assert!(v2 > v1);
+        // a concurrent editor still holding v1 loses
+        let err = database.write_if_version(b"doc", b"c", v1).unwrap_err();
+        let conflict = err.downcast_ref::<VersionConflict>().unwrap();
+        assert_eq!(conflict, &VersionConflict { expected: v1, actual: v2 });
+        assert_eq!(database.read_with_version(b"doc").unwrap().unwrap().1, v2);
+        // plain writes bump the version as well
+        database.write(b"doc", b"d").unwrap();
+        assert!(database.write_if_version(b"doc", b"e", v2).is_err());
+        // deleted key starts over from 0
+        database.delete(b"doc").unwrap();
+        let v3 = database.write_if_version(b"doc", b"f", 0).unwrap();
+        assert!(v3 > v2);
+        drop(database);
+
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        let (value, version) = database.read_with_version(b"doc").unwrap().unwrap();
+        assert_eq!(value.as_slice(), b"f");
+        // versions of an earlier open are never given again
+        assert!(version > v3);
+        assert!(database.write_if_version(b"doc", b"g", v3).is_err());
+        database.write(b"other", b"x").unwrap();
+        assert!(database.write_if_version(b"doc", b"g", v3).is_err());
+        database.write_if_version(b"doc", b"g", version).unwrap();
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let dir = "testdata/delete_many";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i).as_bytes(), b"v").unwrap();
+            }
+            let keys: Vec<String> = (0..50).map(|i| format!("k{}", i)).chain(["missing".to_string(), "k0".to_string()]).collect();
+            let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+            assert_eq!(database.delete_many(&keys).unwrap(), 50);
+            assert_eq!(database.delete_many(&keys).unwrap(), 0);
+            assert!(database.read(b"k0").unwrap().is_none());
+            assert!(database.read(b"k50").unwrap().is_some());
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..100 {
+            assert_eq!(database.read(format!("k{}", i).as_bytes()).unwrap().is_some(), i >= 50);
+        }
+    }
+
+    #[test]
+    fn test_write_many() {
+        let dir = "testdata/write_many";
+        let _ = std::fs::remove_dir_all(dir);
+        let cases: Vec<(String, String)> = (0..5000).map(|i| (format!("k{}", i), format!("v{}", i))).collect();
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            let mut pairs: Vec<(&[u8], &[u8])> = cases.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())).collect();
+            pairs.push((b"k0", b"last"));
+            let versions = database.write_many(&pairs).unwrap();
+            assert_eq!(versions.len(), pairs.len());
+            assert!(versions.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(database.read_with_version(b"k0").unwrap().unwrap().1, *versions.last().unwrap());
+        }
+        for mmap in [false, true] {
+            let database = Database::open(dir, Options::default().mmap(mmap)).unwrap();
+            assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"last");
+            for (key, value) in cases.iter().skip(1) {
+                assert_eq!(database.read(key.as_bytes()).unwrap().unwrap().as_slice(), value.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_writer() {
+        let dir = "testdata/async_writer";
+        let _ = std::fs::remove_dir_all(dir);
+        let writer = Database::open(dir, Options::default()).unwrap().into_async(64);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let writer = &writer;
+                s.spawn(move || {
+                    let handles: Vec<_> = (0..500)
+                        .map(|i| writer.write(format!("t{}-{}", t, i).as_bytes(), b"v").unwrap())
+                        .collect();
+                    for handle in handles {
+                        handle.wait().unwrap();
+                    }
+                });
+            }
+        });
+        assert!(writer.read(b"t0-0").unwrap().is_some());
+        // queued writes are drained by close
+        let last = writer.write(b"last", b"v").unwrap();
+        let database = writer.close().unwrap();
+        last.wait().unwrap();
+        drop(database);
+        let database = Database::open(dir, Options::default()).unwrap();
+        for t in 0..4 {
+            for i in 0..500 {
+                assert!(database.read(format!("t{}-{}", t, i).as_bytes()).unwrap().is_some());
+            }
+        }
+        assert!(database.read(b"last").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_async_read_queued_writes() {
+        let dir = "testdata/async_read_queued_writes";
+        let _ = std::fs::remove_dir_all(dir);
+        let writer = Database::open(dir, Options::default()).unwrap().into_async(1024);
+        writer.write(b"deleted", b"v").unwrap().wait().unwrap();
+        // reads before wait see the latest queued mutation of a key, whether its batch is applied or not
+        let mut handles = Vec::new();
+        for i in 0..200 {
+            handles.push(writer.write(b"k", format!("v{}", i).as_bytes()).unwrap());
+            assert_eq!(writer.read(b"k").unwrap().unwrap().as_slice(), format!("v{}", i).as_bytes());
+        }
+        let deleted = writer.delete(b"deleted").unwrap();
+        assert!(writer.read(b"deleted").unwrap().is_none());
+        for handle in handles {
+            handle.wait().unwrap();
+        }
+        assert!(deleted.wait().unwrap());
+        assert_eq!(writer.read(b"k").unwrap().unwrap().as_slice(), b"v199");
+        assert!(writer.read(b"deleted").unwrap().is_none());
+
+        // concurrent writers of a key are applied in the order reads see them queued
+        for round in 0..20 {
+            let handles: Vec<_> = std::thread::scope(|s| {
+                let writers: Vec<_> = (0..4)
+                    .map(|t| {
+                        let writer = &writer;
+                        s.spawn(move || writer.write(b"raced", format!("{}-{}", round, t).as_bytes()).unwrap())
+                    })
+                    .collect();
+                writers.into_iter().map(|w| w.join().unwrap()).collect()
+            });
+            let queued = writer.read(b"raced").unwrap();
+            for handle in handles {
+                handle.wait().unwrap();
+            }
+            assert_eq!(writer.read(b"raced").unwrap(), queued);
+        }
+    }
+
+    #[test]
+    fn test_sync_always() {
+        let db_dir = "testdata/sync_always";
+        let _ = std::fs::remove_dir_all(db_dir);
+        {
+            let mut database = Database::open(db_dir, Options::default().sync(SyncPolicy::Always)).unwrap();
+            database.write(b"k", b"v").unwrap();
+            database.delete_many(&[b"k".as_slice()]).unwrap();
+            database.write(b"k2", b"v").unwrap();
+            // one fsync per write call
+            assert_eq!(database.io_stats().fsyncs, 3);
+        }
+        let database = Database::open(db_dir, Options::default()).unwrap();
+        assert!(database.read(b"k").unwrap().is_none());
+        assert!(database.read(b"k2").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_async_group_commit() {
+        let dir = "testdata/async_group_commit";
+        let _ = std::fs::remove_dir_all(dir);
+        const THREADS: usize = 8;
+        const WRITES: usize = 50;
+        let options = Options::default().sync(SyncPolicy::Always);
+        // every batch of one write is synced once, by its write
+        let writer = Database::open(dir, options.clone()).unwrap().into_async(64);
+        for i in 0..WRITES {
+            writer.write(format!("k{}", i).as_bytes(), b"v").unwrap().wait().unwrap();
+        }
+        let database = writer.close().unwrap();
+        assert_eq!(database.io_stats().fsyncs, WRITES as u64);
+        drop(database);
+
+        // concurrent callers share the fsync of their batch
+        let writer = Database::open(dir, options).unwrap().into_async(64);
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                let writer = &writer;
+                s.spawn(move || {
+                    for i in 0..WRITES {
+                        writer.write(format!("t{}-{}", t, i).as_bytes(), b"v").unwrap().wait().unwrap();
+                    }
+                });
+            }
+        });
+        let database = writer.close().unwrap();
+        let fsyncs = database.io_stats().fsyncs;
+        assert!(fsyncs > 0);
+        assert!(fsyncs < (THREADS * WRITES) as u64, "{} syncs were not coalesced", fsyncs);
+        drop(database);
+        let database = Database::open(dir, Options::default()).unwrap();
+        for t in 0..THREADS {
+            for i in 0..WRITES {
+                assert!(database.read(format!("t{}-{}", t, i).as_bytes()).unwrap().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_throttle() {
+        let dir = "testdata/write_throttle";
+        let _ = std::fs::remove_dir_all(dir);
+        let value = vec![b'v'; 1000];
+        {
+            let options = Options::default().write_rate_limit(10_000).backpressure(Backpressure::Error);
+            let mut database = Database::open(dir, options).unwrap();
+            let mut accepted = 0;
+            let err = loop {
+                match database.write(format!("k{}", accepted).as_bytes(), &value) {
+                    Ok(_) => accepted += 1,
+                    Err(e) => break e,
+                }
+            };
+            // burst is one second of tokens
+            assert!((9..=10).contains(&accepted));
+            assert!(err.downcast_ref::<WriteThrottled>().is_some());
+        }
+        let options = Options::default().write_rate_limit(20_000);
+        let mut database = Database::open(dir, options).unwrap();
+        let start = std::time::Instant::now();
+        for i in 0..30 {
+            database.write(format!("k{}", i).as_bytes(), &value).unwrap();
+        }
+        // 20KB burst, then 10KB at 20KB/s
+        assert!(start.elapsed() >= std::time::Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let dir = "testdata/read_ahead";
+        let _ = std::fs::remove_dir_all(dir);
+        // values smaller and larger than read-ahead buffer, records cross its boundaries and blocks
+        let sizes = [0usize, 10, 1000, 40 * 1024, 300 * 1024, 7];
+        let cases: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| (format!("k{}", i), vec![(i % 251) as u8; sizes[i % sizes.len()]]))
+            .collect();
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for (key, value) in cases.iter() {
+                database.write(key.as_bytes(), value).unwrap();
+            }
+        }
+        for round in 0..2 {
+            let database = Database::open(dir, Options::default().mmap(false)).unwrap();
+            for (key, value) in cases.iter() {
+                assert_eq!(database.read(key.as_bytes()).unwrap().unwrap().as_slice(), value.as_slice());
+            }
+            if round == 0 {
+                database.merge().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let dir = "testdata/iter";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..2500 {
+            database.write(format!("k{:05}", i).as_bytes(), format!("v{}", i).as_bytes()).unwrap();
+        }
+        database.delete(b"k00001").unwrap();
+        let keys: Vec<Bytes> = database.iter().map(|entry| entry.key().clone()).collect();
+        assert_eq!(keys.len(), 2499);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let entry = database.iter().nth(1).unwrap();
+        assert_eq!(entry.key().as_slice(), b"k00002");
+        assert_eq!(entry.value().unwrap().as_slice(), b"v2");
+    }
+
+    #[test]
+    fn test_for_each() {
+        let dir = "testdata/for_each";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..1000u64 {
+                database.write(format!("k{}", i).as_bytes(), i.to_le_bytes()).unwrap();
+            }
+        }
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        // overwritten and deleted records in older segment are skipped
+        database.write(b"k0", 1000u64.to_le_bytes()).unwrap();
+        database.delete(b"k1").unwrap();
+        let mut count = 0;
+        database
+            .for_each(|_, _| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 999);
+        let sum = database
+            .fold(0u64, |acc, _, value| Ok(acc + u64::from_le_bytes(value.as_slice().try_into()?)))
+            .unwrap();
+        // k0 became 1000 and k1 is deleted
+        assert_eq!(sum, (0..1000u64).sum::<u64>() - 1 + 1000);
+
+        // no lock of segments is held while the callback runs, it may rotate the active segment
+        let mut count = 0;
+        database
+            .for_each(|_, _| {
+                if count == 0 {
+                    database.merge()?;
+                }
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 999);
+    }
+
+    #[test]
+    fn test_par_for_each() {
+        let dir = "testdata/par_for_each";
+        let _ = std::fs::remove_dir_all(dir);
+        // reopen several times to spread records over segments
+        for round in 0..4u64 {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..500u64 {
+                let n = round * 500 + i;
+                database.write(format!("k{}", n).as_bytes(), n.to_le_bytes()).unwrap();
+            }
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        let sum = std::sync::atomic::AtomicU64::new(0);
+        database
+            .par_for_each(4, |_, value| {
+                let n = u64::from_le_bytes(value.as_slice().try_into()?);
+                sum.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sum.into_inner(), (0..2000u64).sum::<u64>());
+        let err = database
+            .par_for_each(4, |key, _| {
+                if key.as_slice() == b"k700" {
+                    return Err(anyhow::anyhow!("bad record"));
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "bad record");
+    }
+
+    #[test]
+    fn test_bytes() {
+        let bytes = Bytes::from(vec![b'a', b'\n', 0xff, b'b']);
+        assert_eq!(bytes.to_string(), "a\n\\xffb");
+        assert_eq!(format!("{:?}", bytes), "\"a\\n\\xffb\"");
+        assert_eq!(bytes.len(), 4);
+        assert_eq!(&bytes[..1], b"a");
+        assert_eq!(Bytes::from("hello").as_ref(), b"hello");
+        let shared = bytes.clone();
+        assert_eq!(bytes.into_vec(), shared.into_vec());
+        assert!(Bytes::new().is_empty());
+    }
+
+    #[test]
+    fn test_generic_key_value() {
+        let dir = "testdata/generic_key_value";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        database.write("str", "v1").unwrap();
+        database.write(String::from("string"), vec![1u8, 2]).unwrap();
+        database.write([0u8, 1], b"array").unwrap();
+        assert_eq!(database.read("str").unwrap().unwrap().as_slice(), b"v1");
+        assert_eq!(database.read(String::from("string")).unwrap().unwrap().as_slice(), &[1, 2]);
+        assert!(database.get([0u8, 1]).unwrap().is_found());
+        // Bytes returned by reads can be passed back as key
+        let key = database.scan("st", None, 1).unwrap().0[0].0.clone();
+        assert!(database.delete(key).unwrap());
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let dir_path = PathBuf::from("testdata/typed_errors");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/typed_errors", Options::default().mmap(false)).unwrap();
+        database.write(b"k", vec![b'v'; 100]).unwrap();
+        assert!(database.read(b"missing").unwrap().is_none());
+        // cut the record in the middle of its value
+        let seg_path = dir_path.join("data").join("1.seg");
+        std::fs::OpenOptions::new().write(true).open(&seg_path).unwrap().set_len(50).unwrap();
+        let err = database.read(b"k").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+
+        let err = Cursor::decode("zz").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_corruption_location() {
+        let dir_path = PathBuf::from("testdata/corruption_location");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/corruption_location", Options::default()).unwrap();
+            database.write(b"a", b"first").unwrap();
+            database.write(b"b", vec![b'v'; 100]).unwrap();
+        }
+        // flip a byte in value of b, lengths stay intact so only checksum can tell
+        let seg_path = dir_path.join("data").join("1.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        let b_offset = (data.len() - 4 - 100 - 1 - 3) as u64;
+        let i = data.len() - 10;
+        data[i] ^= 0xff;
+        std::fs::write(&seg_path, &data).unwrap();
+        for mmap in [true, false] {
+            let database = Database::open("testdata/corruption_location", Options::default().mmap(mmap)).unwrap();
+            assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"first");
+            let err = database.read(b"b").unwrap_err();
+            match err.downcast_ref::<StoreError>() {
+                Some(StoreError::Corruption {
+                    detail,
+                    segment,
+                    offset,
+                    key,
+                }) => {
+                    assert_eq!(detail, "checksum mismatch");
+                    assert_eq!(segment.as_deref(), Some("1"));
+                    assert_eq!(*offset, Some(b_offset));
+                    assert_eq!(key.as_deref(), Some(b"b".as_slice()));
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_progress() {
+        use std::sync::{Arc, Mutex};
+        let dir_path = PathBuf::from("testdata/open_progress");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/open_progress", Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i), b"v1").unwrap();
+            }
+            database.merge().unwrap();
+        }
+        // reopen adopts merged data, its writes land in a segment after the hint file
+        for round in 0..2 {
+            let reports: Arc<Mutex<Vec<OpenProgress>>> = Arc::new(Mutex::new(Vec::new()));
+            let sink = reports.clone();
+            let options = Options::default().on_open_progress(move |p| sink.lock().unwrap().push(p));
+            let mut database = Database::open("testdata/open_progress", options).unwrap();
+            database.write(format!("round{}", round), b"v2").unwrap();
+            let reports = reports.lock().unwrap();
+            let first = reports.first().unwrap();
+            let last = reports.last().unwrap();
+            assert_eq!(reports.len(), first.files_total + 1);
+            assert_eq!((first.files_scanned, first.bytes_processed), (0, 0));
+            assert_eq!(last.files_scanned, last.files_total);
+            assert_eq!(last.bytes_processed, last.bytes_total);
+            assert!(last.bytes_total > 0);
+        }
+    }
+
+    #[test]
+    fn test_lazy_index() {
+        let dir_path = PathBuf::from("testdata/lazy_index");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/lazy_index", Options::default()).unwrap();
+            for i in 0..1000 {
+                database.write(format!("k{}", i), b"merged").unwrap();
+            }
+            database.merge().unwrap();
+        }
+        {
+            // these records stay in a segment after the hint file
+            let mut database = Database::open("testdata/lazy_index", Options::default()).unwrap();
+            for i in 0..1000 {
+                database.write(format!("k{}", i), b"later").unwrap();
+            }
+            database.delete(b"k1").unwrap();
+            database.write(b"new", b"later").unwrap();
+        }
+        let mut database = Database::open("testdata/lazy_index", Options::default().lazy_index(true)).unwrap();
+        // writes during hydration win over older records replayed after them
+        database.write(b"k2", b"current").unwrap();
+        database.delete(b"new").unwrap();
+        database.wait_hydrated().unwrap();
+        assert!(database.is_hydrated());
+        assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"later");
+        assert!(database.read(b"k1").unwrap().is_none());
+        assert_eq!(database.read(b"k2").unwrap().unwrap().as_slice(), b"current");
+        assert_eq!(database.read(b"k999").unwrap().unwrap().as_slice(), b"later");
+        assert!(database.read(b"new").unwrap().is_none());
+        drop(database);
+
+        // the tombstone written during hydration survives reopen
+        let database = Database::open("testdata/lazy_index", Options::default()).unwrap();
+        assert!(database.read(b"new").unwrap().is_none());
+        assert_eq!(database.read(b"k2").unwrap().unwrap().as_slice(), b"current");
+    }
+
+    #[test]
+    fn test_lazy_index_panic() {
+        let dir_path = PathBuf::from("testdata/lazy_index_panic");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/lazy_index_panic", Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i), b"value").unwrap();
+            }
+        }
+        // the segment without hint file is replayed in background, the report after it panics
+        let options = Options::default().lazy_index(true).on_open_progress(|progress| {
+            if progress.files_total > 0 && progress.files_scanned == progress.files_total {
+                panic!("report failed");
+            }
+        });
+        let mut database = Database::open("testdata/lazy_index_panic", options).unwrap();
+        assert!(database.wait_hydrated().is_err());
+        assert!(!database.is_hydrated());
+        assert!(database.merge().is_err());
+        assert!(database.compact_blobs().is_err());
+        drop(database);
+
+        let database = Database::open("testdata/lazy_index_panic", Options::default()).unwrap();
+        assert_eq!(database.read(b"k99").unwrap().unwrap().as_slice(), b"value");
+    }
+
+    #[test]
+    fn test_rebuild_hints() {
+        let dir_path = PathBuf::from("testdata/rebuild_hints");
+        let data_dir = dir_path.join("data");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/rebuild_hints", Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i), b"merged").unwrap();
+            }
+            database.merge().unwrap();
+        }
+        {
+            // merged segment of the next generation comes with its hint file
+            let mut database = Database::open("testdata/rebuild_hints", Options::default()).unwrap();
+            assert!(data_dir.join("1-1.hint").exists());
+            assert!(!data_dir.join("1.seg").exists());
+            database.write(b"k0", b"later").unwrap();
+            database.delete(b"k1").unwrap();
+        }
+        {
+            // hints are rebuilt from segments, a lost hint file is regenerated
+            std::fs::remove_file(data_dir.join("1-1.hint")).unwrap();
+            let database = Database::open("testdata/rebuild_hints", Options::default()).unwrap();
+            database.rebuild_hints().unwrap();
+        }
+        let mut hints: Vec<String> = std::fs::read_dir(&data_dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".hint"))
+            .collect();
+        hints.sort();
+        // the segment sealed by merge keeps its generation, later segments are of the merged one
+        assert_eq!(hints, vec!["1-1.hint", "1-3.hint", "2.hint"]);
+        let database = Database::open("testdata/rebuild_hints", Options::default()).unwrap();
+        assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"later");
+        assert!(database.read(b"k1").unwrap().is_none());
+        assert_eq!(database.read(b"k99").unwrap().unwrap().as_slice(), b"merged");
+        drop(database);
+
+        // a truncated hint file is not read partially, its segment is scanned
+        let hint_path = data_dir.join("1-1.hint");
+        let len = std::fs::metadata(&hint_path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&hint_path).unwrap().set_len(len / 2).unwrap();
+        let database = Database::open("testdata/rebuild_hints", Options::default()).unwrap();
+        assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"later");
+        assert!(database.read(b"k1").unwrap().is_none());
+        for i in 2..100 {
+            assert_eq!(database.read(format!("k{}", i)).unwrap().unwrap().as_slice(), b"merged");
+        }
+    }
+
+    #[test]
+    fn test_background_hints() {
+        let dir_path = PathBuf::from("testdata/background_hints");
+        let data_dir = dir_path.join("data");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/background_hints", Options::default()).unwrap();
+            database.write(b"k", b"v1").unwrap();
+            // exporting a snapshot seals the active segment
+            database.export_snapshot(Vec::new()).unwrap();
+            database.write(b"k", b"v2").unwrap();
+        }
+        // dropping database stops the hint thread before its next segment, segments left are hinted after reopen.
+        // So is the segment sealed by reopen.
+        let hinted = || data_dir.join("1.hint").exists() && data_dir.join("2.hint").exists();
+        let database = Database::open("testdata/background_hints", Options::default()).unwrap();
+        assert!(wait_until(hinted));
+        assert_eq!(database.read(b"k").unwrap().unwrap().as_slice(), b"v2");
+        assert_eq!(database.hint_failures(), 0);
+        drop(database);
+
+        // a segment which does not read to its end gets no hint, the failure is counted
+        let mut database = Database::open("testdata/background_hints", Options::default()).unwrap();
+        for i in 0..10 {
+            database.write(format!("k{}", i), b"v").unwrap();
+        }
+        drop(database);
+        let seg_path = data_dir.join("4.seg");
+        let mut content = std::fs::read(&seg_path).unwrap();
+        content[SEGMENT_HEADER_BYTES as usize + 2] = 0x7F; // value length of the first record
+        std::fs::write(&seg_path, content).unwrap();
+        let database = Database::open("testdata/background_hints", Options::default()).unwrap();
+        wait_until(|| database.hint_failures() > 0);
+        assert_eq!(database.hint_failures(), 1);
+        assert!(!data_dir.join("4.hint").exists());
+    }
+
+    #[test]
+    fn test_hint_record_format() {
+        use crate::storage::segment::Segment;
+        let dir_path = PathBuf::from("testdata/hint_record_format");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/hint_record_format", Options::default()).unwrap();
+            for i in 0..10 {
+                database.write(format!("k{}", i), vec![b'v'; i]).unwrap();
+            }
+            database.merge().unwrap();
+        }
+        let hint_path = dir_path.join("data").join("1-1.hint");
+        let database = Database::open("testdata/hint_record_format", Options::default()).unwrap();
+        assert!(wait_until(|| hint_path.exists()));
+        drop(database);
+        let hint_file = Segment::open_read_only(hint_path);
+        let mut count = 0;
+        for hint in hint_file.iter_with_value() {
+            let value = hint.value.unwrap();
+            // segment, separator, offset, value size and timestamp
+            assert_eq!(value.len(), 3 + 1 + 8 + 8 + 8);
+            let timestamp = u64::from_le_bytes(value[20..28].try_into().unwrap());
+            assert!(timestamp > 0);
+            let (record_index, decoded) = Database::decode_hint(hint.key.clone(), hint.flag, value).unwrap();
+            assert_eq!(decoded, Some(timestamp));
+            let i: usize = hint.key.to_string()[1..].parse().unwrap();
+            assert_eq!(record_index.value_size, i as u64);
+            count += 1;
+        }
+        assert_eq!(count, 10);
+
+        // hint records of former versions have no value size
+        let mut legacy = b"3\0".to_vec();
+        legacy.extend_from_slice(&42u64.to_le_bytes());
+        let (record_index, timestamp) = Database::decode_hint(Bytes::from("k"), 0, Bytes::from(legacy)).unwrap();
+        assert_eq!((&*record_index.segment, record_index.offset, record_index.value_size), ("3", 42, 0));
+        assert!(timestamp.is_none());
+
+        // a compressed index keeps generation and index of segments, a segment name which is none is corruption
+        let data_dir = dir_path.join("data");
+        let hints: Vec<(Bytes, u8, Vec<u8>)> = Segment::open_read_only(data_dir.join("1-1.hint"))
+            .iter_with_value()
+            .map(|hint| {
+                let value = hint.value.unwrap();
+                let pivot = value.as_slice().iter().position(|&b| b == 0).unwrap();
+                (hint.key, hint.flag, [b"bad", &value.as_slice()[pivot..]].concat())
+            })
+            .collect();
+        std::fs::remove_file(data_dir.join("1-1.hint")).unwrap();
+        let hint_file = Segment::create(&data_dir, 1, 1, "hint").unwrap();
+        for (key, flag, value) in hints.iter() {
+            hint_file.write(key.as_slice(), value, *flag).unwrap();
+        }
+        drop(hint_file);
+        let options = Options::default().prefix_compressed_index(true);
+        let err = Database::open("testdata/hint_record_format", options).err().unwrap();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+    }
+
+    #[test]
+    fn test_index_stats() {
+        let dir_path = PathBuf::from("testdata/index_stats");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/index_stats", Options::default()).unwrap();
+        assert_eq!(database.index_stats().estimated_bytes(), 0);
+        for i in 0..100 {
+            database.write(format!("key{:03}", i), b"v").unwrap();
+        }
+        database.delete(b"key000").unwrap();
+        let stats = database.index_stats();
+        assert_eq!(stats.entries, 99);
+        assert_eq!(stats.key_bytes, 99 * 6);
+        // all entries share the name of the only segment
+        assert!(stats.segment_name_bytes > 0 && stats.segment_name_bytes < 99);
+        assert!(stats.bytes_per_entry() > 6);
+        assert_eq!(stats.estimated_bytes(), stats.key_bytes + stats.segment_name_bytes + stats.overhead_bytes);
+    }
+
+    #[test]
+    fn test_prefix_compressed_index() {
+        let dir_path = PathBuf::from("testdata/prefix_compressed_index");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let options = Options::default().prefix_compressed_index(true);
+        let key = |i: usize| format!("tenant/0001/users/{:04}", i);
+        {
+            let mut database = Database::open("testdata/prefix_compressed_index", options.clone()).unwrap();
+            // reverse order inserts before the first block and splits blocks
+            for i in (0..500).rev() {
+                database.write(key(i), format!("v{}", i)).unwrap();
+            }
+            database.write(key(7), "updated").unwrap();
+            database.delete(key(0)).unwrap();
+            database.delete(key(250)).unwrap();
+            database.write(b"other", b"x").unwrap();
+        }
+        let database = Database::open("testdata/prefix_compressed_index", options).unwrap();
+        assert_eq!(database.read(key(7)).unwrap().unwrap().as_slice(), b"updated");
+        assert_eq!(database.read(key(499)).unwrap().unwrap().as_slice(), b"v499");
+        assert!(database.read(key(0)).unwrap().is_none());
+        assert!(database.read(key(250)).unwrap().is_none());
+        assert!(database.read(b"tenant").unwrap().is_none());
+        let mut keys: Vec<Bytes> = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (items, next) = database.scan(b"tenant/0001/users/", cursor.as_ref(), 100).unwrap();
+            keys.extend(items.into_iter().map(|(key, _)| key));
+            if next.is_none() {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(keys.len(), 498);
+        assert_eq!(keys[0].as_slice(), key(1).as_bytes());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let mut live = 0;
+        database.for_each(|_, _| {
+            live += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(live, 499);
+        let stats = database.index_stats();
+        assert_eq!(stats.entries, 499);
+        assert!(stats.prefix_saved_bytes > 498 * 16);
+        assert!(stats.estimated_bytes() < stats.key_bytes);
+    }
+
+    #[test]
+    fn test_merge_stats() {
+        let dir_path = PathBuf::from("testdata/merge_stats");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/merge_stats", Options::default()).unwrap();
+        for i in 0..100 {
+            database.write(format!("key{:03}", i), [b'v'; 100]).unwrap();
+        }
+        for i in 0..50 {
+            database.write(format!("key{:03}", i), [b'w'; 100]).unwrap();
+        }
+        for i in 90..100 {
+            database.delete(format!("key{:03}", i)).unwrap();
+        }
+        let stats = database.merge().unwrap();
+        assert_eq!(stats.segments_merged, 1);
+        assert_eq!(stats.records_retained, 90);
+        // 50 overwritten, 10 deleted and their 10 tombstones
+        assert_eq!(stats.records_dropped, 70);
+        assert!(stats.bytes_reclaimed > 60 * 100);
+    }
+
+    #[test]
+    fn test_segment_ids_not_reused() {
+        let dir_path = PathBuf::from("testdata/segment_ids");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let data_dir = dir_path.join("data");
+        for _ in 0..2 {
+            let mut database = Database::open("testdata/segment_ids", Options::default()).unwrap();
+            database.write(b"key", b"value").unwrap();
+        }
+        assert!(data_dir.join("2.seg").exists());
+        for id in 1..=2 {
+            std::fs::remove_file(data_dir.join(format!("{}.seg", id))).unwrap();
+        }
+        {
+            let mut database = Database::open("testdata/segment_ids", Options::default()).unwrap();
+            database.write(b"key", b"value").unwrap();
+        }
+        assert!(data_dir.join("3.seg").exists());
+        assert!(!data_dir.join("1.seg").exists());
+        let manifest = std::fs::read_to_string(data_dir.join("MANIFEST")).unwrap();
+        assert_eq!(manifest.trim(), "next-segment-id 4");
+    }
+
+    #[test]
+    fn test_merge_generations() {
+        let dir_path = PathBuf::from("testdata/merge_generations");
+        let data_dir = dir_path.join("data");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        for round in 1..=2 {
+            let mut database = Database::open("testdata/merge_generations", Options::default()).unwrap();
+            for i in 0..50 {
+                database.write(format!("k{}", i), format!("v{}", round)).unwrap();
+            }
+            database.delete(format!("k{}", round)).unwrap();
+            database.merge().unwrap();
+        }
+        let database = Database::open("testdata/merge_generations", Options::default()).unwrap();
+        let mut segments: Vec<String> = std::fs::read_dir(&data_dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".seg"))
+            .collect();
+        segments.sort();
+        // output of the second merge replaced the older segments it covered,
+        // the segment which was active during merge keeps generation 1
+        assert_eq!(segments, vec!["1-4.seg", "2-1.seg", "2-5.seg"]);
+        assert_eq!(database.read(b"k0").unwrap().unwrap().as_slice(), b"v2");
+        assert_eq!(database.read(b"k1").unwrap().unwrap().as_slice(), b"v2");
+        assert!(database.read(b"k2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_checksum_algorithm() {
+        let dir_path = PathBuf::from("testdata/checksum_algorithm");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let crc32c = Options::default().checksum(ChecksumAlgorithm::Crc32c);
+        {
+            let mut database = Database::open("testdata/checksum_algorithm", crc32c.clone()).unwrap();
+            database.write(b"a", vec![b'v'; 100]).unwrap();
+        }
+        {
+            // segments of both algorithms are read by either setting
+            let crc16 = Options::default().checksum(ChecksumAlgorithm::Crc16);
+            let mut database = Database::open("testdata/checksum_algorithm", crc16).unwrap();
+            database.write(b"b", b"crc16").unwrap();
+        }
+        let seg_path = dir_path.join("data").join("1.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        assert_eq!(data[0] & (0b11 << 3), 1 << 3);
+        for options in [crc32c.clone(), Options::default().checksum(ChecksumAlgorithm::Crc16).mmap(false)] {
+            let database = Database::open("testdata/checksum_algorithm", options).unwrap();
+            assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), &[b'v'; 100]);
+            assert_eq!(database.read(b"b").unwrap().unwrap().as_slice(), b"crc16");
+        }
+        let i = data.len() - 10;
+        data[i] ^= 0xff;
+        std::fs::write(&seg_path, &data).unwrap();
+        let database = Database::open("testdata/checksum_algorithm", crc32c).unwrap();
+        let err = database.read(b"a").unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { detail, .. }) if detail == "checksum mismatch"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_tar_size() {
+        use crate::utils::tar::{read_size, write_size};
+        // sizes of 8GiB and more do not fit 11 octal digits, they are written in base-256
+        for size in [0, 1, (1u64 << 33) - 1, 1u64 << 33, u64::MAX] {
+            let mut field = [0u8; 12];
+            write_size(&mut field, size);
+            assert_eq!(field[0] & 0x80 != 0, size >= 1u64 << 33, "{}", size);
+            assert_eq!(read_size(&field).unwrap(), size);
+        }
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[3] = 1;
+        assert!(read_size(&field).is_err());
+    }
+
+    #[test]
+    fn test_xxh64() {
+        use crate::utils::xxhash::Xxh64;
+        let hash = |parts: &[&[u8]]| {
+            let mut hasher = Xxh64::new(0);
+            parts.iter().for_each(|part| hasher.update(part));
+            hasher.finish()
+        };
+        assert_eq!(hash(&[]), 0xEF46DB3751D8E999);
+        assert_eq!(hash(&[b"a"]), 0xD24EC4F1A98C6E5B);
+        assert_eq!(hash(&[b"abc"]), 0x44BC2CF5AD770999);
+        // inputs of whole stripes, then 8 and 4 byte words and 0 to 3 single bytes
+        assert_eq!(hash(&[b"Nobody inspects the spammish repetition"]), 0xFBCEA83C8A378BF1);
+        let data: Vec<u8> = (0..200u8).collect();
+        assert_eq!(hash(&[&data]), 0x50DC1079B99E879C);
+        assert_eq!(hash(&[&data[..35]]), 0xF8C4B2DACBDCBA83);
+        // parts split anywhere hash like one slice, across stripes too
+        let whole = hash(&[&data]);
+        for split in [1, 31, 32, 33, 100] {
+            assert_eq!(hash(&[&data[..split], &data[split..]]), whole);
+        }
+
+        let dir_path = PathBuf::from("testdata/xxh64");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let options = Options::default().checksum(ChecksumAlgorithm::Xxh64);
+        {
+            let mut database = Database::open("testdata/xxh64", options.clone()).unwrap();
+            database.write(b"k", vec![b'v'; 1000]).unwrap();
+            database.write(b"small", b"v").unwrap();
+        }
+        for mmap in [true, false] {
+            let database = Database::open("testdata/xxh64", Options::default().mmap(mmap)).unwrap();
+            assert_eq!(database.read(b"k").unwrap().unwrap().len(), 1000);
+            assert_eq!(database.read(b"small").unwrap().unwrap().as_slice(), b"v");
+        }
+    }
+
+    #[test]
+    fn test_header_checksum() {
+        let dir_path = PathBuf::from("testdata/header_checksum");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/header_checksum", Options::default()).unwrap();
+            database.write_with_options(b"a", b"value", &WriteOptions::default().metadata(5)).unwrap();
+        }
+        // a flipped metadata byte leaves key and value intact
+        let seg_path = dir_path.join("data").join("1.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        let i = SEGMENT_HEADER_BYTES as usize + 1;
+        assert_eq!(data[i], 5);
+        data[i] = 6;
+        std::fs::write(&seg_path, &data).unwrap();
+        for options in [Options::default(), Options::default().mmap(false)] {
+            let database = Database::open("testdata/header_checksum", options).unwrap();
+            let err = database.read_with_metadata(b"a").unwrap_err();
+            assert!(
+                matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { detail, .. }) if detail == "checksum mismatch"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_format_v1() {
+        let dir_path = PathBuf::from("testdata/record_format_v1");
+        let data_dir = dir_path.join("data");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        // v1 record: flag 0, lengths, key, value and crc16 of key and value in 4 bytes
+        let mut record = vec![0u8, 1, 5];
+        record.extend_from_slice(b"avalue");
+        record.extend_from_slice(&ChecksumAlgorithm::Crc16.digest(&[], b"a", b"value")[..4]);
+        std::fs::write(data_dir.join("1.seg"), &record).unwrap();
+        std::fs::write(data_dir.join("2.seg"), b"").unwrap();
+        for options in [Options::default(), Options::default().mmap(false)] {
+            let database = Database::open("testdata/record_format_v1", options).unwrap();
+            assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"value");
+        }
+        // migration rewrites it in the current format
+        let stats = Database::open("testdata/record_format_v1", Options::default()).unwrap().migrate_format().unwrap();
+        assert_eq!(stats.records_retained, 1);
+        let database = Database::open("testdata/record_format_v1", Options::default()).unwrap();
+        let data = std::fs::read(data_dir.join("1-1.seg")).unwrap();
+        let header = SEGMENT_HEADER_BYTES as usize;
+        assert_eq!(data[header], (1 << 3) | (1 << 5));
+        assert_eq!(data.len(), header + record.len());
+        assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"value");
+        assert_eq!(database.migrate_format().unwrap().segments_merged, 0);
+    }
+
+    #[test]
+    fn test_format_version() {
+        let dir_path = PathBuf::from("testdata/format_version");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/format_version", Options::default()).unwrap();
+            database.write(b"a", b"value").unwrap();
+        }
+        // segment header is skipped by iteration and reads
+        let seg_path = dir_path.join("data").join("1.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        assert_eq!(&data[3..10], b"bitcask");
+        assert_eq!(data[10], 4);
+        {
+            let database = Database::open("testdata/format_version", Options::default()).unwrap();
+            assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"value");
+            assert_eq!(database.index_stats().entries, 1);
+        }
+        // a segment of a newer format is refused
+        data[10] = 5;
+        let sum = ChecksumAlgorithm::Crc32c.digest(&data[..3], b"bitcask", &[5]);
+        data[11..15].copy_from_slice(&sum[..4]);
+        std::fs::write(&seg_path, &data).unwrap();
+        let err = Database::open("testdata/format_version", Options::default()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::UnsupportedFormat(_))), "{}", err);
+    }
+
+    #[test]
+    fn test_redactor() {
+        let dir_path = PathBuf::from("testdata/redactor");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        let mut database = Database::open("testdata/redactor", Options::default()).unwrap();
+        database.write(b"name", b"bob").unwrap();
+        database.write(b"password", b"hunter2").unwrap();
+        database.set_redactor(|key, _| (key == b"password").then(|| b"***".to_vec()));
+        // reads are not redacted
+        assert_eq!(database.read(b"password").unwrap().unwrap().as_slice(), b"hunter2");
+        let mut out: Vec<u8> = Vec::new();
+        assert_eq!(database.export_resp(&mut out).unwrap(), 2);
+        assert_eq!(
+            out.as_slice(),
+            b"*3\r\n$3\r\nSET\r\n$4\r\nname\r\n$3\r\nbob\r\n*3\r\n$3\r\nSET\r\n$8\r\npassword\r\n$3\r\n***\r\n"
+        );
+        let mut dumped: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        database
+            .dump(|key, value| {
+                dumped.push((key.as_slice().to_vec(), value.as_slice().to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(dumped, vec![(b"name".to_vec(), b"bob".to_vec()), (b"password".to_vec(), b"***".to_vec())]);
+        let mut sst: Vec<u8> = Vec::new();
+        database.export_sstable(&mut sst).unwrap();
+        let mut copy = Database::open("testdata/redactor/copy", Options::default()).unwrap();
+        copy.ingest_sstable(sst.as_slice()).unwrap();
+        assert_eq!(copy.read(b"password").unwrap().unwrap().as_slice(), b"***");
+    }
+
+    #[test]
+    fn test_read_repair() {
+        let dir_path = PathBuf::from("testdata/read_repair");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/read_repair", Options::default()).unwrap();
+            database.write(b"a", b"old-a").unwrap();
+            database.write(b"b", b"old-b").unwrap();
+            database.delete(b"b").unwrap();
+        }
+        {
+            let mut database = Database::open("testdata/read_repair", Options::default()).unwrap();
+            database.write(b"a", b"new-a").unwrap();
+            database.write(b"b", b"new-b").unwrap();
+        }
+        // damage the latest values, the older copies are in the former segment
+        let seg_path = dir_path.join("data").join("2.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        for value in [b"new-a", b"new-b"] {
+            let i = data.windows(5).position(|w| w == value).unwrap();
+            data[i] ^= 0xff;
+        }
+        std::fs::write(&seg_path, &data).unwrap();
+        for options in [Options::default(), Options::default().mmap(false)] {
+            let database = Database::open("testdata/read_repair", options.clone()).unwrap();
+            assert!(database.read(b"a").is_err());
+            let database = Database::open("testdata/read_repair", options.read_repair(true)).unwrap();
+            assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"old-a");
+            // the older copy of b is a deletion, it is no answer
+            let err = database.read(b"b").unwrap_err();
+            assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+            assert_eq!(database.repaired_reads(), 1);
+        }
+
+        // a record with a damaged header ends the scan of its segment, older segments answer anyway
+        let dir_path = PathBuf::from("testdata/read_repair_header");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        for value in [b"old-a", b"new-a"] {
+            let mut database = Database::open("testdata/read_repair_header", Options::default()).unwrap();
+            database.write(b"a", value).unwrap();
+        }
+        // 2.seg is hinted, so it is indexed without a scan
+        let seg_path = dir_path.join("data").join("2.seg");
+        let database = Database::open("testdata/read_repair_header", Options::default()).unwrap();
+        assert!(wait_until(|| seg_path.with_extension("hint").exists()));
+        drop(database);
+        let offset = crate::storage::segment::Segment::open_read_only(seg_path.clone()).iter().next().unwrap().offset;
+        let mut data = std::fs::read(&seg_path).unwrap();
+        // key length follows the flag
+        data[offset as usize + 1] ^= 0xff;
+        std::fs::write(&seg_path, &data).unwrap();
+        let database = Database::open("testdata/read_repair_header", Options::default().read_repair(true)).unwrap();
+        assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"old-a");
+        assert_eq!(database.repaired_reads(), 1);
+    }
+
+    #[test]
+    fn test_paranoid_checks() {
+        use crate::storage::segment::Segment;
+        let dir_path = PathBuf::from("testdata/paranoid_checks");
+        let data_dir = dir_path.join("data");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/paranoid_checks", Options::default()).unwrap();
+            database.write(b"a", b"1").unwrap();
+            database.write(b"b", b"2").unwrap();
+        }
+        // a broken hint file swaps locations of a and b
+        let offsets: Vec<u64> = Segment::open_read_only(data_dir.join("1.seg")).iter().map(|ri| ri.offset).collect();
+        let _ = std::fs::remove_file(data_dir.join("1.hint"));
+        let hint_file = Segment::create(&data_dir, 0, 1, "hint").unwrap();
+        for (key, offset) in [(b"a", offsets[1]), (b"b", offsets[0])] {
+            let mut value = b"1\0".to_vec();
+            for field in [offset, 1, 1] {
+                value.extend_from_slice(&field.to_le_bytes());
+            }
+            hint_file.write(key, &value, 0).unwrap();
+        }
+        drop(hint_file);
+        let database = Database::open("testdata/paranoid_checks", Options::default()).unwrap();
+        assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        let database = Database::open("testdata/paranoid_checks", Options::default().paranoid_checks(true)).unwrap();
+        let err = database.read(b"a").unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { detail, .. }) if detail == "index entry points to a record of another key"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_store_stats() {
+        let dir_path = PathBuf::from("testdata/store_stats");
+        let stats_path = dir_path.join("data").join("STATS");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/store_stats", Options::default()).unwrap();
+            for i in 0..10 {
+                database.write(format!("k{}", i), b"v1").unwrap();
+            }
+            database.write(b"k0", b"v2").unwrap();
+            database.delete(b"k1").unwrap();
+            let stats = database.stats().unwrap();
+            assert_eq!(stats.keys, 9);
+            assert_eq!((stats.segments[0].records, stats.segments[0].live, stats.segments[0].garbage()), (12, 9, 3));
+            assert_eq!(stats.last_merge_ms, None);
+        }
+        // the count of a segment is persisted once its hint is written, before any call of stats
+        assert!(!stats_path.exists());
+        let database = Database::open("testdata/store_stats", Options::default()).unwrap();
+        assert!(wait_until(|| stats_path.exists()));
+        drop(database);
+        assert_eq!(std::fs::read_to_string(&stats_path).unwrap(), "segment 1 12\n");
+        {
+            let database = Database::open("testdata/store_stats", Options::default()).unwrap();
+            let stats = database.stats().unwrap();
+            assert_eq!(stats.segments.len(), 3);
+            assert_eq!((stats.segments[0].segment.as_str(), stats.segments[0].garbage()), ("1", 3));
+            assert_eq!(std::fs::read_to_string(&stats_path).unwrap(), "segment 1 12\nsegment 2 0\n");
+            database.merge().unwrap();
+        }
+        assert!(Database::open("testdata/store_stats", Options::default()).unwrap().stats().unwrap().last_merge_ms.is_some());
+        // counts of sealed segments come from the file after restart
+        let content = std::fs::read_to_string(&stats_path).unwrap();
+        assert!(content.starts_with("last-merge ") && content.contains("segment 1-1 9\n"), "{}", content);
+        std::fs::write(&stats_path, content.replace("segment 1-1 9", "segment 1-1 20")).unwrap();
+        let database = Database::open("testdata/store_stats", Options::default()).unwrap();
+        let stats = database.stats().unwrap();
+        assert!(stats.last_merge_ms.is_some());
+        assert_eq!(stats.segments[0].segment, "1-1");
+        assert_eq!((stats.segments[0].records, stats.segments[0].live), (20, 9));
+    }
+
+    #[test]
+    fn test_atomic_batch() {
+        let dir_path = PathBuf::from("testdata/atomic_batch");
+        let seg_path = dir_path.join("data").join("1.seg");
+        let _ = std::fs::remove_dir_all(&dir_path);
+        std::fs::create_dir_all(&dir_path).unwrap();
+        {
+            let mut database = Database::open("testdata/atomic_batch", Options::default()).unwrap();
+            database.write(b"x", b"single").unwrap();
+            database.write_many(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")]).unwrap();
+        }
+        let data = std::fs::read(&seg_path).unwrap();
+        // a batch without its commit is not loaded at all, the records before it are
+        for cut in [1, 20] {
+            std::fs::write(&seg_path, &data[..data.len() - cut]).unwrap();
+            let _ = std::fs::remove_file(dir_path.join("data").join("1.hint"));
+            let database = Database::open("testdata/atomic_batch", Options::default()).unwrap();
+            assert_eq!(database.read(b"x").unwrap().unwrap().as_slice(), b"single");
+            for key in [b"a", b"b", b"c"] {
+                assert!(database.read(key).unwrap().is_none());
+            }
+        }
+        std::fs::write(&seg_path, &data).unwrap();
+        let _ = std::fs::remove_file(dir_path.join("data").join("1.hint"));
+        let mut database = Database::open("testdata/atomic_batch", Options::default()).unwrap();
+        assert_eq!(database.read(b"c").unwrap().unwrap().as_slice(), b"3");
+        assert_eq!(database.delete_many(&[b"a", b"b"]).unwrap(), 2);
+        database.merge().unwrap();
+        drop(database);
+        let database = Database::open("testdata/atomic_batch", Options::default()).unwrap();
+        assert!(database.read(b"a").unwrap().is_none());
+        assert_eq!(database.read(b"c").unwrap().unwrap().as_slice(), b"3");
+        assert_eq!(database.stats().unwrap().segments[0].records, 2);
+    }
+
+    #[test]
+    fn test_iter_during_merge() {
+        let dir = "testdata/iter_during_merge";
+        let _ = std::fs::remove_dir_all(dir);
+        for round in 0..2 {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..2500 {
+                database.write(format!("k{:05}", i).as_bytes(), format!("v{}-{}", round, i).as_bytes()).unwrap();
+            }
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        let mut iter = database.iter();
+        let first: Vec<Entry> = iter.by_ref().take(1500).collect();
+        database.merge().unwrap();
+        let rest: Vec<Entry> = iter.collect();
+        assert_eq!(first.len() + rest.len(), 2500);
+        for (i, entry) in first.iter().chain(rest.iter()).enumerate() {
+            assert_eq!(entry.key().as_slice(), format!("k{:05}", i).as_bytes());
+            assert_eq!(entry.value().unwrap().as_slice(), format!("v1-{}", i).as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_async_merge() {
+        let dir = "testdata/async_merge";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..2000 {
+                database.write(format!("k{}", i).as_bytes(), b"old").unwrap();
+            }
+        }
+        let writer = Database::open(dir, Options::default()).unwrap().into_async(64);
+        std::thread::scope(|s| {
+            let merge = s.spawn(|| writer.merge().unwrap());
+            // overwrites while merging are newer than merged output
+            let handles: Vec<_> = (0..1000).map(|i| writer.write(format!("k{}", i).as_bytes(), b"new").unwrap()).collect();
+            for handle in handles {
+                handle.wait().unwrap();
+            }
+            assert!(merge.join().unwrap().segments_merged >= 1);
+        });
+        // merged output is adopted by merge, keys overwritten meanwhile keep their entries
+        for i in 0..2000 {
+            let expected: &[u8] = if i < 1000 { b"new" } else { b"old" };
+            assert_eq!(writer.read(format!("k{}", i).as_bytes()).unwrap().unwrap().as_slice(), expected);
+        }
+        let database = writer.close().unwrap();
+        let data_dir = PathBuf::from(dir).join("data");
+        assert!(!data_dir.join("1.seg").exists() && data_dir.join("1-1.seg").exists());
+        assert!(!PathBuf::from(dir).join("merged").exists());
+        let metadata = database.metadata(b"k1999").unwrap().unwrap();
+        assert_eq!(metadata.segment, "1-1");
+        drop(database);
+        let database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..2000 {
+            let expected: &[u8] = if i < 1000 { b"new" } else { b"old" };
+            assert_eq!(database.read(format!("k{}", i).as_bytes()).unwrap().unwrap().as_slice(), expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_adopted_while_open() {
+        let dir = "testdata/merge_adopted_while_open";
+        let _ = std::fs::remove_dir_all(dir);
+        for round in 0..2 {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i), format!("v{}-{}", round, i)).unwrap();
+            }
+        }
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        database.delete(b"k0").unwrap();
+        let (_, version) = database.read_with_version(b"k1").unwrap().unwrap();
+        let stats = database.merge().unwrap();
+        assert_eq!(stats.segments_merged, 3);
+        // moved records keep their versions, sources are gone
+        assert_eq!(database.read_with_version(b"k1").unwrap().unwrap(), (Bytes::from("v1-1"), version));
+        assert!(database.read(b"k0").unwrap().is_none());
+        let data_dir = PathBuf::from(dir).join("data");
+        assert!(!data_dir.join("1.seg").exists() && !data_dir.join("2.seg").exists());
+        database.write(b"k2", b"later").unwrap();
+        assert_eq!(database.stats().unwrap().segments.len(), 2);
+        drop(database);
+        let database = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(database.read(b"k2").unwrap().unwrap().as_slice(), b"later");
+        assert_eq!(database.read(b"k99").unwrap().unwrap().as_slice(), b"v1-99");
+        assert!(database.read(b"k0").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_concurrent_merges() {
+        let dir = "testdata/concurrent_merges";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..2000 {
+                database.write(format!("k{}", i).as_bytes(), format!("v{}", i).as_bytes()).unwrap();
+            }
+        }
+        let writer = Database::open(dir, Options::default()).unwrap().into_async(64);
+        // merges are serialized, none of them removes merge dir while another one writes it
+        std::thread::scope(|s| {
+            let merges: Vec<_> = (0..4).map(|_| s.spawn(|| writer.merge().unwrap())).collect();
+            for merge in merges {
+                merge.join().unwrap();
+            }
+        });
+        drop(writer.close().unwrap());
+        let database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..2000 {
+            assert_eq!(
+                database.read(format!("k{}", i).as_bytes()).unwrap().unwrap().as_slice(),
+                format!("v{}", i).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_async_delete() {
+        let dir = "testdata/async_delete";
+        let _ = std::fs::remove_dir_all(dir);
+        let writer = Database::open(dir, Options::default()).unwrap().into_async(64);
+        writer.write(b"a", b"1").unwrap().wait().unwrap();
+        // mutations of one batch apply in order
+        let b = writer.write(b"b", b"1").unwrap();
+        let deleted = [writer.delete(b"a").unwrap(), writer.delete(b"a").unwrap(), writer.delete(b"missing").unwrap()];
+        let b_again = writer.write(b"b", b"2").unwrap();
+        let delete_b = writer.delete(b"b").unwrap();
+        let a_again = writer.write(b"a", b"2").unwrap();
+        b.wait().unwrap();
+        let existed: Vec<bool> = deleted.into_iter().map(|handle| handle.wait().unwrap()).collect();
+        assert_eq!(existed, vec![true, false, false]);
+        b_again.wait().unwrap();
+        assert!(delete_b.wait().unwrap());
+        a_again.wait().unwrap();
+        drop(writer.close().unwrap());
+        let database = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(database.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        assert!(database.read(b"b").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sharded_database() {
+        let dir = "testdata/sharded";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let database = ShardedDatabase::open(dir, 4, Options::default()).unwrap();
+            std::thread::scope(|s| {
+                for t in 0..4 {
+                    let database = &database;
+                    s.spawn(move || {
+                        for i in 0..500 {
+                            database.write(format!("t{}-{}", t, i).as_bytes(), b"v").unwrap();
+                        }
+                    });
+                }
+            });
+            assert!(database.delete(b"t0-0").unwrap());
+            assert_eq!(database.merge().unwrap().len(), 4);
+            // every shard holds a part of keys
+            for shard in 0..4 {
+                assert!(database.with_shard(shard, |db| db.iter().count()) > 0);
+            }
+        }
+        assert!(ShardedDatabase::open(dir, 2, Options::default()).is_err());
+        let database = ShardedDatabase::open(dir, 4, Options::default()).unwrap();
+        assert!(database.read(b"t0-0").unwrap().is_none());
+        assert_eq!(database.read(b"t3-499").unwrap().unwrap().as_slice(), b"v");
+        let shard = database.shard_of(b"t3-499");
+        assert!(database.with_shard(shard, |db| db.read(b"t3-499").unwrap()).is_some());
+        database.with_shard_mut(shard, |db| db.write(b"t3-500", b"v")).unwrap();
+
+        // pages of a scan hold records of all shards in key order
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = database.scan(b"t1-", cursor.as_ref(), 7).unwrap();
+            assert!(page.len() <= 7);
+            keys.extend(page.into_iter().map(|(key, _)| key.as_slice().to_vec()));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let mut expected: Vec<Vec<u8>> = (0..500).map(|i| format!("t1-{}", i).into_bytes()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        drop(database);
+
+        std::fs::write(std::path::Path::new(dir).join("SHARDS"), b"four\n").unwrap();
+        let err = ShardedDatabase::open(dir, 4, Options::default()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+    }
+
+    #[test]
+    fn test_inline_values() {
+        let dir = "testdata/inline_values";
+        let _ = std::fs::remove_dir_all(dir);
+        let options = Options::default().inline_values(8);
+        {
+            let mut database = Database::open(dir, options.clone()).unwrap();
+            database.write(b"small", b"1").unwrap();
+            database.write_many(&[(b"tiny", b"22"), (b"large", b"too large to inline")]).unwrap();
+            database.write_with_options(b"meta", b"3", &WriteOptions::default().metadata(7)).unwrap();
+            assert_eq!(database.index_stats().inline_value_bytes, 3);
+        }
+        let mut database = Database::open(dir, options).unwrap();
+        // values loaded from disk are inlined on first read
+        assert_eq!(database.index_stats().inline_value_bytes, 0);
+        assert_eq!(database.read(b"small").unwrap().unwrap().as_slice(), b"1");
+        assert_eq!(database.read(b"large").unwrap().unwrap().as_slice(), b"too large to inline");
+        assert_eq!(database.read_with_metadata(b"meta").unwrap().unwrap().1, 7);
+        assert_eq!(database.index_stats().inline_value_bytes, 1);
+        database.write(b"small", b"new").unwrap();
+        assert_eq!(database.read(b"small").unwrap().unwrap().as_slice(), b"new");
+        assert_eq!(database.index_stats().inline_value_bytes, 3);
+        // inlined values are read without disk
+        database.write(b"large", b"also too large").unwrap();
+        let seg_path = PathBuf::from(dir).join("data").join("2.seg");
+        let mut data = std::fs::read(&seg_path).unwrap();
+        for record in [&b"smallnew"[..], b"largealso too large"] {
+            let at = data.windows(record.len()).position(|w| w == record).unwrap();
+            data[at + record.len() - 1] ^= 0xff;
+        }
+        std::fs::write(&seg_path, &data).unwrap();
+        database.read(b"large").unwrap_err();
+        assert_eq!(database.read(b"small").unwrap().unwrap().as_slice(), b"new");
+        assert!(database.delete(b"small").unwrap());
+        assert!(database.read(b"small").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_io_stats() {
+        let dir = "testdata/io_stats";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let options = Options::default().mmap(false).sync(SyncPolicy::Always);
+            let mut database = Database::open(dir, options).unwrap();
+            assert_eq!(database.io_stats(), IoStats::default());
+            // the record ends 2 bytes before the end of the first block, the next header does not fit
+            let value = vec![b'v'; 32741];
+            database.write(b"k", &value).unwrap();
+            database.write(b"x", b"1").unwrap();
+            let stats = database.io_stats();
+            assert_eq!(stats.padding_bytes, 2);
+            assert_eq!(stats.bytes_written, 32768 - SEGMENT_HEADER_BYTES + 9);
+            assert_eq!(stats.fsyncs, 2);
+            assert_eq!(database.read(b"k").unwrap().unwrap().len(), value.len());
+            let stats = database.io_stats();
+            assert_eq!((stats.read_calls, stats.mmap_reads), (2, 0));
+            // a header read of at most 22 bytes, then key, value and checksum
+            assert_eq!(stats.bytes_read, 22 + 1 + 32741 + 4);
+        }
+        let database = Database::open(dir, Options::default()).unwrap();
+        let opened = database.io_stats();
+        assert!(opened.read_calls > 0);
+        database.read(b"x").unwrap().unwrap();
+        let stats = database.io_stats();
+        assert_eq!((stats.read_calls, stats.mmap_reads), (opened.read_calls, opened.mmap_reads + 1));
+    }
+
+    #[test]
+    fn test_sync() {
+        let dir = "testdata/sync";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        database.write(b"k", b"v").unwrap();
+        assert_eq!(database.io_stats().fsyncs, 0);
+        database.sync().unwrap();
+        assert_eq!(database.io_stats().fsyncs, 1);
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = "testdata/segments";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut database = Database::open(dir, Options::default()).unwrap();
+            for i in 0..100 {
+                database.write(format!("k{}", i).as_bytes(), [b'v'; 100]).unwrap();
+            }
+        }
+        let mut database = Database::open(dir, Options::default()).unwrap();
+        for i in 0..50 {
+            database.write(format!("k{}", i).as_bytes(), [b'w'; 100]).unwrap();
+        }
+        database.rebuild_hints().unwrap();
+        let segments = database.segments().unwrap();
+        assert_eq!(segments.len(), 2);
+        let (sealed, active) = (&segments[0], &segments[1]);
+        assert_eq!(sealed.segment, "1");
+        assert!(sealed.has_hint && !sealed.active && active.active && !active.has_hint);
+        assert_eq!((sealed.records, sealed.live, active.records, active.live), (100, 50, 50, 50));
+        assert_eq!(sealed.bytes, std::fs::metadata(&sealed.path).unwrap().len());
+        // 50 overwritten records of 1 + 1 + 1 + 2 or 3 + 100 + 4 bytes and the header
+        assert_eq!(sealed.garbage_bytes, 10 * 109 + 40 * 110 + SEGMENT_HEADER_BYTES);
+        assert_eq!(active.garbage_bytes, SEGMENT_HEADER_BYTES);
+        assert!(sealed.created_secs > 0);
+    }
+
+    #[test]
+    fn test_segment_transfer() {
+        let (source_dir, target_dir) = ("testdata/segment_transfer_source", "testdata/segment_transfer_target");
+        let _ = std::fs::remove_dir_all(source_dir);
+        let _ = std::fs::remove_dir_all(target_dir);
+        {
+            let mut source = Database::open(source_dir, Options::default()).unwrap();
+            source.write(b"a", b"1").unwrap();
+            source.write(b"b", b"1").unwrap();
+            source.write(b"a", b"2").unwrap();
+            source.delete(b"b").unwrap();
+            source.write(b"c", b"1").unwrap();
+        }
+        let mut source = Database::open(source_dir, Options::default()).unwrap();
+        source.rebuild_hints().unwrap();
+        // only sealed segments are exported
+        assert!(source.export_segment("2", Vec::new()).is_err());
+        let unit = source.export_segment("1", Vec::new()).unwrap();
+        source.set_redactor(|_, _| None);
+        assert!(source.export_segment("1", Vec::new()).is_err());
+
+        let mut target = Database::open(target_dir, Options::default()).unwrap();
+        target.write(b"b", b"stays").unwrap();
+        target.write(b"c", b"replaced").unwrap();
+        assert_eq!(target.import_segment(unit.as_slice()).unwrap(), 2);
+        assert_eq!(target.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        assert_eq!(target.read(b"b").unwrap().unwrap().as_slice(), b"stays");
+        assert_eq!(target.read(b"c").unwrap().unwrap().as_slice(), b"1");
+        assert!(target.import_segment(&b"not an archive"[..]).is_err());
+        drop(target);
+        let mut target = Database::open(target_dir, Options::default()).unwrap();
+        assert_eq!(target.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        assert!(!PathBuf::from(target_dir).join("data").join("segment.import").exists());
+
+        // a segment whose iteration ends before its end is not imported
+        drop(source);
+        let path = PathBuf::from(source_dir).join("data").join("1.seg");
+        let mut content = std::fs::read(&path).unwrap();
+        content.push(1); // a padding flag without block behind
+        std::fs::write(&path, content).unwrap();
+        let source = Database::open(source_dir, Options::default()).unwrap();
+        let unit = source.export_segment("1", Vec::new()).unwrap();
+        let err = target.import_segment(unit.as_slice()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+    }
+
+    #[test]
+    fn test_follower() {
+        let dir = "testdata/follower";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut writer = Database::open(dir, Options::default()).unwrap();
+        writer.write(b"a", b"1").unwrap();
+        writer.write(b"b", b"1").unwrap();
+        let follower = Follower::open(dir, None).unwrap();
+        assert_eq!(follower.read(b"a").unwrap().unwrap().as_slice(), b"1");
+
+        // the active segment grows
+        writer.write(b"a", b"2").unwrap();
+        writer.delete(b"b").unwrap();
+        assert_eq!(follower.read(b"a").unwrap().unwrap().as_slice(), b"1");
+        follower.refresh().unwrap();
+        assert_eq!(follower.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        assert!(follower.read(b"b").unwrap().is_none());
+
+        // a reopened writer seals its active segment and writes a new one
+        drop(writer);
+        let mut writer = Database::open(dir, Options::default()).unwrap();
+        writer.write(b"c", b"1").unwrap();
+        follower.refresh().unwrap();
+        assert_eq!(follower.read(b"c").unwrap().unwrap().as_slice(), b"1");
+        assert_eq!(follower.len(), 2);
+
+        // adoption of merge output reloads the keydir
+        writer.write(b"a", b"3").unwrap();
+        writer.merge().unwrap();
+        drop(writer);
+        let mut writer = Database::open(dir, Options::default()).unwrap();
+        writer.write(b"d", b"1").unwrap();
+        follower.refresh().unwrap();
+        assert_eq!(follower.read(b"a").unwrap().unwrap().as_slice(), b"3");
+        assert_eq!(follower.read(b"d").unwrap().unwrap().as_slice(), b"1");
+        assert_eq!(follower.len(), 3);
+
+        // the refresh thread follows too
+        let background = Follower::open(dir, Some(std::time::Duration::from_millis(10))).unwrap();
+        writer.write(b"e", b"1").unwrap();
+        for _ in 0..500 {
+            if background.read(b"e").unwrap().is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(background.read(b"e").unwrap().unwrap().as_slice(), b"1");
+    }
+
+    #[test]
+    fn test_invalidator() {
+        let dir = "testdata/invalidator";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        db.set_invalidator(move |key, op| recorded.lock().unwrap().push((key.to_vec(), op)));
+        db.write(b"a", b"1").unwrap();
+        db.write_many(&[(b"b", b"1"), (b"c", b"1")]).unwrap();
+        db.delete(b"a").unwrap();
+        // missing keys are not deleted, so they are not invalidated
+        db.delete(b"missing").unwrap();
+        db.delete_many(&[b"b", b"missing"]).unwrap();
+        db.merge().unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            *calls,
+            vec![
+                (b"a".to_vec(), WriteOp::Write),
+                (b"b".to_vec(), WriteOp::Write),
+                (b"c".to_vec(), WriteOp::Write),
+                (b"a".to_vec(), WriteOp::Delete),
+                (b"b".to_vec(), WriteOp::Delete),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        let dir = "testdata/tail";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default().blob_threshold(16)).unwrap();
+        db.write(b"before", b"0").unwrap();
+        let tail = db.tail().unwrap();
+        let consumer = std::thread::spawn(move || tail.map(|record| record.unwrap()).collect::<Vec<_>>());
+        db.write(b"a", b"1").unwrap();
+        // exporting a snapshot seals the active segment, tail goes on with the next one
+        db.export_snapshot(Vec::new()).unwrap();
+        db.write_many(&[(b"b", b"2"), (b"c", vec![b'v'; 32].as_slice())]).unwrap();
+        db.delete(b"a").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        db.write(b"d", b"4").unwrap();
+        // dropping the database ends the tail once it yielded every record
+        drop(db);
+        let records = consumer.join().unwrap();
+        let records: Vec<(&[u8], Option<&[u8]>)> =
+            records.iter().map(|(key, value)| (key.as_slice(), value.as_ref().map(|v| v.as_slice()))).collect();
+        let blob = vec![b'v'; 32];
+        assert_eq!(
+            records,
+            vec![
+                (b"a".as_slice(), Some(b"1".as_slice())),
+                (b"b", Some(b"2")),
+                (b"c", Some(blob.as_slice())),
+                (b"a", None),
+                (b"d", Some(b"4")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_filter() {
+        let dir = "testdata/key_filter";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let mut db = Database::open(dir, Options::default()).unwrap();
+            db.write(b"user:1", b"a").unwrap();
+            db.write(b"order:1", b"b").unwrap();
+            db.write(b"user:2", b"c").unwrap();
+            db.delete(b"user:2").unwrap();
+        }
+        let mut db = Database::open(dir, Options::default().key_prefix("user:")).unwrap();
+        assert_eq!(db.index_stats().entries, 1);
+        assert_eq!(db.read(b"user:1").unwrap().unwrap().as_slice(), b"a");
+        assert!(db.read(b"user:2").unwrap().is_none());
+        let err = db.read(b"order:1").unwrap_err();
+        assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::NotIndexed(b"order:1".to_vec())));
+        assert!(db.write(b"order:2", b"d").is_err());
+        assert!(db.delete(b"order:1").is_err());
+        assert!(db.merge().is_err());
+        db.write(b"user:3", b"e").unwrap();
+        drop(db);
+
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.index_stats().entries, 3);
+        assert_eq!(db.read(b"order:1").unwrap().unwrap().as_slice(), b"b");
+    }
+
+    #[test]
+    fn test_hint_audit() {
+        let (source_dir, dir) = ("testdata/hint_audit_source", "testdata/hint_audit");
+        let _ = std::fs::remove_dir_all(source_dir);
+        let _ = std::fs::remove_dir_all(dir);
+        for (path, pairs) in [(source_dir, [(b"a", b"1"), (b"b", b"2")]), (dir, [(b"b", b"3"), (b"a", b"4")])] {
+            let mut db = Database::open(path, Options::default()).unwrap();
+            for (key, value) in pairs {
+                db.write(key, value).unwrap();
+            }
+            drop(db);
+            Database::open(path, Options::default()).unwrap().rebuild_hints().unwrap();
+        }
+        // the hint of another segment 1 points to records of other keys
+        let hint = PathBuf::from(dir).join("data").join("1.hint");
+        let stale = std::fs::read(PathBuf::from(source_dir).join("data").join("1.hint")).unwrap();
+        for audit in [HintAudit::Sample(1), HintAudit::Full] {
+            std::fs::write(&hint, &stale).unwrap();
+            let db = Database::open(dir, Options::default().hint_audit(audit)).unwrap();
+            let report = db.hint_audit();
+            assert_eq!(report.failed, vec!["1".to_string()]);
+            assert!(report.hints_checked >= 1 && report.entries_checked >= 2);
+            assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"4");
+            assert_eq!(db.read(b"b").unwrap().unwrap().as_slice(), b"3");
+        }
+        // sound hints pass
+        let db = Database::open(dir, Options::default()).unwrap();
+        db.rebuild_hints().unwrap();
+        drop(db);
+        let db = Database::open(dir, Options::default().hint_audit(HintAudit::Full)).unwrap();
+        assert!(db.hint_audit().failed.is_empty());
+        assert!(db.hint_audit().hints_checked >= 1);
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.hint_audit().hints_checked, 0);
+    }
+
+    #[test]
+    fn test_open_timings() {
+        let dir = "testdata/open_timings";
+        let _ = std::fs::remove_dir_all(dir);
+        for i in 0..2 {
+            let mut db = Database::open(dir, Options::default()).unwrap();
+            db.write(format!("key{}", i), b"value").unwrap();
+            if i == 1 {
+                db.rebuild_hints().unwrap();
+            }
+        }
+        let db = Database::open(dir, Options::default()).unwrap();
+        let timings = db.open_timings();
+        let segments: Vec<(&str, bool)> = timings.segments.iter().map(|s| (s.segment.as_str(), s.from_hint)).collect();
+        assert_eq!(segments, vec![("1", true), ("2", false)]);
+        assert!(timings.total >= timings.index_load + timings.merge_adoption + timings.hint_audit);
+        assert!(timings.index_load >= timings.segments.iter().map(|s| s.duration).sum());
+    }
+
+    #[test]
+    fn test_merge_source_order() {
+        use crate::storage::segment::Segment;
+        let dir = "testdata/merge_source_order";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        for key in ["b", "a", "c", "a"] {
+            db.write(key, key).unwrap();
+        }
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        db.merge().unwrap();
+        // live records are copied in the order they were written, not in key order
+        let merged = Segment::open_read_only(PathBuf::from(dir).join("data").join("1-1.seg"));
+        let keys: Vec<Bytes> = merged.iter().map(|ri| ri.key).collect();
+        assert_eq!(keys, vec![Bytes::from("b"), Bytes::from("c"), Bytes::from("a")]);
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"a");
+    }
+
+    #[test]
+    fn test_merge_finish_manifest() {
+        let dir = "testdata/merge_finish_manifest";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"a", b"1").unwrap();
+        drop(db);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"a", b"2").unwrap();
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        merge_unadopted(&db);
+        drop(db);
+        let finish_path = PathBuf::from(dir).join("merged").join("merge-finish");
+        let manifest = std::fs::read_to_string(&finish_path).unwrap();
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines[..5], ["merge-finish v2", "generation 1", "source 1", "source 2", "source 3"]);
+        assert_eq!(lines[5], "output 1-1.seg 24");
+        assert!(lines[6].starts_with("output 1-1.hint "));
+        assert!(lines[7].starts_with("crc32c "));
+
+        // a damaged manifest is not adopted, nor is one whose output is missing
+        std::fs::write(&finish_path, manifest.replace("source 2", "source 9")).unwrap();
+        let err = Database::open(dir, Options::default()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })));
+        std::fs::write(&finish_path, manifest.replace("output 1-1.seg 24", "output 1-1.seg 25")).unwrap();
+        assert!(Database::open(dir, Options::default()).is_err());
+        assert!(PathBuf::from(dir).join("data").join("1.seg").exists());
+
+        std::fs::write(&finish_path, &manifest).unwrap();
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"2");
+        let data_dir = PathBuf::from(dir).join("data");
+        assert!(!data_dir.join("1.seg").exists() && !data_dir.join("2.seg").exists());
+        assert!(data_dir.join("1-1.seg").exists() && data_dir.join("4.seg").exists());
+    }
+
+    #[test]
+    fn test_merge_adoption_renames() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = "testdata/merge_adoption_renames";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"a", b"1").unwrap();
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        merge_unadopted(&db);
+        drop(db);
+        let inode = |path: PathBuf| std::fs::metadata(path).unwrap().ino();
+        let merged = inode(PathBuf::from(dir).join("merged").join("1-1.seg"));
+        let db = Database::open(dir, Options::default()).unwrap();
+        // merged output is moved into data dir, not copied
+        assert_eq!(inode(PathBuf::from(dir).join("data").join("1-1.seg")), merged);
+        assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"1");
+    }
+
+    #[test]
+    fn test_mmap_max_segment_bytes() {
+        let dir = "testdata/mmap_max_segment_bytes";
+        let _ = std::fs::remove_dir_all(dir);
+        for value in [vec![b'a'; 10], vec![b'b'; 1000]] {
+            let mut db = Database::open(dir, Options::default()).unwrap();
+            db.write(&value[..1], &value).unwrap();
+        }
+        let db = Database::open(dir, Options::default().mmap_max_segment_bytes(100)).unwrap();
+        // the small segment is read from mmap, the large one by pread
+        let before = db.io_stats();
+        assert_eq!(db.read(b"a").unwrap().unwrap().len(), 10);
+        let after_small = db.io_stats();
+        assert_eq!((after_small.mmap_reads - before.mmap_reads, after_small.read_calls - before.read_calls), (1, 0));
+        assert_eq!(db.read(b"b").unwrap().unwrap().len(), 1000);
+        let after_large = db.io_stats();
+        assert_eq!(after_large.mmap_reads, after_small.mmap_reads);
+        assert!(after_large.read_calls > after_small.read_calls);
+    }
+
+    #[test]
+    fn test_active_segment_remap() {
+        let dir = "testdata/active_segment_remap";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        let value = vec![b'v'; 100 * 1024];
+        for i in 0..50 {
+            db.write(format!("key{}", i), &value).unwrap();
+        }
+        // the first 4MB of active segment are mapped, later records are read by pread
+        let before = db.io_stats();
+        assert_eq!(db.read(b"key0").unwrap().unwrap().len(), value.len());
+        let after_mapped = db.io_stats();
+        assert_eq!(after_mapped.mmap_reads - before.mmap_reads, 1);
+        assert_eq!(after_mapped.read_calls, before.read_calls);
+        assert_eq!(db.read(b"key49").unwrap().unwrap().len(), value.len());
+        assert_eq!(db.io_stats().mmap_reads, after_mapped.mmap_reads);
+
+        drop(db);
+        let mut db = Database::open(dir, Options::default().mmap(false)).unwrap();
+        for i in 0..50 {
+            db.write(format!("key{}", i), &value).unwrap();
+        }
+        db.read(b"key0").unwrap().unwrap();
+        assert_eq!(db.io_stats().mmap_reads, 0);
+    }
+
+    #[test]
+    fn test_raw_segment_reader() {
+        let dir = "testdata/raw_segment_reader";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"a", b"1").unwrap();
+        db.write(b"b", b"2").unwrap();
+        db.delete(b"a").unwrap();
+        drop(db);
+        let path = PathBuf::from(dir).join("data").join("1.seg");
+        let reader = SegmentReader::open(&path).unwrap();
+        assert_eq!(reader.name(), "1");
+        let mut records = reader.records();
+        let read: Vec<_> = records.by_ref().map(|record| record.unwrap()).collect();
+        assert_eq!(records.end_offset(), std::fs::metadata(&path).unwrap().len());
+        let keys: Vec<_> = read.iter().map(|record| (record.key.to_vec(), record.deleted)).collect();
+        assert_eq!(keys, vec![(b"a".to_vec(), false), (b"b".to_vec(), false), (b"a".to_vec(), true)]);
+        assert_eq!(read[0].offset, SEGMENT_HEADER_BYTES);
+        assert_eq!(reader.read_at(read[1].offset).unwrap(), read[1]);
+        // a flipped value byte fails the checksum
+        let mut content = std::fs::read(&path).unwrap();
+        let start = read[1].offset as usize;
+        let value_at = start + content[start..].windows(2).position(|w| w == b"b2").unwrap() + 1;
+        content[value_at] = b'3';
+        std::fs::write(&path, content).unwrap();
+        let reader = SegmentReader::open(&path).unwrap();
+        let err = reader.read_at(read[1].offset).unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+    }
+
+    #[test]
+    fn test_append_raw() {
+        let dir = "testdata/append_raw";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"a", b"1").unwrap();
+        let first = db.append_raw(b"a", b"raw 1", 7).unwrap();
+        let second = db.append_raw(b"bucket", b"raw 2", 0).unwrap();
+        db.write(b"b", b"2").unwrap();
+        assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"1");
+        assert!(db.read(b"bucket").unwrap().is_none());
+        let record = db.read_location(&first).unwrap();
+        assert_eq!((record.key.as_slice(), record.value.as_slice(), record.metadata), (&b"a"[..], &b"raw 1"[..], 7));
+        drop(db);
+        // raw records are not loaded into the keydir but stay readable
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.index_stats().entries, 2);
+        assert_eq!(db.read(b"a").unwrap().unwrap().as_slice(), b"1");
+        assert_eq!(db.read_location(&second).unwrap().value.as_slice(), b"raw 2");
+        let header = Location { offset: 0, ..second };
+        assert!(db.read_location(&header).is_err());
+    }
+
+    #[test]
+    fn test_delete_where() {
+        let dir = "testdata/delete_where";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        // more keys than one walk batch
+        for i in 0..3000 {
+            let tenant = if i % 3 == 0 { "x" } else { "y" };
+            db.write(format!("{}/{:05}", tenant, i), format!("{}", i % 2)).unwrap();
+        }
+        assert_eq!(db.delete_where(|key| key.as_slice().starts_with(b"x/")).unwrap(), 1000);
+        assert_eq!(db.index_stats().entries, 2000);
+        assert!(db.read(b"x/00000").unwrap().is_none());
+        assert_eq!(db.delete_where_value(|_, value| value.as_slice() == b"1").unwrap(), 1000);
+        assert!(db.read(b"y/00001").unwrap().is_none());
+        assert_eq!(db.read(b"y/00002").unwrap().unwrap().as_slice(), b"0");
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.index_stats().entries, 1000);
+    }
+
+    #[test]
+    fn test_persistent_index() {
+        let dir = "testdata/persistent_index";
+        let _ = std::fs::remove_dir_all(dir);
+        let options = || Options::default().persistent_index(true);
+        let mut db = Database::open(dir, options()).unwrap();
+        for i in 0..200 {
+            db.write(format!("key{:03}", i), format!("v{}", i)).unwrap();
+        }
+        db.delete(b"key000").unwrap();
+        db.checkpoint_index().unwrap();
+        // changes after the checkpoint are replayed by open
+        db.write(b"key001", b"new").unwrap();
+        db.delete(b"key002").unwrap();
+        db.write(b"key200", b"v200").unwrap();
+        drop(db);
+        let check = |db: &Database| {
+            assert_eq!(db.index_stats().entries, 199);
+            assert!(db.read(b"key000").unwrap().is_none());
+            assert_eq!(db.read(b"key001").unwrap().unwrap().as_slice(), b"new");
+            assert!(db.read(b"key002").unwrap().is_none());
+            assert_eq!(db.read(b"key150").unwrap().unwrap().as_slice(), b"v150");
+            let (page, _) = db.scan(b"key", None, 3).unwrap();
+            let keys: Vec<_> = page.iter().map(|(key, _)| key.to_vec()).collect();
+            assert_eq!(keys, vec![b"key001".to_vec(), b"key003".to_vec(), b"key004".to_vec()]);
+        };
+        let mut db = Database::open(dir, options()).unwrap();
+        check(&db);
+        db.delete(b"key150").unwrap();
+        db.write(b"key150", b"again").unwrap();
+        assert_eq!(db.index_stats().entries, 199);
+        db.merge().unwrap();
+        drop(db);
+        // merge adoption makes the checkpoint stale, plain open ignores it
+        for options in [options(), Options::default()] {
+            let db = Database::open(dir, options).unwrap();
+            assert_eq!(db.read(b"key150").unwrap().unwrap().as_slice(), b"again");
+        }
+        let path = PathBuf::from(dir).join("KEYDIR");
+        let mut db = Database::open(dir, options()).unwrap();
+        let checkpoint = std::fs::read(&path).unwrap();
+        db.write(b"key150", b"v150").unwrap();
+        drop(db);
+        // drop checkpoints changes kept in memory
+        assert_ne!(std::fs::read(&path).unwrap(), checkpoint);
+        // a damaged block fails lookups in it, drop removes the checkpoint and the next open rebuilds it
+        let mut content = std::fs::read(&path).unwrap();
+        content[0] ^= 1;
+        std::fs::write(&path, content).unwrap();
+        let db = Database::open(dir, options()).unwrap();
+        let err = db.read(b"key001").unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::Corruption { .. })), "{}", err);
+        assert_eq!(db.read(b"key150").unwrap().unwrap().as_slice(), b"v150");
+        drop(db);
+        assert!(!path.exists());
+        check(&Database::open(dir, options()).unwrap());
+        // a damaged footer is found by open, which rebuilds the checkpoint
+        let mut content = std::fs::read(&path).unwrap();
+        let len = content.len();
+        content[len - 20] ^= 1;
+        std::fs::write(&path, content).unwrap();
+        check(&Database::open(dir, options()).unwrap());
+        assert!(Database::open(dir, options().lazy_index(true)).is_err());
+
+        // a write checkpoints once checkpoint_every changes are kept in memory
+        let mut db = Database::open(dir, options().checkpoint_every(10)).unwrap();
+        let checkpoint = std::fs::read(&path).unwrap();
+        for i in 0..10 {
+            db.write(format!("key{:03}", i), b"again").unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), checkpoint);
+        db.write(b"key010", b"again").unwrap();
+        assert_ne!(std::fs::read(&path).unwrap(), checkpoint);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = "testdata/bulk_load";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        db.write(b"key00010", b"old").unwrap();
+        let pairs = (0..5000).map(|i| (format!("key{:05}", i), format!("value{}", i)));
+        assert_eq!(db.bulk_load(pairs).unwrap(), 5000);
+        assert_eq!(db.read(b"key00010").unwrap().unwrap().as_slice(), b"value10");
+        assert_eq!(db.index_stats().entries, 5000);
+        // keys out of order reject the whole input
+        let unsorted = [(&b"b"[..], &b"1"[..]), (b"a", b"2")];
+        assert!(db.bulk_load(unsorted).is_err());
+        assert!(db.read(b"b").unwrap().is_none());
+        let mut files = std::fs::read_dir(PathBuf::from(dir).join("data")).unwrap();
+        assert!(files.all(|entry| entry.unwrap().path().extension().unwrap_or_default() != "ingest"));
+        drop(db);
+        // the loaded segment is indexed from its hint
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.read(b"key04999").unwrap().unwrap().as_slice(), b"value4999");
+        let loaded = db.open_timings().segments.iter().find(|timing| timing.segment == "2").unwrap();
+        assert!(loaded.from_hint);
+    }
+
+    #[test]
+    fn test_ingest_rotation() {
+        use crate::storage::directory::Directory;
+        let dir = "testdata/ingest_rotation";
+        let data_dir = PathBuf::from(dir).join("data");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let value = vec![b'v'; 1024];
+        let records = (0..4000).map(|i| Ok((format!("key{:05}", i).into_bytes(), value.clone())));
+        // an ingested segment is full once a batch of about 1MB reached 1MB
+        let storage = Directory::open(data_dir.to_str().unwrap(), None, false, ChecksumAlgorithm::default()).unwrap();
+        let indexes = storage.ingest_into(records, 1024 * 1024).unwrap();
+        drop(storage);
+        let segments: Vec<&str> = indexes.chunk_by(|a, b| a.segment == b.segment).map(|r| &*r[0].segment).collect();
+        assert!(segments.len() >= 3, "{:?}", segments);
+        for segment in segments.iter() {
+            let path = data_dir.join(format!("{}.seg", segment));
+            assert!(std::fs::metadata(&path).unwrap().len() < 3 * 1024 * 1024);
+        }
+        assert!(indexes.windows(2).all(|w| w[0].key.as_slice() < w[1].key.as_slice()));
+        let mut files = std::fs::read_dir(&data_dir).unwrap();
+        assert!(files.all(|entry| entry.unwrap().path().extension().unwrap_or_default() != "ingest"));
+        let db = Database::open(dir, Options::default()).unwrap();
+        assert_eq!(db.index_stats().entries, 4000);
+        assert_eq!(db.read(b"key03999").unwrap().unwrap().as_slice(), value.as_slice());
+    }
+
+    #[test]
+    fn test_key_metadata() {
+        let dir = "testdata/key_metadata";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default().inline_values(4)).unwrap();
+        db.write(b"a", b"small").unwrap();
+        db.write(b"b", b"tiny").unwrap();
+        let a = db.metadata(b"a").unwrap().unwrap();
+        assert_eq!((a.segment.as_str(), a.offset, a.value_size), ("1", SEGMENT_HEADER_BYTES, 5));
+        assert!(!a.inlined && a.timestamp_secs > 0);
+        assert_eq!(a.checksum, ChecksumAlgorithm::Crc32c);
+        let b = db.metadata(b"b").unwrap().unwrap();
+        assert!(b.inlined && b.version > a.version);
+        assert_eq!(b.flag, a.flag);
+        assert!(db.metadata(b"c").unwrap().is_none());
+        db.delete(b"a").unwrap();
+        assert!(db.metadata(b"a").unwrap().is_none());
+        drop(db);
+
+        // merged output is indexed from its hints, which keep the timestamp of the merged segment
+        let seg_path = PathBuf::from(dir).join("data").join("1.seg");
+        let written_at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::options().write(true).open(&seg_path).unwrap().set_modified(written_at).unwrap();
+        let db = Database::open(dir, Options::default()).unwrap();
+        db.merge().unwrap();
+        drop(db);
+        let db = Database::open(dir, Options::default()).unwrap();
+        let b = db.metadata(b"b").unwrap().unwrap();
+        assert_ne!(b.segment, "1");
+        assert_eq!(b.timestamp_secs, 1_000_000);
+        // merged again, records keep their own timestamp rather than the one of the former output
+        db.merge().unwrap();
+        assert_eq!(db.metadata(b"b").unwrap().unwrap().timestamp_secs, 1_000_000);
+        drop(db);
+
+        // the checksum is the one of the record, not the one of the hint or of the database
+        let mut db = Database::open(dir, Options::default().checksum(ChecksumAlgorithm::Xxh64)).unwrap();
+        db.write(b"c", b"xx").unwrap();
+        assert_eq!(db.metadata(b"c").unwrap().unwrap().checksum, ChecksumAlgorithm::Xxh64);
+        assert_eq!(db.metadata(b"b").unwrap().unwrap().checksum, ChecksumAlgorithm::Crc32c);
+    }
+
+    #[test]
+    fn test_value_size_histogram() {
+        let dir = "testdata/value_size_histogram";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default()).unwrap();
+        for (key, size) in [("a", 0), ("b", 1), ("c", 3), ("d", 100), ("e", 100), ("f", 5000)] {
+            db.write(key, vec![b'x'; size]).unwrap();
+        }
+        // only live values count
+        db.write(b"f", vec![b'x'; 2]).unwrap();
+        db.delete(b"e").unwrap();
+        let check = |db: &Database| {
+            let stats = db.stats().unwrap();
+            assert_eq!(stats.keys, 5);
+            assert_eq!(stats.value_sizes.buckets, vec![1, 1, 2, 0, 0, 0, 0, 1]);
+            assert_eq!(stats.value_sizes.quantile(0.5), 3);
+            assert_eq!(stats.value_sizes.quantile(1.0), 127);
+        };
+        check(&db);
+        db.merge().unwrap();
+        drop(db);
+        check(&Database::open(dir, Options::default()).unwrap());
+    }
+
+    #[test]
+    fn test_blob_segments() {
+        let dir = "testdata/blob_segments";
+        let _ = std::fs::remove_dir_all(dir);
+        let data_dir = PathBuf::from(dir).join("data");
+        let files = |ext: &str| -> Vec<PathBuf> {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(&data_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|e| e == ext))
+                .collect();
+            files.sort();
+            files
+        };
+        let options = || Options::default().blob_threshold(1024).blob_garbage_ratio(0.4);
+        let (big, big2) = (vec![b'x'; 4096], vec![b'y'; 4096]);
+        {
+            let mut db = Database::open(dir, options()).unwrap();
+            db.write(b"big", &big).unwrap();
+            db.write(b"small", b"v").unwrap();
+            db.write(b"big2", &big2).unwrap();
+            assert_eq!(db.read(b"big").unwrap().unwrap().as_slice(), big.as_slice());
+            // both blobs are in one blob segment, the log holds pointers
+            let blobs = files("blob");
+            assert_eq!(blobs.len(), 1);
+            let blob_name = blobs[0].file_stem().unwrap().to_str().unwrap().to_string();
+            assert_eq!(db.metadata(b"big").unwrap().unwrap().segment, blob_name);
+            let mut values: Vec<(Bytes, usize)> = Vec::new();
+            db.for_each(|key, value| {
+                values.push((key.clone(), value.len()));
+                Ok(())
+            })
+            .unwrap();
+            values.sort();
+            assert_eq!(values, vec![(Bytes::from("big"), 4096), (Bytes::from("big2"), 4096), (Bytes::from("small"), 1)]);
+            // merge copies pointers but not blobs
+            db.merge().unwrap();
+        }
+        let mut db = Database::open(dir, options()).unwrap();
+        assert_eq!(db.read(b"big2").unwrap().unwrap().as_slice(), big2.as_slice());
+        let log_bytes: u64 = files("seg").iter().map(|path| std::fs::metadata(path).unwrap().len()).sum();
+        assert!(log_bytes < 4096, "{}", log_bytes);
+        // garbage below the ratio is left alone
+        assert_eq!(db.compact_blobs().unwrap().segments_compacted, 0);
+        db.write(b"big", b"now small").unwrap();
+        let version = db.read_with_version(b"big2").unwrap().unwrap().1;
+        let old_blob = files("blob").remove(0);
+        let stats = db.compact_blobs().unwrap();
+        assert_eq!((stats.segments_compacted, stats.blobs_moved), (1, 1));
+        assert!(stats.bytes_reclaimed > 4096);
+        assert!(!old_blob.exists());
+        assert_eq!(db.read_with_version(b"big2").unwrap().unwrap(), (Bytes::from(big2.clone()), version));
+        drop(db);
+        // the moved blob is found by its newer pointer
+        let db = Database::open(dir, options()).unwrap();
+        assert_eq!(db.read(b"big2").unwrap().unwrap().as_slice(), big2.as_slice());
+        assert_eq!(db.read(b"big").unwrap().unwrap().as_slice(), b"now small");
+        assert_eq!(files("blob").len(), 1);
+    }
+
+    #[test]
+    fn test_hint_audit_after_merge() {
+        let dir = "testdata/hint_audit_after_merge";
+        let _ = std::fs::remove_dir_all(dir);
+        let options = || Options::default().checksum(ChecksumAlgorithm::Xxh64).blob_threshold(100);
+        {
+            let mut db = Database::open(dir, options()).unwrap();
+            db.write(b"a", b"1").unwrap();
+            db.write(b"big", vec![b'x'; 200]).unwrap();
+            db.write(b"b", b"2").unwrap();
+            db.delete(b"b").unwrap();
+            db.merge().unwrap();
+        }
+        // merged hints are written by the hint segment's checksum, records and pointers by others
+        for audit in [HintAudit::Sample(1), HintAudit::Full] {
+            let db = Database::open(dir, options().hint_audit(audit)).unwrap();
+            assert!(db.hint_audit().failed.is_empty(), "{:?}", db.hint_audit().failed);
+            assert!(db.hint_audit().hints_checked >= 1);
+            assert_eq!(db.read(b"big").unwrap().unwrap().len(), 200);
+        }
+    }
+
+    #[test]
+    fn test_blob_batches() {
+        let dir = "testdata/blob_batches";
+        let _ = std::fs::remove_dir_all(dir);
+        let blob_count = || {
+            std::fs::read_dir(PathBuf::from(dir).join("data"))
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|e| e == "blob"))
+                .count()
+        };
+        let options = || Options::default().blob_threshold(1024);
+        let big = vec![b'x'; 2048];
+        let mut db = Database::open(dir, options()).unwrap();
+        db.write_many(&[(b"a", b"1"), (b"big", &big), (b"b", b"2")]).unwrap();
+        assert_eq!(blob_count(), 1);
+        let writer = db.into_async(16);
+        let handle = writer.write(b"async-big", vec![b'y'; 4096]).unwrap();
+        handle.wait().unwrap();
+        let db = writer.close().unwrap();
+        drop(db);
+        // pointers in a batch are loaded with it
+        let db = Database::open(dir, options()).unwrap();
+        assert_eq!(db.read(b"big").unwrap().unwrap().as_slice(), big.as_slice());
+        assert_eq!(db.read(b"async-big").unwrap().unwrap().len(), 4096);
+        assert_eq!(db.read(b"b").unwrap().unwrap().as_slice(), b"2");
+        let log_bytes: u64 = std::fs::read_dir(PathBuf::from(dir).join("data"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "seg"))
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum();
+        assert!(log_bytes < 2048, "{}", log_bytes);
+    }
+
+    #[test]
+    fn test_blob_read_repair() {
+        let dir = "testdata/blob_read_repair";
+        let _ = std::fs::remove_dir_all(dir);
+        let options = || Options::default().blob_threshold(1024);
+        {
+            let mut db = Database::open(dir, options()).unwrap();
+            db.write(b"big", b"small").unwrap();
+            db.write(b"big", vec![b'x'; 2048]).unwrap();
+        }
+        let mut db = Database::open(dir, options()).unwrap();
+        db.write(b"big", vec![b'y'; 2048]).unwrap();
+        // the blob segment of x is garbage only and goes
+        assert_eq!(db.compact_blobs().unwrap().segments_compacted, 1);
+        let blob = PathBuf::from(dir).join("data").join(format!("{}.blob", db.metadata(b"big").unwrap().unwrap().segment));
+        drop(db);
+        let mut data = std::fs::read(&blob).unwrap();
+        let i = data.windows(4).position(|w| w == b"yyyy").unwrap();
+        data[i] ^= 0xff;
+        std::fs::write(&blob, &data).unwrap();
+        // the pointer to the removed blob is skipped, the copy before it answers
+        let db = Database::open(dir, options().mmap(false).read_repair(true)).unwrap();
+        assert_eq!(db.read(b"big").unwrap().unwrap().as_slice(), b"small");
+        assert_eq!(db.repaired_reads(), 1);
+    }
+
+    #[test]
+    fn test_blob_follower() {
+        let dir = "testdata/blob_follower";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut db = Database::open(dir, Options::default().blob_threshold(1024).blob_garbage_ratio(0.3)).unwrap();
+        db.write(b"big1", vec![b'x'; 2048]).unwrap();
+        db.write(b"big2", vec![b'y'; 2048]).unwrap();
+        let follower = Follower::open(dir, None).unwrap();
+        assert_eq!(follower.read(b"big1").unwrap().unwrap().len(), 2048);
+        // big1 moves to a new blob segment, the one the follower knows is removed
+        db.write(b"big2", b"small").unwrap();
+        assert_eq!(db.compact_blobs().unwrap().blobs_moved, 1);
+        assert_eq!(follower.read(b"big1").unwrap().unwrap().as_slice(), vec![b'x'; 2048].as_slice());
+        assert_eq!(follower.read(b"big2").unwrap().unwrap().as_slice(), b"small");
+    }
+
+    #[test]
+    fn test_async_writer_partial_batch() {
+        let dir = "testdata/async_writer_partial_batch";
+        let _ = std::fs::remove_dir_all(dir);
+        let writer = Database::open(dir, Options::default().key_prefix("ok")).unwrap().into_async(4096);
+        // the writer thread takes what is queued as one batch, a failed run does not fail the runs before it
+        let writes: Vec<_> = (0..1000).map(|i| writer.write(format!("ok{}", i), b"v").unwrap()).collect();
+        let failed = writer.delete(b"other").unwrap();
+        let after = writer.write(b"ok-after", b"v").unwrap();
+        for handle in writes {
+            assert!(handle.wait().unwrap() > 0);
+        }
+        let err = failed.wait().unwrap_err();
+        assert!(matches!(err.downcast_ref::<StoreError>(), Some(StoreError::NotIndexed(_))), "{}", err);
+        // a run after the failed one is not applied if it was in its batch
+        let wrote_after = after.wait().is_ok();
+        assert_eq!(writer.read(b"ok-after").unwrap().is_some(), wrote_after);
+        assert_eq!(writer.read(b"ok999").unwrap().unwrap().as_slice(), b"v");
+    }
 }
@@ -1,2 +1,7 @@
 pub(crate) mod varint;
-pub(crate) mod utils;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub(crate) mod utils;
+pub(crate) mod tar;
+pub(crate) mod clock;
+pub(crate) mod throttle;
+pub(crate) mod xxhash;
@@ -0,0 +1,134 @@
+use anyhow::Result;
+
+use crate::error::invalid_input;
+use std::io::{self, Read, Write};
+
+/*
+ * Minimal ustar archive support, only regular files with names shorter than 100 bytes:
+ * | Header(512B) | Content | Padding to 512B | ... | Zero Block(512B) | Zero Block(512B) |
+ * A size of 8GiB or more does not fit the 11 octal digits of its field, it is written in the GNU base-256
+ * form: the high bit of the first byte is set and the size follows as big endian binary.
+ */
+
+const BLOCK: usize = 512;
+
+pub(crate) struct TarWriter<W: Write> {
+    w: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub(crate) fn new(w: W) -> Self {
+        Self { w }
+    }
+
+    // append writes exactly size bytes from content as a file named name
+    pub(crate) fn append<R: Read>(&mut self, name: &str, size: u64, content: &mut R) -> Result<()> {
+        if name.len() >= 100 {
+            return Err(invalid_input(format!("name too long for archive: {}", name)));
+        }
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], 0o644)?;
+        write_octal(&mut header[108..116], 0)?; // uid
+        write_octal(&mut header[116..124], 0)?; // gid
+        write_size(&mut header[124..136], size);
+        write_octal(&mut header[136..148], 0)?; // mtime
+        header[156] = b'0'; // regular file
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // checksum is calculated with checksum field filled by spaces
+        header[148..156].copy_from_slice(&[b' '; 8]);
+        let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+        write_octal(&mut header[148..155], checksum)?;
+        self.w.write_all(&header)?;
+
+        let copied = io::copy(&mut content.take(size), &mut self.w)?;
+        if copied != size {
+            return Err(invalid_input(format!("{} is shorter than expected", name)));
+        }
+        self.w.write_all(&vec![0u8; padding(size)])?;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<W> {
+        self.w.write_all(&[0u8; BLOCK * 2])?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+// read_entries calls visit with name and content of every file in archive
+pub(crate) fn read_entries<R, F>(mut r: R, mut visit: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&str, &mut dyn Read) -> Result<()>,
+{
+    let mut header = [0u8; BLOCK];
+    loop {
+        r.read_exact(&mut header)?;
+        if header.iter().all(|&b| b == 0) {
+            // end of archive
+            return Ok(());
+        }
+        let stored_checksum = read_octal(&header[148..156])?;
+        let mut checksum_header = header;
+        checksum_header[148..156].copy_from_slice(&[b' '; 8]);
+        let checksum: u64 = checksum_header.iter().map(|&b| b as u64).sum();
+        if checksum != stored_checksum {
+            return Err(invalid_input("archive header checksum mismatch"));
+        }
+        let name_end = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let name = std::str::from_utf8(&header[..name_end])?.to_string();
+        let size = read_size(&header[124..136])?;
+        let mut content = (&mut r).take(size);
+        visit(&name, &mut content)?;
+        // skip unread content and padding
+        io::copy(&mut content, &mut io::sink())?;
+        io::copy(&mut (&mut r).take(padding(size) as u64), &mut io::sink())?;
+    }
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size as usize % BLOCK)) % BLOCK
+}
+
+// write_octal fills field with zero padded octal digits and a trailing NUL, v must fit the digits
+fn write_octal(field: &mut [u8], v: u64) -> Result<()> {
+    let digits = field.len() - 1;
+    let s = format!("{:0width$o}", v, width = digits);
+    if s.len() > digits {
+        return Err(invalid_input(format!("{} does not fit {} octal digits", v, digits)));
+    }
+    field[..digits].copy_from_slice(s.as_bytes());
+    field[digits] = 0;
+    Ok(())
+}
+
+// write_size writes size in octal, or in base-256 if it does not fit, see above
+pub(crate) fn write_size(field: &mut [u8], size: u64) {
+    if write_octal(field, size).is_err() {
+        field.fill(0);
+        field[0] = 0x80;
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&size.to_be_bytes());
+    }
+}
+
+pub(crate) fn read_size(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 == 0 {
+        return read_octal(field);
+    }
+    let (high, low) = field.split_at(field.len() - 8);
+    if high[0] & 0x7f != 0 || high[1..].iter().any(|&b| b != 0) {
+        return Err(invalid_input("archive entry size is out of range"));
+    }
+    Ok(u64::from_be_bytes(low.try_into().unwrap()))
+}
+
+fn read_octal(field: &[u8]) -> Result<u64> {
+    let s = std::str::from_utf8(field)?.trim_matches(|c: char| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(s, 8)?)
+}
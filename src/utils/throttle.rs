@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+
+// RateLimiter is a token bucket of bytes, it holds at most one second of tokens as burst.
+// It uses monotonic time rather than Clock, a paused SimClock would block writers forever.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.bytes_per_sec as f64);
+        self.last_refill = now;
+    }
+
+    // try_acquire takes n tokens if available, a request larger than burst is granted once the bucket is full
+    pub(crate) fn try_acquire(&mut self, n: u64) -> bool {
+        self.refill();
+        let needed = n.min(self.bytes_per_sec) as f64;
+        if self.tokens < needed {
+            return false;
+        }
+        self.tokens -= n as f64; // may go negative for large requests, later writers pay for it
+        true
+    }
+
+    // acquire blocks until n tokens are taken
+    pub(crate) fn acquire(&mut self, n: u64) {
+        while !self.try_acquire(n) {
+            let needed = n.min(self.bytes_per_sec) as f64 - self.tokens;
+            std::thread::sleep(Duration::from_secs_f64(needed / self.bytes_per_sec as f64));
+        }
+    }
+}
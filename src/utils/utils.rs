@@ -1,4 +1,4 @@
-use std::ffi::OsStr;
+use std::{ffi::OsStr, fs::File, path::Path};
 
 
 pub(crate) fn os_str_to_string(src: Option<&OsStr>) -> String {
@@ -28,4 +28,16 @@ pub(crate) fn is_empty_file<P: AsRef<std::path::Path>>(path: P) -> bool {
         return metadata.len() == 0;
     }
     false
-}
\ No newline at end of file
+}
+
+// sync_dir makes creation, rename and removal of entries of dir durable
+pub(crate) fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// rename_durable moves a complete file into place, after a crash either the former file or the new one is there
+pub(crate) fn rename_durable(from: &Path, to: &Path) -> std::io::Result<()> {
+    crate::storage::fault::check("file.rename")?;
+    std::fs::rename(from, to)?;
+    sync_dir(to.parent().unwrap_or(Path::new(".")))
+}
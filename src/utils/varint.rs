@@ -1,8 +1,5 @@
-use anyhow::{Result, Ok};
-use memmap::Mmap;
-use std::{
-    io::{Read, Write},
-};
+use anyhow::{anyhow, Result, Ok};
+use std::io::Read;
 
 pub(crate) fn encode_varint_to_vec(mut v: u64) -> Result<Vec<u8>> {
     if v == 0 {
@@ -20,19 +17,20 @@ pub(crate) fn encode_varint_to_vec(mut v: u64) -> Result<Vec<u8>> {
     Ok(result)
 }
 
-pub(crate) fn encode_varint<W: Write>(v: u64, w: &mut W) -> Result<()> {
-    let vector = encode_varint_to_vec(v)?;
-    w.write_all(vector.as_slice())?;
-    Ok(())
-}
-
 pub(crate) fn decode_varint<R: Read>(r: &mut R) -> Result<(u64, u64)> {
     let mut result: u64 = 0;
     let mut shift: u64 = 0;
     let mut buf: [u8; 1] = [0];
     let mut read = 0;
     loop {
-        read += r.read(&mut buf)?;
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            return Err(anyhow!("unexpected end of varint"));
+        }
+        read += n;
+        if shift >= 64 {
+            return Err(anyhow!("varint overflows u64"));
+        }
         let byte = buf[0] as u64;
         result |= (byte & 0x7f) << shift;
         shift += 7;
@@ -43,12 +41,15 @@ pub(crate) fn decode_varint<R: Read>(r: &mut R) -> Result<(u64, u64)> {
     Ok((result, read as u64))
 }
 
-pub(crate) fn decode_varint_from_mmap(slice: &Mmap, i: &mut usize) -> Result<u64> {
+pub(crate) fn decode_varint_from_slice(slice: &[u8], i: &mut usize) -> Result<u64> {
     let mut result: u64 = 0;
     let mut shift: u64 = 0;
     loop {
-        let byte = slice[*i] as u64;
+        let byte = *slice.get(*i).ok_or_else(|| anyhow!("unexpected end of varint"))? as u64;
         (*i) += 1;
+        if shift >= 64 {
+            return Err(anyhow!("varint overflows u64"));
+        }
         result |= (byte & 0x7f) << shift;
         shift += 7;
         if byte & 0x80 == 0 {